@@ -0,0 +1,141 @@
+//! Arbitrary waveform generator personality: waveform download as IEEE 488.2 block data
+//! (`DATA:ARBitrary #<n><len><samples>`), `FREQuency` and `OUTPut`.
+//!
+//! Samples are little-endian `i16`. A block must fit in one command,
+//! [`MAX_SCPI_LEN`](embassy_usbtmc::MAX_SCPI_LEN) bytes with the header; longer waveforms
+//! are sent in pieces with `DATA:ARBitrary:APPend`.
+
+#![no_std]
+#![no_main]
+
+mod common;
+
+use core::panic::PanicInfo;
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
+
+use common::{common_command, reply, text};
+use embassy_executor::{Spawner, main};
+use embassy_usbtmc::params::{Limits, Params, Token, Unit, split_header};
+use embassy_usbtmc::{
+    SCPI_ERR_DATA_TYPE, SCPI_ERR_ILLEGAL_PARAMETER, SCPI_ERR_TOO_MUCH_DATA,
+    SCPI_ERR_UNDEFINED_HEADER, TmcConfig, cmd_receiver, header_matches, push_error, resp_sender,
+};
+use heapless::Vec;
+
+const IDN: &str = "YourCompany,AWG-1,123456,FW1.0";
+const MAX_POINTS: usize = 4096;
+
+const FREQUENCY: Limits = Limits {
+    min: 0.001,
+    max: 10e6,
+    default: 1e3,
+    unit: Some(Unit::Hertz),
+};
+
+struct Awg {
+    samples: Vec<i16, MAX_POINTS>,
+    frequency: f32,
+    output: bool,
+}
+
+impl Awg {
+    fn reset(&mut self) {
+        self.samples.clear();
+        self.frequency = FREQUENCY.default;
+        self.output = false;
+    }
+
+    /// Appends the samples of a block parameter.
+    fn load(&mut self, params: &[u8]) -> Result<(), i16> {
+        let block = match Params::new(params).next() {
+            Some(Ok(Token::Block(block))) => block,
+            Some(Err(code)) => return Err(code),
+            _ => return Err(SCPI_ERR_DATA_TYPE),
+        };
+        if block.len() % 2 != 0 {
+            return Err(SCPI_ERR_ILLEGAL_PARAMETER);
+        }
+        for sample in block.chunks_exact(2) {
+            self.samples
+                .push(i16::from_le_bytes([sample[0], sample[1]]))
+                .map_err(|_| SCPI_ERR_TOO_MUCH_DATA)?;
+        }
+        Ok(())
+    }
+}
+
+#[main]
+async fn main(spawner: Spawner) {
+    common::start(spawner, "RP2350 AWG", TmcConfig::default());
+
+    let cmd_rx = cmd_receiver();
+    let resp_tx = resp_sender();
+    let mut awg = Awg {
+        samples: Vec::new(),
+        frequency: 0.0,
+        output: false,
+    };
+    awg.reset();
+
+    loop {
+        let cmd = cmd_rx.receive().await;
+        if common_command(&cmd, IDN, &mut || awg.reset()) {
+            continue;
+        }
+
+        let (header, params) = split_header(text(&cmd));
+        let result = if header_matches(header, b"DATA:ARBitrary") {
+            awg.samples.clear();
+            awg.load(params)
+        } else if header_matches(header, b"DATA:ARBitrary:APPend") {
+            awg.load(params)
+        } else if header_matches(header, b"DATA:POINts?") {
+            let _ = resp_tx.try_send(reply(cmd.token, format_args!("{}\n", awg.samples.len())));
+            continue;
+        } else if header_matches(header, b"FREQuency") {
+            match Params::new(params).next() {
+                Some(token) => token
+                    .and_then(|token| FREQUENCY.resolve(token))
+                    .map(|hz| awg.frequency = hz),
+                None => Err(SCPI_ERR_ILLEGAL_PARAMETER),
+            }
+        } else if header_matches(header, b"FREQuency?") {
+            let mut resp = reply(cmd.token, format_args!(""));
+            match FREQUENCY.answer_query(params, &mut resp) {
+                Ok(true) => {}
+                Ok(false) => resp = reply(cmd.token, format_args!("{:E}\n", awg.frequency)),
+                Err(code) => {
+                    push_error(code);
+                    continue;
+                }
+            }
+            let _ = resp_tx.try_send(resp);
+            continue;
+        } else if header_matches(header, b"OUTPut") {
+            match Params::new(params).next() {
+                Some(Ok(Token::Chars(word))) if word.eq_ignore_ascii_case(b"ON") => {
+                    awg.output = true;
+                    Ok(())
+                }
+                Some(Ok(Token::Chars(word))) if word.eq_ignore_ascii_case(b"OFF") => {
+                    awg.output = false;
+                    Ok(())
+                }
+                _ => Err(SCPI_ERR_ILLEGAL_PARAMETER),
+            }
+        } else if header_matches(header, b"OUTPut?") {
+            let _ = resp_tx.try_send(reply(cmd.token, format_args!("{}\n", awg.output as u8)));
+            continue;
+        } else {
+            Err(SCPI_ERR_UNDEFINED_HEADER)
+        };
+
+        if let Err(code) = result {
+            push_error(code);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Board setup and the IEEE 488.2 common commands shared by the personality examples.
+
+use core::fmt::{self, Write};
+
+use embassy_executor::Spawner;
+use embassy_rp::bind_interrupts;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::{Driver, InterruptHandler};
+use embassy_time::Duration;
+use embassy_usb::{Builder, Config};
+use embassy_usbtmc::{
+    Command, MAX_SCPI_LEN, Response, ResponseToken, TmcConfig, UsbTmc, header_matches, pop_error,
+    resp_sender, run_device, take_event_status,
+};
+use heapless::Vec;
+use static_cell::StaticCell;
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+});
+
+type MyDriver = Driver<'static, USB>;
+
+/// Brings up USB with the class on it and spawns the device and class runners.
+pub fn start(spawner: Spawner, product: &'static str, tmc_config: TmcConfig) {
+    let p = embassy_rp::init(Default::default());
+    let driver = Driver::new(p.USB, Irqs);
+
+    let mut usb_config = Config::new(0x2E8A, 0x000A);
+    usb_config.manufacturer = Some("YourCompany");
+    usb_config.product = Some(product);
+    usb_config.serial_number = Some("123456");
+    usb_config.max_packet_size_0 = 64;
+    tmc_config.power.apply(&mut usb_config);
+
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+
+    let mut usb_builder = Builder::new(
+        driver,
+        usb_config,
+        CONFIG_DESCRIPTOR.init([0; 256]),
+        BOS_DESCRIPTOR.init([0; 256]),
+        &mut [],
+        CONTROL_BUF.init([0; 64]),
+    );
+
+    let tmc = UsbTmc::new(&mut usb_builder, tmc_config);
+    let usb = usb_builder.build();
+
+    spawner.spawn(usb_task(usb)).unwrap();
+    spawner.spawn(usbtmc_task(tmc)).unwrap();
+}
+
+#[embassy_executor::task]
+async fn usb_task(mut usb: embassy_usb::UsbDevice<'static, MyDriver>) {
+    run_device(&mut usb, Duration::from_millis(200)).await
+}
+
+#[embassy_executor::task]
+async fn usbtmc_task(tmc: UsbTmc<'static, MyDriver>) {
+    tmc.run().await
+}
+
+/// The program message text of a command.
+pub fn text(cmd: &Command) -> &[u8] {
+    &cmd.data[..cmd.len]
+}
+
+/// A complete response formatted from `args`, e.g. `reply(cmd.token, format_args!(...))`.
+/// Text that doesn't fit is cut short.
+pub fn reply(token: Option<ResponseToken>, args: fmt::Arguments) -> Response {
+    let mut resp = Response {
+        len: 0,
+        data: [0; MAX_SCPI_LEN],
+        segments: Vec::new(),
+        eom: true,
+        term_char_matched: false,
+        raw: false,
+        token,
+    };
+    let _ = Body(&mut resp).write_fmt(args);
+    resp
+}
+
+struct Body<'a>(&'a mut Response);
+
+impl Write for Body<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let resp = &mut *self.0;
+        let n = s.len().min(MAX_SCPI_LEN - resp.len);
+        resp.data[resp.len..resp.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        resp.len += n;
+        Ok(())
+    }
+}
+
+/// Answers the common commands every personality shares: `*IDN?`, `*OPC?`, `*ESR?`, `*CLS`,
+/// `*RST` (through `reset`) and `SYSTem:ERRor?`. Returns `false` for anything else.
+pub fn common_command(cmd: &Command, idn: &str, reset: &mut dyn FnMut()) -> bool {
+    let header = text(cmd).trim_ascii();
+    let resp = if header.eq_ignore_ascii_case(b"*IDN?") {
+        reply(cmd.token, format_args!("{idn}\n"))
+    } else if header.eq_ignore_ascii_case(b"*OPC?") {
+        reply(cmd.token, format_args!("1\n"))
+    } else if header.eq_ignore_ascii_case(b"*ESR?") {
+        reply(cmd.token, format_args!("{}\n", take_event_status()))
+    } else if header_matches(header, b"SYSTem:ERRor?")
+        || header_matches(header, b"SYSTem:ERRor:NEXT?")
+    {
+        match pop_error() {
+            Some(code) => reply(cmd.token, format_args!("{code},\"Error\"\n")),
+            None => reply(cmd.token, format_args!("0,\"No error\"\n")),
+        }
+    } else if header.eq_ignore_ascii_case(b"*CLS") {
+        while pop_error().is_some() {}
+        take_event_status();
+        return true;
+    } else if header.eq_ignore_ascii_case(b"*RST") {
+        reset();
+        return true;
+    } else {
+        return false;
+    };
+    let _ = resp_sender().try_send(resp);
+    true
+}
@@ -0,0 +1,154 @@
+//! Digital multimeter personality: `CONFigure`, `MEASure?` and `READ?` for DC voltage and
+//! current, with ranges resolved through [`Limits`] and acquisitions a device clear cancels.
+//!
+//! `acquire` stands in for the ADC; replace it with the real front end.
+
+#![no_std]
+#![no_main]
+
+mod common;
+
+use core::panic::PanicInfo;
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
+
+use common::{common_command, reply, text};
+use embassy_executor::{Spawner, main};
+use embassy_time::{Duration, Timer};
+use embassy_usbtmc::params::{Limits, Params, Unit, split_header};
+use embassy_usbtmc::{
+    SCPI_ERR_PARAMETER_NOT_ALLOWED, SCPI_ERR_UNDEFINED_HEADER, TmcConfig, abortable, cmd_receiver,
+    header_matches, is_query, push_error, resp_sender,
+};
+
+const IDN: &str = "YourCompany,DMM-1,123456,FW1.0";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Function {
+    Voltage,
+    Current,
+}
+
+impl Function {
+    fn limits(self) -> Limits {
+        match self {
+            Function::Voltage => Limits {
+                min: 0.1,
+                max: 1000.0,
+                default: 10.0,
+                unit: Some(Unit::Volt),
+            },
+            Function::Current => Limits {
+                min: 0.001,
+                max: 10.0,
+                default: 1.0,
+                unit: Some(Unit::Ampere),
+            },
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Function::Voltage => "VOLT:DC",
+            Function::Current => "CURR:DC",
+        }
+    }
+}
+
+struct Dmm {
+    function: Function,
+    range: f32,
+}
+
+impl Dmm {
+    fn reset(&mut self) {
+        self.function = Function::Voltage;
+        self.range = Function::Voltage.limits().default;
+    }
+
+    /// `CONFigure:<function>` and the setup half of `MEASure:<function>?`: an optional range
+    /// parameter, `MIN`/`MAX`/`DEF` included.
+    fn configure(&mut self, function: Function, params: &[u8]) -> Result<(), i16> {
+        let limits = function.limits();
+        let mut params = Params::new(params);
+        let range = match params.next() {
+            Some(token) => limits.resolve(token?)?,
+            None => limits.default,
+        };
+        if params.next().is_some() {
+            return Err(SCPI_ERR_PARAMETER_NOT_ALLOWED);
+        }
+        self.function = function;
+        self.range = range;
+        Ok(())
+    }
+}
+
+/// One reading on the present range.
+async fn acquire(dmm: &Dmm) -> f32 {
+    Timer::after(Duration::from_millis(100)).await;
+    dmm.range * 0.1234
+}
+
+#[main]
+async fn main(spawner: Spawner) {
+    common::start(spawner, "RP2350 DMM", TmcConfig::default());
+
+    let cmd_rx = cmd_receiver();
+    let resp_tx = resp_sender();
+    let mut dmm = Dmm {
+        function: Function::Voltage,
+        range: 0.0,
+    };
+    dmm.reset();
+
+    loop {
+        let cmd = cmd_rx.receive().await;
+        if common_command(&cmd, IDN, &mut || dmm.reset()) {
+            continue;
+        }
+
+        let (header, params) = split_header(text(&cmd));
+        let function = if header_matches(header, b"CONFigure:VOLTage:DC")
+            || header_matches(header, b"MEASure:VOLTage:DC?")
+        {
+            Some(Function::Voltage)
+        } else if header_matches(header, b"CONFigure:CURRent:DC")
+            || header_matches(header, b"MEASure:CURRent:DC?")
+        {
+            Some(Function::Current)
+        } else {
+            None
+        };
+
+        let result = if let Some(function) = function {
+            dmm.configure(function, params)
+        } else if header_matches(header, b"CONFigure?") {
+            let _ = resp_tx.try_send(reply(
+                cmd.token,
+                format_args!("\"{} {:E}\"\n", dmm.function.name(), dmm.range),
+            ));
+            continue;
+        } else if !header_matches(header, b"READ?") {
+            push_error(SCPI_ERR_UNDEFINED_HEADER);
+            continue;
+        } else {
+            Ok(())
+        };
+
+        if let Err(code) = result {
+            push_error(code);
+            continue;
+        }
+        if !is_query(header) {
+            continue;
+        }
+        // `MEASure?` and `READ?` take a reading; a device clear drops it mid-conversion.
+        if let Some(value) = abortable(acquire(&dmm)).await {
+            let _ = resp_tx.try_send(reply(cmd.token, format_args!("{value:E}\n")));
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Two-channel power supply personality: `VOLTage`, `CURRent` and `OUTPut<n>`, with
+//! `MIN`/`MAX`/`DEF` from [`Limits`] and a [`SafetyHook`] that switches the outputs off when
+//! the controller goes away.
+//!
+//! `drive` stands in for the regulator; replace it with the real DAC and enable pins.
+
+#![no_std]
+#![no_main]
+
+mod common;
+
+use core::panic::PanicInfo;
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use common::{common_command, reply, text};
+use embassy_executor::{Spawner, main};
+use embassy_time::Duration;
+use embassy_usbtmc::params::{Limits, Params, Token, Unit, split_header};
+use embassy_usbtmc::safety::{self, SafeStateReason, SafetyHook};
+use embassy_usbtmc::{
+    SCPI_ERR_ILLEGAL_PARAMETER, SCPI_ERR_UNDEFINED_HEADER, Suffix, TmcConfig, cmd_receiver,
+    header_matches, match_suffixed, push_error, resp_sender,
+};
+
+const IDN: &str = "YourCompany,PSU-2,123456,FW1.0";
+const CHANNELS: usize = 2;
+
+const VOLTAGE: Limits = Limits {
+    min: 0.0,
+    max: 30.0,
+    default: 0.0,
+    unit: Some(Unit::Volt),
+};
+const CURRENT: Limits = Limits {
+    min: 0.0,
+    max: 3.0,
+    default: 0.1,
+    unit: Some(Unit::Ampere),
+};
+
+/// `OUTPut` with no suffix is channel 1.
+const CHANNEL: Suffix = Suffix {
+    min: 1,
+    max: CHANNELS as u32,
+    default: 1,
+};
+
+/// Output enables, shared with the safety task.
+static OUTPUT_ON: [AtomicBool; CHANNELS] = [const { AtomicBool::new(false) }; CHANNELS];
+
+struct Psu {
+    voltage: f32,
+    current: f32,
+}
+
+impl Psu {
+    fn reset(&mut self) {
+        self.voltage = VOLTAGE.default;
+        self.current = CURRENT.default;
+        for on in &OUTPUT_ON {
+            on.store(false, Ordering::Relaxed);
+        }
+        drive(self);
+    }
+}
+
+/// Applies the settings to the hardware.
+fn drive(_psu: &Psu) {}
+
+struct OutputsOff;
+
+impl SafetyHook for OutputsOff {
+    async fn enter_safe_state(&mut self, _reason: SafeStateReason) {
+        for on in &OUTPUT_ON {
+            on.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn safety_task() {
+    safety::run(&mut OutputsOff, Some(Duration::from_secs(30))).await
+}
+
+/// Reads an `ON`/`OFF`/`1`/`0` parameter.
+fn boolean(params: &[u8]) -> Result<bool, i16> {
+    match Params::new(params).next() {
+        Some(Ok(Token::Chars(word))) if word.eq_ignore_ascii_case(b"ON") => Ok(true),
+        Some(Ok(Token::Chars(word))) if word.eq_ignore_ascii_case(b"OFF") => Ok(false),
+        Some(Ok(Token::Numeric(b"1"))) => Ok(true),
+        Some(Ok(Token::Numeric(b"0"))) => Ok(false),
+        Some(Err(code)) => Err(code),
+        _ => Err(SCPI_ERR_ILLEGAL_PARAMETER),
+    }
+}
+
+/// Sets `value` from the single parameter of a `VOLTage`/`CURRent` command.
+fn set_level(value: &mut f32, limits: &Limits, params: &[u8]) -> Result<(), i16> {
+    let token = Params::new(params)
+        .next()
+        .unwrap_or(Err(SCPI_ERR_ILLEGAL_PARAMETER))?;
+    *value = limits.resolve(token)?;
+    Ok(())
+}
+
+#[main]
+async fn main(spawner: Spawner) {
+    common::start(spawner, "RP2350 PSU", TmcConfig::default());
+    spawner.spawn(safety_task()).unwrap();
+
+    let cmd_rx = cmd_receiver();
+    let resp_tx = resp_sender();
+    let mut psu = Psu {
+        voltage: 0.0,
+        current: 0.0,
+    };
+    psu.reset();
+
+    loop {
+        let cmd = cmd_rx.receive().await;
+        if common_command(&cmd, IDN, &mut || psu.reset()) {
+            continue;
+        }
+
+        let (header, params) = split_header(text(&cmd));
+        let level = if header_matches(header, b"VOLTage?")
+            || header_matches(header, b"SOURce:VOLTage?")
+        {
+            Some((psu.voltage, &VOLTAGE))
+        } else if header_matches(header, b"CURRent?") || header_matches(header, b"SOURce:CURRent?")
+        {
+            Some((psu.current, &CURRENT))
+        } else {
+            None
+        };
+
+        let result = if let Some((value, limits)) = level {
+            let mut resp = reply(cmd.token, format_args!(""));
+            match limits.answer_query(params, &mut resp) {
+                Ok(true) => {}
+                Ok(false) => resp = reply(cmd.token, format_args!("{value:E}\n")),
+                Err(code) => {
+                    push_error(code);
+                    continue;
+                }
+            }
+            let _ = resp_tx.try_send(resp);
+            continue;
+        } else if header_matches(header, b"VOLTage") || header_matches(header, b"SOURce:VOLTage") {
+            set_level(&mut psu.voltage, &VOLTAGE, params)
+        } else if header_matches(header, b"CURRent") || header_matches(header, b"SOURce:CURRent") {
+            set_level(&mut psu.current, &CURRENT, params)
+        } else if let Some(channel) = match_suffixed(header, b"OUTPut#?", CHANNEL) {
+            match channel {
+                Ok(n) => {
+                    let on = OUTPUT_ON[n as usize - 1].load(Ordering::Relaxed);
+                    let _ = resp_tx.try_send(reply(cmd.token, format_args!("{}\n", on as u8)));
+                    continue;
+                }
+                Err(code) => Err(code),
+            }
+        } else if let Some(channel) = match_suffixed(header, b"OUTPut#", CHANNEL) {
+            channel.and_then(|n| {
+                let on = boolean(params)?;
+                OUTPUT_ON[n as usize - 1].store(on, Ordering::Relaxed);
+                Ok(())
+            })
+        } else {
+            Err(SCPI_ERR_UNDEFINED_HEADER)
+        };
+
+        match result {
+            Ok(()) => drive(&psu),
+            Err(code) => push_error(code),
+        }
+    }
+}
@@ -0,0 +1,29 @@
+//! The simulated instrument on real hardware: a ramping source with a noisy meter behind the
+//! whole 488.2/SCPI command set, for developing host software before the firmware exists.
+
+#![no_std]
+#![no_main]
+
+mod common;
+
+use core::panic::PanicInfo;
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
+
+use embassy_executor::{Spawner, main};
+use embassy_usbtmc::TmcConfig;
+use embassy_usbtmc::sim::{SimConfig, SimInstrument};
+
+#[main]
+async fn main(spawner: Spawner) {
+    common::start(spawner, "RP2350 Simulator", TmcConfig::default());
+
+    let mut sim = SimInstrument::new(SimConfig {
+        idn: "YourCompany,SIM-1,123456,FW1.0",
+        ..SimConfig::default()
+    });
+    sim.run().await
+}
@@ -0,0 +1,75 @@
+//! Smallest firmware that runs the class, for measuring what each feature costs.
+//!
+//! `cargo size --release --example size --no-default-features` gives the bare USBTMC core;
+//! add `--features usb488`, `ieee4882` or `scpi` for each layer on top. The application side
+//! is a single task that drops every command, so the numbers are the class and the USB stack.
+
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
+
+use embassy_executor::{Spawner, main};
+use embassy_rp::bind_interrupts;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::{Driver, InterruptHandler};
+use embassy_usb::{Builder, Config};
+use embassy_usbtmc::{TmcConfig, UsbTmc, cmd_receiver};
+use static_cell::StaticCell;
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+});
+
+type MyDriver = Driver<'static, USB>;
+
+#[main]
+async fn main(spawner: Spawner) {
+    let p = embassy_rp::init(Default::default());
+
+    let driver = Driver::new(p.USB, Irqs);
+    let usb_config = Config::new(0x2E8A, 0x000A);
+
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+
+    let mut usb_builder = Builder::new(
+        driver,
+        usb_config,
+        CONFIG_DESCRIPTOR.init([0; 256]),
+        BOS_DESCRIPTOR.init([0; 256]),
+        &mut [],
+        CONTROL_BUF.init([0; 64]),
+    );
+
+    let tmc = UsbTmc::new(&mut usb_builder, TmcConfig::default());
+    let usb = usb_builder.build();
+
+    spawner.spawn(usb_task(usb)).unwrap();
+    spawner.spawn(usbtmc_task(tmc)).unwrap();
+    spawner.spawn(sink_task()).unwrap();
+}
+
+#[embassy_executor::task]
+async fn sink_task() {
+    let cmd_rx = cmd_receiver();
+    loop {
+        cmd_rx.receive().await;
+    }
+}
+
+#[embassy_executor::task]
+async fn usb_task(mut usb: embassy_usb::UsbDevice<'static, MyDriver>) {
+    usb.run().await
+}
+
+#[embassy_executor::task]
+async fn usbtmc_task(tmc: UsbTmc<'static, MyDriver>) {
+    tmc.run().await
+}
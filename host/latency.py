@@ -0,0 +1,78 @@
+"""Command turnaround: time DIAGnostic:ECHO? round trips and print their distribution.
+
+    python3 latency.py --count 10000 --payload 64
+
+Each sample is one write of the query and the read of its answer, timed on the host, so it
+includes the host's USB stack and scheduling as well as the device. The answer must match
+the payload; a mismatch stops the run. The device answers from `diag::echo_inline` (the class
+alone) or `diag::serve` (through the application task), whichever the firmware wires up.
+"""
+
+import argparse
+import statistics
+import sys
+import time
+
+import tmc
+
+
+def percentile(sorted_samples, p):
+    index = min(len(sorted_samples) - 1, int(p / 100 * len(sorted_samples)))
+    return sorted_samples[index]
+
+
+def histogram(samples, buckets, width=50):
+    low, high = samples[0], samples[-1]
+    step = (high - low) / buckets or 1
+    counts = [0] * buckets
+    for sample in samples:
+        counts[min(buckets - 1, int((sample - low) / step))] += 1
+    peak = max(counts)
+    for i, count in enumerate(counts):
+        bar = "#" * round(count / peak * width)
+        print(f"{low + i * step:9.1f} us  {count:7}  {bar}")
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.split("\n\n")[0])
+    parser.add_argument("--count", type=int, default=1000)
+    parser.add_argument("--warmup", type=int, default=50)
+    parser.add_argument("--payload", type=int, default=16, help="payload bytes per query")
+    parser.add_argument("--buckets", type=int, default=20)
+    parser.add_argument("--csv", help="also write every sample, in microseconds, to this file")
+    parser.add_argument("--vid", type=lambda s: int(s, 16), default=0x2E8A)
+    parser.add_argument("--pid", type=lambda s: int(s, 16), default=0x000A)
+    args = parser.parse_args()
+
+    payload = bytes(b"0123456789ABCDEF"[i % 16] for i in range(args.payload))
+    query = b"DIAG:ECHO? " + payload + b"\n"
+    dev = tmc.Tmc(args.vid, args.pid)
+    samples = []
+    try:
+        dev.clear()
+        for i in range(args.warmup + args.count):
+            start = time.perf_counter_ns()
+            answer = dev.query(query)
+            elapsed = (time.perf_counter_ns() - start) / 1000
+            if answer != payload + b"\n":
+                sys.exit(f"sample {i}: answer {answer!r} doesn't match the payload")
+            if i >= args.warmup:
+                samples.append(elapsed)
+    finally:
+        dev.close()
+
+    samples.sort()
+    print(f"{len(samples)} round trips, {args.payload}-byte payload")
+    print(f"  min    {samples[0]:9.1f} us")
+    print(f"  median {statistics.median(samples):9.1f} us")
+    print(f"  p99    {percentile(samples, 99):9.1f} us")
+    print(f"  max    {samples[-1]:9.1f} us")
+    print()
+    histogram(samples, args.buckets)
+    if args.csv:
+        with open(args.csv, "w") as out:
+            out.writelines(f"{sample:.1f}\n" for sample in samples)
+
+
+if __name__ == "__main__":
+    main()
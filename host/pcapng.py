@@ -0,0 +1,90 @@
+"""Writes USB traffic seen through a `tmc.Tmc` tap as a pcapng file Wireshark opens
+directly, in the Linux usbmon format (LINKTYPE_USB_LINUX_MMAPPED), one submission and one
+completion per transfer.
+
+    with UsbPcapng(path, dev) as pcap:
+        dev.tap = pcap.tap
+"""
+
+import struct
+import time
+
+LINKTYPE_USB_LINUX_MMAPPED = 220
+
+XFER_CONTROL = 2
+XFER_BULK = 3
+
+NO_SETUP = ord("-")
+NO_DATA_OUT = ord(">")
+NO_DATA_IN = ord("<")
+
+
+def _block(block_type, body):
+    body += b"\0" * (-len(body) % 4)
+    length = 12 + len(body)
+    return struct.pack("<II", block_type, length) + body + struct.pack("<I", length)
+
+
+class UsbPcapng:
+    def __init__(self, path, dev):
+        self.out = open(path, "wb")
+        self.bus = dev.dev.bus or 0
+        self.devnum = dev.dev.address or 0
+        self.ep_out = dev.ep_out.bEndpointAddress
+        self.ep_in = dev.ep_in.bEndpointAddress
+        self.urb_id = 0
+        # Section header, then one interface with microsecond timestamps (the default).
+        self.out.write(_block(0x0A0D0D0A, struct.pack("<IHHq", 0x1A2B3C4D, 1, 0, -1)))
+        self.out.write(_block(0x00000001, struct.pack("<HHI", LINKTYPE_USB_LINUX_MMAPPED, 0, 0)))
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *exc):
+        self.close()
+
+    def close(self):
+        self.out.close()
+
+    def tap(self, kind, data):
+        self.urb_id += 1
+        if kind == "out":
+            self._packet("S", XFER_BULK, self.ep_out, data=data)
+            self._packet("C", XFER_BULK, self.ep_out, length=len(data), flag_data=NO_DATA_IN)
+        elif kind == "in":
+            self._packet("S", XFER_BULK, self.ep_in, length=len(data), flag_data=NO_DATA_OUT)
+            self._packet("C", XFER_BULK, self.ep_in, data=data)
+        else:
+            setup, answer = data[:8], data[8:]
+            request_type, w_length = setup[0], struct.unpack_from("<H", setup, 6)[0]
+            ep = 0x80 if request_type & 0x80 else 0x00
+            self._packet("S", XFER_CONTROL, ep, setup=setup, length=w_length, flag_data=NO_DATA_OUT)
+            self._packet("C", XFER_CONTROL, ep, data=answer)
+
+    def _packet(self, event, xfer_type, ep, data=b"", setup=None, length=None, flag_data=0):
+        now = time.time_ns() // 1000
+        seconds, micros = divmod(now, 1_000_000)
+        header = struct.pack(
+            "<QBBBBHbbqiiII8siiII",
+            self.urb_id,
+            ord(event),
+            xfer_type,
+            ep,
+            self.devnum,
+            self.bus,
+            0 if setup else NO_SETUP,
+            flag_data if not data else 0,
+            seconds,
+            micros,
+            0 if event == "C" else -115,  # -EINPROGRESS until completed
+            len(data) if length is None else length,
+            len(data),
+            setup or b"\0" * 8,
+            0,
+            0,
+            0,
+            0,
+        )
+        packet = header + data
+        body = struct.pack("<IIIII", 0, now >> 32, now & 0xFFFFFFFF, len(packet), len(packet))
+        self.out.write(_block(0x00000006, body + packet))
@@ -0,0 +1,54 @@
+"""Code size per feature: build `examples/size.rs` for each layer and print the README table.
+
+    python3 size_table.py
+
+Needs cargo-binutils (`cargo install cargo-binutils`, `rustup component add llvm-tools`) and
+the firmware target from `.cargo/config.toml`. Flash is `text` + `data`, RAM is `data` +
+`bss`, as `cargo size` reports them for the release build. The table is headed with the
+toolchain and target it was measured with, since the numbers shift with both.
+"""
+
+import pathlib
+import subprocess
+import sys
+
+ROOT = pathlib.Path(__file__).resolve().parent.parent
+TARGET = "thumbv8m.main-none-eabihf"
+
+# Each layer pulls in the one below it, see [features] in Cargo.toml.
+BUILDS = [
+    ("`--no-default-features`", ["--no-default-features"]),
+    ("`usb488`", ["--no-default-features", "--features", "usb488"]),
+    ("`ieee4882`", ["--no-default-features", "--features", "ieee4882"]),
+    ("`scpi` (default)", []),
+    ("`scpi-rs`", ["--features", "scpi-rs"]),
+]
+
+
+def run(args):
+    return subprocess.run(args, cwd=ROOT, check=True, capture_output=True, text=True).stdout
+
+
+def measure(flags):
+    out = run(["cargo", "size", "--release", "--example", "size", *flags, "--", "-B"])
+    # Berkeley format: a header line, then text, data, bss, dec, hex and the file name.
+    text, data, bss = (int(field) for field in out.strip().splitlines()[-1].split()[:3])
+    return text + data, data + bss
+
+
+def main():
+    rustc = run(["rustc", "--version"]).strip()
+    print(f"Measured with {rustc}, target `{TARGET}`, release profile.")
+    print()
+    print("| Features | Flash (bytes) | RAM (bytes) |")
+    print("|---|---|---|")
+    for name, flags in BUILDS:
+        try:
+            flash, ram = measure(flags)
+        except subprocess.CalledProcessError as err:
+            sys.exit(f"{name}: build failed\n{err.stderr}")
+        print(f"| {name} | {flash} | {ram} |")
+
+
+if __name__ == "__main__":
+    main()
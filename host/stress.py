@@ -0,0 +1,190 @@
+"""Soak test: hammer the device with queries, partial reads, aborts and clears for as long
+as asked, and report every wrong answer and every time it stops responding.
+
+    python3 stress.py --duration 4h
+    python3 stress.py --duration 30m --seed 7 --parallel
+
+Each round picks one of the operations below at random. After an abort or clear the device
+must answer the next query correctly; one that doesn't is given a device clear to recover,
+and one that stays silent through `--lockup-after` recoveries in a row is reported as locked
+up and ends the run. Use `--seed` to replay a failing run.
+
+    query       *IDN? with a full read, compared with the first answer
+    burst       several *OPC? before reading all the answers back
+    timeout     a read with a short random timeout, aborted on expiry as VISA does
+    abort-in    a partial read of *IDN?, then INITIATE_ABORT_BULK_IN
+    abort-out   a DEV_DEP_MSG_OUT without EOM, then INITIATE_ABORT_BULK_OUT
+    clear       a query left unread, then a device clear
+
+With `--parallel` a second thread polls GET_CAPABILITIES on the control pipe throughout,
+checking the answer never changes while the bulk pipes are busy.
+"""
+
+import argparse
+import random
+import re
+import sys
+import threading
+import time
+from collections import Counter
+
+import usb.core
+
+import tmc
+
+OPERATIONS = {
+    "query": 4,
+    "burst": 2,
+    "timeout": 1,
+    "abort-in": 1,
+    "abort-out": 1,
+    "clear": 1,
+}
+
+
+def duration(text):
+    match = re.fullmatch(r"(\d+(?:\.\d+)?)([smh]?)", text)
+    if not match:
+        raise argparse.ArgumentTypeError(f"bad duration {text!r}")
+    return float(match[1]) * {"": 1, "s": 1, "m": 60, "h": 3600}[match[2]]
+
+
+class Stress:
+    def __init__(self, dev, rng, log):
+        self.dev = dev
+        self.rng = rng
+        self.log = log
+        self.counts = Counter()
+        self.idn = dev.query(b"*IDN?\n")
+
+    def check(self, what, actual, expected):
+        if actual != expected:
+            self.counts["mismatch"] += 1
+            self.log(f"MISMATCH {what}: {actual!r}, expected {expected!r}")
+
+    def query(self):
+        self.check("*IDN?", self.dev.query(b"*IDN?\n"), self.idn)
+
+    def burst(self):
+        n = self.rng.randint(2, 3)
+        for _ in range(n):
+            self.dev.write(b"*OPC?\n")
+        for i in range(n):
+            self.check(f"*OPC? {i + 1}/{n}", self.dev.read(), b"1\n")
+
+    def timeout(self):
+        self.dev.write(b"*IDN?\n")
+        try:
+            answer = self.dev.read(timeout_ms=self.rng.randint(1, 20))
+        except usb.core.USBTimeoutError:
+            self.counts["timeout-expired"] += 1
+            self.dev.abort_in()
+            return
+        self.check("*IDN? with a short timeout", answer, self.idn)
+
+    def abort_in(self):
+        self.dev.write(b"*IDN?\n")
+        self.dev.read_transfer(self.rng.randint(1, max(1, len(self.idn) - 1)))
+        self.dev.abort_in()
+
+    def abort_out(self):
+        self.dev.write(b"*IDN"[: self.rng.randint(1, 4)], eom=False)
+        self.dev.abort_out()
+
+    def clear(self):
+        self.dev.write(b"*IDN?\n")
+        if self.rng.random() < 0.5:
+            time.sleep(self.rng.random() * 0.01)
+        self.dev.clear()
+
+    def round(self):
+        name = self.rng.choices(list(OPERATIONS), weights=OPERATIONS.values())[0]
+        self.counts[name] += 1
+        getattr(self, name.replace("-", "_"))()
+        if name not in ("query", "burst"):
+            # The device must be itself again straight after an abort or clear.
+            self.query()
+
+    def recover(self, lockup_after):
+        for attempt in range(1, lockup_after + 1):
+            self.counts["recovery"] += 1
+            try:
+                self.dev.clear()
+                self.check("*IDN? after recovery", self.dev.query(b"*IDN?\n"), self.idn)
+                return True
+            except (usb.core.USBError, tmc.TmcError) as error:
+                self.log(f"recovery {attempt}/{lockup_after} failed: {error}")
+        return False
+
+
+def poll_capabilities(dev, stop, counts, log):
+    expected = dev.capabilities()
+    while not stop.is_set():
+        try:
+            answer = dev.capabilities()
+        except usb.core.USBError as error:
+            counts["capabilities-error"] += 1
+            log(f"GET_CAPABILITIES failed: {error}")
+            continue
+        counts["capabilities"] += 1
+        if answer != expected:
+            counts["mismatch"] += 1
+            log(f"MISMATCH GET_CAPABILITIES: {answer.hex()}, expected {expected.hex()}")
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.split("\n\n")[0])
+    parser.add_argument("--duration", type=duration, default=duration("10m"))
+    parser.add_argument("--seed", type=int, default=None)
+    parser.add_argument("--parallel", action="store_true")
+    parser.add_argument("--lockup-after", type=int, default=3)
+    parser.add_argument("--report-every", type=duration, default=duration("60s"))
+    parser.add_argument("--vid", type=lambda s: int(s, 16), default=0x2E8A)
+    parser.add_argument("--pid", type=lambda s: int(s, 16), default=0x000A)
+    args = parser.parse_args()
+
+    seed = args.seed if args.seed is not None else random.randrange(1 << 32)
+    start = time.monotonic()
+
+    def log(text):
+        print(f"[{time.monotonic() - start:10.1f}s] {text}", flush=True)
+
+    dev = tmc.Tmc(args.vid, args.pid)
+    dev.clear()
+    stress = Stress(dev, random.Random(seed), log)
+    log(f"seed {seed}, *IDN? {stress.idn!r}")
+
+    stop = threading.Event()
+    if args.parallel:
+        threading.Thread(
+            target=poll_capabilities, args=(dev, stop, stress.counts, log), daemon=True
+        ).start()
+
+    locked_up = False
+    next_report = start + args.report_every
+    try:
+        while time.monotonic() - start < args.duration:
+            try:
+                stress.round()
+            except (usb.core.USBError, tmc.TmcError) as error:
+                stress.counts["error"] += 1
+                log(f"round failed: {error}")
+                if not stress.recover(args.lockup_after):
+                    log(f"LOCKUP: no answer through {args.lockup_after} device clears")
+                    locked_up = True
+                    break
+            if time.monotonic() >= next_report:
+                log(" ".join(f"{k}={v}" for k, v in sorted(stress.counts.items())))
+                next_report += args.report_every
+    except KeyboardInterrupt:
+        log("interrupted")
+    finally:
+        stop.set()
+        dev.close()
+
+    log("final: " + " ".join(f"{k}={v}" for k, v in sorted(stress.counts.items())))
+    sys.exit(1 if locked_up or stress.counts["mismatch"] else 0)
+
+
+if __name__ == "__main__":
+    main()
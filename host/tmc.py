@@ -0,0 +1,207 @@
+"""A small USBTMC host client over pyusb.
+
+It frames the bulk messages and issues the class requests itself instead of going through
+VISA or the kernel driver, so the tools built on it see and control every byte on the wire.
+Pass `tap` to observe the traffic: it is called as `tap(kind, data)` with kind `"out"` or
+`"in"` for bulk packets and `"ctrl"` for a class request and its answer.
+"""
+
+import struct
+
+import usb.core
+import usb.util
+
+USBTMC_CLASS = 0xFE
+USBTMC_SUBCLASS = 0x03
+
+DEV_DEP_MSG_OUT = 1
+REQUEST_DEV_DEP_MSG_IN = 2
+DEV_DEP_MSG_IN = 2
+
+INITIATE_ABORT_BULK_OUT = 1
+CHECK_ABORT_BULK_OUT_STATUS = 2
+INITIATE_ABORT_BULK_IN = 3
+CHECK_ABORT_BULK_IN_STATUS = 4
+INITIATE_CLEAR = 5
+CHECK_CLEAR_STATUS = 6
+GET_CAPABILITIES = 7
+INDICATOR_PULSE = 64
+READ_STATUS_BYTE = 128
+
+STATUS_SUCCESS = 0x01
+STATUS_PENDING = 0x02
+STATUS_FAILED = 0x80
+STATUS_TRANSFER_NOT_IN_PROGRESS = 0x81
+
+HEADER_LEN = 12
+
+
+class TmcError(Exception):
+    pass
+
+
+class Tmc:
+    def __init__(self, vid=0x2E8A, pid=0x000A, timeout_ms=5000, tap=None):
+        self.dev = usb.core.find(idVendor=vid, idProduct=pid)
+        if self.dev is None:
+            raise TmcError(f"no device {vid:04x}:{pid:04x}")
+        cfg = self.dev.get_active_configuration()
+        self.intf = usb.util.find_descriptor(
+            cfg, bInterfaceClass=USBTMC_CLASS, bInterfaceSubClass=USBTMC_SUBCLASS
+        )
+        if self.intf is None:
+            raise TmcError("no USBTMC interface")
+        if self.dev.is_kernel_driver_active(self.intf.bInterfaceNumber):
+            self.dev.detach_kernel_driver(self.intf.bInterfaceNumber)
+        usb.util.claim_interface(self.dev, self.intf)
+        self.ep_out = self._endpoint(usb.util.ENDPOINT_OUT)
+        self.ep_in = self._endpoint(usb.util.ENDPOINT_IN)
+        self.timeout_ms = timeout_ms
+        self.tap = tap or (lambda kind, data: None)
+        self.btag = 0
+        self.last_out_btag = 0
+        self.last_in_btag = 0
+        self.stb_btag = 1
+
+    def _endpoint(self, direction):
+        return usb.util.find_descriptor(
+            self.intf,
+            custom_match=lambda ep: usb.util.endpoint_direction(ep.bEndpointAddress) == direction
+            and usb.util.endpoint_type(ep.bmAttributes) == usb.util.ENDPOINT_TYPE_BULK,
+        )
+
+    def close(self):
+        usb.util.release_interface(self.dev, self.intf)
+        usb.util.dispose_resources(self.dev)
+
+    def _next_btag(self):
+        self.btag = self.btag % 255 + 1
+        return self.btag
+
+    def _bulk_out(self, data, timeout_ms=None):
+        self.tap("out", bytes(data))
+        self.ep_out.write(data, timeout_ms or self.timeout_ms)
+
+    def _bulk_in(self, length, timeout_ms=None):
+        data = bytes(self.ep_in.read(length, timeout_ms or self.timeout_ms))
+        self.tap("in", data)
+        return data
+
+    def write(self, message, eom=True, timeout_ms=None):
+        """Sends one DEV_DEP_MSG_OUT transfer. Returns its bTag."""
+        if isinstance(message, str):
+            message = message.encode()
+        btag = self._next_btag()
+        header = struct.pack(
+            "<BBBxIB3x", DEV_DEP_MSG_OUT, btag, ~btag & 0xFF, len(message), int(eom)
+        )
+        padding = b"\0" * (-len(message) % 4)
+        self.last_out_btag = btag
+        self._bulk_out(header + message + padding, timeout_ms)
+        return btag
+
+    def read(self, max_len=1024, term_char=None, timeout_ms=None):
+        """Reads one whole response message, across as many transfers as the device needs."""
+        message = b""
+        while True:
+            payload, eom = self.read_transfer(max_len, term_char, timeout_ms)
+            message += payload
+            if eom:
+                return message
+
+    def read_transfer(self, max_len=1024, term_char=None, timeout_ms=None):
+        """One REQUEST_DEV_DEP_MSG_IN and its transfer. Returns the data and the EOM flag."""
+        btag = self._next_btag()
+        attributes = 0x02 if term_char is not None else 0
+        header = struct.pack(
+            "<BBBxIBB2x",
+            REQUEST_DEV_DEP_MSG_IN,
+            btag,
+            ~btag & 0xFF,
+            max_len,
+            attributes,
+            term_char or 0,
+        )
+        self.last_in_btag = btag
+        self._bulk_out(header, timeout_ms)
+        data = self._bulk_in(max_len + HEADER_LEN + 3, timeout_ms)
+        if len(data) < HEADER_LEN:
+            raise TmcError(f"short Bulk-IN header: {data.hex()}")
+        msg_id, rtag, inverse, size, attributes = struct.unpack_from("<BBBxIB", data)
+        if msg_id != DEV_DEP_MSG_IN or rtag != btag or inverse != ~btag & 0xFF:
+            raise TmcError(f"bad Bulk-IN header: {data[:HEADER_LEN].hex()}")
+        payload = data[HEADER_LEN:]
+        while len(payload) < size:
+            payload += self._bulk_in(max_len + HEADER_LEN + 3, timeout_ms)
+        return payload[:size], bool(attributes & 0x01)
+
+    def query(self, message, max_len=1024, timeout_ms=None):
+        self.write(message, timeout_ms=timeout_ms)
+        return self.read(max_len, timeout_ms=timeout_ms)
+
+    def control(self, request, value=0, length=1, endpoint=None):
+        """Sends a class request to the interface, or to `endpoint`, and returns the answer."""
+        if endpoint is None:
+            request_type, index = 0xA1, self.intf.bInterfaceNumber
+        else:
+            request_type, index = 0xA2, endpoint.bEndpointAddress
+        answer = bytes(self.dev.ctrl_transfer(request_type, request, value, index, length))
+        setup = struct.pack("<BBHHH", request_type, request, value, index, length)
+        self.tap("ctrl", setup + answer)
+        return answer
+
+    def capabilities(self):
+        return self.control(GET_CAPABILITIES, length=0x18)
+
+    def read_stb(self):
+        """READ_STATUS_BYTE over the control pipe. Returns the answer as sent."""
+        self.stb_btag = self.stb_btag % 127 + 1
+        return self.control(READ_STATUS_BYTE, self.stb_btag, 3)
+
+    def clear(self):
+        """The device clear sequence: INITIATE_CLEAR, poll, then clear the Bulk-OUT halt."""
+        status = self.control(INITIATE_CLEAR)[0]
+        if status != STATUS_SUCCESS:
+            raise TmcError(f"INITIATE_CLEAR: {status:#04x}")
+        while True:
+            answer = self.control(CHECK_CLEAR_STATUS, length=2)
+            if answer[0] != STATUS_PENDING:
+                break
+            if answer[1] & 0x01:
+                self._drain_in()
+        self.dev.clear_halt(self.ep_out)
+
+    def abort_out(self):
+        """Aborts the last Bulk-OUT transfer. Returns the bytes the device had taken."""
+        status = self.control(
+            INITIATE_ABORT_BULK_OUT, self.last_out_btag, 2, endpoint=self.ep_out
+        )[0]
+        if status != STATUS_SUCCESS:
+            return None
+        while True:
+            answer = self.control(CHECK_ABORT_BULK_OUT_STATUS, length=8, endpoint=self.ep_out)
+            if answer[0] != STATUS_PENDING:
+                break
+        self.dev.clear_halt(self.ep_out)
+        return struct.unpack_from("<I", answer, 4)[0]
+
+    def abort_in(self):
+        """Aborts the last Bulk-IN transfer. Returns the bytes the device had sent."""
+        status = self.control(INITIATE_ABORT_BULK_IN, self.last_in_btag, 2, endpoint=self.ep_in)[0]
+        if status != STATUS_SUCCESS:
+            return None
+        while True:
+            answer = self.control(CHECK_ABORT_BULK_IN_STATUS, length=8, endpoint=self.ep_in)
+            if answer[0] != STATUS_PENDING:
+                return struct.unpack_from("<I", answer, 4)[0]
+            if answer[1] & 0x01:
+                self._drain_in()
+
+    def _drain_in(self):
+        """Reads and drops Bulk-IN data up to a short packet."""
+        size = self.ep_in.wMaxPacketSize
+        try:
+            while len(self._bulk_in(size, 100)) == size:
+                pass
+        except usb.core.USBTimeoutError:
+            pass
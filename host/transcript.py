@@ -0,0 +1,158 @@
+"""Golden-transcript tests: run a scripted host session against the device, record every
+byte it sends and receives, and compare the record with a checked-in golden file.
+
+    python3 transcript.py sessions/basic.txt            # compare with golden/basic.txt
+    python3 transcript.py --bless sessions/basic.txt    # record golden/basic.txt afresh
+
+A change to the runner, the control handler or the framing then shows up as a diff of the
+transcript, which reviewers read next to the code change. Bless a new golden only after
+checking that diff is the behavior you meant.
+
+Session scripts hold one step per line; `#` starts a comment.
+
+    write <text>         DEV_DEP_MSG_OUT with EOM, `\\n` appended
+    write-part <text>    DEV_DEP_MSG_OUT without EOM, nothing appended
+    read [max_len]       REQUEST_DEV_DEP_MSG_IN and the whole response
+    read-part [max_len]  one REQUEST_DEV_DEP_MSG_IN and its transfer only
+    clear                the device clear sequence
+    abort-out            abort the last Bulk-OUT transfer
+    abort-in             abort the last Bulk-IN transfer
+    capabilities         GET_CAPABILITIES
+    stb                  READ_STATUS_BYTE
+    pulse                INDICATOR_PULSE
+"""
+
+import argparse
+import difflib
+import sys
+from pathlib import Path
+
+import usb.core
+
+import tmc
+from pcapng import UsbPcapng
+
+HERE = Path(__file__).parent
+
+
+def printable(data):
+    return "".join(chr(b) if 0x20 <= b < 0x7F else "." for b in data)
+
+
+class Recorder:
+    """Collects the traffic of a session as transcript lines."""
+
+    def __init__(self):
+        self.lines = []
+
+    def step(self, text):
+        self.lines.append(f"## {text}")
+
+    def tap(self, kind, data):
+        arrow = {"out": ">", "in": "<", "ctrl": "="}[kind]
+        for offset in range(0, max(len(data), 1), 16):
+            chunk = data[offset : offset + 16]
+            self.lines.append(f"{arrow} {kind:4} {chunk.hex(' '):47}  |{printable(chunk)}|")
+
+    def note(self, text):
+        self.lines.append(f"  {text}")
+
+
+def run_session(script, dev, rec):
+    for number, raw in enumerate(script.read_text().splitlines(), 1):
+        line = raw.split("#", 1)[0].strip()
+        if not line:
+            continue
+        rec.step(line)
+        step, _, arg = line.partition(" ")
+        try:
+            if step == "write":
+                dev.write(arg.encode() + b"\n")
+            elif step == "write-part":
+                dev.write(arg.encode(), eom=False)
+            elif step == "read":
+                dev.read(int(arg) if arg else 1024)
+            elif step == "read-part":
+                dev.read_transfer(int(arg) if arg else 1024)
+            elif step == "clear":
+                dev.clear()
+            elif step == "abort-out":
+                rec.note(f"bytes taken: {dev.abort_out()}")
+            elif step == "abort-in":
+                rec.note(f"bytes sent: {dev.abort_in()}")
+            elif step == "capabilities":
+                dev.capabilities()
+            elif step == "stb":
+                dev.read_stb()
+            elif step == "pulse":
+                dev.control(tmc.INDICATOR_PULSE)
+            else:
+                sys.exit(f"{script}:{number}: unknown step {step!r}")
+        except usb.core.USBTimeoutError:
+            rec.note("timeout")
+        except usb.core.USBError as error:
+            rec.note(f"usb error: {error.strerror}")
+        except tmc.TmcError as error:
+            rec.note(f"protocol error: {error}")
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.split("\n\n")[0])
+    parser.add_argument("sessions", nargs="+", type=Path)
+    parser.add_argument("--bless", action="store_true", help="write the goldens afresh")
+    parser.add_argument("--golden-dir", type=Path, default=HERE / "golden")
+    parser.add_argument(
+        "--pcapng",
+        type=Path,
+        metavar="DIR",
+        help="also write each session's traffic to DIR/<session>.pcapng for Wireshark",
+    )
+    parser.add_argument("--vid", type=lambda s: int(s, 16), default=0x2E8A)
+    parser.add_argument("--pid", type=lambda s: int(s, 16), default=0x000A)
+    args = parser.parse_args()
+
+    failed = 0
+    for script in args.sessions:
+        rec = Recorder()
+        dev = tmc.Tmc(args.vid, args.pid)
+        pcap = None
+        try:
+            # Every session starts from a cleared device, whatever the last one left behind.
+            dev.clear()
+            dev.tap = rec.tap
+            if args.pcapng:
+                args.pcapng.mkdir(parents=True, exist_ok=True)
+                pcap = UsbPcapng(args.pcapng / f"{script.stem}.pcapng", dev)
+                dev.tap = lambda kind, data: (rec.tap(kind, data), pcap.tap(kind, data))
+            run_session(script, dev, rec)
+        finally:
+            if pcap:
+                pcap.close()
+            dev.close()
+
+        actual = "\n".join(rec.lines) + "\n"
+        golden = args.golden_dir / script.name
+        if args.bless:
+            golden.parent.mkdir(parents=True, exist_ok=True)
+            golden.write_text(actual)
+            print(f"blessed {golden}")
+            continue
+        expected = golden.read_text() if golden.exists() else ""
+        if actual == expected:
+            print(f"ok      {script}")
+            continue
+        failed += 1
+        print(f"FAILED  {script}")
+        sys.stdout.writelines(
+            difflib.unified_diff(
+                expected.splitlines(True),
+                actual.splitlines(True),
+                str(golden),
+                "actual",
+            )
+        )
+    sys.exit(1 if failed else 0)
+
+
+if __name__ == "__main__":
+    main()
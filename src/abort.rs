@@ -0,0 +1,133 @@
+//! The handshake between the control handler and the runner for aborting one bulk pipe's
+//! transfer: INITIATE_ABORT_* and CHECK_ABORT_*_STATUS on one side, the transfer loop on the
+//! other, meeting only in the atomics of a [`TransferAbort`].
+//!
+//! An abort goes `Idle → Pending → Done → Idle`: the control handler asks for it, the runner
+//! completes it where it stops the transfer, and the host's status check retires it. Either
+//! side may get there first; every step is a single atomic operation on a word holding both
+//! the transfer's bTag and the abort state, so a request that names a transfer the runner has
+//! already finished fails instead of waiting on a `finish` that never comes.
+
+use crate::sync::{AtomicU16, AtomicU32, Ordering};
+
+const IDLE: u8 = 0;
+const PENDING: u8 = 1;
+const DONE: u8 = 2;
+
+/// Abort state of one bulk pipe.
+pub(crate) struct TransferAbort {
+    /// bTag of the transfer the runner is on, 0 when idle, in the low byte; the abort state in
+    /// the high byte.
+    word: AtomicU16,
+    /// Message data bytes moved so far in the current (or last aborted) transfer.
+    bytes: AtomicU32,
+}
+
+const fn word(btag: u8, state: u8) -> u16 {
+    u16::from_le_bytes([btag, state])
+}
+
+const fn split(word: u16) -> (u8, u8) {
+    let [btag, state] = word.to_le_bytes();
+    (btag, state)
+}
+
+impl TransferAbort {
+    #[cfg(not(all(loom, test)))]
+    pub(crate) const fn new() -> Self {
+        Self {
+            word: AtomicU16::new(word(0, IDLE)),
+            bytes: AtomicU32::new(0),
+        }
+    }
+
+    #[cfg(all(loom, test))]
+    pub(crate) fn new() -> Self {
+        Self {
+            word: AtomicU16::new(word(0, IDLE)),
+            bytes: AtomicU32::new(0),
+        }
+    }
+
+    /// Applies `step` to the bTag and state in one atomic update. Returns the bTag and state it
+    /// found, as `Err` if `step` left them alone.
+    fn update(&self, step: impl Fn(u8, u8) -> Option<(u8, u8)>) -> Result<(u8, u8), (u8, u8)> {
+        self.word
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                let (btag, state) = split(current);
+                step(btag, state).map(|(btag, state)| word(btag, state))
+            })
+            .map(split)
+            .map_err(split)
+    }
+
+    /// Runner: starts on the transfer with `btag`. An abort of an earlier transfer the host
+    /// never checked on is forgotten.
+    pub(crate) fn begin(&self, btag: u8) {
+        self.bytes.store(0, Ordering::Relaxed);
+        self.word.store(word(btag, IDLE), Ordering::Relaxed);
+    }
+
+    /// Runner: notes progress for CHECK_ABORT_*_STATUS.
+    pub(crate) fn set_bytes(&self, bytes: u32) {
+        self.bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Runner: done with the transfer. Returns whether it was aborted, either by a pending
+    /// request the runner has now completed or by [`abort_idle`](Self::abort_idle).
+    pub(crate) fn finish(&self) -> bool {
+        let (_, state) = self
+            .update(|_, state| {
+                let state = if state == PENDING { DONE } else { state };
+                Some((0, state))
+            })
+            .unwrap_or_else(|previous| previous);
+        state != IDLE
+    }
+
+    /// bTag of the transfer in progress, 0 when idle.
+    pub(crate) fn btag(&self) -> u8 {
+        split(self.word.load(Ordering::Relaxed)).0
+    }
+
+    pub(crate) fn bytes(&self) -> u32 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Control handler: asks the runner to stop the transfer with `btag`. Fails if the runner
+    /// is no longer on it.
+    pub(crate) fn request(&self, btag: u8) -> bool {
+        self.update(|current, _| (current == btag).then_some((btag, PENDING)))
+            .is_ok()
+    }
+
+    /// Control handler: aborts the transfer with `btag`, which the runner isn't moving,
+    /// completing at once. Fails if the runner is no longer on it.
+    pub(crate) fn abort_idle(&self, btag: u8) -> bool {
+        self.update(|current, _| (current == btag).then_some((0, DONE)))
+            .is_ok()
+    }
+
+    /// Control handler: forgets the transfer's bTag, so no abort can match it.
+    pub(crate) fn forget(&self) {
+        let _ = self.update(|_, state| Some((0, state)));
+    }
+
+    /// Whether the runner has yet to complete a requested abort.
+    pub(crate) fn is_pending(&self) -> bool {
+        split(self.word.load(Ordering::Relaxed)).1 == PENDING
+    }
+
+    /// Control handler, on a status check that found the abort no longer pending: retires it.
+    /// Returns whether there was a completed abort to retire.
+    pub(crate) fn retire(&self) -> bool {
+        self.update(|btag, state| (state == DONE).then_some((btag, IDLE)))
+            .is_ok()
+    }
+
+    /// Back to power-up state, on a bus reset.
+    pub(crate) fn reset(&self) {
+        self.bytes.store(0, Ordering::Relaxed);
+        self.word.store(word(0, IDLE), Ordering::Relaxed);
+    }
+}
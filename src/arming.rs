@@ -0,0 +1,140 @@
+//! An arming gate for commands that can hurt someone, such as enabling a high-voltage output.
+//!
+//! The application lists the headers that need the instrument armed with [`protect`]. While
+//! it is disarmed, those commands are dropped on either link before the application sees
+//! them, with [`SCPI_ERR_COMMAND_PROTECTED`] in the error queue and [`QUES_NOT_ARMED`] raised
+//! for the QUEStionable register.
+//!
+//! | Command | Does |
+//! |---|---|
+//! | `SYSTem:ARM[:STATe] ON\|OFF\|1\|0[,"<key>"]` | Arms, if the authorizer agrees, or disarms |
+//! | `SYSTem:ARM[:STATe]?` | `1` if armed |
+//!
+//! The instrument is also armed and disarmed directly with [`arm`] and [`disarm`], e.g. from
+//! a key switch. An open [`set_interlock`] input disarms it and keeps it disarmed, and so
+//! does losing the USB host.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+use crate::params::{Params, Token, split_header};
+use crate::scpi::{
+    SCPI_ERR_COMMAND_PROTECTED, SCPI_ERR_ILLEGAL_PARAMETER, SCPI_ERR_PARAMETER_NOT_ALLOWED,
+    header_matches,
+};
+use crate::{Command, MAX_SCPI_LEN, RESP_CHANNEL, Response};
+
+/// QUEStionable bit raised when a protected command is refused. Bit 9 is the first the SCPI
+/// standard leaves to the instrument; [`take_questionable_status`] reports it for the
+/// application to fold into its own register.
+pub const QUES_NOT_ARMED: u16 = 1 << 9;
+
+static ARMED: AtomicBool = AtomicBool::new(false);
+static INTERLOCK_CLOSED: AtomicBool = AtomicBool::new(true);
+static QUESTIONABLE: AtomicU16 = AtomicU16::new(0);
+static PROTECTED: Mutex<CriticalSectionRawMutex, Cell<&'static [&'static [u8]]>> =
+    Mutex::new(Cell::new(&[]));
+
+/// Sets the header patterns, in [`header_matches`] form, that need the instrument armed,
+/// e.g. `&[b"OUTPut", b"OUTPut:STATe", b"SOURce:VOLTage"]`.
+pub fn protect(headers: &'static [&'static [u8]]) {
+    PROTECTED.lock(|protected| protected.set(headers));
+}
+
+/// Arms the instrument, unless the interlock is open. Returns whether it is armed.
+pub fn arm() -> bool {
+    let closed = INTERLOCK_CLOSED.load(Ordering::Relaxed);
+    ARMED.store(closed, Ordering::Relaxed);
+    closed
+}
+
+pub fn disarm() {
+    ARMED.store(false, Ordering::Relaxed);
+}
+
+pub fn is_armed() -> bool {
+    ARMED.load(Ordering::Relaxed)
+}
+
+/// Reports the hardware interlock input. Opening it disarms the instrument; closing it
+/// again doesn't re-arm it.
+pub fn set_interlock(closed: bool) {
+    INTERLOCK_CLOSED.store(closed, Ordering::Relaxed);
+    if !closed {
+        disarm();
+    }
+}
+
+/// Reads and clears the QUEStionable bits raised by the gate.
+pub fn take_questionable_status() -> u16 {
+    QUESTIONABLE.swap(0, Ordering::Relaxed)
+}
+
+/// Refuses a protected command while disarmed, raising [`QUES_NOT_ARMED`].
+pub(crate) fn check(message: &[u8]) -> Result<(), i16> {
+    if is_armed() {
+        return Ok(());
+    }
+    let (header, _) = split_header(message);
+    if PROTECTED.lock(|protected| protected.get().iter().any(|p| header_matches(header, p))) {
+        QUESTIONABLE.fetch_or(QUES_NOT_ARMED, Ordering::Relaxed);
+        return Err(SCPI_ERR_COMMAND_PROTECTED);
+    }
+    Ok(())
+}
+
+/// Handles `SYSTem:ARM[:STATe][?]`. `None` if `cmd` is something else; otherwise the
+/// outcome, with an error ready for [`push_error`](crate::push_error).
+///
+/// `authorize` gets the key string, if one was sent, and decides whether the host may arm:
+/// check a password, a front-panel confirmation, or just return `true`. A refusal is
+/// [`SCPI_ERR_COMMAND_PROTECTED`].
+pub async fn serve(
+    cmd: &Command,
+    authorize: impl FnOnce(Option<&[u8]>) -> bool,
+) -> Option<Result<(), i16>> {
+    let (header, params) = split_header(&cmd.data[..cmd.len]);
+    let matches = |pattern: &[u8], long: &[u8]| {
+        header_matches(header, pattern) || header_matches(header, long)
+    };
+    if matches(b"SYSTem:ARM?", b"SYSTem:ARM:STATe?") {
+        let answer: &'static [u8] = if is_armed() { b"1\n" } else { b"0\n" };
+        RESP_CHANNEL
+            .send(Response::from_static(answer, cmd.token))
+            .await;
+        return Some(Ok(()));
+    }
+    if !matches(b"SYSTem:ARM", b"SYSTem:ARM:STATe") {
+        return None;
+    }
+    let mut params = Params::new(params);
+    let on = match params.next() {
+        Some(Ok(Token::Chars(word))) if word.eq_ignore_ascii_case(b"ON") => true,
+        Some(Ok(Token::Chars(word))) if word.eq_ignore_ascii_case(b"OFF") => false,
+        Some(Ok(Token::Numeric(b"1"))) => true,
+        Some(Ok(Token::Numeric(b"0"))) => false,
+        Some(Err(code)) => return Some(Err(code)),
+        _ => return Some(Err(SCPI_ERR_ILLEGAL_PARAMETER)),
+    };
+    let mut key = [0; MAX_SCPI_LEN];
+    let key_len = match params.next() {
+        None => None,
+        Some(Ok(Token::Str(quoted))) => quoted.copy_to(&mut key),
+        Some(Err(code)) => return Some(Err(code)),
+        Some(Ok(_)) => return Some(Err(SCPI_ERR_ILLEGAL_PARAMETER)),
+    };
+    if params.next().is_some() {
+        return Some(Err(SCPI_ERR_PARAMETER_NOT_ALLOWED));
+    }
+    if !on {
+        disarm();
+        return Some(Ok(()));
+    }
+    if !authorize(key_len.map(|len| &key[..len])) || !arm() {
+        return Some(Err(SCPI_ERR_COMMAND_PROTECTED));
+    }
+    Some(Ok(()))
+}
@@ -0,0 +1,135 @@
+//! Calibration tables exchanged with the host as definite-length blocks:
+//! `CALibration:STORe <block>` to upload and `CALibration:LOAD?` to read back.
+//!
+//! A table travels with a [`CalHeader`] in front of it, so a table for another firmware
+//! revision or one damaged in transit is refused before it reaches [`CalStorage`]. The
+//! header is stored with the table and comes back unchanged.
+
+use heapless::Vec;
+
+use crate::params::{MAX_PREAMBLE_LEN, Params, Token, block_preamble, split_header};
+use crate::scpi::{SCPI_ERR_CALIBRATION, SCPI_ERR_DATA_TYPE, header_matches};
+use crate::vendor::crc32;
+use crate::{Command, MAX_SCPI_LEN, RESP_CHANNEL, Response, Segment};
+
+/// Length of [`CalHeader`] on the wire.
+pub const CAL_HEADER_LEN: usize = 8;
+
+/// Longest table, header included, that fits one `CALibration:LOAD?` response.
+pub const MAX_CAL_LEN: usize = MAX_SCPI_LEN - MAX_PREAMBLE_LEN - 1;
+
+/// Leads every calibration block: table format version, table length and the table's
+/// CRC-32 ([`crc32`]), all little-endian.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct CalHeader {
+    pub version: u16,
+    pub len: u16,
+    pub crc: u32,
+}
+
+impl CalHeader {
+    /// Splits a block into its header and table, checking length and CRC.
+    pub fn parse(block: &[u8]) -> Result<(Self, &[u8]), i16> {
+        let (header, table) = block
+            .split_at_checked(CAL_HEADER_LEN)
+            .ok_or(SCPI_ERR_CALIBRATION)?;
+        let header = Self {
+            version: u16::from_le_bytes([header[0], header[1]]),
+            len: u16::from_le_bytes([header[2], header[3]]),
+            crc: u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+        };
+        if table.len() != usize::from(header.len) || crc32(table) != header.crc {
+            return Err(SCPI_ERR_CALIBRATION);
+        }
+        Ok((header, table))
+    }
+
+    /// The header for `table` at `version`.
+    pub fn new(version: u16, table: &[u8]) -> Self {
+        Self {
+            version,
+            len: table.len() as u16,
+            crc: crc32(table),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; CAL_HEADER_LEN] {
+        let mut bytes = [0; CAL_HEADER_LEN];
+        bytes[..2].copy_from_slice(&self.version.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.len.to_le_bytes());
+        bytes[4..].copy_from_slice(&self.crc.to_le_bytes());
+        bytes
+    }
+}
+
+/// Where calibration blocks live: flash, EEPROM, a file.
+#[allow(async_fn_in_trait)]
+pub trait CalStorage {
+    /// Persists a checked block, header included. Errors are SCPI codes, e.g. -250 for a
+    /// failed flash write.
+    async fn store(&mut self, block: &[u8]) -> Result<(), i16>;
+
+    /// Reads the stored block into `out` and returns its length.
+    async fn load(&mut self, out: &mut [u8]) -> Result<usize, i16>;
+}
+
+/// Handles `CALibration:STORe` and `CALibration:LOAD?`, accepting tables at `version`.
+/// `None` if `cmd` is something else; otherwise the outcome, with an error ready for
+/// [`push_error`](crate::push_error). The answer to `LOAD?` is queued for the host.
+pub async fn serve<S: CalStorage>(
+    storage: &mut S,
+    version: u16,
+    cmd: &Command,
+) -> Option<Result<(), i16>> {
+    let (header, params) = split_header(&cmd.data[..cmd.len]);
+    if header_matches(header, b"CALibration:STORe") {
+        Some(store(storage, version, params).await)
+    } else if header_matches(header, b"CALibration:LOAD?") {
+        Some(load(storage, cmd).await)
+    } else {
+        None
+    }
+}
+
+async fn store<S: CalStorage>(storage: &mut S, version: u16, params: &[u8]) -> Result<(), i16> {
+    let block = match Params::new(params).next() {
+        Some(Ok(Token::Block(block))) => block,
+        Some(Err(code)) => return Err(code),
+        _ => return Err(SCPI_ERR_DATA_TYPE),
+    };
+    let (header, _) = CalHeader::parse(block)?;
+    if header.version != version {
+        return Err(SCPI_ERR_CALIBRATION);
+    }
+    storage.store(block).await
+}
+
+async fn load<S: CalStorage>(storage: &mut S, cmd: &Command) -> Result<(), i16> {
+    let mut resp = Response {
+        len: 0,
+        data: [0; MAX_SCPI_LEN],
+        segments: Vec::new(),
+        eom: true,
+        term_char_matched: false,
+        raw: false,
+        token: cmd.token,
+    };
+    // The table goes in after room for the longest preamble, which is then written right
+    // up against it.
+    let table_end = MAX_PREAMBLE_LEN + MAX_CAL_LEN;
+    let len = storage
+        .load(&mut resp.data[MAX_PREAMBLE_LEN..table_end])
+        .await?;
+    let mut preamble = [0; MAX_PREAMBLE_LEN];
+    let preamble_len = block_preamble(len, &mut preamble);
+    let start = MAX_PREAMBLE_LEN - preamble_len;
+    resp.data[start..MAX_PREAMBLE_LEN].copy_from_slice(&preamble[..preamble_len]);
+    let end = MAX_PREAMBLE_LEN + len;
+    resp.data[end] = b'\n';
+    let _ = resp.segments.push(Segment::Data {
+        start: start as u16,
+        end: (end + 1) as u16,
+    });
+    RESP_CHANNEL.send(resp).await;
+    Ok(())
+}
@@ -0,0 +1,132 @@
+//! Where the class reads the time and waits out its timeouts.
+//!
+//! Everything goes to embassy-time until [`set_clock`] installs another [`Clock`]. Host-side
+//! tests install a [`ManualClock`] and step it with [`ManualClock::advance`]: response
+//! timeouts, host-silence detection and the safety task's deadlines then fire exactly when
+//! the test moves the clock past them, with no real delay.
+//!
+//! Measurements of the class's own execution time, such as the control handler's
+//! [`CONTROL_BUDGET`](crate::CONTROL_BUDGET) and self-test durations, stay on embassy-time:
+//! they are about the CPU, not about the host.
+
+use core::cell::{Cell, RefCell};
+use core::future::{Future, poll_fn};
+use core::task::Poll;
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::waitqueue::MultiWakerRegistration;
+use embassy_time::{Duration, Instant, TimeoutError, Timer};
+
+/// A source of the current time.
+pub trait Clock: Sync {
+    fn now(&self) -> Instant;
+
+    /// Whether the clock is embassy-time's, or runs in step with it, so deadlines can be left
+    /// to an embassy-time [`Timer`]. A clock that only moves when told to returns `false` and
+    /// calls [`wake_waiters`] each time it moves.
+    fn follows_timer(&self) -> bool {
+        true
+    }
+}
+
+/// embassy-time's clock; the default.
+pub struct EmbassyClock;
+
+impl Clock for EmbassyClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that stands still until it is advanced.
+pub struct ManualClock {
+    ticks: Mutex<CriticalSectionRawMutex, Cell<u64>>,
+}
+
+impl ManualClock {
+    pub const fn new(start: Instant) -> Self {
+        Self {
+            ticks: Mutex::new(Cell::new(start.as_ticks())),
+        }
+    }
+
+    /// Moves the clock forward, firing every deadline it passes.
+    pub fn advance(&self, by: Duration) {
+        self.ticks
+            .lock(|ticks| ticks.set(ticks.get() + by.as_ticks()));
+        wake_waiters();
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        Instant::from_ticks(self.ticks.lock(Cell::get))
+    }
+
+    fn follows_timer(&self) -> bool {
+        false
+    }
+}
+
+/// Deadline waits a clock that doesn't follow the timer can wake. More than this many at a
+/// time are all woken to check again, which costs a poll but loses none.
+const MAX_WAITERS: usize = 8;
+
+static CLOCK: Mutex<CriticalSectionRawMutex, Cell<&'static dyn Clock>> =
+    Mutex::new(Cell::new(&EmbassyClock));
+static WAITERS: Mutex<CriticalSectionRawMutex, RefCell<MultiWakerRegistration<MAX_WAITERS>>> =
+    Mutex::new(RefCell::new(MultiWakerRegistration::new()));
+
+/// Makes the class keep time by `clock`. Install it before starting the class tasks.
+pub fn set_clock(clock: &'static dyn Clock) {
+    CLOCK.lock(|current| current.set(clock));
+    wake_waiters();
+}
+
+/// The time on the installed clock.
+pub fn now() -> Instant {
+    CLOCK.lock(Cell::get).now()
+}
+
+/// Wakes every deadline wait to check the clock again.
+pub fn wake_waiters() {
+    WAITERS.lock(|waiters| waiters.borrow_mut().wake());
+}
+
+/// Waits until the installed clock reaches `deadline`.
+pub async fn at(deadline: Instant) {
+    if CLOCK.lock(Cell::get).follows_timer() {
+        return Timer::at(deadline).await;
+    }
+    poll_fn(|cx| {
+        if now() >= deadline {
+            return Poll::Ready(());
+        }
+        WAITERS.lock(|waiters| waiters.borrow_mut().register(cx.waker()));
+        Poll::Pending
+    })
+    .await
+}
+
+/// Waits for `duration` on the installed clock.
+pub async fn after(duration: Duration) {
+    at(now() + duration).await
+}
+
+/// Runs `fut` until it completes or `timeout` passes on the installed clock.
+pub async fn with_timeout<F: Future>(timeout: Duration, fut: F) -> Result<F::Output, TimeoutError> {
+    with_deadline(now() + timeout, fut).await
+}
+
+/// Runs `fut` until it completes or the installed clock reaches `deadline`.
+pub async fn with_deadline<F: Future>(
+    deadline: Instant,
+    fut: F,
+) -> Result<F::Output, TimeoutError> {
+    match select(fut, at(deadline)).await {
+        Either::First(output) => Ok(output),
+        Either::Second(()) => Err(TimeoutError),
+    }
+}
@@ -0,0 +1,79 @@
+//! Checks a [`TmcConfig`] against what USBTMC and USB488 require of the descriptors and
+//! capabilities built from it.
+//!
+//! With the `descriptor-check` feature, [`UsbTmc::new`](crate::UsbTmc::new) runs [`check`]
+//! and logs each violation through defmt, so a misconfigured interface shows up at boot
+//! instead of as a host driver quietly refusing the device.
+
+use heapless::Vec;
+
+use crate::TmcConfig;
+
+/// A requirement the interface built from a configuration breaks.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "descriptor-check", derive(defmt::Format))]
+pub enum Violation {
+    /// `bcd_usb488` is set, so bInterfaceProtocol says USB488, but the `usb488` feature is
+    /// off and GET_CAPABILITIES leaves the USB488 fields empty.
+    Usb488WithoutLayer,
+    /// USB488 requires an Interrupt-IN endpoint; `interrupt_in` is off.
+    Usb488WithoutInterruptIn,
+    /// IEEE 488.2 compliance is claimed on a plain USBTMC interface, where it can't be
+    /// advertised.
+    Ieee4882WithoutUsb488,
+    /// A bulk wMaxPacketSize USB doesn't allow: 8, 16, 32 or 64 at full speed, 512 at high.
+    BulkPacketSize(u16),
+    /// An endpoint number outside 1..=15.
+    EndpointNumber(u8),
+    /// The Interrupt-IN endpoint asked for the Bulk-IN endpoint's number.
+    InterruptSharesBulkIn,
+    /// A `max_transfer_size` of 0 refuses every command.
+    NoTransferSize,
+}
+
+/// Most violations one configuration can have.
+pub const MAX_VIOLATIONS: usize = 8;
+
+/// Every violation in `config`, empty for a conforming one.
+pub fn check(config: &TmcConfig) -> Vec<Violation, MAX_VIOLATIONS> {
+    let mut violations = Vec::new();
+    let mut report = |violation| {
+        let _ = violations.push(violation);
+    };
+
+    if config.bcd_usb488.is_some() {
+        if !cfg!(feature = "usb488") {
+            report(Violation::Usb488WithoutLayer);
+        }
+        if !config.interrupt_in {
+            report(Violation::Usb488WithoutInterruptIn);
+        }
+    } else if config.ieee4882 {
+        report(Violation::Ieee4882WithoutUsb488);
+    }
+    if !matches!(config.max_packet_size, 8 | 16 | 32 | 64 | 512) {
+        report(Violation::BulkPacketSize(config.max_packet_size));
+    }
+    for number in [
+        config.bulk_out_endpoint,
+        config.bulk_in_endpoint,
+        config.interrupt_in_endpoint,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        if !(1..=15).contains(&number) {
+            report(Violation::EndpointNumber(number));
+        }
+    }
+    if config.interrupt_in
+        && config.interrupt_in_endpoint.is_some()
+        && config.interrupt_in_endpoint == config.bulk_in_endpoint
+    {
+        report(Violation::InterruptSharesBulkIn);
+    }
+    if config.max_transfer_size == 0 {
+        report(Violation::NoTransferSize);
+    }
+    violations
+}
@@ -0,0 +1,220 @@
+//! USBTMC and USB488 class-specific control requests: bRequest codes, wValue layouts,
+//! USBTMC_status values and the GET_CAPABILITIES bitmaps.
+
+/// bRequest of INITIATE_ABORT_BULK_OUT. Recipient: the Bulk-OUT endpoint.
+pub const INITIATE_ABORT_BULK_OUT: u8 = 1;
+/// bRequest of CHECK_ABORT_BULK_OUT_STATUS. Recipient: the Bulk-OUT endpoint.
+pub const CHECK_ABORT_BULK_OUT_STATUS: u8 = 2;
+/// bRequest of INITIATE_ABORT_BULK_IN. Recipient: the Bulk-IN endpoint.
+pub const INITIATE_ABORT_BULK_IN: u8 = 3;
+/// bRequest of CHECK_ABORT_BULK_IN_STATUS. Recipient: the Bulk-IN endpoint.
+pub const CHECK_ABORT_BULK_IN_STATUS: u8 = 4;
+/// bRequest of INITIATE_CLEAR. Recipient: the interface.
+pub const INITIATE_CLEAR: u8 = 5;
+/// bRequest of CHECK_CLEAR_STATUS. Recipient: the interface.
+pub const CHECK_CLEAR_STATUS: u8 = 6;
+/// bRequest of GET_CAPABILITIES. Recipient: the interface.
+pub const GET_CAPABILITIES: u8 = 7;
+/// bRequest of INDICATOR_PULSE. Recipient: the interface.
+pub const INDICATOR_PULSE: u8 = 64;
+/// bRequest of USB488 READ_STATUS_BYTE. Recipient: the interface.
+pub const READ_STATUS_BYTE: u8 = 128;
+/// bRequest of USB488 REN_CONTROL. Recipient: the interface.
+pub const REN_CONTROL: u8 = 160;
+/// bRequest of USB488 GO_TO_LOCAL. Recipient: the interface.
+pub const GO_TO_LOCAL: u8 = 161;
+/// bRequest of USB488 LOCAL_LOCKOUT. Recipient: the interface.
+pub const LOCAL_LOCKOUT: u8 = 162;
+
+/// A class-specific bRequest this crate knows about.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ControlRequest {
+    InitiateAbortBulkOut = INITIATE_ABORT_BULK_OUT,
+    CheckAbortBulkOutStatus = CHECK_ABORT_BULK_OUT_STATUS,
+    InitiateAbortBulkIn = INITIATE_ABORT_BULK_IN,
+    CheckAbortBulkInStatus = CHECK_ABORT_BULK_IN_STATUS,
+    InitiateClear = INITIATE_CLEAR,
+    CheckClearStatus = CHECK_CLEAR_STATUS,
+    GetCapabilities = GET_CAPABILITIES,
+    IndicatorPulse = INDICATOR_PULSE,
+    ReadStatusByte = READ_STATUS_BYTE,
+    RenControl = REN_CONTROL,
+    GoToLocal = GO_TO_LOCAL,
+    LocalLockout = LOCAL_LOCKOUT,
+}
+
+impl ControlRequest {
+    /// `None` for a bRequest that neither USBTMC nor USB488 defines.
+    pub fn from_u8(request: u8) -> Option<Self> {
+        Some(match request {
+            INITIATE_ABORT_BULK_OUT => ControlRequest::InitiateAbortBulkOut,
+            CHECK_ABORT_BULK_OUT_STATUS => ControlRequest::CheckAbortBulkOutStatus,
+            INITIATE_ABORT_BULK_IN => ControlRequest::InitiateAbortBulkIn,
+            CHECK_ABORT_BULK_IN_STATUS => ControlRequest::CheckAbortBulkInStatus,
+            INITIATE_CLEAR => ControlRequest::InitiateClear,
+            CHECK_CLEAR_STATUS => ControlRequest::CheckClearStatus,
+            GET_CAPABILITIES => ControlRequest::GetCapabilities,
+            INDICATOR_PULSE => ControlRequest::IndicatorPulse,
+            READ_STATUS_BYTE => ControlRequest::ReadStatusByte,
+            REN_CONTROL => ControlRequest::RenControl,
+            GO_TO_LOCAL => ControlRequest::GoToLocal,
+            LOCAL_LOCKOUT => ControlRequest::LocalLockout,
+            _ => return None,
+        })
+    }
+
+    /// Whether the request is addressed to a bulk endpoint rather than the interface.
+    pub fn to_endpoint(self) -> bool {
+        matches!(
+            self,
+            ControlRequest::InitiateAbortBulkOut
+                | ControlRequest::CheckAbortBulkOutStatus
+                | ControlRequest::InitiateAbortBulkIn
+                | ControlRequest::CheckAbortBulkInStatus
+        )
+    }
+}
+
+/// USBTMC_status, the first byte of every class-specific response.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Status {
+    Success = 0x01,
+    Pending = 0x02,
+    /// USB488: the interrupt endpoint will deliver the status byte.
+    InterruptInBusy = 0x20,
+    Failed = 0x80,
+    TransferNotInProgress = 0x81,
+    SplitNotInProgress = 0x82,
+    SplitInProgress = 0x83,
+}
+
+/// wValue of the abort and READ_STATUS_BYTE requests: the bTag they refer to, in the low byte.
+pub fn value_b_tag(w_value: u16) -> u8 {
+    w_value as u8
+}
+
+/// wValue of REN_CONTROL: whether the host asserts Remote Enable.
+pub fn value_ren(w_value: u16) -> bool {
+    w_value & 0x0001 != 0
+}
+
+/// Length of the GET_CAPABILITIES response.
+pub const CAPABILITIES_LEN: usize = 0x18;
+
+macro_rules! capability_flags {
+    ($(#[$meta:meta])* $name:ident { $($(#[$flag_meta:meta])* $flag:ident = $bit:expr;)* }) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, PartialEq, Eq, Default)]
+        pub struct $name(u8);
+
+        impl $name {
+            pub const NONE: Self = Self(0);
+            $($(#[$flag_meta])* pub const $flag: Self = Self(1 << $bit);)*
+
+            pub const fn bits(self) -> u8 {
+                self.0
+            }
+
+            pub const fn contains(self, other: Self) -> bool {
+                self.0 & other.0 == other.0
+            }
+
+            pub const fn union(self, other: Self) -> Self {
+                Self(self.0 | other.0)
+            }
+        }
+
+        impl core::ops::BitOr for $name {
+            type Output = Self;
+
+            fn bitor(self, other: Self) -> Self {
+                self.union(other)
+            }
+        }
+    };
+}
+
+capability_flags! {
+    /// USBTMC interface capabilities, GET_CAPABILITIES byte 4.
+    InterfaceCapabilities {
+        /// Accepts INDICATOR_PULSE.
+        INDICATOR_PULSE = 2;
+        /// Talk-only: never accepts DEV_DEP_MSG_OUT.
+        TALK_ONLY = 1;
+        /// Listen-only: never sends DEV_DEP_MSG_IN.
+        LISTEN_ONLY = 0;
+    }
+}
+
+capability_flags! {
+    /// USBTMC device capabilities, GET_CAPABILITIES byte 5.
+    DeviceCapabilities {
+        /// Ends a DEV_DEP_MSG_IN at the TermChar the host asks for.
+        TERM_CHAR = 0;
+    }
+}
+
+capability_flags! {
+    /// USB488 interface capabilities, GET_CAPABILITIES byte 14.
+    Usb488InterfaceCapabilities {
+        /// IEEE 488.2 interface: accepts the mandatory 488.2 common commands.
+        IEEE4882 = 2;
+        /// Accepts REN_CONTROL, GO_TO_LOCAL and LOCAL_LOCKOUT.
+        REN_CONTROL = 1;
+        /// Accepts the TRIGGER message.
+        TRIGGER = 0;
+    }
+}
+
+capability_flags! {
+    /// USB488 device capabilities, GET_CAPABILITIES byte 15.
+    Usb488DeviceCapabilities {
+        /// Understands all mandatory SCPI commands.
+        SCPI = 3;
+        /// SR1: can request service through the interrupt endpoint.
+        SR1 = 2;
+        /// RL1: full remote/local capability.
+        RL1 = 1;
+        /// DT1: full device trigger capability.
+        DT1 = 0;
+    }
+}
+
+/// Everything GET_CAPABILITIES reports.
+///
+/// Built once from the configuration; the control handler and the runner check requests
+/// against the same value they advertise.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub bcd_usbtmc: u16,
+    pub interface: InterfaceCapabilities,
+    pub device: DeviceCapabilities,
+    /// `None` for a plain USBTMC interface: no USB488 fields, no USB488 requests.
+    pub bcd_usb488: Option<u16>,
+    pub usb488_interface: Usb488InterfaceCapabilities,
+    pub usb488_device: Usb488DeviceCapabilities,
+}
+
+impl Capabilities {
+    /// Whether the device advertises the USB488 interface capability `cap`.
+    pub fn usb488(&self, cap: Usb488InterfaceCapabilities) -> bool {
+        self.bcd_usb488.is_some() && self.usb488_interface.contains(cap)
+    }
+
+    /// The GET_CAPABILITIES response, status byte included.
+    pub fn encode(&self) -> [u8; CAPABILITIES_LEN] {
+        let mut caps = [0u8; CAPABILITIES_LEN];
+        caps[0] = Status::Success as u8;
+        caps[2..4].copy_from_slice(&self.bcd_usbtmc.to_le_bytes());
+        caps[4] = self.interface.bits();
+        caps[5] = self.device.bits();
+        if let Some(bcd_usb488) = self.bcd_usb488 {
+            caps[12..14].copy_from_slice(&bcd_usb488.to_le_bytes());
+            caps[14] = self.usb488_interface.bits();
+            caps[15] = self.usb488_device.bits();
+        }
+        caps
+    }
+}
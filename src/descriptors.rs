@@ -0,0 +1,119 @@
+//! A readable dump of what the host will see at enumeration, logged through defmt, for
+//! checking a configuration without a USB analyzer (`descriptor-dump` feature).
+//!
+//! embassy-usb keeps the descriptor bytes to itself once the device is built, so the dump is
+//! rendered from what they are built from: the device [`Config`], the endpoints
+//! [`UsbTmc::new`](crate::UsbTmc::new) was given, and the GET_CAPABILITIES response.
+//!
+//! ```ignore
+//! let tmc = UsbTmc::new(&mut usb_builder, tmc_config);
+//! tmc.dump_descriptors(&usb_config);
+//! ```
+
+use embassy_usb::Config;
+
+use crate::control::{
+    Capabilities, DeviceCapabilities, InterfaceCapabilities, Usb488DeviceCapabilities,
+    Usb488InterfaceCapabilities,
+};
+use crate::{USBTMC_CLASS, USBTMC_SUBCLASS};
+
+/// The interface and endpoints the class was allocated.
+#[derive(Clone, Copy)]
+pub struct InterfaceLayout {
+    pub interface: u8,
+    /// bInterfaceProtocol: 0 for USBTMC, 1 for USB488.
+    pub protocol: u8,
+    /// bEndpointAddress of each endpoint, direction bit included.
+    pub bulk_out: u8,
+    pub bulk_in: u8,
+    pub interrupt_in: Option<u8>,
+    pub max_packet_size: u16,
+    /// bInterval of the Interrupt-IN endpoint, in milliseconds at full speed.
+    pub interval_ms: u8,
+}
+
+/// Logs the device descriptor fields, the configuration attributes, the USBTMC interface and
+/// its endpoints, and the capabilities the host will read.
+pub fn dump(usb: &Config, layout: &InterfaceLayout, caps: &Capabilities) {
+    defmt::info!(
+        "device: VID {=u16:04x} PID {=u16:04x} bcdDevice {=u16:04x} class {=u8:02x}/{=u8:02x}/{=u8:02x} EP0 {} bytes",
+        usb.vendor_id,
+        usb.product_id,
+        usb.device_release,
+        usb.device_class,
+        usb.device_sub_class,
+        usb.device_protocol,
+        usb.max_packet_size_0
+    );
+    defmt::info!(
+        "strings: manufacturer {} product {} serial {}",
+        usb.manufacturer,
+        usb.product,
+        usb.serial_number
+    );
+    defmt::info!(
+        "configuration: {} mA, self-powered {}, remote wakeup {}",
+        usb.max_power,
+        usb.self_powered,
+        usb.supports_remote_wakeup
+    );
+    defmt::info!(
+        "interface {}: class {=u8:02x}/{=u8:02x}/{=u8:02x} ({})",
+        layout.interface,
+        USBTMC_CLASS,
+        USBTMC_SUBCLASS,
+        layout.protocol,
+        if layout.protocol == 0 {
+            "USBTMC"
+        } else {
+            "USB488"
+        }
+    );
+    defmt::info!(
+        "  bulk OUT {=u8:02x}, {} bytes",
+        layout.bulk_out,
+        layout.max_packet_size
+    );
+    defmt::info!(
+        "  bulk IN {=u8:02x}, {} bytes",
+        layout.bulk_in,
+        layout.max_packet_size
+    );
+    if let Some(address) = layout.interrupt_in {
+        defmt::info!(
+            "  interrupt IN {=u8:02x}, 2 bytes every {} ms",
+            address,
+            layout.interval_ms
+        );
+    }
+
+    defmt::info!(
+        "capabilities: bcdUSBTMC {=u16:04x}, indicator pulse {}, talk-only {}, listen-only {}",
+        caps.bcd_usbtmc,
+        caps.interface
+            .contains(InterfaceCapabilities::INDICATOR_PULSE),
+        caps.interface.contains(InterfaceCapabilities::TALK_ONLY),
+        caps.interface.contains(InterfaceCapabilities::LISTEN_ONLY)
+    );
+    defmt::info!(
+        "  TermChar {}",
+        caps.device.contains(DeviceCapabilities::TERM_CHAR)
+    );
+    if let Some(bcd_usb488) = caps.bcd_usb488 {
+        defmt::info!(
+            "  bcdUSB488 {=u16:04x}, 488.2 {}, REN control {}, trigger {}, SCPI {}, SR1 {}, RL1 {}, DT1 {}",
+            bcd_usb488,
+            caps.usb488_interface
+                .contains(Usb488InterfaceCapabilities::IEEE4882),
+            caps.usb488_interface
+                .contains(Usb488InterfaceCapabilities::REN_CONTROL),
+            caps.usb488_interface
+                .contains(Usb488InterfaceCapabilities::TRIGGER),
+            caps.usb488_device.contains(Usb488DeviceCapabilities::SCPI),
+            caps.usb488_device.contains(Usb488DeviceCapabilities::SR1),
+            caps.usb488_device.contains(Usb488DeviceCapabilities::RL1),
+            caps.usb488_device.contains(Usb488DeviceCapabilities::DT1)
+        );
+    }
+}
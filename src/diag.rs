@@ -0,0 +1,55 @@
+//! `DIAGnostic:ECHO?` for measuring command turnaround: the device answers the query's
+//! parameter text back unchanged, so the host times a round trip with a payload of its
+//! choosing and checks it came back intact.
+//!
+//! Answer it from [`echo_inline`] to time the class alone, from the runner, or from
+//! [`serve`] in the application task to time the path real commands take. `host/latency.py`
+//! drives either and prints the latency distribution.
+
+use heapless::Vec;
+
+use crate::params::split_header;
+use crate::scpi::header_matches;
+use crate::{Command, Inline, MAX_SCPI_LEN, RESP_CHANNEL, Response};
+
+/// [`InlineHandler`](crate::InlineHandler) answering `DIAGnostic:ECHO?` in the runner.
+/// Anything else passes through.
+pub fn echo_inline(cmd: &[u8], resp: &mut Response) -> Inline {
+    match echo_payload(cmd) {
+        Some(payload) => {
+            write_echo(payload, resp);
+            Inline::Done
+        }
+        None => Inline::Pass,
+    }
+}
+
+/// Answers `DIAGnostic:ECHO?` from the application task. `None` if `cmd` is something else.
+pub async fn serve(cmd: &Command) -> Option<()> {
+    let payload = echo_payload(&cmd.data[..cmd.len])?;
+    let mut resp = Response {
+        len: 0,
+        data: [0; MAX_SCPI_LEN],
+        segments: Vec::new(),
+        eom: true,
+        term_char_matched: false,
+        raw: false,
+        token: cmd.token,
+    };
+    write_echo(payload, &mut resp);
+    RESP_CHANNEL.send(resp).await;
+    Some(())
+}
+
+fn echo_payload(cmd: &[u8]) -> Option<&[u8]> {
+    let (header, params) = split_header(cmd);
+    header_matches(header, b"DIAGnostic:ECHO?").then_some(params)
+}
+
+/// The payload and a newline, cut to fit.
+fn write_echo(payload: &[u8], resp: &mut Response) {
+    let len = payload.len().min(MAX_SCPI_LEN - 1);
+    resp.data[..len].copy_from_slice(&payload[..len]);
+    resp.data[len] = b'\n';
+    resp.len = len + 1;
+}
@@ -0,0 +1,157 @@
+//! Class events for tasks that want to hear about them without polling: triggers, device
+//! clears, a lost host, sessions starting and ending.
+//!
+//! Each listener declares a [`Subscription`] with the events it cares about and a queue
+//! depth, and registers it with [`subscribe`]:
+//!
+//! ```ignore
+//! static TRIGGERS: Subscription<2> =
+//!     Subscription::new(EventMask::TRIGGER.union(EventMask::DEVICE_CLEAR));
+//! events::subscribe(&TRIGGERS);
+//! loop {
+//!     match TRIGGERS.next().await { ... }
+//! }
+//! ```
+//!
+//! An event only goes into the queues of subscriptions whose mask includes it, so a
+//! high-priority task listening for triggers isn't woken by session changes. The depth is
+//! the subscription's own: RAM is bounded by what each listener declares, and one that falls
+//! behind loses its newest events, counted in [`Subscription::lagged`], without holding up
+//! the others.
+
+use core::cell::{Cell, RefCell};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use heapless::Vec;
+
+use crate::HostLoss;
+use crate::session::{SessionEnd, SessionId};
+
+/// Most subscriptions [`subscribe`] accepts.
+pub const MAX_SUBSCRIPTIONS: usize = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ClassEvent {
+    /// A USB488 TRIGGER message.
+    Trigger,
+    /// INITIATE_CLEAR.
+    DeviceClear,
+    HostLost(HostLoss),
+    SessionStarted(SessionId),
+    SessionEnded(SessionId, SessionEnd),
+}
+
+impl ClassEvent {
+    fn kind(self) -> EventMask {
+        match self {
+            ClassEvent::Trigger => EventMask::TRIGGER,
+            ClassEvent::DeviceClear => EventMask::DEVICE_CLEAR,
+            ClassEvent::HostLost(_) => EventMask::HOST_LOST,
+            ClassEvent::SessionStarted(_) | ClassEvent::SessionEnded(..) => EventMask::SESSION,
+        }
+    }
+}
+
+/// A set of [`ClassEvent`] kinds.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct EventMask(u8);
+
+impl EventMask {
+    pub const NONE: Self = Self(0);
+    pub const TRIGGER: Self = Self(1 << 0);
+    pub const DEVICE_CLEAR: Self = Self(1 << 1);
+    pub const HOST_LOST: Self = Self(1 << 2);
+    /// Both session events.
+    pub const SESSION: Self = Self(1 << 3);
+    pub const ALL: Self = Self(0x0F);
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl core::ops::BitOr for EventMask {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        self.union(other)
+    }
+}
+
+/// One listener's filter and queue, holding up to `N` events.
+pub struct Subscription<const N: usize> {
+    mask: Mutex<CriticalSectionRawMutex, Cell<EventMask>>,
+    events: Channel<CriticalSectionRawMutex, ClassEvent, N>,
+    lagged: AtomicU32,
+}
+
+impl<const N: usize> Subscription<N> {
+    pub const fn new(mask: EventMask) -> Self {
+        Self {
+            mask: Mutex::new(Cell::new(mask)),
+            events: Channel::new(),
+            lagged: AtomicU32::new(0),
+        }
+    }
+
+    /// Changes the events the subscription takes from now on. Queued ones stay.
+    pub fn set_mask(&self, mask: EventMask) {
+        self.mask.lock(|current| current.set(mask));
+    }
+
+    /// Waits for the next event the mask lets through.
+    pub async fn next(&self) -> ClassEvent {
+        self.events.receive().await
+    }
+
+    pub fn try_next(&self) -> Option<ClassEvent> {
+        self.events.try_receive().ok()
+    }
+
+    /// Events dropped because the queue was full.
+    pub fn lagged(&self) -> u32 {
+        self.lagged.load(Ordering::Relaxed)
+    }
+}
+
+trait Sink: Sync {
+    fn offer(&self, event: ClassEvent);
+}
+
+impl<const N: usize> Sink for Subscription<N> {
+    fn offer(&self, event: ClassEvent) {
+        if !self.mask.lock(Cell::get).contains(event.kind()) {
+            return;
+        }
+        if self.events.try_send(event).is_err() {
+            self.lagged.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+static SUBSCRIPTIONS: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<Vec<&'static dyn Sink, MAX_SUBSCRIPTIONS>>,
+> = Mutex::new(RefCell::new(Vec::new()));
+
+/// Starts delivering events to `subscription`. Returns `false` if [`MAX_SUBSCRIPTIONS`]
+/// are already registered.
+pub fn subscribe<const N: usize>(subscription: &'static Subscription<N>) -> bool {
+    SUBSCRIPTIONS.lock(|subs| subs.borrow_mut().push(subscription).is_ok())
+}
+
+/// Offers `event` to every subscription; only those whose mask takes it are woken.
+pub(crate) fn publish(event: ClassEvent) {
+    SUBSCRIPTIONS.lock(|subs| {
+        for sub in subs.borrow().iter() {
+            sub.offer(event);
+        }
+    });
+}
@@ -0,0 +1,169 @@
+//! Execution time per command header, from the moment the class has the whole command to the
+//! moment its handler is done, so handlers that come close to host timeouts show up before
+//! a host trips over them.
+//!
+//! Run each command's handler under [`timed`]. While accounting is on (see
+//! [`set_enabled`]), the time is booked against the command's header as sent, case folded,
+//! so `VOLT?` and `VOLTAGE?` are counted apart. [`worst`] lists the slowest headers, and
+//! [`serve`] answers them to the host:
+//!
+//! | Command | Does |
+//! |---|---|
+//! | `DIAGnostic:EXECtime?` | `"<header>",<count>,<worst µs>,<mean µs>` per header, slowest first |
+//! | `DIAGnostic:EXECtime:RESet` | Forgets every header |
+
+use core::cell::RefCell;
+use core::fmt::Write;
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Duration;
+use heapless::Vec;
+
+use crate::params::{BodyWriter, split_header};
+use crate::scpi::header_matches;
+use crate::{Command, MAX_SCPI_LEN, RESP_CHANNEL, Response, clock};
+
+/// Headers tracked at once. When a new one arrives with the table full, the header with the
+/// shortest worst time makes room.
+pub const MAX_TRACKED: usize = 16;
+
+/// Longest header kept; longer ones are cut.
+pub const MAX_HEADER_LEN: usize = 24;
+
+/// What one header has cost so far.
+#[derive(Clone)]
+pub struct ExecTime {
+    pub header: Vec<u8, MAX_HEADER_LEN>,
+    pub count: u32,
+    pub worst: Duration,
+    pub total: Duration,
+}
+
+impl ExecTime {
+    pub fn mean(&self) -> Duration {
+        self.total / self.count.max(1)
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TABLE: Mutex<CriticalSectionRawMutex, RefCell<Vec<ExecTime, MAX_TRACKED>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// Turns accounting on or off. Off by default; turning it off keeps what was booked.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Runs `handler` for `cmd` and books the time from [`Command::received`] to its end against
+/// the command's header. The handler should submit its response before it returns.
+pub async fn timed<F: Future>(cmd: &Command, handler: F) -> F::Output {
+    let output = handler.await;
+    if is_enabled() {
+        let (header, _) = split_header(&cmd.data[..cmd.len]);
+        record(header, clock::now().saturating_duration_since(cmd.received));
+    }
+    output
+}
+
+/// Books `elapsed` against `header`.
+pub fn record(header: &[u8], elapsed: Duration) {
+    let mut key = Vec::<u8, MAX_HEADER_LEN>::new();
+    for &byte in header.iter().take(MAX_HEADER_LEN) {
+        let _ = key.push(byte.to_ascii_uppercase());
+    }
+    TABLE.lock(|table| {
+        let mut table = table.borrow_mut();
+        let i = match table.iter().position(|entry| entry.header == key) {
+            Some(i) => i,
+            None => {
+                let fresh = ExecTime {
+                    header: key,
+                    count: 0,
+                    worst: Duration::from_ticks(0),
+                    total: Duration::from_ticks(0),
+                };
+                if table.is_full() {
+                    let Some((cheapest, _)) = table
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, entry)| entry.worst)
+                    else {
+                        return;
+                    };
+                    if table[cheapest].worst > elapsed {
+                        return;
+                    }
+                    table[cheapest] = fresh;
+                    cheapest
+                } else {
+                    let _ = table.push(fresh);
+                    table.len() - 1
+                }
+            }
+        };
+        let entry = &mut table[i];
+        entry.count = entry.count.saturating_add(1);
+        entry.worst = entry.worst.max(elapsed);
+        entry.total += elapsed;
+    });
+}
+
+/// Every tracked header, slowest worst case first.
+pub fn worst() -> Vec<ExecTime, MAX_TRACKED> {
+    let mut entries = TABLE.lock(|table| table.borrow().clone());
+    entries.sort_unstable_by(|a, b| b.worst.cmp(&a.worst));
+    entries
+}
+
+/// Forgets every header.
+pub fn reset() {
+    TABLE.lock(|table| table.borrow_mut().clear());
+}
+
+/// Handles `DIAGnostic:EXECtime?` and `DIAGnostic:EXECtime:RESet`. `None` if `cmd` is
+/// something else. Headers that don't fit the response are left out.
+pub async fn serve(cmd: &Command) -> Option<()> {
+    let (header, _) = split_header(&cmd.data[..cmd.len]);
+    if header_matches(header, b"DIAGnostic:EXECtime:RESet") {
+        reset();
+        return Some(());
+    }
+    if !header_matches(header, b"DIAGnostic:EXECtime?") {
+        return None;
+    }
+    let mut resp = Response {
+        len: 0,
+        data: [0; MAX_SCPI_LEN],
+        segments: Vec::new(),
+        eom: true,
+        term_char_matched: false,
+        raw: false,
+        token: cmd.token,
+    };
+    let mut body = BodyWriter(&mut resp);
+    for (i, entry) in worst().iter().enumerate() {
+        // Room for the separator, the numbers and the final newline.
+        if body.0.len + entry.header.len() + 40 > MAX_SCPI_LEN {
+            break;
+        }
+        let separator = if i == 0 { "" } else { "," };
+        let header = core::str::from_utf8(&entry.header).unwrap_or("?");
+        let _ = write!(
+            body,
+            "{separator}\"{header}\",{},{},{}",
+            entry.count,
+            entry.worst.as_micros(),
+            entry.mean().as_micros()
+        );
+    }
+    let _ = writeln!(body);
+    RESP_CHANNEL.send(resp).await;
+    Some(())
+}
@@ -0,0 +1,109 @@
+//! Build metadata for the host: the firmware field of `*IDN?` and
+//! `SYSTem:VERSion:FIRMware?`, from a [`FirmwareInfo`] the application fills in, typically
+//! from variables its build script sets:
+//!
+//! ```ignore
+//! static FIRMWARE: FirmwareInfo = FirmwareInfo {
+//!     version: env!("CARGO_PKG_VERSION"),
+//!     git_hash: env!("GIT_HASH"),
+//!     build_time: env!("BUILD_TIME"),
+//! };
+//! ```
+
+use core::fmt::{self, Write};
+
+use heapless::Vec;
+
+use crate::scpi::header_matches;
+use crate::{Command, MAX_SCPI_LEN, RESP_CHANNEL, Response};
+
+/// What was built, and when.
+#[derive(Clone, Copy)]
+pub struct FirmwareInfo {
+    pub version: &'static str,
+    /// Commit the firmware was built from, short or full.
+    pub git_hash: &'static str,
+    /// Any format; ISO 8601 reads best.
+    pub build_time: &'static str,
+}
+
+/// The rest of the `*IDN?` answer.
+#[derive(Clone, Copy)]
+pub struct Identity {
+    pub manufacturer: &'static str,
+    pub model: &'static str,
+    pub serial: &'static str,
+}
+
+/// Cargo features of this crate compiled into the firmware.
+pub const CLASS_FEATURES: &[&str] = &[
+    #[cfg(feature = "usb488")]
+    "usb488",
+    #[cfg(feature = "ieee4882")]
+    "ieee4882",
+    #[cfg(feature = "scpi")]
+    "scpi",
+    #[cfg(feature = "tcp")]
+    "tcp",
+    #[cfg(feature = "gateway")]
+    "gateway",
+    #[cfg(feature = "indicator")]
+    "indicator",
+];
+
+/// Answers `*IDN?` as `<manufacturer>,<model>,<serial>,<version>-<git hash>` and
+/// `SYSTem:VERSion:FIRMware?` as
+/// `"<version>","<git hash>","<build time>","<class features, space-separated>"`.
+/// `None` if `cmd` is something else.
+pub async fn serve(identity: &Identity, firmware: &FirmwareInfo, cmd: &Command) -> Option<()> {
+    let header = cmd.data[..cmd.len].trim_ascii();
+    let mut resp = Response {
+        len: 0,
+        data: [0; MAX_SCPI_LEN],
+        segments: Vec::new(),
+        eom: true,
+        term_char_matched: false,
+        raw: false,
+        token: cmd.token,
+    };
+    let mut body = Body(&mut resp);
+    if header.eq_ignore_ascii_case(b"*IDN?") {
+        let _ = writeln!(
+            body,
+            "{},{},{},{}-{}",
+            identity.manufacturer,
+            identity.model,
+            identity.serial,
+            firmware.version,
+            firmware.git_hash
+        );
+    } else if header_matches(header, b"SYSTem:VERSion:FIRMware?") {
+        let _ = write!(
+            body,
+            "\"{}\",\"{}\",\"{}\",\"",
+            firmware.version, firmware.git_hash, firmware.build_time
+        );
+        for (i, feature) in CLASS_FEATURES.iter().enumerate() {
+            let separator = if i == 0 { "" } else { " " };
+            let _ = write!(body, "{separator}{feature}");
+        }
+        let _ = writeln!(body, "\"");
+    } else {
+        return None;
+    }
+    RESP_CHANNEL.send(resp).await;
+    Some(())
+}
+
+/// Appends to a response body, dropping what doesn't fit.
+struct Body<'a>(&'a mut Response);
+
+impl Write for Body<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let resp = &mut *self.0;
+        let n = s.len().min(MAX_SCPI_LEN - resp.len);
+        resp.data[resp.len..resp.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        resp.len += n;
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+//! Bridge mode: forwards USBTMC commands to a serial instrument and relays its answers.
+//!
+//! With [`run`] in place of an application task, the device becomes a USB-to-RS232 (or GPIB
+//! controller) adapter: every command is written to the UART as the class delivered it,
+//! followed by [`GatewayConfig::line_ending`] in place of the terminator the class stripped,
+//! and for queries the reply is read back up to the terminator and queued as the response.
+//! [`run_multidrop`] fronts several instruments, picked by an address prefix on each command.
+
+use embassy_time::Duration;
+use embedded_io_async::{Read, Write};
+use heapless::Vec;
+
+use crate::{
+    CMD_CHANNEL, MAX_SCPI_LEN, RESP_CHANNEL, Response, SCPI_ERR_HARDWARE,
+    SCPI_ERR_HARDWARE_MISSING, SCPI_ERR_TOO_MUCH_DATA, clock, is_query, push_error,
+};
+
+#[derive(Clone, Copy)]
+pub struct GatewayConfig {
+    /// How long the instrument gets to finish its reply to a query.
+    pub response_timeout: Duration,
+    /// Byte that ends a reply from the instrument.
+    pub terminator: u8,
+    /// Written after each command, e.g. `b"\r\n"` for instruments that want a carriage
+    /// return.
+    pub line_ending: &'static [u8],
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        Self {
+            response_timeout: Duration::from_secs(2),
+            terminator: b'\n',
+            line_ending: b"\n",
+        }
+    }
+}
+
+/// Forwards commands to `uart` and relays replies until the end of time.
+///
+/// A query that gets no complete reply within `response_timeout` produces no response and
+/// queues [`SCPI_ERR_HARDWARE`]; a reply that overflows the response buffer queues
+/// [`SCPI_ERR_TOO_MUCH_DATA`] and is dropped.
+pub async fn run<U: Read + Write>(uart: &mut U, config: GatewayConfig) -> ! {
+    let cmd_rx = CMD_CHANNEL.receiver();
+
+    loop {
+        let cmd = cmd_rx.receive().await;
+        forward(uart, &cmd.data[..cmd.len], &config).await;
+    }
+}
+
+/// Like [`run`], but fronting one instrument per entry of `uarts`.
+///
+/// A command prefixed with a decimal address and a colon, `2:*IDN?`, goes to `uarts[2]` with
+/// the prefix stripped, and makes that instrument the current one. Unprefixed commands go to
+/// the current instrument, initially `uarts[0]`. SCPI headers never start with a digit, so the
+/// prefix can't be mistaken for part of a command. Addressing a missing instrument queues
+/// [`SCPI_ERR_HARDWARE_MISSING`].
+pub async fn run_multidrop<U: Read + Write>(uarts: &mut [U], config: GatewayConfig) -> ! {
+    let cmd_rx = CMD_CHANNEL.receiver();
+    let mut current = 0;
+
+    loop {
+        let cmd = cmd_rx.receive().await;
+        let mut data = &cmd.data[..cmd.len];
+
+        if let Some((address, rest)) = split_address(data) {
+            current = address;
+            data = rest;
+        }
+        match uarts.get_mut(current) {
+            Some(uart) => forward(uart, data, &config).await,
+            None => push_error(SCPI_ERR_HARDWARE_MISSING),
+        }
+    }
+}
+
+/// Splits a leading `<digits>:` address off a command.
+fn split_address(data: &[u8]) -> Option<(usize, &[u8])> {
+    let digits = data.iter().take_while(|b| b.is_ascii_digit()).count();
+    if digits == 0 || data.get(digits) != Some(&b':') {
+        return None;
+    }
+    let address = data[..digits].iter().try_fold(0usize, |acc, &b| {
+        acc.checked_mul(10)?.checked_add((b - b'0') as usize)
+    })?;
+    Some((address, &data[digits + 1..]))
+}
+
+async fn forward<U: Read + Write>(uart: &mut U, data: &[u8], config: &GatewayConfig) {
+    let written = uart.write_all(data).await.is_ok()
+        && uart.write_all(config.line_ending).await.is_ok()
+        && uart.flush().await.is_ok();
+    if !written {
+        push_error(SCPI_ERR_HARDWARE);
+        return;
+    }
+    if !is_query(data) {
+        return;
+    }
+
+    match read_reply(uart, config).await {
+        Ok(resp) => RESP_CHANNEL.send(resp).await,
+        Err(code) => push_error(code),
+    }
+}
+
+async fn read_reply<U: Read>(uart: &mut U, config: &GatewayConfig) -> Result<Response, i16> {
+    let deadline = clock::now() + config.response_timeout;
+    let mut resp = Response {
+        len: 0,
+        data: [0; MAX_SCPI_LEN],
+        segments: Vec::new(),
+        eom: true,
+        term_char_matched: false,
+        raw: false,
+        token: None,
+    };
+
+    loop {
+        if resp.len == MAX_SCPI_LEN {
+            return Err(SCPI_ERR_TOO_MUCH_DATA);
+        }
+        let n = match clock::with_deadline(deadline, uart.read(&mut resp.data[resp.len..])).await {
+            Ok(Ok(n)) => n,
+            Ok(Err(_)) | Err(_) => return Err(SCPI_ERR_HARDWARE),
+        };
+
+        let start = resp.len;
+        resp.len += n;
+        if let Some(pos) = resp.data[start..resp.len]
+            .iter()
+            .position(|&b| b == config.terminator)
+        {
+            // Anything after the terminator is the start of an unsolicited message; drop it.
+            resp.len = start + pos + 1;
+            return Ok(resp);
+        }
+    }
+}
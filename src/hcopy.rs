@@ -0,0 +1,139 @@
+//! Display dumps for `HCOPy:SDUMp:DATA?`: the framebuffer, read row by row from an
+//! application [`PixelSource`], streamed to the host as one definite-length block.
+//!
+//! The block starts with an 8-byte header, `"FB"`, width and height (u16 LE), bytes per
+//! pixel and the [`Encoding`], followed by the rows top to bottom. [`Encoding::Rle`] replaces
+//! each row with runs of `count - 1` (one byte) and the repeated pixel, runs never crossing
+//! rows; screens with flat backgrounds shrink severalfold.
+//!
+//! The block length has to lead the block, so RLE reads the framebuffer twice: once to size
+//! the runs and once to send them. Keep the display still for the length of the dump.
+
+use crate::params::{MAX_PREAMBLE_LEN, Params, Token, block_preamble, split_header};
+use crate::scpi::{
+    SCPI_ERR_ILLEGAL_PARAMETER, SCPI_ERR_PARAMETER_NOT_ALLOWED, SCPI_ERR_TOO_MUCH_DATA,
+    header_matches, node_matches,
+};
+use crate::{Command, ResponseStream, ResponseToken};
+
+/// Widest row, in bytes, a dump can read: 480 pixels at four bytes each.
+pub const MAX_ROW_LEN: usize = 1920;
+
+/// Length of the header in front of the pixel data.
+pub const DUMP_HEADER_LEN: usize = 8;
+
+/// How the rows of a dump are packed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Encoding {
+    /// Pixels as the source gives them.
+    Raw = 0,
+    /// Runs of identical pixels, see the module docs.
+    Rle = 1,
+}
+
+/// The display, as seen by a dump.
+#[allow(async_fn_in_trait)]
+pub trait PixelSource {
+    fn width(&self) -> u16;
+    fn height(&self) -> u16;
+    /// 1 to 4.
+    fn bytes_per_pixel(&self) -> u8;
+    /// Fills `out`, `width * bytes_per_pixel` bytes, with row `y`.
+    async fn row(&mut self, y: u16, out: &mut [u8]);
+}
+
+/// Answers `HCOPy:SDUMp:DATA?`, with an optional `RAW` or `RLE` parameter overriding
+/// `encoding`. `None` if `cmd` is something else; otherwise the outcome, with an error ready
+/// for [`push_error`](crate::push_error).
+pub async fn serve<P: PixelSource>(
+    source: &mut P,
+    cmd: &Command,
+    encoding: Encoding,
+) -> Option<Result<(), i16>> {
+    let (header, params) = split_header(&cmd.data[..cmd.len]);
+    if !header_matches(header, b"HCOPy:SDUMp:DATA?") {
+        return None;
+    }
+    let mut params = Params::new(params);
+    let encoding = match params.next() {
+        None => encoding,
+        Some(Ok(Token::Chars(word))) if node_matches(word, b"RAW") => Encoding::Raw,
+        Some(Ok(Token::Chars(word))) if node_matches(word, b"RLE") => Encoding::Rle,
+        Some(Err(code)) => return Some(Err(code)),
+        Some(_) => return Some(Err(SCPI_ERR_ILLEGAL_PARAMETER)),
+    };
+    if params.next().is_some() {
+        return Some(Err(SCPI_ERR_PARAMETER_NOT_ALLOWED));
+    }
+    Some(send_dump(source, cmd.token, encoding).await)
+}
+
+/// Streams a dump of `source` as the answer to the query `token` belongs to.
+pub async fn send_dump<P: PixelSource>(
+    source: &mut P,
+    token: Option<ResponseToken>,
+    encoding: Encoding,
+) -> Result<(), i16> {
+    let (width, height) = (source.width(), source.height());
+    let bpp = usize::from(source.bytes_per_pixel().clamp(1, 4));
+    let row_len = usize::from(width) * bpp;
+    if row_len > MAX_ROW_LEN {
+        return Err(SCPI_ERR_TOO_MUCH_DATA);
+    }
+    let mut row = [0; MAX_ROW_LEN];
+    let row = &mut row[..row_len];
+
+    let pixels_len = match encoding {
+        Encoding::Raw => row_len * usize::from(height),
+        Encoding::Rle => {
+            let mut len = 0;
+            for y in 0..height {
+                source.row(y, row).await;
+                len += runs(row, bpp).count() * (1 + bpp);
+            }
+            len
+        }
+    };
+
+    let mut stream = ResponseStream::new(token);
+    let mut preamble = [0; MAX_PREAMBLE_LEN];
+    let preamble_len = block_preamble(DUMP_HEADER_LEN + pixels_len, &mut preamble);
+    stream.write(&preamble[..preamble_len]).await;
+    let mut header = [0; DUMP_HEADER_LEN];
+    header[..2].copy_from_slice(b"FB");
+    header[2..4].copy_from_slice(&width.to_le_bytes());
+    header[4..6].copy_from_slice(&height.to_le_bytes());
+    header[6] = bpp as u8;
+    header[7] = encoding as u8;
+    stream.write(&header).await;
+
+    for y in 0..height {
+        source.row(y, row).await;
+        match encoding {
+            Encoding::Raw => stream.write(row).await,
+            Encoding::Rle => {
+                for (count, pixel) in runs(row, bpp) {
+                    stream.write(&[(count - 1) as u8]).await;
+                    stream.write(pixel).await;
+                }
+            }
+        }
+    }
+    stream.write(b"\n").await;
+    stream.finish().await;
+    Ok(())
+}
+
+/// Runs of identical pixels in `row`, at most 256 long: `(count, pixel)`.
+fn runs(row: &[u8], bpp: usize) -> impl Iterator<Item = (usize, &[u8])> {
+    let mut pixels = row.chunks_exact(bpp).peekable();
+    core::iter::from_fn(move || {
+        let pixel = pixels.next()?;
+        let mut count = 1;
+        while count < 256 && pixels.next_if_eq(&pixel).is_some() {
+            count += 1;
+        }
+        Some((count, pixel))
+    })
+}
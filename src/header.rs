@@ -0,0 +1,265 @@
+//! USBTMC Bulk-OUT and Bulk-IN message headers, as the runner reads and writes them.
+
+use crate::{
+    DEV_DEP_MSG_IN_EOM, DEV_DEP_MSG_IN_TERM_CHAR_MATCHED, DEV_DEP_MSG_OUT_EOM,
+    REQUEST_DEV_DEP_MSG_IN_TERM_CHAR,
+};
+
+/// Every Bulk-OUT and Bulk-IN transfer starts with a header this long.
+pub const HEADER_LEN: usize = 12;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HeaderError {
+    /// Fewer than [`HEADER_LEN`] bytes.
+    TooShort,
+    /// bTagInverse is not the one's complement of bTag.
+    BadTag,
+}
+
+/// MsgID of a USBTMC message. Bulk-OUT and Bulk-IN number their messages separately, so the
+/// raw value only means something together with the direction, see [`from_out`](Self::from_out)
+/// and [`from_in`](Self::from_in).
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MsgId {
+    /// Bulk-OUT 1: command data for the device.
+    DevDepMsgOut,
+    /// Bulk-OUT 2: the host wants to read a response.
+    RequestDevDepMsgIn,
+    /// Bulk-IN 2: response data for the host.
+    DevDepMsgIn,
+    /// Bulk-OUT 126.
+    VendorSpecificOut,
+    /// Bulk-OUT 127.
+    RequestVendorSpecificIn,
+    /// Bulk-IN 127.
+    VendorSpecificIn,
+    /// Bulk-OUT 128, USB488 TRIGGER (GET, group execute trigger).
+    Trigger,
+    /// 128..=191 other than TRIGGER: set aside for USBTMC subclass specifications.
+    SubclassReserved(u8),
+    /// 192..=255: set aside for the VISA specification.
+    VisaReserved(u8),
+    /// Any other value; no conforming peer sends it.
+    Reserved(u8),
+}
+
+impl MsgId {
+    /// Classifies the MsgID of a Bulk-OUT header.
+    pub fn from_out(id: u8) -> Self {
+        match id {
+            1 => MsgId::DevDepMsgOut,
+            2 => MsgId::RequestDevDepMsgIn,
+            126 => MsgId::VendorSpecificOut,
+            127 => MsgId::RequestVendorSpecificIn,
+            _ => Self::common(id),
+        }
+    }
+
+    /// Classifies the MsgID of a Bulk-IN header.
+    pub fn from_in(id: u8) -> Self {
+        match id {
+            2 => MsgId::DevDepMsgIn,
+            127 => MsgId::VendorSpecificIn,
+            _ => Self::common(id),
+        }
+    }
+
+    fn common(id: u8) -> Self {
+        match id {
+            128 => MsgId::Trigger,
+            129..=191 => MsgId::SubclassReserved(id),
+            192..=255 => MsgId::VisaReserved(id),
+            _ => MsgId::Reserved(id),
+        }
+    }
+
+    /// The raw MsgID byte.
+    pub fn id(self) -> u8 {
+        match self {
+            MsgId::DevDepMsgOut => 1,
+            MsgId::RequestDevDepMsgIn | MsgId::DevDepMsgIn => 2,
+            MsgId::VendorSpecificOut => 126,
+            MsgId::RequestVendorSpecificIn | MsgId::VendorSpecificIn => 127,
+            MsgId::Trigger => 128,
+            MsgId::SubclassReserved(id) | MsgId::VisaReserved(id) | MsgId::Reserved(id) => id,
+        }
+    }
+}
+
+/// Zero bytes that follow `payload_len` bytes of message data, so header, data and padding
+/// end on a 4-byte boundary.
+pub fn padding(payload_len: usize) -> usize {
+    (4 - (HEADER_LEN + payload_len) % 4) % 4
+}
+
+/// Header of a host-to-device message.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct BulkOutHeader {
+    pub msg_id: MsgId,
+    pub b_tag: u8,
+    /// Byte 3, reserved: zero from a conforming host.
+    pub reserved: u8,
+    pub transfer_size: u32,
+    /// Bytes 8..12. For DEV_DEP_MSG_OUT, bmTransferAttributes then three reserved bytes; for
+    /// REQUEST_DEV_DEP_MSG_IN, bmTransferAttributes, TermChar and two reserved bytes.
+    pub msg_specific: [u8; 4],
+}
+
+impl BulkOutHeader {
+    /// Reads the header at the start of `buf`, checking bTagInverse.
+    pub fn parse(buf: &[u8]) -> Result<Self, HeaderError> {
+        if buf.len() < HEADER_LEN {
+            return Err(HeaderError::TooShort);
+        }
+        if buf[2] != !buf[1] {
+            return Err(HeaderError::BadTag);
+        }
+        Ok(Self {
+            msg_id: MsgId::from_out(buf[0]),
+            b_tag: buf[1],
+            reserved: buf[3],
+            transfer_size: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+            msg_specific: buf[8..12].try_into().unwrap(),
+        })
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0u8; HEADER_LEN];
+        header[0] = self.msg_id.id();
+        header[1] = self.b_tag;
+        header[2] = !self.b_tag;
+        header[3] = self.reserved;
+        header[4..8].copy_from_slice(&self.transfer_size.to_le_bytes());
+        header[8..12].copy_from_slice(&self.msg_specific);
+        header
+    }
+
+    /// bmTransferAttributes.
+    pub fn attributes(&self) -> u8 {
+        self.msg_specific[0]
+    }
+
+    /// TermChar of a REQUEST_DEV_DEP_MSG_IN.
+    pub fn term_char(&self) -> u8 {
+        self.msg_specific[1]
+    }
+
+    /// Whether every reserved field is zero, as far as the message is known.
+    pub fn reserved_fields_clear(&self) -> bool {
+        let m = &self.msg_specific;
+        let msg_specific_clear = match self.msg_id {
+            MsgId::DevDepMsgOut => m[0] & !DEV_DEP_MSG_OUT_EOM == 0 && m[1..4] == [0; 3],
+            MsgId::RequestDevDepMsgIn => {
+                m[0] & !REQUEST_DEV_DEP_MSG_IN_TERM_CHAR == 0 && m[2..4] == [0; 2]
+            }
+            MsgId::DevDepMsgIn
+            | MsgId::VendorSpecificOut
+            | MsgId::RequestVendorSpecificIn
+            | MsgId::VendorSpecificIn
+            | MsgId::Trigger
+            | MsgId::SubclassReserved(_)
+            | MsgId::VisaReserved(_)
+            | MsgId::Reserved(_) => true,
+        };
+        self.reserved == 0 && msg_specific_clear
+    }
+}
+
+/// Header of a device-to-host message.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct BulkInHeader {
+    pub msg_id: MsgId,
+    pub b_tag: u8,
+    pub transfer_size: u32,
+    /// bmTransferAttributes; the remaining bytes are reserved and sent as zero.
+    pub attributes: u8,
+}
+
+impl BulkInHeader {
+    /// Header of a DEV_DEP_MSG_IN answering the REQUEST_DEV_DEP_MSG_IN tagged `b_tag`, carrying
+    /// `len` bytes of data.
+    ///
+    /// `eom` ends the message; `term_char_matched` says the data ends with the TermChar the
+    /// host asked for. Both belong on the last piece of a message only.
+    pub fn dev_dep_msg_in(b_tag: u8, len: u32, eom: bool, term_char_matched: bool) -> Self {
+        let mut attributes = 0;
+        if eom {
+            attributes |= DEV_DEP_MSG_IN_EOM;
+        }
+        if term_char_matched {
+            attributes |= DEV_DEP_MSG_IN_TERM_CHAR_MATCHED;
+        }
+        Self {
+            msg_id: MsgId::DevDepMsgIn,
+            b_tag,
+            transfer_size: len,
+            attributes,
+        }
+    }
+
+    /// Reads the header at the start of `buf`, checking bTagInverse. Reserved bytes are
+    /// ignored.
+    pub fn parse(buf: &[u8]) -> Result<Self, HeaderError> {
+        if buf.len() < HEADER_LEN {
+            return Err(HeaderError::TooShort);
+        }
+        if buf[2] != !buf[1] {
+            return Err(HeaderError::BadTag);
+        }
+        Ok(Self {
+            msg_id: MsgId::from_in(buf[0]),
+            b_tag: buf[1],
+            transfer_size: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+            attributes: buf[8],
+        })
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0u8; HEADER_LEN];
+        header[0] = self.msg_id.id();
+        header[1] = self.b_tag;
+        header[2] = !self.b_tag;
+        header[4..8].copy_from_slice(&self.transfer_size.to_le_bytes());
+        header[8] = self.attributes;
+        header
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dev_dep_msg_in_attributes() {
+        for (eom, term_char_matched, attributes) in [
+            (false, false, 0b00),
+            (true, false, 0b01),
+            (false, true, 0b10),
+            (true, true, 0b11),
+        ] {
+            let header = BulkInHeader::dev_dep_msg_in(7, 3, eom, term_char_matched);
+            assert_eq!(header.attributes, attributes);
+            assert_eq!(header.encode()[8], attributes);
+        }
+    }
+
+    #[test]
+    fn dev_dep_msg_in_encoding() {
+        for b_tag in [1, 0x5A, 0x80, 0xFF] {
+            let encoded = BulkInHeader::dev_dep_msg_in(b_tag, 0x0102_0304, true, false).encode();
+            assert_eq!(encoded[0], 2);
+            assert_eq!(encoded[1], b_tag);
+            assert_eq!(encoded[2], !b_tag);
+            assert_eq!(encoded[3], 0);
+            assert_eq!(encoded[4..8], [0x04, 0x03, 0x02, 0x01]);
+            assert_eq!(encoded[9..12], [0, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn dev_dep_msg_in_round_trip() {
+        let header = BulkInHeader::dev_dep_msg_in(0x42, 512, true, true);
+        let parsed = BulkInHeader::parse(&header.encode()).ok().unwrap();
+        assert!(parsed == header);
+    }
+}
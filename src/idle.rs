@@ -0,0 +1,101 @@
+//! Power management hooks for battery instruments: the class tells an [`IdleHook`] when the
+//! host has stopped talking, so the application can drop clocks or switch to a low-power
+//! measurement mode, and tells it again the moment the host is back.
+//!
+//! [`run`] calls the hook from its own task, like [`safety::run`](crate::safety::run), so
+//! the control handler and the runner never wait on it.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant};
+
+use crate::{SUSPENDED, clock, last_host_activity};
+
+static IDLE: AtomicBool = AtomicBool::new(false);
+/// Raised on bus suspend and resume, and on host activity while idle.
+static WAKE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Why the device went idle.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum IdleReason {
+    /// No transfer or class request for the quiet period passed to [`run`].
+    Quiet,
+    /// The host suspended the bus.
+    Suspended,
+}
+
+/// What an instrument does to save power while the host is away.
+#[allow(async_fn_in_trait)]
+pub trait IdleHook {
+    /// Drops into low power: slow or gate clocks, stop the display, lengthen measurement
+    /// intervals. Leave the USB peripheral and its clock running, or the device can't be
+    /// woken.
+    async fn enter_idle(&mut self, reason: IdleReason);
+
+    /// Undoes [`enter_idle`](Self::enter_idle). Called as soon as the bus resumes or a
+    /// transfer or class request arrives; that transfer is already being handled, so restore
+    /// whatever the class's tasks need first.
+    async fn exit_idle(&mut self);
+}
+
+/// Whether the device is idle, between [`IdleHook::enter_idle`] and
+/// [`IdleHook::exit_idle`].
+pub fn is_idle() -> bool {
+    IDLE.load(Ordering::Relaxed)
+}
+
+/// Calls `hook` as the device goes idle and wakes. Never returns; spawn it in its own task.
+///
+/// A bus suspend makes the device idle at once; with `quiet`, so does a host that neither
+/// moves bulk data nor sends class requests for that long.
+pub async fn run<H: IdleHook>(hook: &mut H, quiet: Option<Duration>) -> ! {
+    loop {
+        let reason = idle_start(quiet).await;
+        IDLE.store(true, Ordering::Relaxed);
+        let since = last_host_activity();
+        hook.enter_idle(reason).await;
+        loop {
+            WAKE.reset();
+            let resumed = reason == IdleReason::Suspended && !SUSPENDED.load(Ordering::Relaxed);
+            if resumed || last_host_activity() != since {
+                break;
+            }
+            WAKE.wait().await;
+        }
+        IDLE.store(false, Ordering::Relaxed);
+        hook.exit_idle().await;
+    }
+}
+
+/// Waits until the bus is suspended or has been quiet for `quiet`.
+async fn idle_start(quiet: Option<Duration>) -> IdleReason {
+    loop {
+        WAKE.reset();
+        if SUSPENDED.load(Ordering::Relaxed) {
+            return IdleReason::Suspended;
+        }
+        let deadline = match quiet {
+            Some(quiet) => last_host_activity() + quiet,
+            None => Instant::MAX,
+        };
+        if clock::now() >= deadline {
+            return IdleReason::Quiet;
+        }
+        select(WAKE.wait(), clock::at(deadline)).await;
+    }
+}
+
+/// Called by the class on host activity.
+pub(crate) fn note_activity() {
+    if is_idle() {
+        WAKE.signal(());
+    }
+}
+
+/// Called by the class when the bus is suspended or resumes.
+pub(crate) fn note_suspend() {
+    WAKE.signal(());
+}
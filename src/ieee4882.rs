@@ -0,0 +1,25 @@
+//! IEEE 488.2 layer: the event status bits the class raises and the status byte query.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+static EVENT_STATUS: AtomicU8 = AtomicU8::new(0);
+
+/// Query Error bit of the IEEE 488.2 Standard Event Status Register.
+pub const ESR_QYE: u8 = 1 << 2;
+
+/// Reads and clears the Standard Event Status Register bits raised by the class, as `*ESR?`
+/// does. Fold the result into the application's own ESR.
+pub fn take_event_status() -> u8 {
+    EVENT_STATUS.swap(0, Ordering::Relaxed)
+}
+
+pub(crate) fn raise_event_status(bits: u8) {
+    EVENT_STATUS.fetch_or(bits, Ordering::Relaxed);
+}
+
+/// Classifier for [`TmcConfig::immediate`](crate::TmcConfig::immediate) that fast-tracks
+/// `*STB?`, the bulk fallback for reading the status byte. Answer it with
+/// [`srq::status_byte`](crate::srq::status_byte), which reports MSS rather than RQS.
+pub fn is_status_byte_query(cmd: &[u8]) -> bool {
+    cmd.trim_ascii().eq_ignore_ascii_case(b"*STB?")
+}
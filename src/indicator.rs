@@ -0,0 +1,97 @@
+//! Front-panel indicator driven by the class: IDENTIFY, bus activity and protocol errors.
+//!
+//! The class only queues [`IndicatorEvent`]s; [`run`] plays them on an [`Indicator`] in its own
+//! task, so neither the control handler nor the runner ever waits on an LED.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+#[cfg(feature = "indicator")]
+use embassy_time::Duration;
+
+#[cfg(feature = "indicator")]
+use crate::clock;
+
+static EVENTS: Channel<CriticalSectionRawMutex, IndicatorEvent, 4> = Channel::new();
+
+/// Something the class wants shown on the indicator.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum IndicatorEvent {
+    /// The host sent INDICATOR_PULSE to pick this device out of several identical ones.
+    Pulse,
+    /// A Bulk-OUT message was accepted or a Bulk-IN response sent.
+    Activity,
+    /// The host broke the protocol: bad bTag, oversize transfer, reserved bits, empty read.
+    Error,
+}
+
+/// A light (or buzzer) the class can use to get the user's attention.
+///
+/// Each method plays its pattern to completion and returns the output to its idle state.
+#[allow(async_fn_in_trait)]
+pub trait Indicator {
+    /// The USBTMC identify pulse: on for at least 500 ms, at most 1 s.
+    async fn pulse(&mut self);
+    /// A blink short enough to keep up with back-to-back transfers.
+    async fn activity(&mut self);
+    /// A pattern distinct from the other two, e.g. a quick double blink.
+    async fn error(&mut self);
+}
+
+/// Plays indicator events as they come in. Never returns; spawn it in its own task.
+///
+/// Events that pile up while a pattern plays are dropped, so a busy bus can't build a backlog
+/// of blinks.
+pub async fn run<I: Indicator>(indicator: &mut I) -> ! {
+    loop {
+        match EVENTS.receive().await {
+            IndicatorEvent::Pulse => indicator.pulse().await,
+            IndicatorEvent::Activity => indicator.activity().await,
+            IndicatorEvent::Error => indicator.error().await,
+        }
+    }
+}
+
+pub(crate) fn signal(event: IndicatorEvent) {
+    let _ = EVENTS.try_send(event);
+}
+
+/// [`Indicator`] on an active-high `embedded-hal` output pin, e.g. an LED.
+#[cfg(feature = "indicator")]
+pub struct PinIndicator<P: embedded_hal::digital::OutputPin> {
+    pin: P,
+}
+
+#[cfg(feature = "indicator")]
+impl<P: embedded_hal::digital::OutputPin> PinIndicator<P> {
+    pub fn new(mut pin: P) -> Self {
+        let _ = pin.set_low();
+        Self { pin }
+    }
+
+    async fn blink(&mut self, on: Duration, off: Duration) {
+        let _ = self.pin.set_high();
+        clock::after(on).await;
+        let _ = self.pin.set_low();
+        clock::after(off).await;
+    }
+}
+
+#[cfg(feature = "indicator")]
+impl<P: embedded_hal::digital::OutputPin> Indicator for PinIndicator<P> {
+    async fn pulse(&mut self) {
+        self.blink(Duration::from_millis(750), Duration::from_millis(0))
+            .await;
+    }
+
+    async fn activity(&mut self) {
+        self.blink(Duration::from_millis(20), Duration::from_millis(30))
+            .await;
+    }
+
+    async fn error(&mut self) {
+        for _ in 0..2 {
+            self.blink(Duration::from_millis(100), Duration::from_millis(100))
+                .await;
+        }
+    }
+}
@@ -0,0 +1,3104 @@
+#![cfg_attr(not(test), no_std)]
+
+mod abort;
+#[cfg(feature = "scpi")]
+pub mod arming;
+#[cfg(feature = "scpi")]
+pub mod calibration;
+pub mod clock;
+pub mod conformance;
+pub mod control;
+#[cfg(feature = "descriptor-dump")]
+pub mod descriptors;
+#[cfg(feature = "scpi")]
+pub mod diag;
+pub mod events;
+#[cfg(feature = "scpi")]
+pub mod exectime;
+#[cfg(feature = "scpi")]
+pub mod firmware;
+#[cfg(feature = "gateway")]
+pub mod gateway;
+#[cfg(feature = "scpi")]
+pub mod hcopy;
+pub mod header;
+pub mod idle;
+#[cfg(feature = "ieee4882")]
+pub mod ieee4882;
+pub mod indicator;
+#[cfg(feature = "scpi")]
+pub mod lock;
+#[cfg(feature = "scpi")]
+pub mod mmem;
+#[cfg(feature = "scpi")]
+pub mod params;
+#[cfg(feature = "scpi")]
+pub mod parser;
+#[cfg(test)]
+mod pcapng;
+pub mod safety;
+#[cfg(feature = "scpi")]
+pub mod scpi;
+#[cfg(feature = "scpi-rs")]
+pub mod scpi_tree;
+#[cfg(feature = "scpi")]
+pub mod selftest;
+pub mod session;
+#[cfg(feature = "scpi")]
+pub mod sim;
+#[cfg(feature = "usb488")]
+pub mod srq;
+mod sync;
+#[cfg(feature = "scpi")]
+pub mod systime;
+#[cfg(feature = "tcp")]
+pub mod tcp;
+pub mod telemetry;
+#[cfg(test)]
+mod testing;
+#[cfg(test)]
+mod tests;
+#[cfg(test)]
+mod transcript;
+pub mod transport;
+pub mod tuning;
+pub mod upload;
+#[cfg(feature = "instrument")]
+pub mod usage;
+#[cfg(feature = "usb488")]
+pub mod usb488;
+pub mod vendor;
+
+#[cfg(feature = "ieee4882")]
+pub use ieee4882::{ESR_QYE, is_status_byte_query, take_event_status};
+#[cfg(feature = "scpi")]
+pub use scpi::{
+    SCPI_ERR_CALIBRATION, SCPI_ERR_COMMAND, SCPI_ERR_COMMAND_PROTECTED, SCPI_ERR_DATA_OUT_OF_RANGE,
+    SCPI_ERR_DATA_TYPE, SCPI_ERR_FILE_NOT_FOUND, SCPI_ERR_HARDWARE, SCPI_ERR_HARDWARE_MISSING,
+    SCPI_ERR_HEADER_SUFFIX, SCPI_ERR_ILLEGAL_PARAMETER, SCPI_ERR_INPUT_OVERRUN,
+    SCPI_ERR_INVALID_BLOCK, SCPI_ERR_INVALID_EXPRESSION, SCPI_ERR_INVALID_SEPARATOR,
+    SCPI_ERR_INVALID_STRING, SCPI_ERR_INVALID_SUFFIX, SCPI_ERR_MASS_STORAGE, SCPI_ERR_NUMERIC_DATA,
+    SCPI_ERR_PARAMETER_NOT_ALLOWED, SCPI_ERR_PARITY, SCPI_ERR_QUEUE_OVERFLOW, SCPI_ERR_SELF_TEST,
+    SCPI_ERR_TOO_MUCH_DATA, SCPI_ERR_UNDEFINED_HEADER, Suffix, echo, error_count, header_matches,
+    match_suffixed, pop_error, push_error, render_mnemonic, set_echo, set_verbose, system_queries,
+    verbose,
+};
+#[cfg(feature = "usb488")]
+pub use usb488::{RemoteState, last_trigger, remote_state, trigger_count};
+
+use core::cell::Cell;
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU16, AtomicU32, Ordering};
+
+use abort::TransferAbort;
+use control::{
+    CAPABILITIES_LEN, Capabilities, ControlRequest, DeviceCapabilities, InterfaceCapabilities,
+    Status, Usb488DeviceCapabilities, Usb488InterfaceCapabilities,
+};
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::{Channel, Receiver, Sender, TrySendError};
+use embassy_sync::signal::Signal;
+use embassy_sync::watch::Watch;
+use embassy_time::{Duration, Instant};
+use embassy_usb::control::{InResponse, Recipient, RequestType};
+use embassy_usb::driver::{Direction, Driver, EndpointAddress};
+use embassy_usb::types::InterfaceNumber;
+use embassy_usb::{Builder, Handler, UsbDevice};
+use events::ClassEvent;
+use header::{BulkInHeader, BulkOutHeader, HEADER_LEN, HeaderError, MsgId, padding};
+use heapless::Vec;
+#[cfg(feature = "ieee4882")]
+use ieee4882::raise_event_status;
+use indicator::IndicatorEvent;
+#[cfg(feature = "scpi")]
+use lock::{LockReply, Session};
+use safety::SafeStateReason;
+use session::SessionEnd;
+use static_cell::StaticCell;
+use transport::{EndpointTransport, Pipe, TmcTransport, TransportError, write_transfer_paced};
+use tuning::Control;
+
+static CMD_CHANNEL: Channel<CriticalSectionRawMutex, Command, 4> = Channel::new();
+static RESP_CHANNEL: Channel<CriticalSectionRawMutex, Response, 4> = Channel::new();
+static PRIORITY_CHANNEL: Channel<CriticalSectionRawMutex, Command, 2> = Channel::new();
+
+pub const MAX_SCPI_LEN: usize = 512;
+
+#[derive(Clone)]
+pub struct Command {
+    pub len: usize,
+    pub data: [u8; MAX_SCPI_LEN],
+    /// Set for queries. Copy it into [`Response::token`] so the answer can't be mistaken for
+    /// the answer to a different query.
+    pub token: Option<ResponseToken>,
+    /// The message holds a query, see [`is_query`], so the host expects an answer.
+    pub query: bool,
+    /// When the class had the whole command, on the installed [`clock`].
+    pub received: Instant,
+}
+
+/// Identifies the query a response answers.
+///
+/// The runner sends tagged responses strictly in query order: one for a query that was
+/// already answered, cleared or timed out is dropped and counted in
+/// [`TmcStats::stale_responses`], and one that arrives before the answer to an earlier query
+/// is held back until that answer has been sent. Up to eight are held back at once; past
+/// that, early ones are dropped and counted as stale too.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ResponseToken(u32);
+
+/// Most pieces one [`Response`] can be gathered from.
+pub const MAX_SEGMENTS: usize = 4;
+
+/// Longest [`TmcConfig::response_terminator`].
+pub const MAX_TERMINATOR_LEN: usize = 4;
+
+/// One piece of a gathered response body, see [`Response::segments`].
+#[derive(Clone, Copy)]
+pub enum Segment {
+    /// `Response::data[start..end]`.
+    Data { start: u16, end: u16 },
+    /// Bytes that outlive the response, e.g. a header or table in flash.
+    Static(&'static [u8]),
+}
+
+#[derive(Clone)]
+pub struct Response {
+    pub len: usize,
+    pub data: [u8; MAX_SCPI_LEN],
+    /// Pieces the body is gathered from, sent back to back and split across packets wherever
+    /// they fall. Empty means the body is `data[..len]`.
+    pub segments: Vec<Segment, MAX_SEGMENTS>,
+    /// Whether this piece ends the logical message. Queue a long reply as several responses
+    /// with `eom: false` on all but the last; the host keeps reading until it sees EOM.
+    pub eom: bool,
+    /// Sets TermCharMatched in the DEV_DEP_MSG_IN header: the data ends with the TermChar
+    /// the host asked for. For applications that split their output at the TermChar.
+    pub term_char_matched: bool,
+    /// Send the body exactly as it is, without [`TmcConfig::response_terminator`], e.g. raw
+    /// binary data.
+    pub raw: bool,
+    /// The [`Command::token`] of the query this answers. `None` skips the ordering checks.
+    pub token: Option<ResponseToken>,
+}
+
+impl Response {
+    /// A response whose whole body is `bytes`, e.g. help text, a catalog listing or a banner
+    /// in flash.
+    ///
+    /// `data` stays unused: the runner reads `bytes` in place as it packs packets, so the body
+    /// may be longer than [`MAX_SCPI_LEN`] and the host reads it over as many transfers as
+    /// it takes.
+    pub fn from_static(bytes: &'static [u8], token: Option<ResponseToken>) -> Self {
+        let mut segments = Vec::new();
+        let _ = segments.push(Segment::Static(bytes));
+        Self {
+            len: 0,
+            data: [0; MAX_SCPI_LEN],
+            segments,
+            eom: true,
+            term_char_matched: false,
+            raw: false,
+            token,
+        }
+    }
+
+    /// The body, piece by piece.
+    pub fn parts(&self) -> impl Iterator<Item = &[u8]> {
+        let whole = self
+            .segments
+            .is_empty()
+            .then(|| &self.data[..self.len.min(MAX_SCPI_LEN)]);
+        let gathered = self.segments.iter().map(|segment| match *segment {
+            Segment::Data { start, end } => {
+                self.data.get(start as usize..end as usize).unwrap_or(&[])
+            }
+            Segment::Static(bytes) => bytes,
+        });
+        whole.into_iter().chain(gathered)
+    }
+
+    /// Length of the body in bytes.
+    pub fn body_len(&self) -> usize {
+        self.parts().map(<[u8]>::len).sum()
+    }
+
+    /// Appends `terminator` to a non-empty body that doesn't already end with it. Left as
+    /// it is if there's no room for it in `data` or [`segments`](Self::segments).
+    pub fn terminate(&mut self, terminator: &'static [u8]) {
+        let len = self.body_len();
+        if terminator.is_empty() || len == 0 {
+            return;
+        }
+        let mut tail = [0u8; MAX_TERMINATOR_LEN];
+        let tail = &mut tail[..terminator.len().min(MAX_TERMINATOR_LEN).min(len)];
+        self.copy_body(len - tail.len(), tail);
+        if tail == terminator {
+            return;
+        }
+        if self.segments.is_empty() {
+            let end = self.len + terminator.len();
+            if end <= MAX_SCPI_LEN {
+                self.data[self.len..end].copy_from_slice(terminator);
+                self.len = end;
+                return;
+            }
+            // Gather the full buffer and the terminator instead.
+            let _ = self.segments.push(Segment::Data {
+                start: 0,
+                end: self.len.min(MAX_SCPI_LEN) as u16,
+            });
+        }
+        let _ = self.segments.push(Segment::Static(terminator));
+    }
+
+    /// Copies body bytes from `offset` on into `out`, as many as fit. Returns the number
+    /// copied.
+    pub fn copy_body(&self, mut offset: usize, out: &mut [u8]) -> usize {
+        let mut copied = 0;
+        for part in self.parts() {
+            if offset >= part.len() {
+                offset -= part.len();
+                continue;
+            }
+            let n = (part.len() - offset).min(out.len() - copied);
+            out[copied..copied + n].copy_from_slice(&part[offset..offset + n]);
+            copied += n;
+            offset = 0;
+            if copied == out.len() {
+                break;
+            }
+        }
+        copied
+    }
+}
+
+/// Builds a reply longer than one [`Response`], e.g. a file or a framebuffer, queueing each
+/// piece with `eom: false` as it fills and the last one with EOM. Every piece carries the
+/// same token, so the host reads them back to back as one message.
+pub struct ResponseStream {
+    resp: Response,
+}
+
+impl ResponseStream {
+    pub fn new(token: Option<ResponseToken>) -> Self {
+        Self {
+            resp: Response {
+                len: 0,
+                data: [0; MAX_SCPI_LEN],
+                segments: Vec::new(),
+                eom: false,
+                term_char_matched: false,
+                raw: false,
+                token,
+            },
+        }
+    }
+
+    /// Room left in the piece being filled. Write into it, then [`commit`](Self::commit)
+    /// what was written, to produce data in place without a second buffer.
+    pub fn spare(&mut self) -> &mut [u8] {
+        &mut self.resp.data[self.resp.len..]
+    }
+
+    /// Adds `n` bytes written into [`spare`](Self::spare), queueing the piece once full.
+    pub async fn commit(&mut self, n: usize) {
+        self.resp.len = (self.resp.len + n).min(MAX_SCPI_LEN);
+        if self.resp.len == MAX_SCPI_LEN {
+            RESP_CHANNEL.send(self.resp.clone()).await;
+            self.resp.len = 0;
+        }
+    }
+
+    pub async fn write(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let spare = self.spare();
+            let n = bytes.len().min(spare.len());
+            spare[..n].copy_from_slice(&bytes[..n]);
+            bytes = &bytes[n..];
+            self.commit(n).await;
+        }
+    }
+
+    /// Queues the last piece, ending the message.
+    pub async fn finish(mut self) {
+        self.resp.eom = true;
+        RESP_CHANNEL.send(self.resp).await;
+    }
+}
+
+pub fn cmd_receiver() -> Receiver<'static, CriticalSectionRawMutex, Command, 4> {
+    CMD_CHANNEL.receiver()
+}
+
+pub fn resp_sender() -> Sender<'static, CriticalSectionRawMutex, Response, 4> {
+    RESP_CHANNEL.sender()
+}
+
+/// Commands waiting in [`cmd_receiver`]'s queue.
+pub fn cmd_queue_depth() -> usize {
+    CMD_CHANNEL.len()
+}
+
+/// How full the command queue is, relative to [`TmcConfig::cmd_queue_watermarks`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum QueueLevel {
+    /// The queue reached the high watermark: shed load, or answer new work with -363
+    /// before messages are lost.
+    High,
+    /// The queue drained to the low watermark again.
+    Low,
+}
+
+/// Thresholds for [`TmcConfig::on_queue_level`], in queued commands. `low` below `high`
+/// keeps a queue hovering at one value from flapping.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Watermarks {
+    pub high: usize,
+    pub low: usize,
+}
+
+/// Reports queue level crossings, checked whenever the runner queues a command or services
+/// control work, so a drained queue is noticed by the next message at the latest.
+fn update_queue_level(config: &TmcConfig) {
+    let Some(marks) = config.cmd_queue_watermarks else {
+        return;
+    };
+    let depth = CMD_CHANNEL.len();
+    let high = QUEUE_HIGH.load(Ordering::Relaxed);
+    let level = if !high && depth >= marks.high {
+        QueueLevel::High
+    } else if high && depth <= marks.low {
+        QueueLevel::Low
+    } else {
+        return;
+    };
+    QUEUE_HIGH.store(level == QueueLevel::High, Ordering::Relaxed);
+    if let Some(on_queue_level) = config.on_queue_level {
+        on_queue_level(level);
+    }
+}
+
+/// Commands picked out by [`TmcConfig::immediate`], delivered ahead of [`cmd_receiver`].
+pub fn priority_cmd_receiver() -> Receiver<'static, CriticalSectionRawMutex, Command, 2> {
+    PRIORITY_CHANNEL.receiver()
+}
+
+/// Delivery lane of a command, see [`next_command`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Normal,
+    Immediate,
+}
+
+/// Waits for the next command, taking immediate ones first however deep the normal queue is.
+pub async fn next_command() -> (Command, Priority) {
+    if let Ok(cmd) = PRIORITY_CHANNEL.try_receive() {
+        return (cmd, Priority::Immediate);
+    }
+    match select(PRIORITY_CHANNEL.receive(), CMD_CHANNEL.receive()).await {
+        Either::First(cmd) => (cmd, Priority::Immediate),
+        Either::Second(cmd) => (cmd, Priority::Normal),
+    }
+}
+
+/// Halts the Bulk-IN pipe: the runner stops answering REQUEST_DEV_DEP_MSG_IN until
+/// [`clear_halt`] is called.
+///
+/// embassy-usb gives classes no way to STALL their own endpoints, so the halt is emulated by
+/// the runner leaving the endpoint alone; the host sees NAKs and times out its transfer.
+pub fn halt_bulk_in() {
+    HALT_IN.store(true, Ordering::Relaxed);
+}
+
+/// Halts the Bulk-OUT pipe. A transfer in progress is dropped and no further packets are read
+/// until [`clear_halt`] is called.
+pub fn halt_bulk_out() {
+    HALT_OUT.store(true, Ordering::Relaxed);
+    ABORT_OUT_SIGNAL.signal(());
+}
+
+/// Clears a halt on both bulk pipes and lets the runner resume.
+pub fn clear_halt() {
+    HALT_IN.store(false, Ordering::Relaxed);
+    clear_halt_out();
+}
+
+fn clear_halt_out() {
+    HALT_OUT.store(false, Ordering::Relaxed);
+    HALT_CLEARED.signal(());
+}
+
+/// Whether a bulk transfer is currently moving through one of the pipes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TransferState {
+    Idle,
+    InProgress,
+}
+
+/// State of the Bulk-OUT pipe, `InProgress` while the runner is reading a DEV_DEP_MSG_OUT.
+pub fn bulk_out_state() -> TransferState {
+    load_transfer_state(&BULK_OUT_STATE)
+}
+
+/// State of the Bulk-IN pipe, `InProgress` while the runner is writing a DEV_DEP_MSG_IN.
+pub fn bulk_in_state() -> TransferState {
+    load_transfer_state(&BULK_IN_STATE)
+}
+
+fn load_transfer_state(state: &AtomicBool) -> TransferState {
+    if state.load(Ordering::Relaxed) {
+        TransferState::InProgress
+    } else {
+        TransferState::Idle
+    }
+}
+
+fn set_transfer_state(state: &AtomicBool, value: TransferState) {
+    state.store(value == TransferState::InProgress, Ordering::Relaxed);
+}
+
+/// Protocol counters maintained by the runner, see [`stats`].
+#[derive(Clone, Copy, Default)]
+pub struct TmcStats {
+    /// Bulk-OUT headers with non-zero reserved fields.
+    pub reserved_violations: u32,
+    /// Tagged responses dropped because their query was no longer waiting for an answer.
+    pub stale_responses: u32,
+    /// Responses dropped under [`TmcConfig::strict_pairing`] because no query was open.
+    pub unpaired_responses: u32,
+    /// Control requests that took longer than [`CONTROL_BUDGET`] to handle.
+    pub control_overruns: u32,
+    /// Vendor-specific messages, and commands in CRC mode, dropped for a bad CRC-32 trailer,
+    /// and vendor-specific reads refused as too short to carry one.
+    pub crc_errors: u32,
+    /// Commands dropped because their queue was full.
+    pub dropped_commands: u32,
+    /// Queries whose answers expired unread, see [`TmcConfig::response_ttl`].
+    pub expired_responses: u32,
+    /// Interrupt-IN notifications dropped because the queue was full, see
+    /// [`TmcConfig::notification_depth`].
+    pub notification_overflows: u32,
+}
+
+/// Returns a snapshot of the class's protocol counters.
+pub fn stats() -> TmcStats {
+    TmcStats {
+        reserved_violations: RESERVED_VIOLATIONS.load(Ordering::Relaxed),
+        stale_responses: STALE_RESPONSES.load(Ordering::Relaxed),
+        unpaired_responses: UNPAIRED_RESPONSES.load(Ordering::Relaxed),
+        control_overruns: CONTROL_OVERRUNS.load(Ordering::Relaxed),
+        crc_errors: CRC_ERRORS.load(Ordering::Relaxed),
+        dropped_commands: DROPPED_COMMANDS.load(Ordering::Relaxed),
+        expired_responses: EXPIRED_RESPONSES.load(Ordering::Relaxed),
+        notification_overflows: NOTIFICATION_OVERFLOWS.load(Ordering::Relaxed),
+    }
+}
+
+/// Deviations from USBTMC that some host drivers need tolerated, see [`set_host_quirks`].
+///
+/// All off is the conformant behavior.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct HostQuirks {
+    /// The host doesn't pad DEV_DEP_MSG_OUT to a 4-byte boundary: a transfer ends with its
+    /// data, and alignment bytes aren't waited for.
+    pub unpadded_out: bool,
+    /// Don't close Bulk-IN transfers that end on a packet boundary with a zero-length packet,
+    /// for hosts that read exactly the header plus transferSize and then see the ZLP as the
+    /// start of the next read.
+    pub no_zlp: bool,
+    /// The host clears the Bulk-OUT halt after INITIATE_ABORT_BULK_OUT without polling
+    /// CHECK_ABORT_BULK_OUT_STATUS: resume reading as soon as the abort is done rather than
+    /// after its status has been reported.
+    pub abort_without_check: bool,
+}
+
+impl HostQuirks {
+    pub const NONE: Self = Self {
+        unpadded_out: false,
+        no_zlp: false,
+        abort_without_check: false,
+    };
+
+    const UNPADDED_OUT: u8 = 1 << 0;
+    const NO_ZLP: u8 = 1 << 1;
+    const ABORT_WITHOUT_CHECK: u8 = 1 << 2;
+
+    fn to_bits(self) -> u8 {
+        (self.unpadded_out as u8 * Self::UNPADDED_OUT)
+            | (self.no_zlp as u8 * Self::NO_ZLP)
+            | (self.abort_without_check as u8 * Self::ABORT_WITHOUT_CHECK)
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        Self {
+            unpadded_out: bits & Self::UNPADDED_OUT != 0,
+            no_zlp: bits & Self::NO_ZLP != 0,
+            abort_without_check: bits & Self::ABORT_WITHOUT_CHECK != 0,
+        }
+    }
+}
+
+/// Host quirks in effect. Starts out as [`TmcConfig::host_quirks`]; always none under
+/// [`Conformance::Strict`].
+pub fn host_quirks() -> HostQuirks {
+    if STRICT.load(Ordering::Relaxed) {
+        return HostQuirks::NONE;
+    }
+    HostQuirks::from_bits(HOST_QUIRKS.load(Ordering::Relaxed))
+}
+
+/// Switches host quirks at runtime, e.g. from a `SYSTem:COMMunicate:USB:QUIRks` command once
+/// the application has worked out which host it talks to. Takes effect from the next message.
+pub fn set_host_quirks(quirks: HostQuirks) {
+    HOST_QUIRKS.store(quirks.to_bits(), Ordering::Relaxed);
+}
+
+/// Kind of the last USBTMC protocol event worth knowing about when debugging from the host
+/// side, see [`last_protocol_error`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ProtocolError {
+    /// bTagInverse didn't match bTag.
+    BadTag = 1,
+    /// A Bulk-OUT header had non-zero reserved fields.
+    ReservedFields = 2,
+    /// A DEV_DEP_MSG_OUT was bigger than [`TmcConfig::max_transfer_size`].
+    Overflow = 3,
+    /// The host read with no response queued or owed.
+    EmptyRead = 4,
+    /// The host aborted a Bulk-OUT or Bulk-IN transfer.
+    Aborted = 5,
+    /// The host cleared the device.
+    Cleared = 6,
+    /// A vendor-specific message or, in CRC mode, a command failed its CRC-32 check, or a
+    /// vendor-specific read had no room for the trailer, see [`TmcConfig::vendor_crc`] and
+    /// [`TmcConfig::crc_mode_request`].
+    Crc = 7,
+    /// The answer to a query went unread for [`TmcConfig::response_ttl`] and was dropped. The
+    /// bTag is that of the transfer the query arrived in.
+    Expired = 8,
+    /// A chunk under [`TmcConfig::vendor_upload`] didn't fit the upload in progress.
+    Upload = 9,
+}
+
+/// The last protocol error and the bTag of the transfer it happened in (0 for a clear).
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolErrorRecord {
+    pub error: ProtocolError,
+    pub b_tag: u8,
+    /// Protocol errors since power-up, this one included. Wraps at `u16::MAX`.
+    pub count: u16,
+}
+
+/// The most recent protocol error, `None` if there hasn't been one. Reading doesn't clear it.
+///
+/// The host can read the same record with [`TmcConfig::error_readback_request`].
+pub fn last_protocol_error() -> Option<ProtocolErrorRecord> {
+    let error = match LAST_PROTOCOL_ERROR.load(Ordering::Relaxed) {
+        1 => ProtocolError::BadTag,
+        2 => ProtocolError::ReservedFields,
+        3 => ProtocolError::Overflow,
+        4 => ProtocolError::EmptyRead,
+        5 => ProtocolError::Aborted,
+        6 => ProtocolError::Cleared,
+        7 => ProtocolError::Crc,
+        8 => ProtocolError::Expired,
+        9 => ProtocolError::Upload,
+        _ => return None,
+    };
+    Some(ProtocolErrorRecord {
+        error,
+        b_tag: LAST_PROTOCOL_ERROR_BTAG.load(Ordering::Relaxed),
+        count: PROTOCOL_ERROR_COUNT.load(Ordering::Relaxed),
+    })
+}
+
+fn record_protocol_error(error: ProtocolError, b_tag: u8) {
+    LAST_PROTOCOL_ERROR.store(error as u8, Ordering::Relaxed);
+    LAST_PROTOCOL_ERROR_BTAG.store(b_tag, Ordering::Relaxed);
+    PROTOCOL_ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Bus speed of the link, see [`bus_speed`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BusSpeed {
+    Full,
+    High,
+}
+
+/// Max packet size of the bulk endpoints the runner is using, 0 before it has started.
+///
+/// Size application blocks (waveform chunks, response pieces) in multiples of it: a response
+/// that ends in a short packet needs no zero-length packet to close it.
+pub fn max_packet_size() -> usize {
+    PACKET_SIZE.load(Ordering::Relaxed) as usize
+}
+
+/// Caps the message data sent per DEV_DEP_MSG_IN transfer at about `bytes`; 0 lifts the cap.
+///
+/// Longer responses go out as several transfers with EOM on the last, exactly as for a host
+/// that asks for less than the whole response. Smaller chunks keep Bulk-IN transfers short,
+/// larger ones save the host a REQUEST_DEV_DEP_MSG_IN per chunk. The host's transferSize
+/// still applies on top, and the cap is rounded down so a chunk with its header fills whole
+/// packets, but never below the first packet's worth of data. Takes effect from the next
+/// transfer.
+pub fn set_in_chunk_size(bytes: u32) {
+    IN_CHUNK_SIZE.store(bytes, Ordering::Relaxed);
+}
+
+/// The cap set with [`set_in_chunk_size`], 0 when there is none.
+pub fn in_chunk_size() -> u32 {
+    IN_CHUNK_SIZE.load(Ordering::Relaxed)
+}
+
+/// Limits Bulk-IN to about `bytes_per_ms` (1 byte/ms is 1 kB/s); 0 lifts the limit.
+///
+/// For devices that share the bus with other endpoints, e.g. CDC logging or HID, which a long
+/// waveform readout at full speed would starve. The rate holds within each DEV_DEP_MSG_IN
+/// transfer; the host sees NAKs while a packet waits its turn. Takes effect from the next
+/// transfer.
+pub fn set_in_pacing(bytes_per_ms: u32) {
+    IN_PACING.store(bytes_per_ms, Ordering::Relaxed);
+}
+
+/// The limit set with [`set_in_pacing`], 0 when there is none.
+pub fn in_pacing() -> u32 {
+    IN_PACING.load(Ordering::Relaxed)
+}
+
+/// Data bytes per DEV_DEP_MSG_IN transfer for `mps`-byte packets under [`in_chunk_size`].
+fn in_chunk_len(mps: usize) -> usize {
+    match in_chunk_size() as usize {
+        0 => usize::MAX,
+        chunk => ((HEADER_LEN + chunk) / mps).max(HEADER_LEN / mps + 1) * mps - HEADER_LEN,
+    }
+}
+
+/// Bus speed, as told by the bulk max packet size: 512-byte bulk packets only exist at high
+/// speed. embassy-usb doesn't report the negotiated speed itself. `None` before the runner
+/// has started.
+pub fn bus_speed() -> Option<BusSpeed> {
+    match max_packet_size() {
+        0 => None,
+        MAX_PACKET_SIZE => Some(BusSpeed::High),
+        _ => Some(BusSpeed::Full),
+    }
+}
+
+/// Where the device takes its power from, as declared in the configuration descriptor.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PowerSource {
+    /// Everything comes from VBUS; `max_power_ma` is the most drawn once configured.
+    Bus { max_power_ma: u16 },
+    /// A local supply powers the instrument; `max_power_ma` is what is still drawn from VBUS.
+    SelfPowered { max_power_ma: u16 },
+}
+
+impl PowerSource {
+    /// Writes bmAttributes.SelfPowered and bMaxPower into the device config. Call it on the
+    /// config handed to `Builder::new`, with the same value as [`TmcConfig::power`].
+    ///
+    /// GET_STATUS(device) reports the same bit; embassy-usb answers it without consulting
+    /// the class, so it can't follow a supply that comes and goes at runtime.
+    pub fn apply(self, usb_config: &mut embassy_usb::Config) {
+        usb_config.self_powered = matches!(self, PowerSource::SelfPowered { .. });
+        usb_config.max_power = self.max_power_ma();
+    }
+
+    pub fn max_power_ma(self) -> u16 {
+        match self {
+            PowerSource::Bus { max_power_ma } | PowerSource::SelfPowered { max_power_ma } => {
+                max_power_ma
+            }
+        }
+    }
+}
+
+/// Unconfigured devices may draw one unit load.
+const UNCONFIGURED_BUDGET_MA: u16 = 100;
+/// Suspended devices may draw 2.5 mA; rounded down.
+const SUSPENDED_BUDGET_MA: u16 = 2;
+
+/// Current the device may draw from VBUS right now, in mA: one unit load until the host
+/// configures it, [`PowerSource::max_power_ma`] once it has, next to nothing while suspended.
+pub fn power_budget_ma() -> u16 {
+    POWER_BUDGET_MA.load(Ordering::Relaxed)
+}
+
+fn set_power_budget(budget_ma: u16) {
+    POWER_BUDGET_MA.store(budget_ma, Ordering::Relaxed);
+    CONTROL_WORK.signal(());
+}
+
+/// Whether power-hungry parts of the instrument may be switched on, see [`frontend_gate`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrontendGate {
+    /// Not configured by the host, or suspended: stay within the unconfigured budget.
+    Unconfigured,
+    /// Configured, but [`TmcConfig::power_approval`] is set and [`approve_power`] hasn't
+    /// been called, e.g. while a USB-PD contract is still being negotiated.
+    AwaitingApproval,
+    /// Go ahead.
+    Enabled,
+}
+
+/// Current state of the frontend gate. Follows the host (configuration, suspend, bus reset)
+/// and, with [`TmcConfig::power_approval`], the application's [`approve_power`] calls.
+pub fn frontend_gate() -> FrontendGate {
+    match FRONTEND_GATE.load(Ordering::Relaxed) {
+        1 => FrontendGate::AwaitingApproval,
+        2 => FrontendGate::Enabled,
+        _ => FrontendGate::Unconfigured,
+    }
+}
+
+/// Waits for the frontend gate to change and returns the new state.
+///
+/// Meant for a single task that owns the frontend supplies: switch them on for
+/// [`FrontendGate::Enabled`] and off for anything else. Changes between two calls are
+/// coalesced into the latest one.
+pub async fn frontend_gate_changed() -> FrontendGate {
+    FRONTEND_GATE_SIGNAL.wait().await
+}
+
+/// Approves (or withdraws approval for) full power, for devices that negotiate it outside
+/// USB enumeration. Without [`TmcConfig::power_approval`] the gate ignores this.
+pub fn approve_power(approved: bool) {
+    POWER_APPROVED.store(approved, Ordering::Relaxed);
+    update_frontend_gate();
+}
+
+fn update_frontend_gate() {
+    let gate = if !CONFIGURED.load(Ordering::Relaxed) || SUSPENDED.load(Ordering::Relaxed) {
+        FrontendGate::Unconfigured
+    } else if APPROVAL_REQUIRED.load(Ordering::Relaxed) && !POWER_APPROVED.load(Ordering::Relaxed) {
+        FrontendGate::AwaitingApproval
+    } else {
+        FrontendGate::Enabled
+    };
+    if FRONTEND_GATE.swap(gate as u8, Ordering::Relaxed) != gate as u8 {
+        FRONTEND_GATE_SIGNAL.signal(gate);
+    }
+}
+
+/// Why [`host_lost`] decided the controlling host is gone.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HostLoss {
+    /// VBUS went away or the peripheral was disabled: the cable is out or the hub lost power.
+    Disconnected,
+    /// The host reset the bus after configuring the device, e.g. while rebooting.
+    Reset,
+    /// The bus went idle: no SOFs for 3 ms, as when the host sleeps or its controller dies.
+    Suspended,
+    /// No transfer or class request for longer than the silence limit passed to
+    /// [`host_lost`].
+    Silent,
+    /// Several bulk transfers in a row failed.
+    TransferErrors,
+}
+
+/// Bulk transfers that may fail back to back before [`HostLoss::TransferErrors`].
+const TRANSFER_FAILURE_LIMIT: u8 = 3;
+
+/// Waits until the host looks gone, so the instrument can end a sweep and put its outputs in
+/// a safe state when the controlling PC crashes mid-test.
+///
+/// Bus events (disconnect, reset, suspend) and repeated transfer failures count from the
+/// moment of the call. With `silence`, a host that neither moves bulk data nor sends class
+/// requests for that long counts as lost too; pick it well above the host's polling interval.
+/// embassy-usb doesn't report individual SOFs, so a host whose controller keeps the bus
+/// running but whose software has hung is only caught by `silence`.
+///
+/// Meant for a single task, e.g. raced with the sweep it guards. To make outputs safe whenever
+/// this happens, whatever the application is doing, use [`safety::run`] instead.
+pub async fn host_lost(silence: Option<Duration>) -> HostLoss {
+    HOST_LOST_SIGNAL.reset();
+    let Some(silence) = silence else {
+        return HOST_LOST_SIGNAL.wait().await;
+    };
+    let watch_start = clock::now();
+    loop {
+        let last = LAST_HOST_ACTIVITY.lock(Cell::get).max(watch_start);
+        let deadline = last + silence;
+        if clock::now() >= deadline {
+            return HostLoss::Silent;
+        }
+        if let Either::First(loss) = select(HOST_LOST_SIGNAL.wait(), clock::at(deadline)).await {
+            return loss;
+        }
+    }
+}
+
+/// When the host last moved bulk data or sent a class request.
+pub fn last_host_activity() -> Instant {
+    LAST_HOST_ACTIVITY.lock(Cell::get)
+}
+
+fn note_host_activity() {
+    LAST_HOST_ACTIVITY.lock(|last| last.set(clock::now()));
+    TRANSFER_FAILURES.store(0, Ordering::Relaxed);
+    idle::note_activity();
+}
+
+fn lose_host(loss: HostLoss) {
+    #[cfg(feature = "scpi")]
+    {
+        lock::release(Session::Usb);
+        arming::disarm();
+    }
+    HOST_LOST_SIGNAL.signal(loss);
+    session::end(SessionEnd::HostLost(loss));
+    events::publish(ClassEvent::HostLost(loss));
+    safety::signal(SafeStateReason::HostLost(loss));
+}
+
+fn note_transfer_failure() {
+    if TRANSFER_FAILURES.fetch_add(1, Ordering::Relaxed) + 1 == TRANSFER_FAILURE_LIMIT {
+        lose_host(HostLoss::TransferErrors);
+    }
+}
+
+/// Makes [`run_device`] drop off the bus and come back, so the host enumerates the device
+/// afresh.
+///
+/// Class state survives: the error queue, remote/local state, trigger and protocol counters.
+/// The bus reset that follows empties the command and response queues like a device clear.
+/// Descriptors built into the `UsbDevice` stay as they are; strings served from a
+/// [`Handler::get_string`] are fetched again, which is how a new mode can show up under a new
+/// name.
+pub fn reenumerate() {
+    DETACH_SIGNAL.signal(());
+}
+
+/// Runs `device` in place of `UsbDevice::run`, detaching for `detach_time` whenever
+/// [`reenumerate`] is called. Never returns.
+///
+/// Detaching disables the USB peripheral, which on most drivers turns off the D+ pull-up; the
+/// host sees an unplug. Give it at least 100 ms to notice.
+pub async fn run_device<'d, D: Driver<'d>>(
+    device: &mut UsbDevice<'d, D>,
+    detach_time: Duration,
+) -> ! {
+    loop {
+        DETACH_SIGNAL.reset();
+        select(device.run(), DETACH_SIGNAL.wait()).await;
+        device.disable().await;
+        clock::after(detach_time).await;
+    }
+}
+
+async fn wait_unhalted<T: TmcTransport>(transport: &mut T, pipe: Pipe, halted: &AtomicBool) {
+    if !halted.load(Ordering::Relaxed) {
+        return;
+    }
+    transport.set_halt(pipe, true);
+    while halted.load(Ordering::Relaxed) {
+        HALT_CLEARED.wait().await;
+    }
+    transport.set_halt(pipe, false);
+}
+
+const USBTMC_CLASS: u8 = 0xFE;
+const USBTMC_SUBCLASS: u8 = 0x03;
+const USBTMC_PROTOCOL: u8 = 0x00;
+const USB488_PROTOCOL: u8 = 0x01;
+
+const DEV_DEP_MSG_OUT_EOM: u8 = 1 << 0;
+const DEV_DEP_MSG_IN_EOM: u8 = 1 << 0;
+const DEV_DEP_MSG_IN_TERM_CHAR_MATCHED: u8 = 1 << 1;
+const REQUEST_DEV_DEP_MSG_IN_TERM_CHAR: u8 = 1 << 1;
+
+/// USB488 notifications are two bytes; a packet of two fits every speed.
+const INTERRUPT_PACKET_SIZE: u16 = 2;
+const INTERRUPT_INTERVAL_MS: u8 = 1;
+
+/// Largest bulk max packet size USB allows, 512 bytes at high speed.
+const MAX_PACKET_SIZE: usize = 512;
+/// Smallest Bulk-IN staging buffer a talking device can get by with.
+const MIN_IN_STAGING_LEN: usize = HEADER_LEN + 4;
+
+/// The DEV_DEP_MSG_OUT transfer the runner is reading, and its abort.
+static ABORT_OUT: TransferAbort = TransferAbort::new();
+/// The REQUEST_DEV_DEP_MSG_IN the runner is answering, and its abort.
+static ABORT_IN: TransferAbort = TransferAbort::new();
+/// Wakes the runner out of a blocked Bulk-OUT read when the transfer is aborted or the pipe halted.
+static ABORT_OUT_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static HALT_IN: AtomicBool = AtomicBool::new(false);
+static HALT_OUT: AtomicBool = AtomicBool::new(false);
+static HALT_CLEARED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static BULK_OUT_STATE: AtomicBool = AtomicBool::new(false);
+static BULK_IN_STATE: AtomicBool = AtomicBool::new(false);
+/// Set by INITIATE_CLEAR until CHECK_CLEAR_STATUS has reported completion.
+static CLEAR_ACTIVE: AtomicBool = AtomicBool::new(false);
+/// Set by [`hold_clear`] while the application is still clearing.
+static CLEAR_HELD: AtomicBool = AtomicBool::new(false);
+/// Tells the runner to forget a partially read response after a device clear.
+static DROP_REMAINDER: AtomicBool = AtomicBool::new(false);
+/// Tells the runner to drop the Bulk-OUT message it is reading, set when the interface is
+/// re-selected mid-transfer.
+static DROP_TRANSFER: AtomicBool = AtomicBool::new(false);
+/// Wakes the runner out of waiting for a response when the host clears the device.
+static CLEAR_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Device clears and bus resets so far, see [`AbortToken`].
+static CLEAR_GENERATION: AtomicU32 = AtomicU32::new(0);
+static HANDLER_ABORT: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Responses announced with [`defer_response`] and not yet sent.
+static DEFERRED_RESPONSES: AtomicU8 = AtomicU8::new(0);
+static RESERVED_VIOLATIONS: AtomicU32 = AtomicU32::new(0);
+static STALE_RESPONSES: AtomicU32 = AtomicU32::new(0);
+static EXPIRED_RESPONSES: AtomicU32 = AtomicU32::new(0);
+static UNPAIRED_RESPONSES: AtomicU32 = AtomicU32::new(0);
+static CONTROL_OVERRUNS: AtomicU32 = AtomicU32::new(0);
+static CRC_ERRORS: AtomicU32 = AtomicU32::new(0);
+static DROPPED_COMMANDS: AtomicU32 = AtomicU32::new(0);
+static NOTIFICATION_OVERFLOWS: AtomicU32 = AtomicU32::new(0);
+static QUEUE_HIGH: AtomicBool = AtomicBool::new(false);
+/// Queue flush requested by a device clear or bus reset, done by the runner.
+static FLUSH_QUEUES: AtomicBool = AtomicBool::new(false);
+/// [`TmcConfig::on_clear`] is due once the queues are flushed.
+static CLEAR_CALLBACK: AtomicBool = AtomicBool::new(false);
+/// Last values the application callbacks were told about.
+static NOTIFIED_POWER_BUDGET: AtomicU16 = AtomicU16::new(UNCONFIGURED_BUDGET_MA);
+/// Wakes the runner to do work the control handler left for it, see [`service_control`].
+static CONTROL_WORK: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static HOST_QUIRKS: AtomicU8 = AtomicU8::new(0);
+/// [`TmcConfig::conformance`] is [`Conformance::Strict`].
+static STRICT: AtomicBool = AtomicBool::new(false);
+/// The host turned on CRC mode, see [`TmcConfig::crc_mode_request`].
+static COMMAND_CRC: AtomicBool = AtomicBool::new(false);
+static LAST_PROTOCOL_ERROR: AtomicU8 = AtomicU8::new(0);
+static LAST_PROTOCOL_ERROR_BTAG: AtomicU8 = AtomicU8::new(0);
+static PROTOCOL_ERROR_COUNT: AtomicU16 = AtomicU16::new(0);
+static PACKET_SIZE: AtomicU16 = AtomicU16::new(0);
+static IN_CHUNK_SIZE: AtomicU32 = AtomicU32::new(0);
+static IN_PACING: AtomicU32 = AtomicU32::new(0);
+static POWER_BUDGET_MA: AtomicU16 = AtomicU16::new(UNCONFIGURED_BUDGET_MA);
+static CONFIGURED: AtomicBool = AtomicBool::new(false);
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+/// Copy of [`TmcConfig::power_approval`] for [`approve_power`], which has no config at hand.
+static APPROVAL_REQUIRED: AtomicBool = AtomicBool::new(false);
+static POWER_APPROVED: AtomicBool = AtomicBool::new(false);
+static FRONTEND_GATE: AtomicU8 = AtomicU8::new(FrontendGate::Unconfigured as u8);
+static FRONTEND_GATE_SIGNAL: Signal<CriticalSectionRawMutex, FrontendGate> = Signal::new();
+static DETACH_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static LAST_HOST_ACTIVITY: Mutex<CriticalSectionRawMutex, Cell<Instant>> =
+    Mutex::new(Cell::new(Instant::from_ticks(0)));
+static TRANSFER_FAILURES: AtomicU8 = AtomicU8::new(0);
+static HOST_LOST_SIGNAL: Signal<CriticalSectionRawMutex, HostLoss> = Signal::new();
+static READ_REQUESTED: Signal<CriticalSectionRawMutex, ReadRequest> = Signal::new();
+static SETTLED: Watch<CriticalSectionRawMutex, Settled, MAX_CONSUMED_WAITERS> =
+    Watch::new_with(Settled {
+        next: 0,
+        discarded: 0,
+    });
+static HANDLER: StaticCell<TmcControlHandler> = StaticCell::new();
+
+/// Longest the control handler may run per request.
+///
+/// Some drivers call it from the USB interrupt, so it only flips atomics and signals; queue
+/// flushes and application callbacks run later in the runner, see [`service_control`]. Requests
+/// that still overrun are counted in [`TmcStats::control_overruns`].
+pub const CONTROL_BUDGET: Duration = Duration::from_micros(50);
+
+/// Does the work the control handler left for the runner: flushes queues after a clear or
+/// reset, then calls the application hooks for what changed since the last call.
+///
+/// Callbacks see the latest state; transitions in between are coalesced.
+fn service_control(config: &TmcConfig) {
+    if FLUSH_QUEUES.load(Ordering::Relaxed) {
+        CMD_CHANNEL.clear();
+        PRIORITY_CHANNEL.clear();
+        RESP_CHANNEL.clear();
+        vendor::VENDOR_OUT_CHANNEL.clear();
+        vendor::VENDOR_IN_CHANNEL.clear();
+        DEFERRED_RESPONSES.store(0, Ordering::Relaxed);
+        if CLEAR_CALLBACK.swap(false, Ordering::Relaxed)
+            && let Some(on_clear) = config.on_clear
+        {
+            on_clear();
+        }
+        // Cleared last, so CHECK_CLEAR_STATUS can't see the flush done before `on_clear` had
+        // its chance to hold the clear.
+        FLUSH_QUEUES.store(false, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "usb488")]
+    usb488::notify_remote_change(config);
+
+    update_queue_level(config);
+
+    let budget = POWER_BUDGET_MA.load(Ordering::Relaxed);
+    if NOTIFIED_POWER_BUDGET.swap(budget, Ordering::Relaxed) != budget
+        && let Some(on_power_budget) = config.on_power_budget
+    {
+        on_power_budget(budget);
+    }
+}
+
+/// Awaits `fut`, doing control work whenever the handler asks for it in the meantime.
+async fn serviced<F: Future>(fut: F, config: &TmcConfig) -> F::Output {
+    let mut fut = pin!(fut);
+    loop {
+        match select(fut.as_mut(), CONTROL_WORK.wait()).await {
+            Either::First(output) => return output,
+            Either::Second(()) => service_control(config),
+        }
+    }
+}
+
+struct TmcControlHandler {
+    config: TmcConfig,
+    caps: Capabilities,
+    iface: InterfaceNumber,
+}
+
+/// Returns both pipes to their power-up state: queues emptied, partial transfers dropped,
+/// halts and abort/clear sequences forgotten.
+fn reset_transfers() {
+    FLUSH_QUEUES.store(true, Ordering::Relaxed);
+    CONTROL_WORK.signal(());
+    DROP_REMAINDER.store(true, Ordering::Relaxed);
+    DROP_TRANSFER.store(true, Ordering::Relaxed);
+    ABORT_OUT.reset();
+    ABORT_IN.reset();
+    CLEAR_ACTIVE.store(false, Ordering::Relaxed);
+    ABORT_OUT_SIGNAL.signal(());
+    CLEAR_SIGNAL.signal(());
+    abort_handlers();
+    clear_halt();
+}
+
+impl Handler for TmcControlHandler {
+    fn enabled(&mut self, enabled: bool) {
+        if !enabled {
+            lose_host(HostLoss::Disconnected);
+        }
+    }
+
+    fn reset(&mut self) {
+        reset_transfers();
+        if CONFIGURED.swap(false, Ordering::Relaxed) {
+            lose_host(HostLoss::Reset);
+        }
+        SUSPENDED.store(false, Ordering::Relaxed);
+        COMMAND_CRC.store(false, Ordering::Relaxed);
+        #[cfg(feature = "usb488")]
+        srq::clear();
+        idle::note_suspend();
+        set_power_budget(UNCONFIGURED_BUDGET_MA);
+        update_frontend_gate();
+    }
+
+    fn configured(&mut self, configured: bool) {
+        CONFIGURED.store(configured, Ordering::Relaxed);
+        let budget = if configured {
+            self.config.power.max_power_ma()
+        } else {
+            UNCONFIGURED_BUDGET_MA
+        };
+        set_power_budget(budget);
+        update_frontend_gate();
+    }
+
+    fn suspended(&mut self, suspended: bool) {
+        SUSPENDED.store(suspended, Ordering::Relaxed);
+        idle::note_suspend();
+        if suspended && CONFIGURED.load(Ordering::Relaxed) {
+            lose_host(HostLoss::Suspended);
+        }
+        let budget = if suspended {
+            SUSPENDED_BUDGET_MA
+        } else if CONFIGURED.load(Ordering::Relaxed) {
+            self.config.power.max_power_ma()
+        } else {
+            UNCONFIGURED_BUDGET_MA
+        };
+        set_power_budget(budget);
+        update_frontend_gate();
+    }
+
+    fn set_alternate_setting(&mut self, iface: InterfaceNumber, _alternate_setting: u8) {
+        // Hosts re-select the interface to recover from errors, and SET_INTERFACE clears
+        // endpoint halts and data toggles, so nothing in flight survives it.
+        if iface == self.iface {
+            reset_transfers();
+            session::end(SessionEnd::Reselected);
+        }
+    }
+
+    fn control_in<'a>(
+        &mut self,
+        req: embassy_usb::control::Request,
+        buf: &'a mut [u8],
+    ) -> Option<InResponse<'a>> {
+        note_host_activity();
+        let start = clock::now();
+        let response = self.handle_control_in(req, buf);
+        if clock::now() - start > CONTROL_BUDGET {
+            CONTROL_OVERRUNS.fetch_add(1, Ordering::Relaxed);
+        }
+        response
+    }
+}
+
+impl TmcControlHandler {
+    fn handle_control_in<'a>(
+        &mut self,
+        req: embassy_usb::control::Request,
+        buf: &'a mut [u8],
+    ) -> Option<InResponse<'a>> {
+        if req.request_type == RequestType::Vendor
+            && req.recipient == Recipient::Interface
+            && self.config.error_readback_request == Some(req.request)
+        {
+            if buf.len() < 4 {
+                return Some(InResponse::Rejected);
+            }
+            // [error, bTag, count (LE)], all zero if nothing went wrong yet.
+            buf[..4].fill(0);
+            if let Some(record) = last_protocol_error() {
+                buf[0] = record.error as u8;
+                buf[1] = record.b_tag;
+                buf[2..4].copy_from_slice(&record.count.to_le_bytes());
+            }
+            return Some(InResponse::Accepted(&buf[..4]));
+        }
+        if req.request_type == RequestType::Vendor
+            && req.recipient == Recipient::Interface
+            && self.config.crc_mode_request == Some(req.request)
+        {
+            let on = match req.value {
+                0 => false,
+                1 => true,
+                _ => return Some(InResponse::Rejected),
+            };
+            if buf.is_empty() {
+                return Some(InResponse::Rejected);
+            }
+            COMMAND_CRC.store(on, Ordering::Relaxed);
+            buf[0] = on as u8;
+            return Some(InResponse::Accepted(&buf[..1]));
+        }
+        if req.request_type != RequestType::Class {
+            return None;
+        }
+        let request = ControlRequest::from_u8(req.request)?;
+
+        // Abort requests are addressed to the bulk endpoint, everything else to the interface.
+        let expected_recipient = if request.to_endpoint() {
+            Recipient::Endpoint
+        } else {
+            Recipient::Interface
+        };
+        if req.recipient != expected_recipient {
+            return None;
+        }
+
+        match request {
+            ControlRequest::GetCapabilities => {
+                if buf.len() < CAPABILITIES_LEN {
+                    return Some(InResponse::Rejected);
+                }
+                let caps = &mut buf[..CAPABILITIES_LEN];
+                caps.copy_from_slice(&self.caps.encode());
+                Some(InResponse::Accepted(caps))
+            }
+            ControlRequest::InitiateAbortBulkOut => {
+                if buf.len() < 2 {
+                    return Some(InResponse::Rejected);
+                }
+                let btag = control::value_b_tag(req.value);
+                let current = ABORT_OUT.btag();
+
+                let status = if current == 0 {
+                    Status::Failed
+                } else if current != btag {
+                    Status::TransferNotInProgress
+                } else if bulk_out_state() == TransferState::Idle {
+                    // A transfer rejected up front: nothing is being read, so it is aborted as
+                    // soon as the host asks.
+                    if ABORT_OUT.abort_idle(btag) {
+                        if host_quirks().abort_without_check {
+                            clear_halt_out();
+                        }
+                        record_protocol_error(ProtocolError::Aborted, btag);
+                        Status::Success
+                    } else {
+                        // The runner finished the transfer after we looked.
+                        Status::Failed
+                    }
+                } else if ABORT_OUT.request(btag) {
+                    // Keep leftover packets of the aborted message from being parsed as a header
+                    // until the host has seen the abort complete.
+                    halt_bulk_out();
+                    record_protocol_error(ProtocolError::Aborted, btag);
+                    Status::Success
+                } else {
+                    // The runner finished the transfer after we looked; halting now would
+                    // leave no `finish` to complete the abort.
+                    Status::Failed
+                };
+
+                buf[0] = status as u8;
+                buf[1] = current;
+                Some(InResponse::Accepted(&buf[..2]))
+            }
+            ControlRequest::CheckAbortBulkOutStatus => {
+                if buf.len() < 8 {
+                    return Some(InResponse::Rejected);
+                }
+                let status = if ABORT_OUT.is_pending() {
+                    Status::Pending
+                } else {
+                    Status::Success
+                };
+
+                buf[0] = status as u8;
+                buf[1..4].fill(0);
+                buf[4..8].copy_from_slice(&ABORT_OUT.bytes().to_le_bytes());
+
+                if status == Status::Success && ABORT_OUT.retire() {
+                    // The host follows up with CLEAR_FEATURE(ENDPOINT_HALT), which the stack
+                    // handles without telling us, so release our side of the halt here.
+                    clear_halt_out();
+                }
+
+                Some(InResponse::Accepted(&buf[..8]))
+            }
+            ControlRequest::RenControl
+            | ControlRequest::GoToLocal
+            | ControlRequest::LocalLockout => {
+                // Never advertised without the `usb488` feature, see `capabilities`.
+                if buf.is_empty() || !self.caps.usb488(Usb488InterfaceCapabilities::REN_CONTROL) {
+                    return Some(InResponse::Rejected);
+                }
+                #[cfg(feature = "usb488")]
+                usb488::remote_request(request, req.value);
+
+                buf[0] = Status::Success as u8;
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+            ControlRequest::IndicatorPulse => {
+                if buf.is_empty() {
+                    return Some(InResponse::Rejected);
+                }
+                let status = if self
+                    .caps
+                    .interface
+                    .contains(InterfaceCapabilities::INDICATOR_PULSE)
+                {
+                    indicator::signal(IndicatorEvent::Pulse);
+                    Status::Success
+                } else {
+                    Status::Failed
+                };
+                buf[0] = status as u8;
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+            ControlRequest::InitiateClear => {
+                if buf.is_empty() {
+                    return Some(InResponse::Rejected);
+                }
+                FLUSH_QUEUES.store(true, Ordering::Relaxed);
+                CLEAR_CALLBACK.store(true, Ordering::Relaxed);
+                CONTROL_WORK.signal(());
+                DROP_REMAINDER.store(true, Ordering::Relaxed);
+                if bulk_out_state() == TransferState::Idle {
+                    ABORT_OUT.forget();
+                }
+                CLEAR_ACTIVE.store(true, Ordering::Relaxed);
+                halt_bulk_out();
+                CLEAR_SIGNAL.signal(());
+                CLEAR_HELD.store(false, Ordering::Relaxed);
+                abort_handlers();
+                record_protocol_error(ProtocolError::Cleared, 0);
+                safety::signal(SafeStateReason::DeviceClear);
+                events::publish(ClassEvent::DeviceClear);
+                session::end(SessionEnd::DeviceClear);
+
+                buf[0] = Status::Success as u8;
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+            ControlRequest::CheckClearStatus => {
+                if buf.len() < 2 {
+                    return Some(InResponse::Rejected);
+                }
+                let out_busy = bulk_out_state() == TransferState::InProgress;
+                let in_busy = bulk_in_state() == TransferState::InProgress;
+                let app_busy =
+                    FLUSH_QUEUES.load(Ordering::Relaxed) || CLEAR_HELD.load(Ordering::Relaxed);
+
+                // bmClear.D0 tells the host there is still Bulk-IN data for it to drain.
+                let status = if out_busy || in_busy || app_busy {
+                    Status::Pending
+                } else {
+                    Status::Success
+                };
+                buf[0] = status as u8;
+                buf[1] = in_busy as u8;
+
+                if status == Status::Success && CLEAR_ACTIVE.swap(false, Ordering::Relaxed) {
+                    // A device clear is the host's way out of a Bulk-IN halted on an empty read.
+                    clear_halt();
+                }
+
+                Some(InResponse::Accepted(&buf[..2]))
+            }
+            ControlRequest::InitiateAbortBulkIn => {
+                if buf.len() < 2 {
+                    return Some(InResponse::Rejected);
+                }
+                let btag = control::value_b_tag(req.value);
+                let current = ABORT_IN.btag();
+
+                let status = if current == 0 {
+                    Status::Failed
+                } else if current != btag {
+                    Status::TransferNotInProgress
+                } else if ABORT_IN.request(btag) {
+                    // A runner still waiting for the response gives up on it; one already
+                    // writing finishes once the host has drained what is in the FIFO.
+                    DROP_REMAINDER.store(true, Ordering::Relaxed);
+                    CLEAR_SIGNAL.signal(());
+                    record_protocol_error(ProtocolError::Aborted, btag);
+                    Status::Success
+                } else {
+                    // The runner finished the transfer after we looked.
+                    Status::Failed
+                };
+
+                buf[0] = status as u8;
+                buf[1] = current;
+                Some(InResponse::Accepted(&buf[..2]))
+            }
+            ControlRequest::CheckAbortBulkInStatus => {
+                if buf.len() < 8 {
+                    return Some(InResponse::Rejected);
+                }
+                let in_busy = bulk_in_state() == TransferState::InProgress;
+                let status = if ABORT_IN.is_pending() {
+                    Status::Pending
+                } else {
+                    ABORT_IN.retire();
+                    Status::Success
+                };
+
+                // bmAbortBulkIn.D0 tells the host to keep reading until a short packet.
+                buf[0] = status as u8;
+                buf[1] = in_busy as u8;
+                buf[2..4].fill(0);
+                buf[4..8].copy_from_slice(&ABORT_IN.bytes().to_le_bytes());
+                Some(InResponse::Accepted(&buf[..8]))
+            }
+            ControlRequest::ReadStatusByte => {
+                // Never advertised without the `usb488` feature, see `capabilities`.
+                if buf.len() < 3 || self.caps.bcd_usb488.is_none() {
+                    return Some(InResponse::Rejected);
+                }
+                let btag = control::value_b_tag(req.value);
+                buf[0] = Status::Success as u8;
+                buf[1] = btag;
+                buf[2] = 0;
+                #[cfg(feature = "usb488")]
+                if !self.config.interrupt_in {
+                    buf[2] = srq::serial_poll();
+                } else if srq::status_byte_pending() {
+                    buf[0] = Status::InterruptInBusy as u8;
+                } else {
+                    let status = srq::serial_poll();
+                    if !srq::push(srq::Notification::StatusByte {
+                        b_tag: btag,
+                        status,
+                    }) {
+                        buf[0] = Status::Failed as u8;
+                    }
+                }
+                Some(InResponse::Accepted(&buf[..3]))
+            }
+        }
+    }
+}
+
+/// How closely the runner holds the host to USBTMC.
+///
+/// Either way every violation is counted and recorded for [`last_protocol_error`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Conformance {
+    /// Keep going wherever the message can still be made sense of, so buggy hosts keep
+    /// working: headers with non-zero reserved fields or a zero bTag are processed, and
+    /// messages with a bad bTagInverse or a reserved MsgID are skipped.
+    Lenient,
+    /// Enforce what the specification requires of the host, for conformance testing. Headers
+    /// with non-zero reserved fields are discarded; a zero bTag, a bad bTagInverse or a
+    /// reserved MsgID halts Bulk-OUT until the host clears it; [`host_quirks`] are all off.
+    Strict,
+}
+
+/// What the runner does with a REQUEST_DEV_DEP_MSG_IN when no response is queued.
+///
+/// IEEE 488.2 calls this the UNTERMINATED condition; every policy but `Wait` raises [`ESR_QYE`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EmptyReadPolicy {
+    /// Hold the IN transfer until the application submits a response.
+    Wait,
+    /// Halt Bulk-IN so the host's read fails; a device clear releases the halt.
+    Halt,
+    /// Answer with a zero-length DEV_DEP_MSG_IN with EOM set.
+    EmptyMessage,
+}
+
+/// Which command the runner drops when the application falls behind and the command queue
+/// is full. Either way the drop is counted in [`TmcStats::dropped_commands`] and, with
+/// `scpi`, [`SCPI_ERR_INPUT_OVERRUN`] queued.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the command that didn't fit, keeping the queue in the order the host sent it.
+    DropNewest,
+    /// Drop the oldest queued command to make room, for instruments where the latest
+    /// setting is the one that matters. A dropped query is never answered: the runner skips
+    /// it like an expired one. With eight of those still open, the new command is dropped
+    /// instead.
+    DropOldest,
+}
+
+/// What ends an inbound message, see [`TmcConfig::termination`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    /// A transfer with EOM set.
+    Eom,
+    /// A transfer whose data ends in a newline, whatever its EOM bit says.
+    Newline,
+    /// Whichever comes first.
+    Either,
+}
+
+impl Termination {
+    /// Whether a transfer with `data` and the EOM bit `eom` ends the message.
+    pub fn ends(self, data: &[u8], eom: bool) -> bool {
+        let newline = data.last() == Some(&b'\n');
+        match self {
+            Termination::Eom => eom,
+            Termination::Newline => newline,
+            Termination::Either => eom || newline,
+        }
+    }
+}
+
+/// Length of `data` without one trailing `\n` or `\r\n`.
+pub fn strip_terminator(data: &[u8]) -> usize {
+    match data {
+        [rest @ .., b'\r', b'\n'] | [rest @ .., b'\n'] => rest.len(),
+        _ => data.len(),
+    }
+}
+
+/// Class configuration, passed to [`UsbTmc::new`].
+#[derive(Clone, Copy)]
+pub struct TmcConfig {
+    /// bcdUSBTMC, e.g. `0x0100` for USBTMC 1.00.
+    pub bcd_usbtmc: u16,
+    /// bcdUSB488, or `None` for a plain USBTMC device without the USB488 subclass fields.
+    pub bcd_usb488: Option<u16>,
+    /// Claim IEEE 488.2 compliance in the USB488 interface capabilities. Ignored without
+    /// `bcd_usb488`.
+    pub ieee4882: bool,
+    /// Strict or lenient handling of host protocol errors.
+    pub conformance: Conformance,
+    /// What to do when the host reads while the response queue is empty.
+    pub empty_read: EmptyReadPolicy,
+    /// What to drop when the command queue is full.
+    pub overflow: OverflowPolicy,
+    /// Largest DEV_DEP_MSG_OUT transferSize accepted. Bigger transfers are refused from the
+    /// header alone: Bulk-OUT is halted and [`SCPI_ERR_TOO_MUCH_DATA`] queued, so the host
+    /// aborts instead of streaming data that would be thrown away.
+    pub max_transfer_size: u32,
+    /// Runs commands inside the runner instead of handing them to the application task.
+    pub inline_handler: Option<InlineHandler>,
+    /// Longest a host read is held waiting for a response that is owed, either under
+    /// [`EmptyReadPolicy::Wait`] or for a [deferred](defer_response) query. On expiry the read
+    /// is answered with an empty message and [`ESR_QYE`] raised. `None` waits forever.
+    pub response_timeout: Option<Duration>,
+    /// Longest the answer to a query may wait for the host to read it, counted from the
+    /// query's arrival. Past it the answer is dropped, whether queued or still to come,
+    /// [`ESR_QYE`] raised and [`ProtocolError::Expired`] recorded, so a query the host
+    /// forgot about can't answer a read made much later. `None` keeps answers forever.
+    pub response_ttl: Option<Duration>,
+    /// Picks commands that skip the normal queue and go to [`priority_cmd_receiver`], e.g.
+    /// [`is_status_byte_query`] or app-level abort commands.
+    pub immediate: Option<fn(&[u8]) -> bool>,
+    /// Enforce IEEE 488.2 query/response pairing. Responses submitted while no query is open
+    /// are dropped and counted in [`TmcStats::unpaired_responses`] rather than returned by
+    /// the next read. A read with no query open is answered at once per
+    /// [`empty_read`](Self::empty_read) (an empty message under `Wait`); a read with a query
+    /// open waits for its answer up to [`response_timeout`](Self::response_timeout).
+    pub strict_pairing: bool,
+    /// Join DEV_DEP_MSG_OUT transfers into one command until a transfer ends the message
+    /// per [`termination`](Self::termination), for host code that writes `*IDN?` and its
+    /// terminator separately. Off delivers each transfer as a command of its own. A message
+    /// that outgrows [`MAX_SCPI_LEN`] is dropped up to its end with
+    /// [`SCPI_ERR_TOO_MUCH_DATA`].
+    pub assemble_lines: bool,
+    /// What ends an inbound message under [`assemble_lines`](Self::assemble_lines). Either
+    /// way one trailing `\n` or `\r\n` is stripped before a command is delivered, so
+    /// handlers see the same bytes whichever way the host's VISA terminates its writes.
+    pub termination: Termination,
+    /// Appended to every response message that doesn't already end with it, so a handler
+    /// that forgets the newline doesn't leave pyvisa's `query()` waiting for one. Only the
+    /// last piece of a message is terminated, empty messages and [`Response::raw`] ones are
+    /// left alone. Up to [`MAX_TERMINATOR_LEN`] bytes; empty turns it off.
+    pub response_terminator: &'static [u8],
+    /// Called from the runner the moment a USB488 TRIGGER message is parsed, before anything
+    /// else is processed; e.g. pulse a GPIO to sync external hardware. Must not block.
+    #[cfg(feature = "usb488")]
+    pub on_trigger: Option<fn()>,
+    /// Called from the runner when the [`RemoteState`] has changed. Use
+    /// [`RemoteState::front_panel_enabled`] to lock or release buttons and encoders. Must not
+    /// block.
+    #[cfg(feature = "usb488")]
+    pub on_remote_change: Option<fn(RemoteState)>,
+    /// Advertise and accept INDICATOR_PULSE. Enable it when something runs [`indicator::run`].
+    pub indicator_pulse: bool,
+    /// Power source and bMaxPower, see [`PowerSource::apply`].
+    pub power: PowerSource,
+    /// Called from the runner whenever [`power_budget_ma`] has changed: when the host
+    /// configures the device, on bus reset and around suspend. Switch high-power circuits on
+    /// only once the budget allows it. Must not block.
+    pub on_power_budget: Option<fn(u16)>,
+    /// Hold [`frontend_gate`] at [`FrontendGate::AwaitingApproval`] after configuration until
+    /// the application calls [`approve_power`], e.g. from a USB-PD negotiation.
+    pub power_approval: bool,
+    /// Called from the runner after INITIATE_CLEAR, once the class has emptied its queues, so
+    /// the application can abort acquisitions and drop its own buffered output. Must not
+    /// block; use [`hold_clear`] to finish in a task before the clear completes.
+    pub on_clear: Option<fn()>,
+    /// wMaxPacketSize of the bulk endpoints: 64 for full speed, 512 for a high-speed driver.
+    pub max_packet_size: u16,
+    /// Alignment, in bytes, the USB driver needs of the buffers it DMAs from and into, for
+    /// drivers that would otherwise bounce unaligned slices through a hidden copy. With it
+    /// set, the class hands the driver only slices of the [`TransferBuffers`] passed to
+    /// [`UsbTmc::run_with_buffers`], each starting on the alignment, and asserts they are
+    /// aligned; Bulk-OUT payloads are then read into the packet buffer and copied into the
+    /// [`Command`] rather than read into it directly. `None` lets the driver read into any
+    /// buffer.
+    pub dma_alignment: Option<usize>,
+    /// Vendor-specific bRequest (IN, interface recipient) that reads back
+    /// [`last_protocol_error`] as four bytes: error code, bTag, error count (LE). `None`
+    /// disables it.
+    pub error_readback_request: Option<u8>,
+    /// Vendor-specific bRequest (IN, interface recipient) that turns CRC mode on (wValue 1)
+    /// or off (wValue 0), answering one byte with the mode now in effect. In CRC mode every
+    /// DEV_DEP_MSG_OUT payload ends in a little-endian CRC-32 of the bytes before it (see
+    /// [`vendor::crc32`]), counted in transferSize. A transfer whose trailer doesn't match is
+    /// dropped with [`ProtocolError::Crc`] recorded and, with `scpi`, -361 "Parity error in
+    /// program message" queued. A bus reset turns the mode off. `None` disables it.
+    pub crc_mode_request: Option<u8>,
+    /// Endpoint numbers (1..=15) to ask the driver for, for hosts that care about the order
+    /// endpoints of a composite device come in. `None` takes the next free one. A driver that
+    /// can't honor the request panics in `UsbTmc::new`.
+    pub bulk_out_endpoint: Option<u8>,
+    pub bulk_in_endpoint: Option<u8>,
+    pub interrupt_in_endpoint: Option<u8>,
+    /// Allocate the USB488 Interrupt-IN endpoint, placed after the bulk pair. See
+    /// [`UsbTmc::take_interrupt_in`].
+    pub interrupt_in: bool,
+    /// Interrupt-IN notifications that may wait for the host to poll, up to
+    /// [`srq::MAX_NOTIFICATIONS`]. Only used with [`interrupt_in`](Self::interrupt_in).
+    pub notification_depth: usize,
+    /// Host quirks to start with, see [`set_host_quirks`].
+    pub host_quirks: HostQuirks,
+    /// Advertise LISTEN_ONLY: the device takes commands but never sends DEV_DEP_MSG_IN, and
+    /// REQUEST_DEV_DEP_MSG_IN goes unanswered. Lets [`UsbTmc`] run without a Bulk-IN staging
+    /// buffer.
+    pub listen_only: bool,
+    /// Carry a CRC-32 trailer (little-endian, see [`vendor::crc32`]) at the end of every
+    /// vendor-specific message. The class checks it on VENDOR_SPECIFIC_OUT, dropping a bad
+    /// message and recording [`ProtocolError::Crc`], and appends it to VENDOR_SPECIFIC_IN.
+    /// transferSize includes the trailer both ways. A REQUEST_VENDOR_SPECIFIC_IN with room
+    /// for less than the trailer goes unanswered and is recorded the same way.
+    pub vendor_crc: bool,
+    /// Run vendor-specific messages as a resumable upload, see [`upload`]: the class checks
+    /// and orders the chunks for [`upload::chunk_receiver`] and answers
+    /// REQUEST_VENDOR_SPECIFIC_IN with the progress itself. [`vendor::vendor_receiver`] and
+    /// [`vendor::vendor_sender`] go unused.
+    pub vendor_upload: bool,
+    /// Command queue depths at which [`on_queue_level`](Self::on_queue_level) is called.
+    /// `None` disables the check; [`cmd_queue_depth`] is always available.
+    pub cmd_queue_watermarks: Option<Watermarks>,
+    /// Called from the runner when the command queue crosses a watermark. Must not block.
+    pub on_queue_level: Option<fn(QueueLevel)>,
+}
+
+/// What an [`InlineHandler`] did with a command.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Inline {
+    /// Not handled here; deliver it to [`cmd_receiver`] as usual.
+    Pass,
+    /// Handled. The reply written into `resp` is queued if it has a body or answers a query;
+    /// an empty answer reaches the host as an empty message.
+    Done,
+    /// Handled, but the reply is computed elsewhere and submitted later through
+    /// [`complete_deferred`]. Host reads wait for it, see [`defer_response`].
+    Deferred,
+}
+
+/// Handles a command synchronously in the runner, the moment its last packet arrives.
+///
+/// The reply is queued behind responses already waiting, so ordering is kept, but there is no
+/// trip through the executor: command to response costs one pass of the runner.
+///
+/// The runner is blocked while it runs, so it must finish in bounded time: no waiting on
+/// hardware, no long computations. Slow queries return [`Inline::Deferred`].
+pub type InlineHandler = fn(cmd: &[u8], resp: &mut Response) -> Inline;
+
+/// Keeps a device clear pending while the application flushes its own pipelines.
+///
+/// Call it from [`TmcConfig::on_clear`] and hand the work to a task; CHECK_CLEAR_STATUS
+/// answers STATUS_PENDING until that task calls [`release_clear`].
+pub fn hold_clear() {
+    CLEAR_HELD.store(true, Ordering::Relaxed);
+}
+
+/// Lets a device clear held with [`hold_clear`] complete.
+pub fn release_clear() {
+    CLEAR_HELD.store(false, Ordering::Relaxed);
+}
+
+/// Tells a running handler that the host has cleared the device (or reset the bus) since
+/// the command started, for handlers that poll between steps rather than run under
+/// [`abortable`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct AbortToken(u32);
+
+impl AbortToken {
+    /// A token for a command starting now.
+    pub fn new() -> Self {
+        Self(CLEAR_GENERATION.load(Ordering::Relaxed))
+    }
+
+    /// Whether a device clear has arrived since the token was taken.
+    pub fn is_aborted(&self) -> bool {
+        CLEAR_GENERATION.load(Ordering::Relaxed) != self.0
+    }
+
+    /// Waits until the command is aborted.
+    pub async fn aborted(&self) {
+        while !self.is_aborted() {
+            HANDLER_ABORT.wait().await;
+        }
+    }
+}
+
+impl Default for AbortToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs a command handler, dropping it at its next await point if the host clears the
+/// device first, so `INIT;*OPC?` and other long operations can be interrupted from the host.
+/// `None` means the handler was cancelled; the clear has already flushed its response.
+///
+/// Wakeups go to one waiter, so run one abortable handler at a time, as a single command
+/// task does; other tasks poll an [`AbortToken`].
+pub async fn abortable<F: Future>(fut: F) -> Option<F::Output> {
+    let token = AbortToken::new();
+    match select(fut, token.aborted()).await {
+        Either::First(output) => Some(output),
+        Either::Second(()) => None,
+    }
+}
+
+/// Aborts handlers running under [`abortable`] or holding an [`AbortToken`].
+fn abort_handlers() {
+    CLEAR_GENERATION.fetch_add(1, Ordering::Relaxed);
+    HANDLER_ABORT.signal(());
+}
+
+/// Announces that a response to the current query will be submitted later with
+/// [`complete_deferred`].
+///
+/// Until it arrives, host reads are held (the host sees NAKs) regardless of
+/// [`TmcConfig::empty_read`], for at most [`TmcConfig::response_timeout`]. Lets a task run a
+/// seconds-long measurement without the read being failed as an empty queue.
+pub fn defer_response() {
+    DEFERRED_RESPONSES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Submits the response announced by [`defer_response`].
+pub async fn complete_deferred(resp: Response) {
+    settle_deferred_response();
+    RESP_CHANNEL.send(resp).await;
+}
+
+fn settle_deferred_response() {
+    let _ =
+        DEFERRED_RESPONSES.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+}
+
+/// A host read that found no response queued, see [`read_requested`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ReadRequest {
+    /// The oldest query still owed an answer; put it in [`Response::token`]. `None` if no
+    /// query is open.
+    pub token: Option<ResponseToken>,
+    /// The most the host asked for in this read.
+    pub max_len: u32,
+}
+
+/// Waits for a REQUEST_DEV_DEP_MSG_IN that finds nothing to send.
+///
+/// Lets results that are costly to produce, such as the data behind `FETCh?`, be computed
+/// when the host actually reads instead of after every command that might be followed by a
+/// read. Whether the host is then kept waiting for the answer is up to
+/// [`TmcConfig::empty_read`], [`TmcConfig::strict_pairing`] or a [`defer_response`] made
+/// when the query came in. Meant for a single task; reads between two calls are coalesced
+/// into the latest one.
+pub async fn read_requested() -> ReadRequest {
+    READ_REQUESTED.wait().await
+}
+
+/// What became of a query's answer, see [`response_consumed`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Consumed {
+    /// The last byte went out on Bulk-IN.
+    Sent,
+    /// Dropped unsent: by a device clear or bus reset, an aborted or failed Bulk-IN
+    /// transfer, or [`TmcConfig::response_ttl`].
+    Discarded,
+}
+
+/// Tasks that may wait in [`response_consumed`] at once; more poll the clock every
+/// millisecond instead.
+pub const MAX_CONSUMED_WAITERS: usize = 4;
+
+/// Answers the runner is done with: every query before `next` has had its answer sent or
+/// discarded. Bit `i` of `discarded` is set if the answer to query `next - 1 - i` was
+/// discarded.
+#[derive(Clone, Copy)]
+struct Settled {
+    next: u32,
+    discarded: u32,
+}
+
+impl Settled {
+    fn outcome(self, token: ResponseToken) -> Option<Consumed> {
+        let behind = self.next.wrapping_sub(token.0) as i32;
+        if behind <= 0 {
+            return None;
+        }
+        let age = behind as u32 - 1;
+        if age < u32::BITS && self.discarded & (1 << age) != 0 {
+            return Some(Consumed::Discarded);
+        }
+        Some(Consumed::Sent)
+    }
+}
+
+/// Records that the answers to the next `count` open queries were sent or discarded.
+fn settle(count: u32, discarded: bool) {
+    if count == 0 {
+        return;
+    }
+    SETTLED.sender().send_modify(|settled| {
+        let Some(settled) = settled else {
+            return;
+        };
+        let bits = 1u32.checked_shl(count).map_or(u32::MAX, |bit| bit - 1);
+        settled.discarded = settled.discarded.checked_shl(count).unwrap_or(0);
+        if discarded {
+            settled.discarded |= bits;
+        }
+        settled.next = settled.next.wrapping_add(count);
+    });
+}
+
+/// Waits until the answer to the query `token` was tagged for has left the device, or been
+/// discarded, e.g. so a trigger is only re-armed once the host has the previous reading.
+///
+/// Answers go out in query order, so this also covers every earlier query. Returns at once
+/// if that has already happened; an answer settled more than 32 queries ago reads as
+/// [`Consumed::Sent`].
+pub async fn response_consumed(token: ResponseToken) -> Consumed {
+    let Some(mut rx) = SETTLED.receiver() else {
+        loop {
+            if let Some(outcome) = SETTLED.try_get().and_then(|s| s.outcome(token)) {
+                return outcome;
+            }
+            clock::after(Duration::from_millis(1)).await;
+        }
+    };
+    if let Some(outcome) = rx.try_get().and_then(|s| s.outcome(token)) {
+        return outcome;
+    }
+    loop {
+        if let Some(outcome) = rx.changed().await.outcome(token) {
+            return outcome;
+        }
+    }
+}
+
+impl TmcConfig {
+    /// Settings for the Linux `usbtmc` kernel driver, so that
+    /// `echo "*IDN?" > /dev/usbtmc0; cat /dev/usbtmc0` works without tuning the driver.
+    ///
+    /// - `cat` reads until it gets zero bytes, and the driver only returns zero bytes for an
+    ///   empty DEV_DEP_MSG_IN. With [`strict_pairing`](Self::strict_pairing), the read after
+    ///   the answer finds no query open and gets that empty message at once instead of
+    ///   hanging until the driver times out.
+    /// - A query that is still open is waited for, but only up to 4 s. The driver gives up
+    ///   after 5 s by default and sends INITIATE_ABORT_BULK_IN, which ends `cat` with an error
+    ///   rather than EOF.
+    /// - The driver reads with a fixed buffer (1024 to 4096 bytes depending on version) and
+    ///   relies on a short packet or ZLP to finish the URB, so zero-length packets stay on.
+    /// - TermChar isn't advertised, so the driver never asks for it.
+    pub fn linux_usbtmc() -> Self {
+        Self {
+            strict_pairing: true,
+            empty_read: EmptyReadPolicy::EmptyMessage,
+            response_timeout: Some(Duration::from_secs(4)),
+            ..Self::default()
+        }
+    }
+
+    /// What GET_CAPABILITIES advertises for this configuration.
+    pub fn capabilities(&self) -> Capabilities {
+        let mut interface = InterfaceCapabilities::NONE;
+        if self.indicator_pulse {
+            interface = interface | InterfaceCapabilities::INDICATOR_PULSE;
+        }
+        if self.listen_only {
+            interface = interface | InterfaceCapabilities::LISTEN_ONLY;
+        }
+        let mut usb488_interface =
+            Usb488InterfaceCapabilities::REN_CONTROL | Usb488InterfaceCapabilities::TRIGGER;
+        if self.ieee4882 {
+            usb488_interface = usb488_interface | Usb488InterfaceCapabilities::IEEE4882;
+        }
+        let mut usb488_device = Usb488DeviceCapabilities::RL1 | Usb488DeviceCapabilities::DT1;
+        if self.interrupt_in && cfg!(feature = "usb488") {
+            usb488_device = usb488_device | Usb488DeviceCapabilities::SR1;
+        }
+        Capabilities {
+            bcd_usbtmc: self.bcd_usbtmc,
+            interface,
+            device: DeviceCapabilities::NONE,
+            // Without the layer there is nothing to back the USB488 capabilities.
+            bcd_usb488: self.bcd_usb488.filter(|_| cfg!(feature = "usb488")),
+            usb488_interface,
+            usb488_device,
+        }
+    }
+}
+
+impl Default for TmcConfig {
+    fn default() -> Self {
+        Self {
+            bcd_usbtmc: 0x0100,
+            bcd_usb488: None,
+            ieee4882: false,
+            conformance: Conformance::Lenient,
+            empty_read: EmptyReadPolicy::Wait,
+            overflow: OverflowPolicy::DropNewest,
+            max_transfer_size: MAX_SCPI_LEN as u32,
+            inline_handler: None,
+            response_timeout: None,
+            response_ttl: None,
+            immediate: None,
+            strict_pairing: false,
+            assemble_lines: false,
+            termination: Termination::Either,
+            response_terminator: b"\n",
+            #[cfg(feature = "usb488")]
+            on_trigger: None,
+            #[cfg(feature = "usb488")]
+            on_remote_change: None,
+            indicator_pulse: false,
+            power: PowerSource::Bus { max_power_ma: 100 },
+            on_power_budget: None,
+            power_approval: false,
+            on_clear: None,
+            max_packet_size: 64,
+            dma_alignment: None,
+            error_readback_request: None,
+            crc_mode_request: None,
+            bulk_out_endpoint: None,
+            bulk_in_endpoint: None,
+            interrupt_in_endpoint: None,
+            interrupt_in: false,
+            notification_depth: 4,
+            host_quirks: HostQuirks::NONE,
+            listen_only: false,
+            vendor_crc: false,
+            vendor_upload: false,
+            cmd_queue_watermarks: None,
+            on_queue_level: None,
+        }
+    }
+}
+
+/// Default size of the Bulk-IN staging buffer: room for one DEV_DEP_MSG_IN with a full
+/// [`Response`].
+pub const IN_STAGING_LEN: usize = HEADER_LEN + MAX_SCPI_LEN;
+
+/// Every buffer the class gives the USB driver, for placing in DMA-capable RAM, see
+/// [`TmcConfig::dma_alignment`]:
+///
+/// ```ignore
+/// #[unsafe(link_section = ".sram1")]
+/// static BUFFERS: StaticCell<TransferBuffers> = StaticCell::new();
+/// usbtmc.run_with_buffers(BUFFERS.init(TransferBuffers::new())).await
+/// ```
+///
+/// Aligned to 32 bytes, a cache line on the Cortex-M7 parts, with both buffers starting on
+/// it. A driver that needs more must be given a section aligned to match.
+#[repr(C, align(32))]
+pub struct TransferBuffers<const IN_STAGING: usize = IN_STAGING_LEN> {
+    /// Bulk-OUT packets, headers included.
+    packet: [u8; MAX_PACKET_SIZE],
+    /// DEV_DEP_MSG_IN transfers, framed.
+    staging: [u8; IN_STAGING],
+}
+
+impl<const IN_STAGING: usize> TransferBuffers<IN_STAGING> {
+    pub const fn new() -> Self {
+        Self {
+            packet: [0; MAX_PACKET_SIZE],
+            staging: [0; IN_STAGING],
+        }
+    }
+}
+
+impl<const IN_STAGING: usize> Default for TransferBuffers<IN_STAGING> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The USBTMC class on a pair of embassy-usb bulk endpoints.
+///
+/// `IN_STAGING` is the size of the buffer DEV_DEP_MSG_IN transfers are framed in, part of
+/// the runner's future. A smaller one sends long responses as several transfers, each ended
+/// with EOM=0 for the host to read on. Devices that never answer can set it to 0 together
+/// with [`TmcConfig::listen_only`].
+pub struct UsbTmc<'d, D: Driver<'d>, const IN_STAGING: usize = IN_STAGING_LEN> {
+    transport: EndpointTransport<'d, D>,
+    interrupt_in: Option<D::EndpointIn>,
+    config: TmcConfig,
+    #[cfg(feature = "descriptor-dump")]
+    layout: descriptors::InterfaceLayout,
+}
+
+impl<'d, D: Driver<'d>, const IN_STAGING: usize> UsbTmc<'d, D, IN_STAGING> {
+    /// Panics if `IN_STAGING` can't hold a header and some data while the device talks.
+    pub fn new(builder: &mut Builder<'d, D>, config: TmcConfig) -> Self {
+        assert!(
+            config.listen_only || IN_STAGING >= MIN_IN_STAGING_LEN,
+            "Bulk-IN staging buffer too small"
+        );
+        #[cfg(feature = "descriptor-check")]
+        for violation in conformance::check(&config) {
+            defmt::warn!("USBTMC descriptor check: {}", violation);
+        }
+        APPROVAL_REQUIRED.store(config.power_approval, Ordering::Relaxed);
+        set_host_quirks(config.host_quirks);
+        STRICT.store(config.conformance == Conformance::Strict, Ordering::Relaxed);
+        tuning::init(&config);
+        #[cfg(feature = "usb488")]
+        srq::set_depth(config.notification_depth);
+
+        let protocol = if config.bcd_usb488.is_some() {
+            USB488_PROTOCOL
+        } else {
+            USBTMC_PROTOCOL
+        };
+        let mut func = builder.function(USBTMC_CLASS, USBTMC_SUBCLASS, protocol);
+        let mut iface = func.interface();
+        let iface_number = iface.interface_number();
+        let mut alt = iface.alt_setting(USBTMC_CLASS, USBTMC_SUBCLASS, protocol, None);
+
+        // Descriptor order is Bulk-OUT, Bulk-IN, then the optional Interrupt-IN, as USB488
+        // lays it out; some host drivers take the endpoints in that order.
+        let mps = config.max_packet_size.min(MAX_PACKET_SIZE as u16);
+        let endpoint = |number: Option<u8>, direction| {
+            number.map(|number| EndpointAddress::from_parts(number as usize, direction))
+        };
+        let out = alt.endpoint_bulk_out(endpoint(config.bulk_out_endpoint, Direction::Out), mps);
+        let inp = alt.endpoint_bulk_in(endpoint(config.bulk_in_endpoint, Direction::In), mps);
+        let interrupt_in = config.interrupt_in.then(|| {
+            alt.endpoint_interrupt_in(
+                endpoint(config.interrupt_in_endpoint, Direction::In),
+                INTERRUPT_PACKET_SIZE,
+                INTERRUPT_INTERVAL_MS,
+            )
+        });
+        drop(func);
+
+        #[cfg(feature = "descriptor-dump")]
+        let layout = {
+            use embassy_usb::driver::Endpoint;
+            descriptors::InterfaceLayout {
+                interface: iface_number.0,
+                protocol,
+                bulk_out: out.info().addr.into(),
+                bulk_in: inp.info().addr.into(),
+                interrupt_in: interrupt_in.as_ref().map(|ep| ep.info().addr.into()),
+                max_packet_size: mps,
+                interval_ms: INTERRUPT_INTERVAL_MS,
+            }
+        };
+
+        builder.handler(HANDLER.init(TmcControlHandler {
+            config,
+            caps: config.capabilities(),
+            iface: iface_number,
+        }));
+
+        Self {
+            transport: EndpointTransport::new(out, inp),
+            interrupt_in,
+            config,
+            #[cfg(feature = "descriptor-dump")]
+            layout,
+        }
+    }
+
+    /// Logs the descriptors and capabilities the host will see, see [`descriptors`]. `usb` is
+    /// the device configuration the builder was created with.
+    #[cfg(feature = "descriptor-dump")]
+    pub fn dump_descriptors(&self, usb: &embassy_usb::Config) {
+        descriptors::dump(usb, &self.layout, &self.config.capabilities());
+    }
+
+    /// Takes the Interrupt-IN endpoint allocated for [`TmcConfig::interrupt_in`], e.g. for
+    /// [`srq::run`] to send USB488 notifications from another task. `None` if it wasn't
+    /// requested or was already taken.
+    pub fn take_interrupt_in(&mut self) -> Option<D::EndpointIn> {
+        self.interrupt_in.take()
+    }
+
+    /// A handle for changing timeouts and policies while the class runs, see [`tuning`].
+    pub fn control(&self) -> Control {
+        Control::new()
+    }
+
+    /// Runs the class. Never returns; spawn it in its own task next to `UsbDevice::run`.
+    ///
+    /// The transfer buffers are part of the task's future, wherever the executor keeps it.
+    /// Use [`run_with_buffers`](Self::run_with_buffers) to put them somewhere else.
+    pub async fn run(mut self) -> ! {
+        let mut staging = [0u8; IN_STAGING];
+        message_loop(&mut self.transport, &self.config, &mut staging).await
+    }
+
+    /// Runs the class with its transfer buffers in `buffers`, e.g. a static in DMA-capable
+    /// RAM. Panics if they don't meet [`TmcConfig::dma_alignment`].
+    pub async fn run_with_buffers(
+        mut self,
+        buffers: &'static mut TransferBuffers<IN_STAGING>,
+    ) -> ! {
+        let TransferBuffers { packet, staging } = buffers;
+        message_loop_with_buffers(&mut self.transport, &self.config, packet, staging).await
+    }
+}
+
+/// The USBTMC message layer: parses Bulk-OUT headers, reassembles commands and frames
+/// responses, over any [`TmcTransport`].
+///
+/// [`UsbTmc::run`] calls this with its endpoint pair; other links can drive it directly.
+/// DEV_DEP_MSG_IN transfers are framed in `in_staging`, see [`UsbTmc`]; one shorter than a
+/// header and four bytes of data leaves REQUEST_DEV_DEP_MSG_IN unanswered.
+pub async fn message_loop<T: TmcTransport>(
+    transport: &mut T,
+    config: &TmcConfig,
+    in_staging: &mut [u8],
+) -> ! {
+    let mut packet = [0u8; MAX_PACKET_SIZE];
+    message_loop_with_buffers(transport, config, &mut packet, in_staging).await
+}
+
+/// [`message_loop`] with the Bulk-OUT packet buffer supplied too, so that every buffer the
+/// transport sees is the caller's. Panics if `packet` or `in_staging` doesn't meet
+/// [`TmcConfig::dma_alignment`].
+pub async fn message_loop_with_buffers<T: TmcTransport>(
+    transport: &mut T,
+    config: &TmcConfig,
+    packet: &mut [u8; MAX_PACKET_SIZE],
+    in_staging: &mut [u8],
+) -> ! {
+    if let Some(align) = config.dma_alignment {
+        assert!(
+            align.is_power_of_two(),
+            "dma_alignment must be a power of two"
+        );
+        assert!(
+            packet.as_ptr() as usize % align == 0 && in_staging.as_ptr() as usize % align == 0,
+            "transfer buffers not aligned to dma_alignment"
+        );
+    }
+    let resp_rx = RESP_CHANNEL.receiver();
+    let mps = transport.max_packet_size().min(MAX_PACKET_SIZE);
+    PACKET_SIZE.store(mps as u16, Ordering::Relaxed);
+    let caps = config.capabilities();
+    // Part of a response the host's last read had no room for, and where it continues.
+    let mut remainder: Option<(Response, usize)> = None;
+    // Token for the next query, and of the oldest query still waiting for its answer.
+    let mut next_token = ResponseToken(0);
+    let mut expected = next_token;
+    // Tagged responses that arrived ahead of the answer to an earlier query, in arrival order.
+    let mut parked: Vec<Response, TRACKED_QUERIES> = Vec::new();
+    // When each open query arrived, for `response_ttl`, and the bTag it came in.
+    let mut queries = [(Instant::MIN, 0u8); TRACKED_QUERIES];
+    // Open queries whose command `OverflowPolicy::DropOldest` dropped from the queue.
+    let mut abandoned: Vec<ResponseToken, TRACKED_QUERIES> = Vec::new();
+    let mut assembler = LineAssembler::new();
+    // The configuration with the changes made through `Control`.
+    let mut tuned = *config;
+
+    'messages: loop {
+        tuning::apply(&mut tuned);
+        let config = &tuned;
+        let buf = &mut packet[..mps];
+
+        service_control(config);
+        if DROP_REMAINDER.swap(false, Ordering::Relaxed) {
+            drop_open_queries(&mut remainder, &mut parked, &mut expected, next_token);
+        }
+        skip_abandoned(&mut abandoned, &mut expected);
+        serviced(wait_unhalted(transport, Pipe::BulkOut, &HALT_OUT), config).await;
+        ABORT_OUT_SIGNAL.reset();
+
+        let expiry = expire_responses(
+            config,
+            &resp_rx,
+            &queries,
+            &mut expected,
+            next_token,
+            &mut remainder,
+            &mut parked,
+        );
+        let read = select(
+            transport.read(buf),
+            select(ABORT_OUT_SIGNAL.wait(), clock::at(expiry)),
+        );
+        let n = match serviced(read, config).await {
+            Either::First(Ok(n)) => {
+                note_host_activity();
+                n
+            }
+            Either::First(Err(TransportError::Disabled)) => {
+                // Unplugged, detached or not configured yet.
+                serviced(transport.wait_enabled(), config).await;
+                continue;
+            }
+            Either::First(Err(_)) => {
+                note_transfer_failure();
+                continue;
+            }
+            Either::Second(_) => continue,
+        };
+        let strict = config.conformance == Conformance::Strict;
+        let header = match BulkOutHeader::parse(&buf[..n]) {
+            Ok(header) if header.b_tag != 0 || !strict => header,
+            Err(HeaderError::TooShort) => continue,
+            Ok(_) | Err(HeaderError::BadTag) => {
+                record_protocol_error(ProtocolError::BadTag, buf[1]);
+                indicator::signal(IndicatorEvent::Error);
+                if strict {
+                    halt_bulk_out();
+                }
+                continue;
+            }
+        };
+        let b_tag = header.b_tag;
+        let transfer_len = header.transfer_size as usize;
+
+        let mut discard = false;
+        if !header.reserved_fields_clear() {
+            RESERVED_VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+            record_protocol_error(ProtocolError::ReservedFields, header.b_tag);
+            indicator::signal(IndicatorEvent::Error);
+            discard = strict;
+        }
+        session::note_message();
+
+        match header.msg_id {
+            MsgId::DevDepMsgOut => {
+                #[cfg(feature = "usb488")]
+                usb488::addressed();
+
+                // Payload bytes land in the command that is queued, so apart from the packet
+                // holding the header (and a tail too short for a whole packet) each byte is
+                // written once by the endpoint and once into the channel.
+                let mut cmd = Command {
+                    len: 0,
+                    data: [0; MAX_SCPI_LEN],
+                    token: None,
+                    query: false,
+                    received: Instant::MIN,
+                };
+                let received = receive_payload(transport, config, buf, n, &header, &mut cmd.data);
+                let Some(copied) = received.await else {
+                    // The host gave up on the message, including what was assembled of it.
+                    assembler.reset();
+                    continue;
+                };
+                if discard {
+                    continue;
+                }
+                cmd.len = copied;
+                if COMMAND_CRC.load(Ordering::Relaxed) {
+                    // Each transfer carries its own trailer, so a corrupt piece of a long
+                    // command is caught before it is assembled.
+                    let Some(len) = vendor::check_crc(&cmd.data[..copied]) else {
+                        CRC_ERRORS.fetch_add(1, Ordering::Relaxed);
+                        record_protocol_error(ProtocolError::Crc, b_tag);
+                        indicator::signal(IndicatorEvent::Error);
+                        #[cfg(feature = "scpi")]
+                        push_error(SCPI_ERR_PARITY);
+                        continue;
+                    };
+                    cmd.len = len;
+                }
+                indicator::signal(IndicatorEvent::Activity);
+
+                if config.assemble_lines {
+                    let eom = header.attributes() & DEV_DEP_MSG_OUT_EOM != 0;
+                    let piece = &cmd.data[..cmd.len];
+                    let ends = config.termination.ends(piece, eom);
+                    let Some(line) = assembler.push(piece, ends, b_tag) else {
+                        continue;
+                    };
+                    cmd = line;
+                }
+                cmd.len = strip_terminator(&cmd.data[..cmd.len]);
+                cmd.received = clock::now();
+                let (token, received) = (next_token, cmd.received);
+                deliver_command(cmd, config, &mut next_token, &mut abandoned);
+                if next_token != token {
+                    queries[token.0 as usize % TRACKED_QUERIES] = (received, b_tag);
+                }
+            }
+
+            MsgId::Trigger => {
+                if discard || !caps.usb488(Usb488InterfaceCapabilities::TRIGGER) {
+                    continue;
+                }
+                #[cfg(feature = "usb488")]
+                usb488::trigger(config);
+            }
+
+            MsgId::RequestDevDepMsgIn => {
+                if discard || config.listen_only || in_staging.len() < MIN_IN_STAGING_LEN {
+                    continue;
+                }
+                // Header, data and padding must fit the staging buffer; HEADER_LEN keeps the
+                // 4-byte alignment.
+                let staged = (in_staging.len() - HEADER_LEN) & !3;
+                let max_resp = transfer_len.min(in_chunk_len(mps)).min(staged);
+                serviced(wait_unhalted(transport, Pipe::BulkIn, &HALT_IN), config).await;
+                ABORT_IN.begin(b_tag);
+                #[cfg(feature = "instrument")]
+                usage::note_read(RESP_CHANNEL.len());
+                if DROP_REMAINDER.swap(false, Ordering::Relaxed) {
+                    drop_open_queries(&mut remainder, &mut parked, &mut expected, next_token);
+                }
+                skip_abandoned(&mut abandoned, &mut expected);
+                expire_responses(
+                    config,
+                    &resp_rx,
+                    &queries,
+                    &mut expected,
+                    next_token,
+                    &mut remainder,
+                    &mut parked,
+                );
+                // Set when the read is answered with an empty message in place of the answer.
+                let mut stand_in = false;
+                let (resp, offset) = match remainder.take() {
+                    Some(partial) => partial,
+                    None => {
+                        CLEAR_SIGNAL.reset();
+                        let parked_ready = parked.iter().any(|p| p.token == Some(expected));
+                        if !parked_ready && RESP_CHANNEL.is_empty() {
+                            READ_REQUESTED.signal(ReadRequest {
+                                token: (expected != next_token).then_some(expected),
+                                max_len: transfer_len as u32,
+                            });
+                        }
+                        let mut resp = loop {
+                            if let Some(i) = parked.iter().position(|p| p.token == Some(expected)) {
+                                break parked.remove(i);
+                            }
+                            let query_open = expected != next_token;
+                            let next = next_response(&resp_rx, config, query_open);
+                            match serviced(next, config).await {
+                                NextResponse::Ready(resp)
+                                    if config.strict_pairing
+                                        && !query_open
+                                        && resp.token.is_none() =>
+                                {
+                                    UNPAIRED_RESPONSES.fetch_add(1, Ordering::Relaxed);
+                                }
+                                NextResponse::Ready(resp) => {
+                                    let ahead = match resp.token {
+                                        Some(ResponseToken(token)) => {
+                                            token.wrapping_sub(expected.0) as i32
+                                        }
+                                        None => 0,
+                                    };
+                                    if ahead == 0 {
+                                        break resp;
+                                    } else if ahead > 0 && !parked.is_full() {
+                                        let _ = parked.push(resp);
+                                    } else {
+                                        STALE_RESPONSES.fetch_add(1, Ordering::Relaxed);
+                                    }
+                                }
+                                NextResponse::Cleared => {
+                                    finish_in_transfer();
+                                    continue 'messages;
+                                }
+                                NextResponse::Missing => {
+                                    record_protocol_error(ProtocolError::EmptyRead, b_tag);
+                                    indicator::signal(IndicatorEvent::Error);
+                                    if config.empty_read == EmptyReadPolicy::Halt {
+                                        #[cfg(feature = "ieee4882")]
+                                        raise_event_status(ESR_QYE);
+                                        halt_bulk_in();
+                                        finish_in_transfer();
+                                        continue 'messages;
+                                    }
+                                    #[cfg(feature = "ieee4882")]
+                                    raise_event_status(ESR_QYE);
+                                    stand_in = true;
+                                    break Response {
+                                        len: 0,
+                                        data: [0; MAX_SCPI_LEN],
+                                        segments: Vec::new(),
+                                        eom: true,
+                                        term_char_matched: false,
+                                        raw: false,
+                                        token: None,
+                                    };
+                                }
+                            }
+                        };
+                        if resp.eom && !resp.raw {
+                            resp.terminate(config.response_terminator);
+                        }
+                        (resp, 0)
+                    }
+                };
+                let len = resp.body_len();
+                let send_len = (len - offset).min(max_resp);
+                // A host read shorter than the response gets EOM=0 and the rest next time.
+                let last = offset + send_len == len;
+
+                let framed = frame_in_transfer(in_staging, b_tag, &resp, offset, send_len, last);
+                #[cfg(feature = "instrument")]
+                usage::note_response(len, framed);
+                let answered = last && resp.eom && expected != next_token;
+                if !last {
+                    remainder = Some((resp, offset + send_len));
+                } else if answered {
+                    // The oldest open query has its answer.
+                    expected.0 = expected.0.wrapping_add(1);
+                }
+
+                let sent =
+                    send_in_transfer(transport, config, &in_staging[..framed], send_len).await;
+                if answered {
+                    settle(1, stand_in || !sent);
+                }
+            }
+
+            MsgId::VendorSpecificOut => {
+                let mut msg = Command {
+                    len: 0,
+                    data: [0; MAX_SCPI_LEN],
+                    token: None,
+                    query: false,
+                    received: Instant::MIN,
+                };
+                let received = receive_payload(transport, config, buf, n, &header, &mut msg.data);
+                let Some(copied) = received.await else {
+                    continue;
+                };
+                if discard {
+                    continue;
+                }
+                msg.len = if config.vendor_crc {
+                    match vendor::check_crc(&msg.data[..copied]) {
+                        Some(len) => len,
+                        None => {
+                            CRC_ERRORS.fetch_add(1, Ordering::Relaxed);
+                            record_protocol_error(ProtocolError::Crc, b_tag);
+                            indicator::signal(IndicatorEvent::Error);
+                            continue;
+                        }
+                    }
+                } else {
+                    copied
+                };
+                indicator::signal(IndicatorEvent::Activity);
+                if config.vendor_upload {
+                    if upload::accept(&msg.data[..msg.len]) == upload::Accepted::Invalid {
+                        record_protocol_error(ProtocolError::Upload, b_tag);
+                        indicator::signal(IndicatorEvent::Error);
+                    }
+                    continue;
+                }
+                msg.received = clock::now();
+                let _ = vendor::VENDOR_OUT_CHANNEL.try_send(msg);
+            }
+
+            MsgId::RequestVendorSpecificIn => {
+                if discard || config.listen_only || in_staging.len() < MIN_IN_STAGING_LEN {
+                    continue;
+                }
+                let max_len = transfer_len.min((in_staging.len() - HEADER_LEN) & !3);
+                if config.vendor_crc && max_len < vendor::CRC_LEN {
+                    // No room for the trailer: a message without it would pass for unchecked.
+                    CRC_ERRORS.fetch_add(1, Ordering::Relaxed);
+                    record_protocol_error(ProtocolError::Crc, b_tag);
+                    indicator::signal(IndicatorEvent::Error);
+                    continue;
+                }
+                serviced(wait_unhalted(transport, Pipe::BulkIn, &HALT_IN), config).await;
+                ABORT_IN.begin(b_tag);
+                let resp = if config.vendor_upload {
+                    let report = upload::progress_report();
+                    let mut resp = Response {
+                        len: report.len(),
+                        data: [0; MAX_SCPI_LEN],
+                        segments: Vec::new(),
+                        eom: true,
+                        term_char_matched: false,
+                        raw: true,
+                        token: None,
+                    };
+                    resp.data[..report.len()].copy_from_slice(&report);
+                    resp
+                } else {
+                    CLEAR_SIGNAL.reset();
+                    let next = select(vendor::VENDOR_IN_CHANNEL.receive(), CLEAR_SIGNAL.wait());
+                    match serviced(next, config).await {
+                        Either::First(resp) => resp,
+                        Either::Second(()) => {
+                            finish_in_transfer();
+                            continue;
+                        }
+                    }
+                };
+                let (framed, len) =
+                    frame_vendor_in(in_staging, b_tag, &resp, max_len, config.vendor_crc);
+                send_in_transfer(transport, config, &in_staging[..framed], len).await;
+            }
+            MsgId::SubclassReserved(_) | MsgId::VisaReserved(_) | MsgId::Reserved(_) => {
+                if strict {
+                    halt_bulk_out();
+                }
+            }
+            // Bulk-IN ids; `from_out` never produces them.
+            MsgId::DevDepMsgIn | MsgId::VendorSpecificIn => {}
+        }
+    }
+}
+
+/// Ends the Bulk-IN transfer the runner was answering, completing an abort the host started
+/// on it.
+fn finish_in_transfer() {
+    ABORT_IN.finish();
+}
+
+/// Reads the payload of the Bulk-OUT transfer that `header`, the first `n` bytes of `buf`,
+/// starts into `data`, as far as it fits, and returns the number of bytes kept.
+///
+/// `None` if the transfer is refused as too long, aborted, dropped by a clear or ends in a
+/// halt; the host considers such a message gone.
+async fn receive_payload<T: TmcTransport>(
+    transport: &mut T,
+    config: &TmcConfig,
+    buf: &mut [u8],
+    n: usize,
+    header: &BulkOutHeader,
+    data: &mut [u8; MAX_SCPI_LEN],
+) -> Option<usize> {
+    let b_tag = header.b_tag;
+    let transfer_len = header.transfer_size as usize;
+    let quirks = host_quirks();
+    let mps = buf.len();
+
+    if transfer_len > config.max_transfer_size as usize {
+        // Leave the bTag current so the host's INITIATE_ABORT_BULK_OUT matches it.
+        ABORT_OUT.begin(b_tag);
+        #[cfg(feature = "scpi")]
+        push_error(SCPI_ERR_TOO_MUCH_DATA);
+        record_protocol_error(ProtocolError::Overflow, b_tag);
+        indicator::signal(IndicatorEvent::Error);
+        halt_bulk_out();
+        return None;
+    }
+
+    let bytes_to_consume = if quirks.unpadded_out {
+        transfer_len
+    } else {
+        transfer_len + padding(transfer_len)
+    };
+
+    ABORT_OUT.begin(b_tag);
+    DROP_TRANSFER.store(false, Ordering::Relaxed);
+    set_transfer_state(&BULK_OUT_STATE, TransferState::InProgress);
+
+    let mut copied = 0usize;
+
+    let first_payload = (n - HEADER_LEN).min(transfer_len);
+    if first_payload > 0 {
+        let to_copy = first_payload.min(MAX_SCPI_LEN);
+        data[0..to_copy].copy_from_slice(&buf[HEADER_LEN..HEADER_LEN + to_copy]);
+        copied = to_copy;
+    }
+    let mut received = first_payload;
+    ABORT_OUT.set_bytes(received as u32);
+
+    let mut remaining = bytes_to_consume.saturating_sub(n - HEADER_LEN);
+    // A short packet ends the transfer, even if the header promised more.
+    let mut short = n < mps;
+    while remaining > 0 && !short {
+        // Read straight into `data` while a whole packet fits, unless the driver must only
+        // see the caller's DMA buffers.
+        let direct = config.dma_alignment.is_none() && MAX_SCPI_LEN - copied >= mps;
+        let target = if direct {
+            &mut data[copied..copied + mps]
+        } else {
+            &mut *buf
+        };
+        // The host may abort mid-payload and stop sending, so don't block on the endpoint alone.
+        let read = select(transport.read(target), ABORT_OUT_SIGNAL.wait());
+        let read_n = match serviced(read, config).await {
+            Either::First(Ok(r)) => {
+                note_host_activity();
+                r
+            }
+            Either::First(Err(_)) => {
+                note_transfer_failure();
+                break;
+            }
+            Either::Second(()) => break,
+        };
+        let take = read_n.min(remaining);
+        let message_bytes = take.min(transfer_len - received);
+
+        let to_copy = message_bytes.min(MAX_SCPI_LEN - copied);
+        if !direct {
+            data[copied..copied + to_copy].copy_from_slice(&buf[0..to_copy]);
+        }
+        copied += to_copy;
+
+        received += message_bytes;
+        ABORT_OUT.set_bytes(received as u32);
+        remaining -= take;
+        short = read_n < mps;
+    }
+
+    // Whether we stopped early or the last packet raced the abort request, the host considers
+    // this message aborted: drop it and wait for the next header. Idle goes out first, so a
+    // request that still finds the bTag is either completed here or refused.
+    set_transfer_state(&BULK_OUT_STATE, TransferState::Idle);
+    let aborted = ABORT_OUT.finish();
+    if aborted && quirks.abort_without_check {
+        clear_halt_out();
+    }
+    let dropped = DROP_TRANSFER.swap(false, Ordering::Relaxed);
+    if aborted || dropped || HALT_OUT.load(Ordering::Relaxed) {
+        return None;
+    }
+    Some(copied)
+}
+
+/// Joins DEV_DEP_MSG_OUT transfers into commands for [`TmcConfig::assemble_lines`].
+struct LineAssembler {
+    line: Command,
+    /// Taken when the line was started; a device clear since then discards it.
+    started: AbortToken,
+    /// The line outgrew the buffer; drop transfers until one ends it.
+    overflow: bool,
+}
+
+impl LineAssembler {
+    fn new() -> Self {
+        Self {
+            line: Command {
+                len: 0,
+                data: [0; MAX_SCPI_LEN],
+                token: None,
+                query: false,
+                received: Instant::MIN,
+            },
+            started: AbortToken::new(),
+            overflow: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.line.len = 0;
+        self.overflow = false;
+    }
+
+    /// Adds one transfer's data. Returns the whole command once a transfer `ends` it.
+    fn push(&mut self, data: &[u8], ends: bool, b_tag: u8) -> Option<Command> {
+        if self.started.is_aborted() {
+            self.reset();
+        }
+        if self.line.len == 0 && !self.overflow {
+            self.started = AbortToken::new();
+        }
+        let end = self.line.len + data.len();
+        if !self.overflow && end > MAX_SCPI_LEN {
+            #[cfg(feature = "scpi")]
+            push_error(SCPI_ERR_TOO_MUCH_DATA);
+            record_protocol_error(ProtocolError::Overflow, b_tag);
+            indicator::signal(IndicatorEvent::Error);
+            self.overflow = true;
+        }
+        if self.overflow {
+            if ends {
+                self.reset();
+            }
+            return None;
+        }
+        self.line.data[self.line.len..end].copy_from_slice(data);
+        self.line.len = end;
+        if !ends {
+            return None;
+        }
+        let line = self.line.clone();
+        self.reset();
+        Some(line)
+    }
+}
+
+/// Sends a framed Bulk-IN transfer carrying `message_len` bytes of message data and ends the
+/// transfer the runner was answering.
+async fn send_in_transfer<T: TmcTransport>(
+    transport: &mut T,
+    config: &TmcConfig,
+    data: &[u8],
+    message_len: usize,
+) -> bool {
+    set_transfer_state(&BULK_IN_STATE, TransferState::InProgress);
+    let zlp = !host_quirks().no_zlp;
+    let pacing = in_pacing();
+    let sent = match serviced(write_transfer_paced(transport, data, zlp, pacing), config).await {
+        Ok(()) => {
+            note_host_activity();
+            true
+        }
+        Err(_) => {
+            note_transfer_failure();
+            false
+        }
+    };
+    ABORT_IN.set_bytes(message_len as u32);
+    indicator::signal(IndicatorEvent::Activity);
+    set_transfer_state(&BULK_IN_STATE, TransferState::Idle);
+    finish_in_transfer();
+    sent
+}
+
+/// Forgets the queries a device clear or bus reset left open: they will never be answered.
+fn drop_open_queries(
+    remainder: &mut Option<(Response, usize)>,
+    parked: &mut Vec<Response, TRACKED_QUERIES>,
+    expected: &mut ResponseToken,
+    next_token: ResponseToken,
+) {
+    *remainder = None;
+    parked.clear();
+    settle(next_token.0.wrapping_sub(expected.0), true);
+    *expected = next_token;
+}
+
+// The runner is generic over the transport; the helpers below don't depend on it and are
+// kept out of line so each transport adds only the I/O glue to the image.
+
+/// Hands a received DEV_DEP_MSG_OUT to the inline handler or the command queues, tagging
+/// queries with the next response token. `cmd` is moved into the queue as assembled.
+///
+/// The token of a query dropped from the queue to make room goes to `abandoned`.
+#[inline(never)]
+fn deliver_command(
+    mut cmd: Command,
+    config: &TmcConfig,
+    next_token: &mut ResponseToken,
+    abandoned: &mut Vec<ResponseToken, TRACKED_QUERIES>,
+) {
+    let message = &cmd.data[..cmd.len];
+    // Refused commands get no token, so a read after a refused query isn't left waiting.
+    #[cfg(feature = "scpi")]
+    let lock_answer = match lock::command(Session::Usb, message) {
+        LockReply::Pass => None,
+        LockReply::Refused => {
+            push_error(SCPI_ERR_COMMAND_PROTECTED);
+            return;
+        }
+        LockReply::Done => return,
+        LockReply::Answer(answer) => Some(answer),
+    };
+    #[cfg(feature = "scpi")]
+    if let Err(code) = arming::check(message) {
+        push_error(code);
+        return;
+    }
+    let query = is_query(message);
+    // The token is only taken once the command or its answer is queued: a query lost to a
+    // full queue would otherwise leave the runner waiting for an answer that never comes.
+    let token = query.then_some(*next_token);
+    #[cfg(feature = "scpi")]
+    if let Some(answer) = lock_answer {
+        if queue_answer(message, Response::from_static(answer, token)) {
+            issue(next_token, token);
+        }
+        return;
+    }
+    // Under `scpi::echo` a query goes nowhere without room for its echo: once it is queued or
+    // deferred its answer will come, and the host must not read it without the echo.
+    #[cfg(feature = "scpi")]
+    if token.is_some() && scpi::echo() && RESP_CHANNEL.is_full() {
+        note_dropped_command();
+        return;
+    }
+
+    if let Some(handler) = config.inline_handler {
+        let mut resp = Response {
+            len: 0,
+            data: [0; MAX_SCPI_LEN],
+            segments: Vec::new(),
+            eom: true,
+            term_char_matched: false,
+            raw: false,
+            token,
+        };
+        match handler(message, &mut resp) {
+            Inline::Pass => {}
+            Inline::Done => {
+                // A query's reply goes out even when empty, or the host's read would wait
+                // for it forever.
+                if (resp.body_len() > 0 || token.is_some()) && queue_answer(message, resp) {
+                    issue(next_token, token);
+                }
+                return;
+            }
+            Inline::Deferred => {
+                #[cfg(feature = "scpi")]
+                if token.is_some() && scpi::echo() {
+                    echo_query(message, token);
+                }
+                defer_response();
+                issue(next_token, token);
+                return;
+            }
+        }
+    }
+
+    let immediate = config
+        .immediate
+        .is_some_and(|is_immediate| is_immediate(message));
+    cmd.token = token;
+    cmd.query = query;
+    // The echo goes out after the command is queued, so only with a token that was taken, into
+    // the room checked for above.
+    #[cfg(feature = "scpi")]
+    let echo = (token.is_some() && scpi::echo()).then(|| cmd.clone());
+    #[cfg(feature = "instrument")]
+    let len = cmd.len;
+    // With no room left to remember another dropped query, drop the new command instead.
+    let overflow = if abandoned.is_full() {
+        OverflowPolicy::DropNewest
+    } else {
+        config.overflow
+    };
+    let (queued, evicted) = if immediate {
+        enqueue(&PRIORITY_CHANNEL, cmd, overflow)
+    } else {
+        enqueue(&CMD_CHANNEL, cmd, overflow)
+    };
+    #[cfg(feature = "instrument")]
+    usage::note_command(len, CMD_CHANNEL.len(), PRIORITY_CHANNEL.len());
+    if let Some(evicted) = evicted {
+        if let Some(token) = evicted.token {
+            let _ = abandoned.push(token);
+        }
+        note_dropped_command();
+    }
+    if queued {
+        issue(next_token, token);
+        #[cfg(feature = "scpi")]
+        if let Some(echo) = echo {
+            echo_query(&echo.data[..echo.len], token);
+        }
+    } else {
+        note_dropped_command();
+    }
+    update_queue_level(config);
+}
+
+fn note_dropped_command() {
+    DROPPED_COMMANDS.fetch_add(1, Ordering::Relaxed);
+    #[cfg(feature = "scpi")]
+    push_error(SCPI_ERR_INPUT_OVERRUN);
+}
+
+/// Moves `expected` past the queries in `abandoned`: their commands were dropped and they
+/// will never be answered. Forgets the ones already behind it, e.g. after a device clear.
+fn skip_abandoned(
+    abandoned: &mut Vec<ResponseToken, TRACKED_QUERIES>,
+    expected: &mut ResponseToken,
+) {
+    while let Some(i) = abandoned.iter().position(|token| token == expected) {
+        abandoned.swap_remove(i);
+        expected.0 = expected.0.wrapping_add(1);
+        settle(1, true);
+    }
+    abandoned.retain(|token| token.0.wrapping_sub(expected.0) as i32 >= 0);
+}
+
+/// Takes `token`, if there is one, as the next query's.
+fn issue(next_token: &mut ResponseToken, token: Option<ResponseToken>) {
+    if token.is_some() {
+        next_token.0 = next_token.0.wrapping_add(1);
+    }
+}
+
+/// Queues an answer made in the runner, behind the echo of its query under [`scpi::echo`]:
+/// both or neither, so a query's answer is never left half queued. Returns whether it was.
+#[cfg_attr(not(feature = "scpi"), allow(unused_variables))]
+fn queue_answer(message: &[u8], resp: Response) -> bool {
+    #[cfg(feature = "scpi")]
+    if resp.token.is_some() && scpi::echo() {
+        if RESP_CHANNEL.free_capacity() < 2 {
+            return false;
+        }
+        echo_query(message, resp.token);
+    }
+    RESP_CHANNEL.try_send(resp).is_ok()
+}
+
+/// Queues `cmd`, dropping a command per `overflow` if the queue is full. Returns whether
+/// `cmd` was queued, and the older command dropped to make room for it. Both can be lost if
+/// another link refills the queue in between.
+fn enqueue<const N: usize>(
+    queue: &Channel<CriticalSectionRawMutex, Command, N>,
+    cmd: Command,
+    overflow: OverflowPolicy,
+) -> (bool, Option<Command>) {
+    match queue.try_send(cmd) {
+        Ok(()) => (true, None),
+        Err(TrySendError::Full(cmd)) => {
+            if overflow != OverflowPolicy::DropOldest {
+                return (false, None);
+            }
+            let evicted = queue.try_receive().ok();
+            (queue.try_send(cmd).is_ok(), evicted)
+        }
+    }
+}
+
+/// Queues the first piece of a query's answer under [`scpi::echo`]: the query itself and
+/// ` -> `, without EOM, so the host reads it and the answer as one message.
+#[cfg(feature = "scpi")]
+fn echo_query(message: &[u8], token: Option<ResponseToken>) {
+    let message = message.trim_ascii();
+    let mut resp = Response {
+        len: 0,
+        data: [0; MAX_SCPI_LEN],
+        segments: Vec::new(),
+        eom: false,
+        term_char_matched: false,
+        raw: false,
+        token,
+    };
+    let len = message.len().min(MAX_SCPI_LEN - 4);
+    resp.data[..len].copy_from_slice(&message[..len]);
+    resp.data[len..len + 4].copy_from_slice(b" -> ");
+    resp.len = len + 4;
+    let _ = RESP_CHANNEL.try_send(resp);
+}
+
+/// Writes a DEV_DEP_MSG_IN carrying `send_len` bytes of `resp` from `offset` into `out_buf`:
+/// header, data and alignment padding. Returns the transfer length.
+#[inline(never)]
+fn frame_in_transfer(
+    out_buf: &mut [u8],
+    b_tag: u8,
+    resp: &Response,
+    offset: usize,
+    send_len: usize,
+    last: bool,
+) -> usize {
+    let in_header = BulkInHeader::dev_dep_msg_in(
+        b_tag,
+        send_len as u32,
+        last && resp.eom,
+        last && resp.term_char_matched,
+    );
+    let total = HEADER_LEN + send_len;
+    out_buf[..HEADER_LEN].copy_from_slice(&in_header.encode());
+    resp.copy_body(offset, &mut out_buf[HEADER_LEN..total]);
+    out_buf[total..total + padding(send_len)].fill(0);
+    total + padding(send_len)
+}
+
+/// Writes a VENDOR_SPECIFIC_IN carrying the body of `resp` into `out_buf`, cut to `max_len`
+/// bytes including the CRC-32 trailer if `crc` is set, which `max_len` must have room for.
+/// Vendor messages have no EOM, so whatever doesn't fit is lost. Returns the transfer and
+/// message lengths.
+#[inline(never)]
+fn frame_vendor_in(
+    out_buf: &mut [u8],
+    b_tag: u8,
+    resp: &Response,
+    max_len: usize,
+    crc: bool,
+) -> (usize, usize) {
+    let trailer = if crc { vendor::CRC_LEN } else { 0 };
+    let body = HEADER_LEN..HEADER_LEN + max_len - trailer;
+    let mut len = resp.copy_body(0, &mut out_buf[body]);
+    if trailer > 0 {
+        let crc = vendor::crc32(&out_buf[HEADER_LEN..HEADER_LEN + len]);
+        out_buf[HEADER_LEN + len..HEADER_LEN + len + trailer].copy_from_slice(&crc.to_le_bytes());
+        len += trailer;
+    }
+    let in_header = BulkInHeader {
+        msg_id: MsgId::VendorSpecificIn,
+        b_tag,
+        transfer_size: len as u32,
+        attributes: 0,
+    };
+    out_buf[..HEADER_LEN].copy_from_slice(&in_header.encode());
+    let total = HEADER_LEN + len;
+    out_buf[total..total + padding(len)].fill(0);
+    (total + padding(len), len)
+}
+
+/// Open queries whose arrival times the runner keeps for [`TmcConfig::response_ttl`]. With
+/// more open at once, the oldest are aged from a later query's arrival.
+const TRACKED_QUERIES: usize = 8;
+
+/// Drops the answers to open queries older than [`TmcConfig::response_ttl`], oldest first,
+/// wherever they are: half sent, parked or queued. Returns when the oldest query left
+/// expires, [`Instant::MAX`] if none will.
+fn expire_responses(
+    config: &TmcConfig,
+    resp_rx: &Receiver<'static, CriticalSectionRawMutex, Response, 4>,
+    queries: &[(Instant, u8); TRACKED_QUERIES],
+    expected: &mut ResponseToken,
+    next_token: ResponseToken,
+    remainder: &mut Option<(Response, usize)>,
+    parked: &mut Vec<Response, TRACKED_QUERIES>,
+) -> Instant {
+    let Some(ttl) = config.response_ttl else {
+        return Instant::MAX;
+    };
+    while *expected != next_token {
+        let (received, b_tag) = queries[expected.0 as usize % TRACKED_QUERIES];
+        let deadline = received + ttl;
+        if clock::now() < deadline {
+            return deadline;
+        }
+        let token = Some(*expected);
+        remainder.take_if(|(resp, _)| resp.token == token);
+        parked.retain(|resp| resp.token != token);
+        while resp_rx.try_peek().is_ok_and(|resp| resp.token == token) {
+            let _ = resp_rx.try_receive();
+        }
+        EXPIRED_RESPONSES.fetch_add(1, Ordering::Relaxed);
+        record_protocol_error(ProtocolError::Expired, b_tag);
+        #[cfg(feature = "ieee4882")]
+        raise_event_status(ESR_QYE);
+        expected.0 = expected.0.wrapping_add(1);
+        settle(1, true);
+    }
+    Instant::MAX
+}
+
+enum NextResponse {
+    Ready(Response),
+    /// A device clear interrupted the wait.
+    Cleared,
+    /// Nothing queued and, per the config, nothing worth waiting for.
+    Missing,
+}
+
+async fn next_response(
+    resp_rx: &Receiver<'static, CriticalSectionRawMutex, Response, 4>,
+    config: &TmcConfig,
+    query_open: bool,
+) -> NextResponse {
+    if let Ok(resp) = resp_rx.try_receive() {
+        return NextResponse::Ready(resp);
+    }
+    let deferred = DEFERRED_RESPONSES.load(Ordering::Relaxed) > 0;
+    let owed = if config.strict_pairing {
+        query_open || deferred
+    } else {
+        config.empty_read == EmptyReadPolicy::Wait || deferred
+    };
+    if !owed {
+        return NextResponse::Missing;
+    }
+
+    let wait = select(resp_rx.receive(), CLEAR_SIGNAL.wait());
+    let waited = match config.response_timeout {
+        Some(timeout) => clock::with_timeout(timeout, wait).await.ok(),
+        None => Some(wait.await),
+    };
+    match waited {
+        Some(Either::First(resp)) => NextResponse::Ready(resp),
+        Some(Either::Second(())) => NextResponse::Cleared,
+        None => {
+            settle_deferred_response();
+            NextResponse::Missing
+        }
+    }
+}
+
+/// Whether the message holds a query: a `?` outside string data and arbitrary blocks, per
+/// IEEE 488.2. `CAT "why?"` and `DATA #15a?b?c` aren't queries; `SET 1;VAL?` is.
+pub fn is_query(data: &[u8]) -> bool {
+    let mut i = 0;
+    while i < data.len() {
+        match data[i] {
+            b'?' => return true,
+            quote @ (b'"' | b'\'') => {
+                // A doubled quote closes and reopens the string, which comes to the same.
+                i += 1;
+                while i < data.len() && data[i] != quote {
+                    i += 1;
+                }
+            }
+            // The rest of the message is an indefinite-length block.
+            b'#' if data.get(i + 1) == Some(&b'0') => return false,
+            b'#' if data.get(i + 1).is_some_and(|d| (b'1'..=b'9').contains(d)) => {
+                let digits = usize::from(data[i + 1] - b'0');
+                let len = data.get(i + 2..i + 2 + digits).and_then(|text| {
+                    text.iter().try_fold(0usize, |n, &d| {
+                        d.is_ascii_digit()
+                            .then(|| n.checked_mul(10)?.checked_add(usize::from(d - b'0')))?
+                    })
+                });
+                if let Some(len) = len {
+                    i = i.saturating_add(2 + digits).saturating_add(len);
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    false
+}
@@ -0,0 +1,88 @@
+//! `SYSTem:LOCK`: one session at a time may take the instrument for itself, as a VISA
+//! exclusive lock does. While it holds the lock, commands from other sessions are refused
+//! with [`SCPI_ERR_COMMAND_PROTECTED`]; the lock queries themselves stay open to everyone so
+//! a second controller can see who is in charge.
+//!
+//! | Command | Does |
+//! |---|---|
+//! | `SYSTem:LOCK:REQuest?` | `1` if the lock is now this session's, `0` if another has it |
+//! | `SYSTem:LOCK:RELease` | Gives the lock up, if this session holds it |
+//! | `SYSTem:LOCK:OWNer?` | `NONE`, `USB` or `TCP` |
+//!
+//! A lock ends with its session: when the USB host goes away or the socket closes.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::scpi::{SCPI_ERR_COMMAND_PROTECTED, header_matches};
+
+/// A link commands arrive on.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Session {
+    Usb = 1,
+    Tcp = 2,
+}
+
+const NO_OWNER: u8 = 0;
+
+static OWNER: AtomicU8 = AtomicU8::new(NO_OWNER);
+
+/// The session holding the lock.
+pub fn lock_owner() -> Option<Session> {
+    match OWNER.load(Ordering::Relaxed) {
+        1 => Some(Session::Usb),
+        2 => Some(Session::Tcp),
+        _ => None,
+    }
+}
+
+/// What a link does with a command, decided by [`command`].
+pub(crate) enum LockReply {
+    /// Not a lock command, and the session may send it.
+    Pass,
+    /// Another session holds the lock; drop the command and report
+    /// [`SCPI_ERR_COMMAND_PROTECTED`].
+    Refused,
+    /// A lock command without an answer, already done.
+    Done,
+    /// A lock query and its answer.
+    Answer(&'static [u8]),
+}
+
+/// Handles the lock commands and checks everything else against the lock.
+pub(crate) fn command(session: Session, message: &[u8]) -> LockReply {
+    let header = message.trim_ascii();
+    if header_matches(header, b"SYSTem:LOCK:REQuest?") {
+        let taken = OWNER.compare_exchange(
+            NO_OWNER,
+            session as u8,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        let granted = taken.is_ok() || taken == Err(session as u8);
+        LockReply::Answer(if granted { b"1\n" } else { b"0\n" })
+    } else if header_matches(header, b"SYSTem:LOCK:RELease") {
+        release(session);
+        LockReply::Done
+    } else if header_matches(header, b"SYSTem:LOCK:OWNer?") {
+        LockReply::Answer(match lock_owner() {
+            None => b"NONE\n",
+            Some(Session::Usb) => b"USB\n",
+            Some(Session::Tcp) => b"TCP\n",
+        })
+    } else if lock_owner().is_some_and(|owner| owner != session) {
+        LockReply::Refused
+    } else {
+        LockReply::Pass
+    }
+}
+
+/// Drops the lock if `session` holds it.
+pub(crate) fn release(session: Session) {
+    let _ = OWNER.compare_exchange(
+        session as u8,
+        NO_OWNER,
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+    );
+}
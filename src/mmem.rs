@@ -0,0 +1,177 @@
+//! A subset of the SCPI `MMEMory` subsystem over an application [`FileStore`], so logged data
+//! can be fetched by name without the instrument implementing USB mass storage.
+//!
+//! | Command | Does |
+//! |---|---|
+//! | `MMEMory:CATalog?` | `<used>,<free>{,"<name>,BIN,<size>"}` |
+//! | `MMEMory:DATA "<name>",<block>` | Writes a file |
+//! | `MMEMory:DATA? "<name>"` | Streams a file back as a definite-length block |
+//! | `MMEMory:DELete "<name>"` | Deletes a file |
+//!
+//! File contents are streamed through a [`ResponseStream`], so a file can be far larger than
+//! one response.
+
+use core::fmt::Write;
+
+use heapless::String;
+
+use crate::params::{MAX_PREAMBLE_LEN, Params, Token, block_preamble, split_header};
+use crate::scpi::{
+    SCPI_ERR_DATA_TYPE, SCPI_ERR_FILE_NOT_FOUND, SCPI_ERR_MASS_STORAGE,
+    SCPI_ERR_PARAMETER_NOT_ALLOWED, header_matches,
+};
+use crate::{Command, ResponseStream};
+
+/// Longest file name taken from the host or listed in the catalog.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// The application's files: a flash file system, a ring of log records, a RAM disk. Errors
+/// are SCPI codes such as [`SCPI_ERR_FILE_NOT_FOUND`] or [`SCPI_ERR_MASS_STORAGE`].
+#[allow(async_fn_in_trait)]
+pub trait FileStore {
+    /// Bytes in use and bytes free.
+    async fn space(&mut self) -> Result<(u32, u32), i16>;
+
+    /// The `index`th file: its name, written into `name`, as a length, and its size. `None`
+    /// past the last file.
+    async fn entry(
+        &mut self,
+        index: usize,
+        name: &mut [u8; MAX_NAME_LEN],
+    ) -> Result<Option<(usize, u32)>, i16>;
+
+    async fn size(&mut self, name: &[u8]) -> Result<u32, i16>;
+
+    /// Reads from `offset` into `out`, returning the number of bytes read.
+    async fn read(&mut self, name: &[u8], offset: u32, out: &mut [u8]) -> Result<usize, i16>;
+
+    /// Creates or replaces a file.
+    async fn write(&mut self, name: &[u8], data: &[u8]) -> Result<(), i16>;
+
+    async fn delete(&mut self, name: &[u8]) -> Result<(), i16>;
+}
+
+/// Handles the `MMEMory` commands above. `None` if `cmd` is something else; otherwise the
+/// outcome, with an error ready for [`push_error`](crate::push_error). Query answers are
+/// queued for the host.
+pub async fn serve<F: FileStore>(files: &mut F, cmd: &Command) -> Option<Result<(), i16>> {
+    let (header, params) = split_header(&cmd.data[..cmd.len]);
+    let result = if header_matches(header, b"MMEMory:CATalog?") {
+        catalog(files, cmd).await
+    } else if header_matches(header, b"MMEMory:DATA?") {
+        read(files, cmd, params).await
+    } else if header_matches(header, b"MMEMory:DATA") {
+        write(files, params).await
+    } else if header_matches(header, b"MMEMory:DELete") {
+        delete(files, params).await
+    } else {
+        return None;
+    };
+    Some(result)
+}
+
+/// The file name leading `params`, undoubled into `name`.
+fn file_name<'a>(params: &mut Params, name: &'a mut [u8; MAX_NAME_LEN]) -> Result<&'a [u8], i16> {
+    match params.next() {
+        Some(Ok(Token::Str(quoted))) => {
+            let len = quoted.copy_to(name).ok_or(SCPI_ERR_FILE_NOT_FOUND)?;
+            Ok(&name[..len])
+        }
+        Some(Err(code)) => Err(code),
+        _ => Err(SCPI_ERR_DATA_TYPE),
+    }
+}
+
+fn no_more(mut params: Params) -> Result<(), i16> {
+    match params.next() {
+        None => Ok(()),
+        Some(_) => Err(SCPI_ERR_PARAMETER_NOT_ALLOWED),
+    }
+}
+
+async fn catalog<F: FileStore>(files: &mut F, cmd: &Command) -> Result<(), i16> {
+    let (used, free) = files.space().await?;
+    let mut stream = ResponseStream::new(cmd.token);
+    let mut text = String::<24>::new();
+    let _ = write!(text, "{used},{free}");
+    stream.write(text.as_bytes()).await;
+
+    let mut name = [0; MAX_NAME_LEN];
+    let mut index = 0;
+    // An error partway through still ends the message, so the host isn't left reading.
+    let result = loop {
+        match files.entry(index, &mut name).await {
+            Ok(Some((len, size))) => {
+                stream.write(b",\"").await;
+                stream.write(&name[..len.min(MAX_NAME_LEN)]).await;
+                text.clear();
+                let _ = write!(text, ",BIN,{size}\"");
+                stream.write(text.as_bytes()).await;
+                index += 1;
+            }
+            Ok(None) => break Ok(()),
+            Err(code) => break Err(code),
+        }
+    };
+    stream.write(b"\n").await;
+    stream.finish().await;
+    result
+}
+
+async fn read<F: FileStore>(files: &mut F, cmd: &Command, params: &[u8]) -> Result<(), i16> {
+    let mut params = Params::new(params);
+    let mut name = [0; MAX_NAME_LEN];
+    let name = file_name(&mut params, &mut name)?;
+    no_more(params)?;
+    let size = files.size(name).await?;
+
+    let mut stream = ResponseStream::new(cmd.token);
+    let mut preamble = [0; MAX_PREAMBLE_LEN];
+    let preamble_len = block_preamble(size as usize, &mut preamble);
+    stream.write(&preamble[..preamble_len]).await;
+
+    let mut offset = 0;
+    let mut result = Ok(());
+    while offset < size {
+        let want = ((size - offset) as usize).min(stream.spare().len());
+        match files.read(name, offset, &mut stream.spare()[..want]).await {
+            Ok(n) if n > 0 => {
+                stream.commit(n).await;
+                offset += n as u32;
+            }
+            // The preamble promised `size` bytes; the host sees a short block and the error.
+            Ok(_) => {
+                result = Err(SCPI_ERR_MASS_STORAGE);
+                break;
+            }
+            Err(code) => {
+                result = Err(code);
+                break;
+            }
+        }
+    }
+    stream.write(b"\n").await;
+    stream.finish().await;
+    result
+}
+
+async fn write<F: FileStore>(files: &mut F, params: &[u8]) -> Result<(), i16> {
+    let mut params = Params::new(params);
+    let mut name = [0; MAX_NAME_LEN];
+    let name = file_name(&mut params, &mut name)?;
+    let data = match params.next() {
+        Some(Ok(Token::Block(data))) => data,
+        Some(Err(code)) => return Err(code),
+        _ => return Err(SCPI_ERR_DATA_TYPE),
+    };
+    no_more(params)?;
+    files.write(name, data).await
+}
+
+async fn delete<F: FileStore>(files: &mut F, params: &[u8]) -> Result<(), i16> {
+    let mut params = Params::new(params);
+    let mut name = [0; MAX_NAME_LEN];
+    let name = file_name(&mut params, &mut name)?;
+    no_more(params)?;
+    files.delete(name).await
+}
@@ -0,0 +1,917 @@
+//! SCPI program data: splitting a program message into its header and parameters,
+//! tokenizing the comma-separated parameter list into the IEEE 488.2 data types, and scaling
+//! numeric values with unit suffixes to SI or resolving `MIN`/`MAX`/`DEF` against [`Limits`].
+//!
+//! Everything borrows from the command buffer; nothing is copied or allocated.
+
+use core::fmt::{self, Write};
+
+use crate::Response;
+use crate::scpi::{
+    SCPI_ERR_DATA_OUT_OF_RANGE, SCPI_ERR_DATA_TYPE, SCPI_ERR_ILLEGAL_PARAMETER,
+    SCPI_ERR_INVALID_BLOCK, SCPI_ERR_INVALID_EXPRESSION, SCPI_ERR_INVALID_SEPARATOR,
+    SCPI_ERR_INVALID_STRING, SCPI_ERR_INVALID_SUFFIX, SCPI_ERR_NUMERIC_DATA,
+    SCPI_ERR_PARAMETER_NOT_ALLOWED, SCPI_ERR_TOO_MUCH_DATA, node_matches,
+};
+
+/// Splits a program message unit into its header and the parameter text after it, e.g.
+/// `SOUR:VOLT 1.5` into `SOUR:VOLT` and `1.5`.
+pub fn split_header(cmd: &[u8]) -> (&[u8], &[u8]) {
+    let cmd = cmd.trim_ascii();
+    match cmd.iter().position(u8::is_ascii_whitespace) {
+        Some(i) => (&cmd[..i], cmd[i..].trim_ascii_start()),
+        None => (cmd, &[]),
+    }
+}
+
+/// One parameter of a program message.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Token<'a> {
+    /// Character program data: a mnemonic such as `ON`, `MAXimum` or `IMMediate`.
+    Chars(&'a [u8]),
+    /// Decimal or `#H`/`#Q`/`#B` numeric data, with any unit suffix, as written.
+    Numeric(&'a [u8]),
+    /// String program data.
+    Str(Quoted<'a>),
+    /// Expression program data such as `(TRACE1-TRACE2)` or a channel list `(@1:4)`,
+    /// without the outer parentheses. Parentheses inside are balanced and nested at most
+    /// [`MAX_EXPRESSION_DEPTH`] deep; those in quoted strings don't count.
+    Expr(&'a [u8]),
+    /// Arbitrary block program data, without the `#<n><length>` preamble.
+    Block(&'a [u8]),
+}
+
+/// String program data as written, quotes removed but doubled quotes still doubled.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Quoted<'a> {
+    raw: &'a [u8],
+    quote: u8,
+}
+
+impl<'a> Quoted<'a> {
+    /// The string's bytes, with each doubled quote read as one.
+    pub fn bytes(&self) -> impl Iterator<Item = u8> + 'a {
+        let quote = self.quote;
+        let mut skip = false;
+        self.raw.iter().filter_map(move |&b| {
+            if skip {
+                skip = false;
+                return None;
+            }
+            skip = b == quote;
+            Some(b)
+        })
+    }
+
+    /// Copies the string into `out`, undoubling quotes. Returns the length, or `None` if
+    /// `out` is too short.
+    pub fn copy_to(&self, out: &mut [u8]) -> Option<usize> {
+        let mut len = 0;
+        for b in self.bytes() {
+            *out.get_mut(len)? = b;
+            len += 1;
+        }
+        Some(len)
+    }
+
+    /// The bytes between the quotes, doubled quotes included.
+    pub fn raw(&self) -> &'a [u8] {
+        self.raw
+    }
+}
+
+/// The parameters of a program message, tokenized lazily. Each item is a [`Token`] or the
+/// SCPI error code for the malformed parameter, after which iteration ends.
+pub struct Params<'a> {
+    rest: &'a [u8],
+    first: bool,
+}
+
+impl<'a> Params<'a> {
+    /// Tokenizes `params`, the text after the header, e.g. from [`split_header`].
+    pub fn new(params: &'a [u8]) -> Self {
+        Self {
+            rest: params.trim_ascii(),
+            first: true,
+        }
+    }
+
+    fn fail(&mut self, code: i16) -> Option<Result<Token<'a>, i16>> {
+        self.rest = &[];
+        Some(Err(code))
+    }
+}
+
+impl<'a> Iterator for Params<'a> {
+    type Item = Result<Token<'a>, i16>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut rest = self.rest;
+        if !self.first {
+            rest = rest.trim_ascii_start();
+            if rest.is_empty() {
+                return None;
+            }
+            let Some(after) = rest.strip_prefix(b",") else {
+                return self.fail(SCPI_ERR_INVALID_SEPARATOR);
+            };
+            rest = after;
+        } else if rest.is_empty() {
+            return None;
+        }
+        self.first = false;
+        rest = rest.trim_ascii_start();
+
+        let (token, used) = match rest.first() {
+            Some(&quote @ (b'"' | b'\'')) => match string_len(rest, quote) {
+                Some(len) => (
+                    Token::Str(Quoted {
+                        raw: &rest[1..len - 1],
+                        quote,
+                    }),
+                    len,
+                ),
+                None => return self.fail(SCPI_ERR_INVALID_STRING),
+            },
+            Some(b'(') => match expression_len(rest) {
+                Some(len) => (Token::Expr(&rest[1..len - 1]), len),
+                None => return self.fail(SCPI_ERR_INVALID_EXPRESSION),
+            },
+            Some(b'#') if rest.get(1).is_some_and(u8::is_ascii_digit) => match block(rest) {
+                Some((data, len)) => (Token::Block(data), len),
+                None => return self.fail(SCPI_ERR_INVALID_BLOCK),
+            },
+            Some(_) => {
+                let len = rest.iter().position(|&b| b == b',').unwrap_or(rest.len());
+                let text = rest[..len].trim_ascii_end();
+                if text.is_empty() {
+                    return self.fail(SCPI_ERR_INVALID_SEPARATOR);
+                }
+                if text.iter().any(|&b| b == b'(' || b == b')') {
+                    return self.fail(SCPI_ERR_INVALID_EXPRESSION);
+                }
+                let token = if text[0].is_ascii_alphabetic() {
+                    Token::Chars(text)
+                } else {
+                    Token::Numeric(text)
+                };
+                (token, len)
+            }
+            None => return self.fail(SCPI_ERR_INVALID_SEPARATOR),
+        };
+        self.rest = &rest[used..];
+        Some(Ok(token))
+    }
+}
+
+/// Length of the quoted string at the start of `data`, both quotes included.
+fn string_len(data: &[u8], quote: u8) -> Option<usize> {
+    let mut i = 1;
+    loop {
+        if *data.get(i)? == quote {
+            if data.get(i + 1) != Some(&quote) {
+                return Some(i + 1);
+            }
+            i += 1;
+        }
+        i += 1;
+    }
+}
+
+/// Deepest parenthesis nesting accepted in expression program data, the outer pair
+/// included, so a handler that evaluates expressions recursively has a bound to size its
+/// stack for.
+pub const MAX_EXPRESSION_DEPTH: usize = 8;
+
+/// Length of the parenthesized expression at the start of `data`, parentheses included.
+/// `None` if it is unbalanced, nested too deep or holds an unterminated string.
+fn expression_len(data: &[u8]) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut i = 0;
+    while let Some(&b) = data.get(i) {
+        match b {
+            b'(' => {
+                depth += 1;
+                if depth > MAX_EXPRESSION_DEPTH {
+                    return None;
+                }
+            }
+            b')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            quote @ (b'"' | b'\'') => i += string_len(&data[i..], quote)? - 1,
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// The data of the arbitrary block at the start of `data` and the block's whole length.
+/// `#0` (indefinite length) takes the rest of the message.
+fn block(data: &[u8]) -> Option<(&[u8], usize)> {
+    let digits = usize::from(data[1] - b'0');
+    if digits == 0 {
+        return Some((&data[2..], data.len()));
+    }
+    let len_text = data.get(2..2 + digits)?;
+    let len = len_text.iter().try_fold(0usize, |n, &d| {
+        d.is_ascii_digit()
+            .then(|| n.checked_mul(10)?.checked_add(usize::from(d - b'0')))?
+    })?;
+    let start = 2 + digits;
+    Some((data.get(start..start + len)?, start + len))
+}
+
+/// Longest `#<n><length>` preamble a definite-length block can have: `#9` and nine digits.
+pub const MAX_PREAMBLE_LEN: usize = 11;
+
+/// Writes the preamble of a definite-length block of `len` bytes into `out`, e.g. `#3512`.
+/// Returns the preamble length; `out` needs [`MAX_PREAMBLE_LEN`] bytes for any `len` below
+/// 10^9.
+pub fn block_preamble(len: usize, out: &mut [u8]) -> usize {
+    let mut digits = [0u8; 20];
+    let mut n = 0;
+    let mut rest = len;
+    loop {
+        digits[n] = b'0' + (rest % 10) as u8;
+        n += 1;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    out[0] = b'#';
+    out[1] = b'0' + n as u8;
+    for (i, &digit) in digits[..n].iter().rev().enumerate() {
+        out[2 + i] = digit;
+    }
+    2 + n
+}
+
+/// Splits decimal numeric data into the number and its suffix, e.g. `10.5 mV` into `10.5`
+/// and `mV`. The suffix is empty when the host left it off, and for `#H`/`#Q`/`#B` data,
+/// which takes none.
+pub fn split_unit(numeric: &[u8]) -> (&[u8], &[u8]) {
+    if numeric.first() == Some(&b'#') {
+        return (numeric, &[]);
+    }
+    let digits = |from: usize| {
+        numeric[from..]
+            .iter()
+            .position(|b| !b.is_ascii_digit())
+            .map_or(numeric.len(), |n| from + n)
+    };
+    let mut end = usize::from(matches!(numeric.first(), Some(b'+' | b'-')));
+    end = digits(end);
+    if numeric.get(end) == Some(&b'.') {
+        end = digits(end + 1);
+    }
+    if matches!(numeric.get(end), Some(b'e' | b'E')) {
+        let sign = usize::from(matches!(numeric.get(end + 1), Some(b'+' | b'-')));
+        if numeric.get(end + 1 + sign).is_some_and(u8::is_ascii_digit) {
+            end = digits(end + 1 + sign);
+        }
+    }
+    (&numeric[..end], numeric[end..].trim_ascii())
+}
+
+/// The SI unit a numeric parameter is expressed in.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Volt,
+    Ampere,
+    Hertz,
+    Second,
+    Watt,
+    Ohm,
+    Farad,
+    Henry,
+}
+
+impl Unit {
+    fn symbol(self) -> &'static [u8] {
+        match self {
+            Unit::Volt => b"V",
+            Unit::Ampere => b"A",
+            Unit::Hertz => b"HZ",
+            Unit::Second => b"S",
+            Unit::Watt => b"W",
+            Unit::Ohm => b"OHM",
+            Unit::Farad => b"F",
+            Unit::Henry => b"H",
+        }
+    }
+}
+
+/// SCPI multiplier mnemonics. `M` is milli; mega is `MA`, except in `MHZ` and `MOHM`.
+const MULTIPLIERS: [(&[u8], f32); 12] = [
+    (b"EX", 1e18),
+    (b"PE", 1e15),
+    (b"T", 1e12),
+    (b"G", 1e9),
+    (b"MA", 1e6),
+    (b"K", 1e3),
+    (b"M", 1e-3),
+    (b"U", 1e-6),
+    (b"N", 1e-9),
+    (b"P", 1e-12),
+    (b"F", 1e-15),
+    (b"A", 1e-18),
+];
+
+/// Converts `value`, given with `suffix` (as from [`split_unit`]), to `unit` without a
+/// multiplier: `1.5` with `mV` gives 0.0015 V, `10` with `kHz` gives 10000 Hz. No suffix
+/// means the value is already in `unit`. A suffix that isn't `unit`, with or without a SCPI
+/// multiplier, is [`SCPI_ERR_INVALID_SUFFIX`].
+///
+/// Logarithmic units such as dBm are not converted; handlers that accept them match the
+/// suffix themselves before calling this.
+pub fn to_si(value: f32, suffix: &[u8], unit: Unit) -> Result<f32, i16> {
+    if suffix.is_empty() {
+        return Ok(value);
+    }
+    let symbol = unit.symbol();
+    let mega_exception = matches!(unit, Unit::Hertz | Unit::Ohm)
+        && suffix.len() == symbol.len() + 1
+        && suffix[0].eq_ignore_ascii_case(&b'M');
+    if mega_exception && suffix[1..].eq_ignore_ascii_case(symbol) {
+        return Ok(value * 1e6);
+    }
+    if suffix.len() < symbol.len()
+        || !suffix[suffix.len() - symbol.len()..].eq_ignore_ascii_case(symbol)
+    {
+        return Err(SCPI_ERR_INVALID_SUFFIX);
+    }
+    let prefix = &suffix[..suffix.len() - symbol.len()];
+    if prefix.is_empty() {
+        return Ok(value);
+    }
+    MULTIPLIERS
+        .iter()
+        .find(|(mnemonic, _)| prefix.eq_ignore_ascii_case(mnemonic))
+        .map(|&(_, scale)| value * scale)
+        .ok_or(SCPI_ERR_INVALID_SUFFIX)
+}
+
+/// Range and default of a numeric parameter, so `MINimum`, `MAXimum` and `DEFault` resolve
+/// without per-command code.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub min: f32,
+    pub max: f32,
+    pub default: f32,
+    /// Unit the value is held in; suffixes are scaled to it with [`to_si`]. `None` refuses
+    /// any suffix.
+    pub unit: Option<Unit>,
+}
+
+impl Limits {
+    /// Resolves a parameter to a value: `MIN`/`MAX`/`DEF` in short or long form, or a number
+    /// scaled by its suffix and checked against the range. Errors are SCPI codes for
+    /// [`push_error`](crate::push_error).
+    pub fn resolve(&self, token: Token) -> Result<f32, i16> {
+        let text = match token {
+            Token::Chars(mnemonic) => {
+                return self.named(mnemonic).ok_or(SCPI_ERR_ILLEGAL_PARAMETER);
+            }
+            Token::Numeric(text) => text,
+            _ => return Err(SCPI_ERR_DATA_TYPE),
+        };
+        let (number, suffix) = split_unit(text);
+        let value = parse_f32(number).ok_or(SCPI_ERR_NUMERIC_DATA)?;
+        let value = match self.unit {
+            Some(unit) => to_si(value, suffix, unit)?,
+            None if suffix.is_empty() => value,
+            None => return Err(SCPI_ERR_INVALID_SUFFIX),
+        };
+        if (self.min..=self.max).contains(&value) {
+            Ok(value)
+        } else {
+            Err(SCPI_ERR_DATA_OUT_OF_RANGE)
+        }
+    }
+
+    /// Answers the `VOLTage? MIN` form of a query: if `params` is `MIN`, `MAX` or `DEF`,
+    /// writes that value as NR3 into `resp` and returns `true`. `false` for a plain query
+    /// (empty `params`), which the handler answers with the present setting.
+    pub fn answer_query(&self, params: &[u8], resp: &mut Response) -> Result<bool, i16> {
+        let mut params = Params::new(params);
+        let value = match params.next() {
+            None => return Ok(false),
+            Some(Ok(Token::Chars(mnemonic))) => {
+                self.named(mnemonic).ok_or(SCPI_ERR_ILLEGAL_PARAMETER)?
+            }
+            Some(Ok(_)) => return Err(SCPI_ERR_DATA_TYPE),
+            Some(Err(code)) => return Err(code),
+        };
+        if params.next().is_some() {
+            return Err(SCPI_ERR_PARAMETER_NOT_ALLOWED);
+        }
+        writeln!(BodyWriter(resp), "{value:E}").map_err(|_| SCPI_ERR_TOO_MUCH_DATA)?;
+        Ok(true)
+    }
+
+    fn named(&self, mnemonic: &[u8]) -> Option<f32> {
+        if node_matches(mnemonic, b"MINimum") {
+            Some(self.min)
+        } else if node_matches(mnemonic, b"MAXimum") {
+            Some(self.max)
+        } else if node_matches(mnemonic, b"DEFault") {
+            Some(self.default)
+        } else {
+            None
+        }
+    }
+}
+
+/// Parses numeric data without a suffix: decimal (`-1.5`, `.5`, `1.`, `2E-3`, `2 E -3`) or
+/// `#H`/`#Q`/`#B` non-decimal data.
+///
+/// The digits are gathered into an integer and scaled in f64 by exact powers of ten, then
+/// rounded to f32. Rounding twice keeps the result within an f32 ulp of the written value but
+/// not always nearest: one that falls just off an f32 halfway point can land on it in f64 and
+/// then go the wrong way. A fraction of the code `str::parse` pulls in, and the same whatever
+/// the host's locale: the decimal point is always `.`.
+pub fn parse_f32(number: &[u8]) -> Option<f32> {
+    if number.first() == Some(&b'#') {
+        return parse_int(number).map(|value| value as f32);
+    }
+    let (negative, mut rest) = split_sign(number);
+    let mut mantissa = 0u64;
+    let mut exponent = 0i32;
+    let mut any_digits = false;
+    let mut fraction = false;
+    while let Some((&b, tail)) = rest.split_first() {
+        match b {
+            b'0'..=b'9' => {
+                any_digits = true;
+                if mantissa < 1_000_000_000_000_000_000 {
+                    mantissa = mantissa * 10 + u64::from(b - b'0');
+                    exponent -= i32::from(fraction);
+                } else {
+                    // Beyond what f32 can tell apart; keep the magnitude only.
+                    exponent += i32::from(!fraction);
+                }
+            }
+            b'.' if !fraction => fraction = true,
+            _ => break,
+        }
+        rest = tail;
+    }
+    if !any_digits {
+        return None;
+    }
+    let rest = rest.trim_ascii_start();
+    if let Some((&(b'e' | b'E'), tail)) = rest.split_first() {
+        let (exp_negative, digits) = split_sign(tail.trim_ascii_start());
+        let digits = digits.trim_ascii_start();
+        if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        let written = digits.iter().try_fold(0i32, |n, &d| {
+            n.checked_mul(10)?.checked_add(i32::from(d - b'0'))
+        });
+        // Too large to mean anything but 0 or infinity either way.
+        let written = written.unwrap_or(1000).min(1000);
+        exponent += if exp_negative { -written } else { written };
+    } else if !rest.is_empty() {
+        return None;
+    }
+    let value = scale_pow10(mantissa as f64, exponent) as f32;
+    Some(if negative { -value } else { value })
+}
+
+/// Parses integer numeric data: decimal with an optional sign, or 488.2 non-decimal data,
+/// `#H1F`, `#Q17`, `#B1010`, with the radix letter and hex digits in either case.
+/// `None` for anything else, fractions and exponents included, and on overflow.
+pub fn parse_int(number: &[u8]) -> Option<i64> {
+    if let Some(rest) = number.strip_prefix(b"#") {
+        let (&radix, digits) = rest.split_first()?;
+        let radix = match radix.to_ascii_uppercase() {
+            b'H' => 16,
+            b'Q' => 8,
+            b'B' => 2,
+            _ => return None,
+        };
+        if digits.is_empty() {
+            return None;
+        }
+        return digits.iter().try_fold(0i64, |n, &d| {
+            let digit = (d as char).to_digit(radix)?;
+            n.checked_mul(i64::from(radix))?
+                .checked_add(i64::from(digit))
+        });
+    }
+    let (negative, digits) = split_sign(number);
+    if digits.is_empty() {
+        return None;
+    }
+    let magnitude = digits.iter().try_fold(0i64, |n, &d| {
+        d.is_ascii_digit()
+            .then(|| n.checked_mul(10)?.checked_sub(i64::from(d - b'0')))?
+    })?;
+    // Accumulated negative so that i64::MIN parses.
+    if negative {
+        Some(magnitude)
+    } else {
+        magnitude.checked_neg()
+    }
+}
+
+fn split_sign(text: &[u8]) -> (bool, &[u8]) {
+    match text.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        Some((b'+', rest)) => (false, rest),
+        _ => (false, text),
+    }
+}
+
+/// Powers of ten that f64 holds exactly.
+const EXACT_POW10: [f64; 23] = [
+    1e0, 1e1, 1e2, 1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9, 1e10, 1e11, 1e12, 1e13, 1e14, 1e15, 1e16,
+    1e17, 1e18, 1e19, 1e20, 1e21, 1e22,
+];
+
+/// `value` times 10^`exponent`, one exact power at a time.
+fn scale_pow10(mut value: f64, mut exponent: i32) -> f64 {
+    let largest = EXACT_POW10.len() as i32 - 1;
+    while exponent != 0 && value != 0.0 && value.is_finite() {
+        let step = exponent.clamp(-largest, largest);
+        let pow = EXACT_POW10[step.unsigned_abs() as usize];
+        value = if step < 0 { value / pow } else { value * pow };
+        exponent -= step;
+    }
+    value
+}
+
+/// Appends formatted text to a response body.
+pub(crate) struct BodyWriter<'a>(pub(crate) &'a mut Response);
+
+impl Write for BodyWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let resp = &mut *self.0;
+        let end = resp.len + s.len();
+        resp.data
+            .get_mut(resp.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        resp.len = end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(params: &[u8]) -> std::vec::Vec<Result<Token<'_>, i16>> {
+        Params::new(params).collect()
+    }
+
+    fn nested(depth: usize) -> std::vec::Vec<u8> {
+        let mut expr = b"(".repeat(depth);
+        expr.push(b'1');
+        expr.extend(b")".repeat(depth));
+        expr
+    }
+
+    #[test]
+    fn split_header_and_params() {
+        assert!(split_header(b"  SOUR:VOLT  1.5, 2 ") == (&b"SOUR:VOLT"[..], &b"1.5, 2"[..]));
+        assert!(split_header(b"*RST") == (&b"*RST"[..], &b""[..]));
+    }
+
+    #[test]
+    fn chars_and_numeric() {
+        let parsed = tokens(b"ON, 1.5 mV ,#H1F");
+        assert!(
+            parsed
+                == [
+                    Ok(Token::Chars(b"ON")),
+                    Ok(Token::Numeric(b"1.5 mV")),
+                    Ok(Token::Numeric(b"#H1F")),
+                ]
+        );
+        assert!(tokens(b"1,,2")[1] == Err(SCPI_ERR_INVALID_SEPARATOR));
+        assert!(tokens(b"").is_empty());
+    }
+
+    #[test]
+    fn quotes_undoubled() {
+        let parsed = tokens(br#""say ""hi""", 'it''s', "a'b""#);
+        let strings: std::vec::Vec<_> = parsed
+            .iter()
+            .map(|token| match token {
+                Ok(Token::Str(quoted)) => quoted.bytes().collect::<std::vec::Vec<u8>>(),
+                _ => panic!("not a string"),
+            })
+            .collect();
+        assert_eq!(strings, [&b"say \"hi\""[..], b"it's", b"a'b"]);
+
+        let Ok(Token::Str(quoted)) = parsed[0] else {
+            unreachable!()
+        };
+        assert_eq!(quoted.raw(), br#"say ""hi"""#);
+        let mut out = [0; 8];
+        assert_eq!(quoted.copy_to(&mut out), Some(8));
+        assert_eq!(&out, b"say \"hi\"");
+        assert_eq!(quoted.copy_to(&mut out[..7]), None);
+
+        assert!(tokens(b"\"open") == [Err(SCPI_ERR_INVALID_STRING)]);
+        assert!(tokens(b"\"a\"\"") == [Err(SCPI_ERR_INVALID_STRING)]);
+    }
+
+    #[test]
+    fn expressions_nest_to_the_limit() {
+        let deepest = nested(MAX_EXPRESSION_DEPTH);
+        let inner = &deepest[1..deepest.len() - 1];
+        assert!(tokens(&deepest) == [Ok(Token::Expr(inner))]);
+        assert!(tokens(&nested(MAX_EXPRESSION_DEPTH + 1)) == [Err(SCPI_ERR_INVALID_EXPRESSION)]);
+
+        assert!(tokens(b"(@1:4),2") == [Ok(Token::Expr(b"@1:4")), Ok(Token::Numeric(b"2"))]);
+        assert!(tokens(b"(\")\" )") == [Ok(Token::Expr(b"\")\" "))]);
+        assert!(tokens(b"(1") == [Err(SCPI_ERR_INVALID_EXPRESSION)]);
+        assert!(tokens(b"1)") == [Err(SCPI_ERR_INVALID_EXPRESSION)]);
+    }
+
+    #[test]
+    fn blocks() {
+        assert!(tokens(b"#15hello,1") == [Ok(Token::Block(b"hello")), Ok(Token::Numeric(b"1"))]);
+        assert!(tokens(b"#212a,b\"c(d)e,f!,2")[0] == Ok(Token::Block(b"a,b\"c(d)e,f!")));
+        assert!(tokens(b"#0a,b") == [Ok(Token::Block(b"a,b"))]);
+        assert!(tokens(b"#15hi") == [Err(SCPI_ERR_INVALID_BLOCK)]);
+        assert!(tokens(b"#3x1") == [Err(SCPI_ERR_INVALID_BLOCK)]);
+    }
+
+    #[test]
+    fn block_preambles() {
+        let mut out = [0; MAX_PREAMBLE_LEN];
+        let cases: &[(usize, &[u8])] =
+            &[(0, b"#10"), (512, b"#3512"), (999_999_999, b"#9999999999")];
+        for &(len, preamble) in cases {
+            let n = block_preamble(len, &mut out);
+            assert_eq!(&out[..n], preamble);
+        }
+    }
+
+    #[test]
+    fn units_split_off() {
+        let cases: &[(&[u8], &[u8], &[u8])] = &[
+            (b"10.5 mV", b"10.5", b"mV"),
+            (b"-2E-3V", b"-2E-3", b"V"),
+            (b"+.5 KHZ", b"+.5", b"KHZ"),
+            (b"1e+3", b"1e+3", b""),
+            (b"3EX", b"3", b"EX"),
+            (b"7", b"7", b""),
+            (b"#B101", b"#B101", b""),
+        ];
+        for &(numeric, number, suffix) in cases {
+            assert!(split_unit(numeric) == (number, suffix));
+        }
+    }
+
+    #[test]
+    fn suffixes_scaled_to_si() {
+        assert_eq!(to_si(2.0, b"mV", Unit::Volt), Ok(0.002));
+        assert_eq!(to_si(2.0, b"V", Unit::Volt), Ok(2.0));
+        assert_eq!(to_si(2.0, b"", Unit::Volt), Ok(2.0));
+        assert_eq!(to_si(10.0, b"kHz", Unit::Hertz), Ok(10_000.0));
+        assert_eq!(to_si(3.0, b"MAV", Unit::Volt), Ok(3e6));
+        assert_eq!(to_si(4.0, b"us", Unit::Second), Ok(4.0 * 1e-6));
+        assert_eq!(to_si(1.0, b"GW", Unit::Watt), Ok(1e9));
+
+        // Mega, not milli, in MHZ and MOHM; MAHZ and MAOHM mean the same.
+        assert_eq!(to_si(5.0, b"MHZ", Unit::Hertz), Ok(5e6));
+        assert_eq!(to_si(5.0, b"mhz", Unit::Hertz), Ok(5e6));
+        assert_eq!(to_si(5.0, b"MAHZ", Unit::Hertz), Ok(5e6));
+        assert_eq!(to_si(5.0, b"MOHM", Unit::Ohm), Ok(5e6));
+        assert_eq!(to_si(5.0, b"MAOHM", Unit::Ohm), Ok(5e6));
+        // Anywhere else M is milli.
+        assert_eq!(to_si(5.0, b"MV", Unit::Volt), Ok(5.0 * 1e-3));
+        assert_eq!(to_si(5.0, b"MA", Unit::Ampere), Ok(5.0 * 1e-3));
+        assert_eq!(to_si(5.0, b"MS", Unit::Second), Ok(5.0 * 1e-3));
+
+        assert_eq!(to_si(1.0, b"A", Unit::Volt), Err(SCPI_ERR_INVALID_SUFFIX));
+        assert_eq!(to_si(1.0, b"XV", Unit::Volt), Err(SCPI_ERR_INVALID_SUFFIX));
+        assert_eq!(to_si(1.0, b"dBm", Unit::Watt), Err(SCPI_ERR_INVALID_SUFFIX));
+        assert_eq!(to_si(1.0, b"HZ", Unit::Ohm), Err(SCPI_ERR_INVALID_SUFFIX));
+    }
+
+    /// The f32 after `value`.
+    fn next_up(value: f32) -> f32 {
+        f32::from_bits(value.to_bits() + 1)
+    }
+
+    #[test]
+    fn decimal_numbers() {
+        let cases: &[(&[u8], f32)] = &[
+            (b"0", 0.0),
+            (b"-1.5", -1.5),
+            (b"+.5", 0.5),
+            (b"1.", 1.0),
+            (b"2E-3", 2e-3),
+            (b"2 e - 3", 2e-3),
+            (b"0.1", 0.1),
+            (b"16777217", 16_777_216.0),
+            (b"3.4028235E38", f32::MAX),
+            (b"1.17549435E-38", f32::MIN_POSITIVE),
+        ];
+        for &(text, value) in cases {
+            assert_eq!(parse_f32(text), Some(value), "{}", text.escape_ascii());
+        }
+        for text in [
+            &b""[..],
+            b"-",
+            b".",
+            b"1..2",
+            b"1E",
+            b"1E+",
+            b"1EX",
+            b"1 2",
+            b"0x10",
+        ] {
+            assert_eq!(parse_f32(text), None, "{}", text.escape_ascii());
+        }
+    }
+
+    #[test]
+    fn f32_halfway_points() {
+        // Exactly halfway between two f32s: ties go to the even one.
+        assert_eq!(parse_f32(b"1.000000059604644775390625"), Some(1.0));
+        let one_up = next_up(1.0);
+        assert_eq!(
+            parse_f32(b"1.000000178813934326171875"),
+            Some(next_up(one_up))
+        );
+        // Just either side of halfway, close enough to round in f64 first.
+        assert_eq!(parse_f32(b"1.00000005960464"), Some(1.0));
+        let just_above = parse_f32(b"1.0000000596046448").unwrap();
+        assert!(just_above == 1.0 || just_above == one_up);
+        // Far enough from halfway that f64 keeps them apart.
+        assert_eq!(parse_f32(b"1.0000000596047"), Some(one_up));
+    }
+
+    #[test]
+    fn exponent_extremes() {
+        assert_eq!(parse_f32(b"1E39"), Some(f32::INFINITY));
+        assert_eq!(parse_f32(b"-1E39"), Some(f32::NEG_INFINITY));
+        assert_eq!(parse_f32(b"1E-46"), Some(0.0));
+        assert_eq!(parse_f32(b"1.4E-45"), Some(f32::from_bits(1)));
+        assert_eq!(parse_f32(b"1E99999999999"), Some(f32::INFINITY));
+        assert_eq!(parse_f32(b"1E-99999999999"), Some(0.0));
+        assert_eq!(parse_f32(b"0E99999999999"), Some(0.0));
+        // Digits past what the mantissa holds still count towards the magnitude.
+        let long = [&b"1"[..], &[b'0'; 40]].concat();
+        assert_eq!(parse_f32(&long), Some(1e40f64 as f32));
+        assert_eq!(parse_f32(&[&long[..], b"E-40"].concat()), Some(1.0));
+    }
+
+    #[test]
+    fn integers() {
+        let cases: &[(&[u8], i64)] = &[
+            (b"0", 0),
+            (b"-42", -42),
+            (b"+7", 7),
+            (b"9223372036854775807", i64::MAX),
+            (b"-9223372036854775808", i64::MIN),
+            (b"#H1F", 31),
+            (b"#hff", 255),
+            (b"#Q17", 15),
+            (b"#B1010", 10),
+            (b"#H7FFFFFFFFFFFFFFF", i64::MAX),
+        ];
+        for &(text, value) in cases {
+            assert_eq!(parse_int(text), Some(value), "{}", text.escape_ascii());
+        }
+        for text in [
+            &b"9223372036854775808"[..],
+            b"-9223372036854775809",
+            b"99999999999999999999",
+            b"#H8000000000000000",
+            b"#B2",
+            b"#H",
+            b"#X1",
+            b"1.5",
+            b"1E3",
+            b"",
+            b"-",
+        ] {
+            assert_eq!(parse_int(text), None, "{}", text.escape_ascii());
+        }
+        assert_eq!(parse_f32(b"#H10"), Some(16.0));
+    }
+
+    const VOLTS: Limits = Limits {
+        min: -2.5,
+        max: 10.0,
+        default: 0.5,
+        unit: Some(Unit::Volt),
+    };
+
+    fn empty_response() -> Response {
+        Response {
+            len: 0,
+            data: [0; crate::MAX_SCPI_LEN],
+            segments: heapless::Vec::new(),
+            eom: true,
+            term_char_matched: false,
+            raw: false,
+            token: None,
+        }
+    }
+
+    #[test]
+    fn limits_resolve_names() {
+        for (mnemonic, value) in [
+            ("MIN", -2.5),
+            ("minimum", -2.5),
+            ("MAX", 10.0),
+            ("Def", 0.5),
+        ] {
+            assert_eq!(VOLTS.resolve(Token::Chars(mnemonic.as_bytes())), Ok(value));
+        }
+        for mnemonic in ["MINI", "ON", "DEFA"] {
+            let token = Token::Chars(mnemonic.as_bytes());
+            assert_eq!(VOLTS.resolve(token), Err(SCPI_ERR_ILLEGAL_PARAMETER));
+        }
+    }
+
+    #[test]
+    fn limits_resolve_numbers() {
+        let cases: &[(&[u8], Result<f32, i16>)] = &[
+            (b"5", Ok(5.0)),
+            (b"5 V", Ok(5.0)),
+            (b"2 mV", Ok(0.002)),
+            (b"-2.5", Ok(-2.5)),
+            (b"10", Ok(10.0)),
+            (b"10.5", Err(SCPI_ERR_DATA_OUT_OF_RANGE)),
+            (b"1 KV", Err(SCPI_ERR_DATA_OUT_OF_RANGE)),
+            (b"-3", Err(SCPI_ERR_DATA_OUT_OF_RANGE)),
+            (b"1 A", Err(SCPI_ERR_INVALID_SUFFIX)),
+            (b"#H1G", Err(SCPI_ERR_NUMERIC_DATA)),
+            (b"-", Err(SCPI_ERR_NUMERIC_DATA)),
+        ];
+        for &(text, result) in cases {
+            let resolved = VOLTS.resolve(Token::Numeric(text));
+            assert_eq!(resolved, result, "{}", text.escape_ascii());
+        }
+
+        let unitless = Limits {
+            unit: None,
+            ..VOLTS
+        };
+        assert_eq!(unitless.resolve(Token::Numeric(b"5")), Ok(5.0));
+        let suffixed = unitless.resolve(Token::Numeric(b"5 V"));
+        assert_eq!(suffixed, Err(SCPI_ERR_INVALID_SUFFIX));
+    }
+
+    #[test]
+    fn limits_resolve_data_types() {
+        let quoted = Quoted {
+            raw: b"5",
+            quote: b'"',
+        };
+        for token in [Token::Str(quoted), Token::Expr(b"5"), Token::Block(b"5")] {
+            assert_eq!(VOLTS.resolve(token), Err(SCPI_ERR_DATA_TYPE));
+        }
+    }
+
+    #[test]
+    fn limits_answer_queries() {
+        let answers: &[(&[u8], &[u8])] = &[
+            (b"MIN", b"-2.5E0\n"),
+            (b"MAXimum", b"1E1\n"),
+            (b" def ", b"5E-1\n"),
+        ];
+        for &(params, answer) in answers {
+            let mut resp = empty_response();
+            assert_eq!(VOLTS.answer_query(params, &mut resp), Ok(true));
+            assert_eq!(&resp.data[..resp.len], answer);
+        }
+
+        let mut resp = empty_response();
+        assert_eq!(VOLTS.answer_query(b"", &mut resp), Ok(false));
+        assert_eq!(VOLTS.answer_query(b"  ", &mut resp), Ok(false));
+        assert_eq!(resp.len, 0);
+
+        let refused: &[(&[u8], i16)] = &[
+            (b"MAX,MIN", SCPI_ERR_PARAMETER_NOT_ALLOWED),
+            (b"5", SCPI_ERR_DATA_TYPE),
+            (b"BOGUS", SCPI_ERR_ILLEGAL_PARAMETER),
+            (b"\"MAX", SCPI_ERR_INVALID_STRING),
+        ];
+        for &(params, code) in refused {
+            assert_eq!(VOLTS.answer_query(params, &mut resp), Err(code));
+        }
+
+        resp.len = crate::MAX_SCPI_LEN - 2;
+        assert_eq!(
+            VOLTS.answer_query(b"MAX", &mut resp),
+            Err(SCPI_ERR_TOO_MUCH_DATA)
+        );
+    }
+}
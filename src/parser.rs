@@ -0,0 +1,137 @@
+//! Plugging a third-party command parser, nom-based or otherwise, into the command queue.
+//!
+//! A [`CommandParser`] turns the bytes of one program message into the application's own
+//! command type. [`ParsedCommands`] takes commands off the queue, runs them through it and
+//! hands back only the ones that parsed, with the token to answer queries with; the others
+//! go to the error queue as the parser's [`error_code`](CommandParser::error_code), so every
+//! parser reports failures the same way, and a query among them is answered with an empty
+//! message. A closure `FnMut(&[u8]) -> Result<C, i16>` is a
+//! parser whose error already is the code.
+
+use crate::scpi::{SCPI_ERR_COMMAND, push_error};
+use crate::{Priority, Response, ResponseToken, next_command, resp_sender};
+
+/// Bytes in, typed command or error out.
+pub trait CommandParser {
+    type Command;
+    type Error;
+
+    /// Parses one program message, terminator already stripped.
+    fn parse(&mut self, message: &[u8]) -> Result<Self::Command, Self::Error>;
+
+    /// The SCPI error `error` goes into the error queue as. -100, "Command error", unless the
+    /// parser can tell more, e.g. [`SCPI_ERR_UNDEFINED_HEADER`](crate::SCPI_ERR_UNDEFINED_HEADER)
+    /// for a header it doesn't know.
+    fn error_code(&self, _error: &Self::Error) -> i16 {
+        SCPI_ERR_COMMAND
+    }
+}
+
+impl<F, C> CommandParser for F
+where
+    F: FnMut(&[u8]) -> Result<C, i16>,
+{
+    type Command = C;
+    type Error = i16;
+
+    fn parse(&mut self, message: &[u8]) -> Result<C, i16> {
+        self(message)
+    }
+
+    fn error_code(&self, error: &i16) -> i16 {
+        *error
+    }
+}
+
+/// A command that parsed, and what is needed to answer it.
+pub struct Parsed<C> {
+    pub command: C,
+    /// Copy into [`Response::token`](crate::Response::token) when answering a query.
+    pub token: Option<ResponseToken>,
+    pub query: bool,
+    pub priority: Priority,
+}
+
+/// The command queue seen through a [`CommandParser`].
+pub struct ParsedCommands<P> {
+    parser: P,
+}
+
+impl<P: CommandParser> ParsedCommands<P> {
+    pub fn new(parser: P) -> Self {
+        Self { parser }
+    }
+
+    /// Waits for the next command that parses, immediate ones first as with
+    /// [`next_command`]. Ones that don't parse are pushed to the error queue and skipped; a
+    /// query among them is answered with an empty message, so the host's read for it ends
+    /// and the next query's answer isn't held back behind it.
+    pub async fn next(&mut self) -> Parsed<P::Command> {
+        loop {
+            let (cmd, priority) = next_command().await;
+            match self.parser.parse(&cmd.data[..cmd.len]) {
+                Ok(command) => {
+                    return Parsed {
+                        command,
+                        token: cmd.token,
+                        query: cmd.query,
+                        priority,
+                    };
+                }
+                Err(error) => {
+                    push_error(self.parser.error_code(&error));
+                    if cmd.token.is_some() {
+                        resp_sender()
+                            .send(Response::from_static(b"", cmd.token))
+                            .await;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn parser(&mut self) -> &mut P {
+        &mut self.parser
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SCPI_ERR_UNDEFINED_HEADER;
+
+    #[test]
+    fn closure_parser() {
+        let mut parser = |message: &[u8]| -> Result<u8, i16> {
+            match message {
+                b"ONE" => Ok(1),
+                _ => Err(SCPI_ERR_UNDEFINED_HEADER),
+            }
+        };
+        assert_eq!(parser.parse(b"ONE"), Ok(1));
+        let error = parser.parse(b"TWO").unwrap_err();
+        assert_eq!(parser.error_code(&error), SCPI_ERR_UNDEFINED_HEADER);
+    }
+
+    struct Words;
+
+    impl CommandParser for Words {
+        type Command = ();
+        type Error = ();
+
+        fn parse(&mut self, message: &[u8]) -> Result<(), ()> {
+            message
+                .iter()
+                .all(u8::is_ascii_alphabetic)
+                .then_some(())
+                .ok_or(())
+        }
+    }
+
+    #[test]
+    fn default_error_code() {
+        let mut parser = Words;
+        assert_eq!(parser.parse(b"WORD"), Ok(()));
+        assert_eq!(parser.error_code(&()), SCPI_ERR_COMMAND);
+    }
+}
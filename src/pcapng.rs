@@ -0,0 +1,181 @@
+//! Reference traces: the sessions of [`transcript`](crate::transcript) written as pcapng in
+//! the Linux usbmon format, the same as `host/pcapng.py` writes from a device, so Wireshark
+//! opens a known-good exchange next to a capture from a misbehaving setup.
+//!
+//! Each session's traffic goes to `host/traces/<session>.pcapng`, one submission and one
+//! completion per transfer. Time is synthetic, a millisecond per transfer from zero, so a
+//! trace only changes when the traffic does. A missing trace fails the test; `BLESS=1`
+//! writes them all afresh.
+
+use std::fs;
+use std::path::Path;
+
+use crate::testing::Traffic;
+use crate::transcript::{EP_IN, EP_OUT, record, sessions};
+
+const LINKTYPE_USB_LINUX_MMAPPED: u16 = 220;
+
+const XFER_CONTROL: u8 = 2;
+const XFER_BULK: u8 = 3;
+
+const NO_SETUP: u8 = b'-';
+const NO_DATA_OUT: u8 = b'>';
+const NO_DATA_IN: u8 = b'<';
+
+/// -EINPROGRESS, the status of a submission.
+const EINPROGRESS: i32 = -115;
+
+/// Bus and address the traces put the device at.
+const BUS: u16 = 1;
+const DEVNUM: u8 = 1;
+
+/// One pcapng block: type, length, body padded to 4 bytes, length again.
+fn block(out: &mut Vec<u8>, block_type: u32, mut body: Vec<u8>) {
+    body.resize(body.len().next_multiple_of(4), 0);
+    let length = (12 + body.len()) as u32;
+    out.extend_from_slice(&block_type.to_le_bytes());
+    out.extend_from_slice(&length.to_le_bytes());
+    out.extend_from_slice(&body);
+    out.extend_from_slice(&length.to_le_bytes());
+}
+
+/// One usbmon event: a submission (`S`) or completion (`C`) of URB `urb_id`.
+struct Event<'a> {
+    urb_id: u64,
+    kind: u8,
+    xfer_type: u8,
+    ep: u8,
+    setup: Option<&'a [u8]>,
+    data: &'a [u8],
+    /// The URB's length when it differs from the data carried, as in a submission for IN.
+    length: Option<u32>,
+    flag_data: u8,
+}
+
+impl<'a> Event<'a> {
+    fn new(urb_id: u64, kind: u8, xfer_type: u8, ep: u8, data: &'a [u8]) -> Self {
+        Self {
+            urb_id,
+            kind,
+            xfer_type,
+            ep,
+            setup: None,
+            data,
+            length: None,
+            flag_data: 0,
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let micros = self.urb_id * 1000;
+        let mut packet = Vec::with_capacity(64 + self.data.len());
+        packet.extend_from_slice(&self.urb_id.to_le_bytes());
+        packet.extend_from_slice(&[self.kind, self.xfer_type, self.ep, DEVNUM]);
+        packet.extend_from_slice(&BUS.to_le_bytes());
+        packet.push(if self.setup.is_some() { 0 } else { NO_SETUP });
+        packet.push(if self.data.is_empty() {
+            self.flag_data
+        } else {
+            0
+        });
+        packet.extend_from_slice(&((micros / 1_000_000) as i64).to_le_bytes());
+        packet.extend_from_slice(&((micros % 1_000_000) as i32).to_le_bytes());
+        let status = if self.kind == b'C' { 0 } else { EINPROGRESS };
+        packet.extend_from_slice(&status.to_le_bytes());
+        let length = self.length.unwrap_or(self.data.len() as u32);
+        packet.extend_from_slice(&length.to_le_bytes());
+        packet.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        packet.extend_from_slice(self.setup.unwrap_or(&[0; 8]));
+        // Interval, start frame, transfer flags, ISO descriptors: all unused.
+        packet.extend_from_slice(&[0; 16]);
+        packet.extend_from_slice(self.data);
+
+        let captured = packet.len() as u32;
+        let mut body = Vec::new();
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&captured.to_le_bytes());
+        body.extend_from_slice(&captured.to_le_bytes());
+        body.extend_from_slice(&packet);
+        block(out, 0x0000_0006, body);
+    }
+}
+
+/// `traffic` as a pcapng file.
+pub(crate) fn trace(traffic: &[Traffic]) -> Vec<u8> {
+    let mut out = Vec::new();
+    // Section header, then one interface with microsecond timestamps (the default).
+    let mut section = Vec::new();
+    section.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+    section.extend_from_slice(&1u16.to_le_bytes());
+    section.extend_from_slice(&0u16.to_le_bytes());
+    section.extend_from_slice(&(-1i64).to_le_bytes());
+    block(&mut out, 0x0A0D_0D0A, section);
+    let mut interface = Vec::new();
+    interface.extend_from_slice(&LINKTYPE_USB_LINUX_MMAPPED.to_le_bytes());
+    interface.extend_from_slice(&0u16.to_le_bytes());
+    interface.extend_from_slice(&0u32.to_le_bytes());
+    block(&mut out, 0x0000_0001, interface);
+
+    for (urb_id, tapped) in (1..).zip(traffic) {
+        let (submit, complete) = match tapped {
+            Traffic::Out(data) => (
+                Event::new(urb_id, b'S', XFER_BULK, EP_OUT as u8, data),
+                Event {
+                    length: Some(data.len() as u32),
+                    flag_data: NO_DATA_IN,
+                    ..Event::new(urb_id, b'C', XFER_BULK, EP_OUT as u8, &[])
+                },
+            ),
+            Traffic::In(data) => (
+                Event {
+                    length: Some(data.len() as u32),
+                    flag_data: NO_DATA_OUT,
+                    ..Event::new(urb_id, b'S', XFER_BULK, EP_IN as u8, &[])
+                },
+                Event::new(urb_id, b'C', XFER_BULK, EP_IN as u8, data),
+            ),
+            Traffic::Ctrl(data) => {
+                let (setup, answer) = data.split_at(8);
+                let ep = setup[0] & 0x80;
+                let w_length = u16::from_le_bytes([setup[6], setup[7]]);
+                (
+                    Event {
+                        setup: Some(setup),
+                        length: Some(w_length.into()),
+                        flag_data: NO_DATA_OUT,
+                        ..Event::new(urb_id, b'S', XFER_CONTROL, ep, &[])
+                    },
+                    Event::new(urb_id, b'C', XFER_CONTROL, ep, answer),
+                )
+            }
+        };
+        submit.write(&mut out);
+        complete.write(&mut out);
+    }
+    out
+}
+
+#[test]
+fn traces() {
+    let trace_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("host/traces");
+    let bless = std::env::var_os("BLESS").is_some();
+    for script in sessions() {
+        let (_, traffic) = record(&fs::read_to_string(&script).unwrap());
+        let actual = trace(&traffic);
+        let path = trace_dir.join(script.with_extension("pcapng").file_name().unwrap());
+        if bless {
+            fs::write(&path, &actual).unwrap();
+            continue;
+        }
+        let Ok(expected) = fs::read(&path) else {
+            panic!("{} is missing; BLESS=1 writes it", path.display());
+        };
+        assert!(
+            expected == actual,
+            "{} differs from the replay; BLESS=1 writes it afresh",
+            path.display()
+        );
+    }
+}
@@ -0,0 +1,67 @@
+//! One place for an instrument to drop its outputs to a safe state when it loses its
+//! controller.
+//!
+//! The class queues a [`SafeStateReason`] on every event that means nobody is in charge any
+//! more; [`run`] hands them to a [`SafetyHook`] in its own task, so the control handler and
+//! the runner never wait on the application.
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant};
+
+use crate::{HostLoss, clock, last_host_activity};
+
+static EVENTS: Channel<CriticalSectionRawMutex, SafeStateReason, 4> = Channel::new();
+
+/// Why the class wants the outputs made safe.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SafeStateReason {
+    /// The host is gone, see [`HostLoss`]. [`HostLoss::Silent`] only comes from [`run`] when
+    /// it is given a silence limit.
+    HostLost(HostLoss),
+    /// The host sent INITIATE_CLEAR, abandoning whatever it had set up.
+    DeviceClear,
+}
+
+/// What a power supply or source instrument does when it loses its controller.
+#[allow(async_fn_in_trait)]
+pub trait SafetyHook {
+    /// Stops driving outputs: disable them, ramp them down, open relays. Runs to completion
+    /// before the next event is handled; repeated events call it again, so make it
+    /// idempotent.
+    async fn enter_safe_state(&mut self, reason: SafeStateReason);
+}
+
+/// Calls `hook` for every safe-state event. Never returns; spawn it in its own task.
+///
+/// With `silence`, a host that neither moves bulk data nor sends class requests for that long
+/// counts as lost, once per silent stretch. Events that arrive while the hook runs are kept,
+/// up to a handful.
+pub async fn run<S: SafetyHook>(hook: &mut S, silence: Option<Duration>) -> ! {
+    let mut reported_silence = None;
+    loop {
+        let deadline = match silence {
+            Some(silence) if reported_silence != Some(last_host_activity()) => {
+                last_host_activity() + silence
+            }
+            _ => Instant::MAX,
+        };
+        let reason = match select(EVENTS.receive(), clock::at(deadline)).await {
+            Either::First(reason) => reason,
+            Either::Second(()) => {
+                let last = last_host_activity();
+                if clock::now() < last + silence.unwrap_or_default() {
+                    continue;
+                }
+                reported_silence = Some(last);
+                SafeStateReason::HostLost(HostLoss::Silent)
+            }
+        };
+        hook.enter_safe_state(reason).await;
+    }
+}
+
+pub(crate) fn signal(reason: SafeStateReason) {
+    let _ = EVENTS.try_send(reason);
+}
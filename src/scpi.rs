@@ -0,0 +1,264 @@
+//! SCPI layer: the error queue, the error codes the class and its links report, and the
+//! mandated `SYSTem` queries.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use heapless::Deque;
+
+use crate::params::{Params, Token, split_header};
+use crate::{Inline, Response};
+
+const ERROR_QUEUE_LEN: usize = 8;
+
+/// SCPI error -100, "Command error".
+pub const SCPI_ERR_COMMAND: i16 = -100;
+/// SCPI error -103, "Invalid separator".
+pub const SCPI_ERR_INVALID_SEPARATOR: i16 = -103;
+/// SCPI error -104, "Data type error".
+pub const SCPI_ERR_DATA_TYPE: i16 = -104;
+/// SCPI error -108, "Parameter not allowed".
+pub const SCPI_ERR_PARAMETER_NOT_ALLOWED: i16 = -108;
+/// SCPI error -113, "Undefined header".
+pub const SCPI_ERR_UNDEFINED_HEADER: i16 = -113;
+/// SCPI error -114, "Header suffix out of range".
+pub const SCPI_ERR_HEADER_SUFFIX: i16 = -114;
+/// SCPI error -120, "Numeric data error".
+pub const SCPI_ERR_NUMERIC_DATA: i16 = -120;
+/// SCPI error -131, "Invalid suffix".
+pub const SCPI_ERR_INVALID_SUFFIX: i16 = -131;
+/// SCPI error -151, "Invalid string data".
+pub const SCPI_ERR_INVALID_STRING: i16 = -151;
+/// SCPI error -161, "Invalid block data".
+pub const SCPI_ERR_INVALID_BLOCK: i16 = -161;
+/// SCPI error -171, "Invalid expression".
+pub const SCPI_ERR_INVALID_EXPRESSION: i16 = -171;
+/// SCPI error -203, "Command protected".
+pub const SCPI_ERR_COMMAND_PROTECTED: i16 = -203;
+/// SCPI error -222, "Data out of range".
+pub const SCPI_ERR_DATA_OUT_OF_RANGE: i16 = -222;
+/// SCPI error -223, "Too much data".
+pub const SCPI_ERR_TOO_MUCH_DATA: i16 = -223;
+/// SCPI error -224, "Illegal parameter value".
+pub const SCPI_ERR_ILLEGAL_PARAMETER: i16 = -224;
+/// SCPI error -240, "Hardware error".
+pub const SCPI_ERR_HARDWARE: i16 = -240;
+/// SCPI error -241, "Hardware missing".
+pub const SCPI_ERR_HARDWARE_MISSING: i16 = -241;
+/// SCPI error -363, "Input buffer overrun".
+pub const SCPI_ERR_INPUT_OVERRUN: i16 = -363;
+/// SCPI error -361, "Parity error in program message".
+pub const SCPI_ERR_PARITY: i16 = -361;
+/// SCPI error -250, "Mass storage error".
+pub const SCPI_ERR_MASS_STORAGE: i16 = -250;
+/// SCPI error -256, "File name not found".
+pub const SCPI_ERR_FILE_NOT_FOUND: i16 = -256;
+/// SCPI error -330, "Self-test failed".
+pub const SCPI_ERR_SELF_TEST: i16 = -330;
+/// SCPI error -340, "Calibration failed".
+pub const SCPI_ERR_CALIBRATION: i16 = -340;
+/// SCPI error -350, "Queue overflow".
+pub const SCPI_ERR_QUEUE_OVERFLOW: i16 = -350;
+
+static ERROR_QUEUE: Mutex<CriticalSectionRawMutex, RefCell<Deque<i16, ERROR_QUEUE_LEN>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+
+/// Appends an error to the SCPI error queue. When the queue is full the newest entry is
+/// replaced with [`SCPI_ERR_QUEUE_OVERFLOW`], as SCPI requires.
+pub fn push_error(code: i16) {
+    ERROR_QUEUE.lock(|queue| {
+        let mut queue = queue.borrow_mut();
+        if queue.is_full() {
+            queue.pop_back();
+            let _ = queue.push_back(SCPI_ERR_QUEUE_OVERFLOW);
+        } else {
+            let _ = queue.push_back(code);
+        }
+    });
+}
+
+/// Pops the oldest error, as `SYSTem:ERRor?` does. `None` means "0, No error".
+pub fn pop_error() -> Option<i16> {
+    ERROR_QUEUE.lock(|queue| queue.borrow_mut().pop_front())
+}
+
+/// Errors waiting in the queue, for the Error/Event Available bit of the status byte.
+pub fn error_count() -> usize {
+    ERROR_QUEUE.lock(|queue| queue.borrow().len())
+}
+
+/// Answer to `SYSTem:VERSion?`: the SCPI revision the class follows.
+pub const SCPI_VERSION: &[u8] = b"1999.0\n";
+
+/// Answer to `SYSTem:CAPability?`, naming the class layers compiled in.
+pub const SCPI_CAPABILITY: &[u8] = if cfg!(feature = "gateway") && cfg!(feature = "tcp") {
+    b"\"USBTMC USB488 IEEE488.2 SCPI TCP GATEWAY\"\n"
+} else if cfg!(feature = "gateway") {
+    b"\"USBTMC USB488 IEEE488.2 SCPI GATEWAY\"\n"
+} else if cfg!(feature = "tcp") {
+    b"\"USBTMC USB488 IEEE488.2 SCPI TCP\"\n"
+} else {
+    b"\"USBTMC USB488 IEEE488.2 SCPI\"\n"
+};
+
+/// Whether the program header `header` names `pattern`, a SCPI header written with its short
+/// form in capitals, e.g. `SYSTem:VERSion?`. Each node may be given short or long, in any
+/// case, and a leading colon is ignored.
+pub fn header_matches(header: &[u8], pattern: &[u8]) -> bool {
+    match_suffixed(header, pattern, Suffix::default()).is_some()
+}
+
+/// The numeric suffix a pattern node marked with `#` accepts, e.g. the 3 of `OUTPut3`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Suffix {
+    pub min: u32,
+    pub max: u32,
+    /// Taken when the host leaves the suffix off, `OUTPut:VOLTage`.
+    pub default: u32,
+}
+
+impl Default for Suffix {
+    fn default() -> Self {
+        Self {
+            min: 1,
+            max: u32::MAX,
+            default: 1,
+        }
+    }
+}
+
+/// Like [`header_matches`], but the pattern may mark one node with `#` to take a numeric
+/// suffix, `OUTPut#:VOLTage`, which is parsed and returned so the handler gets it as an
+/// argument. `None` if the header doesn't match; `Some(Err(`[`SCPI_ERR_HEADER_SUFFIX`]`))`
+/// if it does but the suffix is outside `suffix`, ready for [`push_error`].
+pub fn match_suffixed(header: &[u8], pattern: &[u8], suffix: Suffix) -> Option<Result<u32, i16>> {
+    let header = header.strip_prefix(b":").unwrap_or(header);
+    let (header, pattern) = match (header.strip_suffix(b"?"), pattern.strip_suffix(b"?")) {
+        (Some(header), Some(pattern)) => (header, pattern),
+        (None, None) => (header, pattern),
+        _ => return None,
+    };
+    let mut nodes = header.split(|&b| b == b':');
+    let mut value = Ok(suffix.default);
+    for expected in pattern.split(|&b| b == b':') {
+        let node = nodes.next()?;
+        let Some(expected) = expected.strip_suffix(b"#") else {
+            if !node_matches(node, expected) {
+                return None;
+            }
+            continue;
+        };
+        let name_len = node
+            .iter()
+            .rposition(|b| !b.is_ascii_digit())
+            .map_or(0, |i| i + 1);
+        let (name, digits) = node.split_at(name_len);
+        if !node_matches(name, expected) {
+            return None;
+        }
+        if !digits.is_empty() {
+            value = parse_suffix(digits)
+                .filter(|n| (suffix.min..=suffix.max).contains(n))
+                .ok_or(SCPI_ERR_HEADER_SUFFIX);
+        }
+    }
+    nodes.next().is_none().then_some(value)
+}
+
+fn parse_suffix(digits: &[u8]) -> Option<u32> {
+    digits.iter().try_fold(0u32, |n, &d| {
+        n.checked_mul(10)?.checked_add(u32::from(d - b'0'))
+    })
+}
+
+pub(crate) fn node_matches(node: &[u8], expected: &[u8]) -> bool {
+    if node.eq_ignore_ascii_case(expected) {
+        return true;
+    }
+    let short = expected.iter().filter(|b| !b.is_ascii_lowercase()).copied();
+    node.len() == short.clone().count()
+        && node
+            .iter()
+            .zip(short)
+            .all(|(a, b)| a.eq_ignore_ascii_case(&b))
+}
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+static ECHO: AtomicBool = AtomicBool::new(false);
+
+/// Whether query replies name things in long form, `VOLTAGE:DC`, rather than the terse
+/// `VOLT:DC` SCPI defaults to. Set by the host with `SYSTem:COMMunicate:VERBose`.
+pub fn verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+/// Whether each answer is preceded by the query it answers and ` -> `, for reading a
+/// transcript while debugging. Set by the host with `SYSTem:COMMunicate:ECHO`.
+pub fn echo() -> bool {
+    ECHO.load(Ordering::Relaxed)
+}
+
+pub fn set_echo(echo: bool) {
+    ECHO.store(echo, Ordering::Relaxed);
+}
+
+/// Renders `mnemonic`, written with its short form in capitals (`VOLTage:DC`), as a reply
+/// should show it under the current [`verbose`] setting: `VOLT:DC` or `VOLTAGE:DC`. Returns
+/// the length written to `out`, cut short if `out` is.
+pub fn render_mnemonic(mnemonic: &[u8], out: &mut [u8]) -> usize {
+    let verbose = verbose();
+    let rendered = mnemonic
+        .iter()
+        .filter(|b| verbose || !b.is_ascii_lowercase())
+        .map(u8::to_ascii_uppercase);
+    let mut len = 0;
+    for (slot, b) in out.iter_mut().zip(rendered) {
+        *slot = b;
+        len += 1;
+    }
+    len
+}
+
+/// [`InlineHandler`](crate::InlineHandler) answering `SYSTem:VERSion?` and
+/// `SYSTem:CAPability?` in the runner, so conformance checkers get sane answers without any
+/// application code, and taking `SYSTem:COMMunicate:VERBose` and `SYSTem:COMMunicate:ECHO`
+/// (`ON`/`OFF`/`1`/`0`, and their queries). Anything else passes through; an application
+/// with its own inline handler can call this first.
+pub fn system_queries(cmd: &[u8], resp: &mut Response) -> Inline {
+    let (header, params) = split_header(cmd);
+    let answer = if header_matches(header, b"SYSTem:VERSion?") {
+        SCPI_VERSION
+    } else if header_matches(header, b"SYSTem:CAPability?") {
+        SCPI_CAPABILITY
+    } else if header_matches(header, b"SYSTem:COMMunicate:VERBose?") {
+        if verbose() { b"1\n" } else { b"0\n" }
+    } else if header_matches(header, b"SYSTem:COMMunicate:ECHO?") {
+        if echo() { b"1\n" } else { b"0\n" }
+    } else if header_matches(header, b"SYSTem:COMMunicate:VERBose") {
+        return set_switch(params, set_verbose);
+    } else if header_matches(header, b"SYSTem:COMMunicate:ECHO") {
+        return set_switch(params, set_echo);
+    } else {
+        return Inline::Pass;
+    };
+    *resp = Response::from_static(answer, resp.token);
+    Inline::Done
+}
+
+fn set_switch(params: &[u8], set: fn(bool)) -> Inline {
+    match Params::new(params).next() {
+        Some(Ok(Token::Chars(word))) if word.eq_ignore_ascii_case(b"ON") => set(true),
+        Some(Ok(Token::Chars(word))) if word.eq_ignore_ascii_case(b"OFF") => set(false),
+        Some(Ok(Token::Numeric(b"1"))) => set(true),
+        Some(Ok(Token::Numeric(b"0"))) => set(false),
+        Some(Err(code)) => push_error(code),
+        _ => push_error(SCPI_ERR_ILLEGAL_PARAMETER),
+    }
+    Inline::Done
+}
@@ -0,0 +1,114 @@
+//! Running a command tree written for the [`scpi`](https://docs.rs/scpi) crate on this class,
+//! so firmware already built on that crate's `Node`/`Device` types needs no glue of its own.
+//!
+//! [`serve`] takes program messages off the command queue, runs each through the tree and
+//! queues whatever it formatted as the answer, with the query's token. The crate is a
+//! dependency under the name `scpi_rs`, to keep it apart from this crate's own
+//! [`scpi`](crate::scpi) module.
+//!
+//! The two halves of the status model the crate leaves to the device are mapped onto ours:
+//!
+//! - Errors: [`report`] puts an error from the tree into the class's error queue, where
+//!   `SYSTem:ERRor?` and the Error/Event Available bit find it alongside the ones the class
+//!   raises itself. Call it from `Device::handle_error`; [`serve`] reports the errors the
+//!   tree returns the same way.
+//! - Status byte: after every message [`serve`] reads the device's summary bits and `*SRE`
+//!   mask through [`TmcStatus`] and hands them to [`srq`](crate::srq), which keeps RQS/MSS
+//!   and requests service. The device's `*STB?` should answer
+//!   [`srq::status_byte`](crate::srq::status_byte), and its `*ESR?` fold in
+//!   [`take_event_status`](crate::take_event_status).
+//!
+//! Written against scpi 1.0 with its `arrayvec` formatter.
+//!
+//! ```ignore
+//! use embassy_usbtmc::scpi_tree;
+//!
+//! struct Meter { esr: u8, sre: u8 }
+//!
+//! impl scpi::Device for Meter {
+//!     fn handle_error(&mut self, err: scpi::error::Error) {
+//!         scpi_tree::report(&err);
+//!     }
+//! }
+//!
+//! impl scpi_tree::TmcStatus for Meter {
+//!     fn status_summary(&mut self) -> u8 {
+//!         if self.esr != 0 { 1 << 5 } else { 0 }
+//!     }
+//!     fn service_request_enable(&mut self) -> u8 {
+//!         self.sre
+//!     }
+//! }
+//!
+//! scpi_tree::serve(&TREE, &mut Meter { esr: 0, sre: 0 }).await
+//! ```
+
+use arrayvec::ArrayVec;
+use heapless::Vec;
+use scpi_rs::error::Error;
+use scpi_rs::tree::Node;
+use scpi_rs::{Context, Device};
+
+use crate::scpi::{error_count, push_error};
+use crate::{MAX_SCPI_LEN, RESP_CHANNEL, Response, next_command, srq};
+
+/// Error/Event Available, bit 2 of the status byte.
+const STB_EAV: u8 = 1 << 2;
+
+/// The status a device built on the scpi crate keeps and the class needs for serial polls and
+/// service requests.
+pub trait TmcStatus {
+    /// The device's status byte summary bits: MAV, ESB, the OPERation and QUEStionable
+    /// summaries. Error/Event Available is added from the class's error queue; bit 6 is
+    /// ignored.
+    fn status_summary(&mut self) -> u8;
+
+    /// The `*SRE` mask.
+    fn service_request_enable(&mut self) -> u8;
+}
+
+/// Puts `err` into the class's error queue under its SCPI code.
+pub fn report(err: &Error) {
+    push_error(err.get_code());
+}
+
+/// Runs every command through `tree` and answers it. Never returns; spawn it in its own task.
+///
+/// A query is always answered, with an empty message when the tree wrote nothing (it failed,
+/// or the handler had nothing to say), so the host's read for it ends. An answer longer than
+/// [`MAX_SCPI_LEN`] fails in the formatter, and the tree's error goes to the queue like any
+/// other.
+pub async fn serve<D>(tree: &Node<'_, D>, device: &mut D) -> !
+where
+    D: Device + TmcStatus,
+{
+    let mut context = Context::default();
+    loop {
+        let (cmd, _) = next_command().await;
+        let mut out = ArrayVec::<u8, MAX_SCPI_LEN>::new();
+        if let Err(err) = tree.run(&cmd.data[..cmd.len], device, &mut context, &mut out) {
+            report(&err);
+        }
+        if cmd.token.is_some() || !out.is_empty() {
+            let mut resp = Response {
+                len: out.len(),
+                data: [0; MAX_SCPI_LEN],
+                segments: Vec::new(),
+                eom: true,
+                term_char_matched: false,
+                raw: false,
+                token: cmd.token,
+            };
+            resp.data[..out.len()].copy_from_slice(&out);
+            RESP_CHANNEL.send(resp).await;
+        }
+        sync_status(device);
+    }
+}
+
+/// Hands the device's status byte to the class, so serial polls and service requests see it.
+fn sync_status<D: TmcStatus>(device: &mut D) {
+    let eav = if error_count() > 0 { STB_EAV } else { 0 };
+    srq::set_service_request_enable(device.service_request_enable());
+    srq::set_status_byte(device.status_summary() | eav);
+}
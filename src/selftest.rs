@@ -0,0 +1,116 @@
+//! `*TST?` backed by self-tests that firmware modules [`register`] at start-up, instead of a
+//! stub that always answers 0.
+//!
+//! `*TST?` runs every registered test in order and answers a bit mask of the ones that
+//! failed, bit 0 for the first registered, so 0 still means all passed. Each failure leaves
+//! the test's own error code and [`SCPI_ERR_SELF_TEST`] in the error queue; the names and
+//! outcomes of the last run stay readable through [`last_results`].
+
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant};
+use heapless::{String, Vec};
+
+use crate::scpi::{SCPI_ERR_SELF_TEST, push_error};
+use crate::{Command, MAX_SCPI_LEN, RESP_CHANNEL, Response};
+
+/// Most tests the registry holds, one per bit of the `*TST?` answer.
+pub const MAX_SELF_TESTS: usize = 16;
+
+/// One module's self-test.
+#[derive(Clone, Copy)]
+pub struct SelfTest {
+    pub name: &'static str,
+    /// Checks the hardware and returns a SCPI error code on failure, e.g.
+    /// [`SCPI_ERR_HARDWARE_MISSING`](crate::SCPI_ERR_HARDWARE_MISSING). Runs in the task
+    /// answering `*TST?`, so it must not wait on that task.
+    pub run: fn() -> Result<(), i16>,
+    /// Longest the test may take. One that passes but overruns still fails, as a hint that it
+    /// is close to hanging.
+    pub limit: Duration,
+}
+
+/// How one test did in the last run.
+#[derive(Clone, Copy)]
+pub struct SelfTestResult {
+    pub name: &'static str,
+    /// `Ok` or the failure's error code; an overrun is [`SCPI_ERR_SELF_TEST`].
+    pub outcome: Result<(), i16>,
+    pub elapsed: Duration,
+}
+
+static TESTS: Mutex<CriticalSectionRawMutex, RefCell<Vec<SelfTest, MAX_SELF_TESTS>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+static RESULTS: Mutex<CriticalSectionRawMutex, RefCell<Vec<SelfTestResult, MAX_SELF_TESTS>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// Adds a test to the registry. Returns it back if the registry is full.
+pub fn register(test: SelfTest) -> Result<(), SelfTest> {
+    TESTS.lock(|tests| tests.borrow_mut().push(test))
+}
+
+/// Runs every registered test and returns the mask of failures. `feed_watchdog` is called
+/// before each test, so a test that hangs trips the hardware watchdog instead of wedging the
+/// instrument for good.
+pub fn run_self_tests(feed_watchdog: &mut dyn FnMut()) -> u16 {
+    let tests = TESTS.lock(|tests| tests.borrow().clone());
+    let mut results = Vec::<SelfTestResult, MAX_SELF_TESTS>::new();
+    let mut failed = 0;
+    for (i, test) in tests.iter().enumerate() {
+        feed_watchdog();
+        let start = Instant::now();
+        let mut outcome = (test.run)();
+        let elapsed = start.elapsed();
+        if outcome.is_ok() && elapsed > test.limit {
+            outcome = Err(SCPI_ERR_SELF_TEST);
+        }
+        if let Err(code) = outcome {
+            failed |= 1 << i;
+            if code != SCPI_ERR_SELF_TEST {
+                push_error(code);
+            }
+            push_error(SCPI_ERR_SELF_TEST);
+        }
+        let _ = results.push(SelfTestResult {
+            name: test.name,
+            outcome,
+            elapsed,
+        });
+    }
+    RESULTS.lock(|last| *last.borrow_mut() = results);
+    failed
+}
+
+/// Results of the last run, in registration order.
+pub fn last_results() -> Vec<SelfTestResult, MAX_SELF_TESTS> {
+    RESULTS.lock(|last| last.borrow().clone())
+}
+
+/// Answers `*TST?`. `None` if `cmd` is something else.
+pub async fn serve(cmd: &Command, feed_watchdog: &mut dyn FnMut()) -> Option<()> {
+    if !cmd.data[..cmd.len]
+        .trim_ascii()
+        .eq_ignore_ascii_case(b"*TST?")
+    {
+        return None;
+    }
+    let failed = run_self_tests(feed_watchdog);
+    let mut resp = Response {
+        len: 0,
+        data: [0; MAX_SCPI_LEN],
+        segments: Vec::new(),
+        eom: true,
+        term_char_matched: false,
+        raw: false,
+        token: cmd.token,
+    };
+    let mut text = String::<8>::new();
+    let _ = writeln!(text, "{failed}");
+    resp.data[..text.len()].copy_from_slice(text.as_bytes());
+    resp.len = text.len();
+    RESP_CHANNEL.send(resp).await;
+    Some(())
+}
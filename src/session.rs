@@ -0,0 +1,83 @@
+//! Controller sessions on the USB link, so the application can scope state to the
+//! controller that created it: clear the error queue for a new one, drop a lock or a
+//! half-set-up sweep when one ends.
+//!
+//! A session starts with the first Bulk-OUT message after the device is configured or the
+//! last session ended, and ends at the first of:
+//!
+//! - SET_INTERFACE on the USBTMC interface, which hosts send when they claim it afresh;
+//! - INITIATE_CLEAR, the device clear every VISA open and `viClear` does;
+//! - the host going away, see [`HostLoss`].
+//!
+//! embassy-usb answers CLEAR_FEATURE(ENDPOINT_HALT) itself without telling the class, so a
+//! host that only clears the bulk halts on open isn't seen; its first message after an
+//! earlier session ended still starts a new one.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+
+use crate::HostLoss;
+use crate::events::{self, ClassEvent};
+
+/// Identifies a session; the first is 1 and each new one counts up.
+pub type SessionId = u32;
+
+/// What ended a session.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    /// SET_INTERFACE on the USBTMC interface.
+    Reselected,
+    /// INITIATE_CLEAR.
+    DeviceClear,
+    HostLost(HostLoss),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    Started(SessionId),
+    Ended(SessionId, SessionEnd),
+}
+
+/// Events the application hasn't taken yet. Past this many the newest are dropped.
+const MAX_PENDING_EVENTS: usize = 4;
+
+const NO_SESSION: SessionId = 0;
+
+static CURRENT: AtomicU32 = AtomicU32::new(NO_SESSION);
+static LAST: AtomicU32 = AtomicU32::new(NO_SESSION);
+static EVENTS: Channel<CriticalSectionRawMutex, SessionEvent, MAX_PENDING_EVENTS> = Channel::new();
+
+/// The session in progress, if any.
+pub fn current() -> Option<SessionId> {
+    match CURRENT.load(Ordering::Relaxed) {
+        NO_SESSION => None,
+        id => Some(id),
+    }
+}
+
+/// Waits for the next session to start or end.
+pub async fn next_event() -> SessionEvent {
+    EVENTS.receive().await
+}
+
+/// Runner: a Bulk-OUT message arrived. Starts a session if none is in progress.
+pub(crate) fn note_message() {
+    if CURRENT.load(Ordering::Relaxed) != NO_SESSION {
+        return;
+    }
+    let id = LAST.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+    CURRENT.store(id, Ordering::Relaxed);
+    let _ = EVENTS.try_send(SessionEvent::Started(id));
+    events::publish(ClassEvent::SessionStarted(id));
+}
+
+/// Ends the session in progress, if any.
+pub(crate) fn end(why: SessionEnd) {
+    let id = CURRENT.swap(NO_SESSION, Ordering::Relaxed);
+    if id != NO_SESSION {
+        let _ = EVENTS.try_send(SessionEvent::Ended(id, why));
+        events::publish(ClassEvent::SessionEnded(id, why));
+    }
+}
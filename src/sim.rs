@@ -0,0 +1,337 @@
+//! A simulated instrument that answers the whole command set with synthetic data, for host
+//! software to be developed against the device before the measurement firmware exists.
+//!
+//! [`SimInstrument`] is a voltage source wired to its own meter: the source ramps towards
+//! its setpoint at the slew rate while the output is on, and readings are the present level
+//! plus noise. It keeps the IEEE 488.2 status registers, answers the common commands and
+//! reports malformed commands through the error queue like the real thing would.
+//!
+//! | Command | Does |
+//! |---|---|
+//! | `[SOURce:]VOLTage[:LEVel] <v>\|MIN\|MAX\|DEF`, `?` | Output setpoint |
+//! | `[SOURce:]VOLTage:SLEW <v/s>`, `?` | Ramp rate |
+//! | `OUTPut[:STATe] ON\|OFF`, `?` | Output enable |
+//! | `MEASure:VOLTage[:DC]?`, `READ?`, `FETCh?` | A reading; `FETCh?` repeats the last one |
+//! | `MEASure:CURRent[:DC]?` | Reading through the simulated load |
+//!
+//! plus `*IDN?`, `*RST`, `*CLS`, `*ESE`, `*ESR?`, `*SRE`, `*STB?`, `*OPC`, `*WAI`, `*TST?`
+//! (backed by [`selftest`](crate::selftest)) and `SYSTem:ERRor?`.
+
+use core::fmt::Write;
+
+use embassy_time::Instant;
+use heapless::Vec;
+
+use crate::clock;
+use crate::params::{BodyWriter, Limits, Params, Token, Unit, split_header};
+use crate::scpi::{
+    SCPI_ERR_DATA_TYPE, SCPI_ERR_ILLEGAL_PARAMETER, SCPI_ERR_PARAMETER_NOT_ALLOWED,
+    SCPI_ERR_UNDEFINED_HEADER, error_count, header_matches, pop_error, push_error,
+};
+use crate::selftest::run_self_tests;
+use crate::{Command, MAX_SCPI_LEN, RESP_CHANNEL, Response, cmd_receiver, take_event_status};
+
+/// Operation Complete bit of the Standard Event Status Register.
+const ESR_OPC: u8 = 1 << 0;
+const ESR_DDE: u8 = 1 << 3;
+const ESR_EXE: u8 = 1 << 4;
+const ESR_CME: u8 = 1 << 5;
+
+const STB_EAV: u8 = 1 << 2;
+const STB_ESB: u8 = 1 << 5;
+const STB_MSS: u8 = 1 << 6;
+
+/// How the simulated instrument behaves.
+#[derive(Clone, Copy)]
+pub struct SimConfig {
+    /// The whole `*IDN?` answer, without the newline.
+    pub idn: &'static str,
+    /// Output range, volts.
+    pub voltage: Limits,
+    /// Slew rate after `*RST`, volts per second.
+    pub slew: f32,
+    /// Peak noise added to each reading, volts.
+    pub noise: f32,
+    /// Load the output drives, for `MEASure:CURRent?`.
+    pub load_ohms: f32,
+    /// Seeds the noise, so a run can be repeated exactly.
+    pub seed: u32,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            idn: "YourCompany,SIM-1,0,FW0.0",
+            voltage: Limits {
+                min: 0.0,
+                max: 30.0,
+                default: 0.0,
+                unit: Some(Unit::Volt),
+            },
+            slew: 10.0,
+            noise: 0.001,
+            load_ohms: 100.0,
+            seed: 0x2545_f491,
+        }
+    }
+}
+
+/// The simulated instrument. [`run`](Self::run) takes over the command queue; an application
+/// that implements some commands itself hands the rest to [`serve`](Self::serve).
+pub struct SimInstrument {
+    config: SimConfig,
+    setpoint: f32,
+    slew: f32,
+    output: bool,
+    /// Level the present ramp started from, and when.
+    ramp_from: f32,
+    ramp_start: Instant,
+    last_reading: f32,
+    rng: u32,
+    esr: u8,
+    ese: u8,
+    sre: u8,
+}
+
+impl SimInstrument {
+    pub fn new(config: SimConfig) -> Self {
+        let mut sim = Self {
+            config,
+            setpoint: 0.0,
+            slew: 0.0,
+            output: false,
+            ramp_from: 0.0,
+            ramp_start: clock::now(),
+            last_reading: 0.0,
+            rng: config.seed.max(1),
+            esr: 0,
+            ese: 0,
+            sre: 0,
+        };
+        sim.reset();
+        sim
+    }
+
+    /// `*RST`: output off, setpoint and slew rate back to their defaults.
+    pub fn reset(&mut self) {
+        self.output = false;
+        self.setpoint = self.config.voltage.default;
+        self.slew = self.config.slew;
+        self.ramp_from = 0.0;
+        self.ramp_start = clock::now();
+    }
+
+    /// The output level now, partway along its ramp.
+    pub fn level(&self) -> f32 {
+        let target = if self.output { self.setpoint } else { 0.0 };
+        let seconds = (clock::now() - self.ramp_start).as_micros() as f32 / 1e6;
+        let step = self.slew * seconds;
+        if (target - self.ramp_from).abs() <= step {
+            target
+        } else if target > self.ramp_from {
+            self.ramp_from + step
+        } else {
+            self.ramp_from - step
+        }
+    }
+
+    /// Answers commands from the queue. Never returns; spawn it in its own task.
+    pub async fn run(&mut self) -> ! {
+        let cmd_rx = cmd_receiver();
+        loop {
+            let cmd = cmd_rx.receive().await;
+            self.serve(&cmd).await;
+        }
+    }
+
+    /// Executes one command. Errors go to the error queue, and raise the matching ESR bit.
+    pub async fn serve(&mut self, cmd: &Command) {
+        let mut resp = Response {
+            len: 0,
+            data: [0; MAX_SCPI_LEN],
+            segments: Vec::new(),
+            eom: true,
+            term_char_matched: false,
+            raw: false,
+            token: cmd.token,
+        };
+        match self.execute(&cmd.data[..cmd.len], &mut resp) {
+            Ok(()) if resp.len > 0 => RESP_CHANNEL.send(resp).await,
+            Ok(()) => {}
+            Err(code) => {
+                push_error(code);
+                self.esr |= match code {
+                    -199..=-100 => ESR_CME,
+                    -299..=-200 => ESR_EXE,
+                    _ => ESR_DDE,
+                };
+            }
+        }
+    }
+
+    fn execute(&mut self, message: &[u8], resp: &mut Response) -> Result<(), i16> {
+        let (header, params) = split_header(message);
+        let out = &mut BodyWriter(resp);
+        self.esr |= take_event_status();
+
+        if header.eq_ignore_ascii_case(b"*IDN?") {
+            let _ = writeln!(out, "{}", self.config.idn);
+        } else if header.eq_ignore_ascii_case(b"*RST") {
+            self.reset();
+        } else if header.eq_ignore_ascii_case(b"*CLS") {
+            while pop_error().is_some() {}
+            self.esr = 0;
+        } else if header.eq_ignore_ascii_case(b"*ESE") {
+            self.ese = register(params)?;
+        } else if header.eq_ignore_ascii_case(b"*ESE?") {
+            let _ = writeln!(out, "{}", self.ese);
+        } else if header.eq_ignore_ascii_case(b"*ESR?") {
+            let _ = writeln!(out, "{}", self.esr);
+            self.esr = 0;
+        } else if header.eq_ignore_ascii_case(b"*SRE") {
+            self.sre = register(params)? & !STB_MSS;
+        } else if header.eq_ignore_ascii_case(b"*SRE?") {
+            let _ = writeln!(out, "{}", self.sre);
+        } else if header.eq_ignore_ascii_case(b"*STB?") {
+            let _ = writeln!(out, "{}", self.status_byte());
+        } else if header.eq_ignore_ascii_case(b"*OPC") {
+            // Commands run one at a time, so everything before this one is done.
+            self.esr |= ESR_OPC;
+        } else if header.eq_ignore_ascii_case(b"*OPC?") {
+            let _ = writeln!(out, "1");
+        } else if header.eq_ignore_ascii_case(b"*WAI") {
+            // Nothing is ever pending.
+        } else if header.eq_ignore_ascii_case(b"*TST?") {
+            let _ = writeln!(out, "{}", run_self_tests(&mut || {}));
+        } else if header_matches(header, b"SYSTem:ERRor?")
+            || header_matches(header, b"SYSTem:ERRor:NEXT?")
+        {
+            let _ = match pop_error() {
+                Some(code) => writeln!(out, "{code},\"Error\""),
+                None => writeln!(out, "0,\"No error\""),
+            };
+        } else if matches_any(header, &[b"SOURce:VOLTage", b"SOURce:VOLTage:LEVel"]) {
+            if is_query(header) {
+                if !self.config.voltage.answer_query(params, out.0)? {
+                    let _ = writeln!(out, "{:E}", self.setpoint);
+                }
+            } else {
+                let setpoint = self.config.voltage.resolve(single(params)?)?;
+                self.retarget(|sim| sim.setpoint = setpoint);
+            }
+        } else if matches_any(header, &[b"SOURce:VOLTage:SLEW"]) {
+            if is_query(header) {
+                let _ = writeln!(out, "{:E}", self.slew);
+            } else {
+                let limits = Limits {
+                    min: 0.001,
+                    max: 1000.0,
+                    default: self.config.slew,
+                    unit: None,
+                };
+                let slew = limits.resolve(single(params)?)?;
+                self.retarget(|sim| sim.slew = slew);
+            }
+        } else if header_matches(header, b"OUTPut") || header_matches(header, b"OUTPut:STATe") {
+            let on = match single(params)? {
+                Token::Chars(word) if word.eq_ignore_ascii_case(b"ON") => true,
+                Token::Chars(word) if word.eq_ignore_ascii_case(b"OFF") => false,
+                Token::Numeric(b"1") => true,
+                Token::Numeric(b"0") => false,
+                _ => return Err(SCPI_ERR_ILLEGAL_PARAMETER),
+            };
+            self.retarget(|sim| sim.output = on);
+        } else if header_matches(header, b"OUTPut?") || header_matches(header, b"OUTPut:STATe?") {
+            let _ = writeln!(out, "{}", u8::from(self.output));
+        } else if header_matches(header, b"MEASure:VOLTage?")
+            || header_matches(header, b"MEASure:VOLTage:DC?")
+            || header_matches(header, b"READ?")
+        {
+            let reading = self.read();
+            let _ = writeln!(out, "{reading:E}");
+        } else if header_matches(header, b"FETCh?") {
+            let _ = writeln!(out, "{:E}", self.last_reading);
+        } else if header_matches(header, b"MEASure:CURRent?")
+            || header_matches(header, b"MEASure:CURRent:DC?")
+        {
+            let current = self.read() / self.config.load_ohms;
+            let _ = writeln!(out, "{current:E}");
+        } else {
+            return Err(SCPI_ERR_UNDEFINED_HEADER);
+        }
+        Ok(())
+    }
+
+    /// Changes what the output ramps towards, starting the new ramp from where it is now.
+    fn retarget(&mut self, change: impl FnOnce(&mut Self)) {
+        self.ramp_from = self.level();
+        self.ramp_start = clock::now();
+        change(self);
+    }
+
+    fn read(&mut self) -> f32 {
+        self.last_reading = self.level() + self.config.noise * self.uniform();
+        self.last_reading
+    }
+
+    /// Uniform in [-1, 1], from a xorshift32 generator.
+    fn uniform(&mut self) -> f32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        (self.rng >> 8) as f32 / (1 << 23) as f32 - 1.0
+    }
+
+    fn status_byte(&self) -> u8 {
+        let mut stb = 0;
+        if error_count() > 0 {
+            stb |= STB_EAV;
+        }
+        if self.esr & self.ese != 0 {
+            stb |= STB_ESB;
+        }
+        if stb & self.sre != 0 {
+            stb |= STB_MSS;
+        }
+        stb
+    }
+}
+
+/// Matches `patterns` with or without their `SOURce` root, and with or without a `?`.
+fn matches_any(header: &[u8], patterns: &[&[u8]]) -> bool {
+    let header = header.strip_suffix(b"?").unwrap_or(header);
+    patterns.iter().any(|pattern| {
+        header_matches(header, pattern)
+            || pattern
+                .strip_prefix(b"SOURce:")
+                .is_some_and(|pattern| header_matches(header, pattern))
+    })
+}
+
+fn is_query(header: &[u8]) -> bool {
+    header.ends_with(b"?")
+}
+
+/// The one parameter a setting command takes.
+fn single(params: &[u8]) -> Result<Token<'_>, i16> {
+    let mut params = Params::new(params);
+    let token = params.next().ok_or(SCPI_ERR_ILLEGAL_PARAMETER)??;
+    if params.next().is_some() {
+        return Err(SCPI_ERR_PARAMETER_NOT_ALLOWED);
+    }
+    Ok(token)
+}
+
+/// The 0–255 value of `*ESE` and `*SRE`.
+fn register(params: &[u8]) -> Result<u8, i16> {
+    let limits = Limits {
+        min: 0.0,
+        max: 255.0,
+        default: 0.0,
+        unit: None,
+    };
+    match single(params)? {
+        token @ Token::Numeric(_) => Ok(limits.resolve(token)? as u8),
+        _ => Err(SCPI_ERR_DATA_TYPE),
+    }
+}
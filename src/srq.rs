@@ -0,0 +1,297 @@
+//! USB488 Interrupt-IN notifications: service requests and the answers to READ_STATUS_BYTE.
+//!
+//! The host polls the interrupt endpoint at its own pace, so notifications queue up in
+//! between, up to [`TmcConfig::notification_depth`](crate::TmcConfig::notification_depth).
+//! They coalesce the way USB488 expects:
+//!
+//! - A service request joins one already queued, which then carries the newer status byte:
+//!   the host serial-polls on the first and would find nothing to service on the second.
+//! - Each READ_STATUS_BYTE answer carries the bTag its request was sent with and is never
+//!   merged with another; one for a bTag already queued, as from a retried request, replaces
+//!   it instead of going out twice.
+//!
+//! Anything that still doesn't fit is dropped and counted in
+//! [`TmcStats::notification_overflows`](crate::TmcStats::notification_overflows).
+//!
+//! [`run`] moves the queue onto the endpoint from
+//! [`UsbTmc::take_interrupt_in`](crate::UsbTmc::take_interrupt_in).
+//!
+//! # Status byte
+//!
+//! The application reports its status byte summary bits with [`set_status_byte`] and the
+//! `*SRE` mask with [`set_service_request_enable`]; the class works out bit 6 from them.
+//! It means different things depending on who reads it, as in IEEE 488.1/488.2:
+//!
+//! - MSS, master summary status, is set while any summary bit enabled in SRE is. `*STB?`
+//!   reports it, see [`status_byte`], and reading it clears nothing.
+//! - RQS, request service, is set when MSS rises, together with a service request
+//!   notification. READ_STATUS_BYTE, the USB serial poll, reports RQS and clears it, so a
+//!   second poll shows 0 in bit 6 while MSS, and `*STB?`, still reads 1. RQS also drops if
+//!   MSS does before the host polls.
+//!
+//! The serial poll answer is a snapshot taken when READ_STATUS_BYTE arrives, even when it
+//! goes out later on the interrupt endpoint.
+
+use core::cell::{Cell, RefCell};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_usb::driver::{EndpointError, EndpointIn};
+use heapless::Deque;
+
+use crate::NOTIFICATION_OVERFLOWS;
+
+/// bNotify1 of a service request notification.
+pub const NOTIFY_SRQ: u8 = 0x81;
+/// bNotify1 bit marking a READ_STATUS_BYTE answer; the low 7 bits hold its bTag.
+pub const NOTIFY_STATUS_BYTE: u8 = 0x80;
+
+/// Most notifications the queue can be configured to hold.
+pub const MAX_NOTIFICATIONS: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Notification {
+    /// The device requests service; `status` is its status byte, RQS set.
+    ServiceRequest { status: u8 },
+    /// The answer to the READ_STATUS_BYTE request sent with `b_tag`.
+    StatusByte { b_tag: u8, status: u8 },
+}
+
+impl Notification {
+    /// bNotify1 and bNotify2 as sent on the wire.
+    pub fn encode(self) -> [u8; 2] {
+        match self {
+            Notification::ServiceRequest { status } => [NOTIFY_SRQ, status],
+            Notification::StatusByte { b_tag, status } => {
+                [NOTIFY_STATUS_BYTE | (b_tag & 0x7F), status]
+            }
+        }
+    }
+}
+
+static QUEUE: Mutex<CriticalSectionRawMutex, RefCell<Deque<Notification, MAX_NOTIFICATIONS>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+static DEPTH: AtomicUsize = AtomicUsize::new(MAX_NOTIFICATIONS);
+static QUEUED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static STATUS: Mutex<CriticalSectionRawMutex, Cell<StatusModel>> =
+    Mutex::new(Cell::new(StatusModel {
+        summary: 0,
+        enable: 0,
+        rqs: false,
+    }));
+
+/// Bit 6 of the status byte: RQS in a serial poll, MSS in `*STB?`.
+pub const STB_RQS_MSS: u8 = 1 << 6;
+
+#[derive(Clone, Copy)]
+struct StatusModel {
+    /// Summary bits, bit 6 clear.
+    summary: u8,
+    /// `*SRE`, bit 6 clear.
+    enable: u8,
+    rqs: bool,
+}
+
+impl StatusModel {
+    fn mss(self) -> bool {
+        self.summary & self.enable != 0
+    }
+}
+
+/// Sets the status byte summary bits: the error/event queue, MAV, ESB, the OPERation and
+/// QUEStionable summaries. Bit 6 is ignored.
+pub fn set_status_byte(summary: u8) {
+    update(|status| status.summary = summary & !STB_RQS_MSS);
+}
+
+/// Sets the service request enable mask, as `*SRE` does. Bit 6 is ignored.
+pub fn set_service_request_enable(enable: u8) {
+    update(|status| status.enable = enable & !STB_RQS_MSS);
+}
+
+/// The mask set with [`set_service_request_enable`], for `*SRE?`.
+pub fn service_request_enable() -> u8 {
+    STATUS.lock(Cell::get).enable
+}
+
+/// The status byte as `*STB?` reports it: the summary bits, and MSS in bit 6.
+pub fn status_byte() -> u8 {
+    let status = STATUS.lock(Cell::get);
+    status.summary | if status.mss() { STB_RQS_MSS } else { 0 }
+}
+
+/// The status byte as a serial poll reads it, with RQS in bit 6, which the poll clears.
+pub(crate) fn serial_poll() -> u8 {
+    STATUS.lock(|cell| {
+        let mut status = cell.get();
+        let polled = status.summary | if status.rqs { STB_RQS_MSS } else { 0 };
+        status.rqs = false;
+        cell.set(status);
+        polled
+    })
+}
+
+/// Applies `change` and requests service if it raised MSS.
+fn update(change: impl FnOnce(&mut StatusModel)) {
+    let request = STATUS.lock(|cell| {
+        let mut status = cell.get();
+        let before = status.mss();
+        change(&mut status);
+        let rising = status.mss() && !before;
+        status.rqs = rising || (status.rqs && status.mss());
+        cell.set(status);
+        rising.then_some(status.summary | STB_RQS_MSS)
+    });
+    if let Some(status) = request {
+        push(Notification::ServiceRequest { status });
+    }
+}
+
+pub(crate) fn set_depth(depth: usize) {
+    DEPTH.store(depth.clamp(1, MAX_NOTIFICATIONS), Ordering::Relaxed);
+}
+
+/// Whether a READ_STATUS_BYTE answer is still waiting for the host to poll it.
+pub(crate) fn status_byte_pending() -> bool {
+    QUEUE.lock(|queue| {
+        queue
+            .borrow()
+            .iter()
+            .any(|n| matches!(n, Notification::StatusByte { .. }))
+    })
+}
+
+/// Queues `notification`, coalescing as described in the [module docs](self).
+pub(crate) fn push(notification: Notification) -> bool {
+    let queued = QUEUE.lock(|queue| {
+        let mut queue = queue.borrow_mut();
+        let same = queue
+            .iter_mut()
+            .find(|queued| match (**queued, notification) {
+                (Notification::ServiceRequest { .. }, Notification::ServiceRequest { .. }) => true,
+                (
+                    Notification::StatusByte { b_tag: a, .. },
+                    Notification::StatusByte { b_tag: b, .. },
+                ) => a == b,
+                _ => false,
+            });
+        if let Some(same) = same {
+            *same = notification;
+            return true;
+        }
+        queue.len() < DEPTH.load(Ordering::Relaxed) && queue.push_back(notification).is_ok()
+    });
+    if queued {
+        QUEUED.signal(());
+    } else {
+        NOTIFICATION_OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+    }
+    queued
+}
+
+/// Empties the queue, e.g. when the bus resets.
+pub(crate) fn clear() {
+    QUEUE.lock(|queue| queue.borrow_mut().clear());
+}
+
+/// Sends queued notifications on `ep` as the host polls for them. Never returns; spawn it in
+/// its own task.
+pub async fn run<E: EndpointIn>(ep: &mut E) -> ! {
+    loop {
+        ep.wait_enabled().await;
+        let next = QUEUE.lock(|queue| queue.borrow().front().copied());
+        let Some(notification) = next else {
+            QUEUED.wait().await;
+            continue;
+        };
+        match ep.write(&notification.encode()).await {
+            // Taken off only once sent and unchanged: one lost to a disable goes out again, and
+            // one updated while on the wire goes out again with the newer status byte.
+            Ok(()) => {
+                QUEUE.lock(|queue| {
+                    let mut queue = queue.borrow_mut();
+                    if queue.front() == Some(&notification) {
+                        queue.pop_front();
+                    }
+                });
+            }
+            Err(EndpointError::Disabled) => {}
+            Err(EndpointError::BufferOverflow) => {
+                QUEUE.lock(|queue| queue.borrow_mut().pop_front());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::exclusive;
+
+    fn reset() {
+        set_service_request_enable(0);
+        set_status_byte(0);
+        clear();
+    }
+
+    fn queued() -> Option<Notification> {
+        QUEUE.lock(|queue| queue.borrow_mut().pop_front())
+    }
+
+    #[test]
+    fn serial_poll_clears_rqs_not_mss() {
+        let _guard = exclusive();
+        reset();
+        set_service_request_enable(0x10);
+        set_status_byte(0x10);
+        assert!(queued() == Some(Notification::ServiceRequest { status: 0x50 }));
+
+        assert_eq!(status_byte(), 0x50);
+        assert_eq!(serial_poll(), 0x50);
+        assert_eq!(serial_poll(), 0x10);
+        assert_eq!(status_byte(), 0x50);
+        reset();
+    }
+
+    #[test]
+    fn rqs_rearms_on_a_new_rise() {
+        let _guard = exclusive();
+        reset();
+        set_service_request_enable(0x10);
+        set_status_byte(0x10);
+        assert_eq!(serial_poll(), 0x50);
+        assert!(queued().is_some());
+
+        // MSS stays set: nothing new to request.
+        set_status_byte(0x30);
+        assert!(queued().is_none());
+        assert_eq!(serial_poll(), 0x30);
+
+        // MSS falls and rises again.
+        set_status_byte(0x20);
+        set_status_byte(0x10);
+        assert!(queued() == Some(Notification::ServiceRequest { status: 0x50 }));
+        assert_eq!(serial_poll(), 0x50);
+
+        // A rise through SRE counts too.
+        set_status_byte(0x20);
+        assert_eq!(serial_poll(), 0x20);
+        set_service_request_enable(0x20);
+        assert_eq!(serial_poll(), 0x60);
+        reset();
+    }
+
+    #[test]
+    fn rqs_drops_with_mss() {
+        let _guard = exclusive();
+        reset();
+        set_service_request_enable(0x10);
+        set_status_byte(0x10);
+        set_status_byte(0);
+        assert_eq!(serial_poll(), 0);
+        assert_eq!(status_byte(), 0);
+        reset();
+    }
+}
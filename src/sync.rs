@@ -0,0 +1,16 @@
+//! The atomics the shared-state types are built on: core's in the crate, loom's when
+//! `tests/loom_abort.rs` lifts a type into a loom model, so the synchronization logic can be
+//! model-checked on std:
+//!
+//! ```bash
+//! RUSTFLAGS="--cfg loom" cargo test --release --test loom_abort --target x86_64-unknown-linux-gnu
+//! ```
+//!
+//! Types built only on these, such as [`TransferAbort`](crate::abort::TransferAbort), depend
+//! on nothing else in the crate, so the test includes their file as it is. The crate itself
+//! keeps core's atomics under `--cfg loom`; its statics need the const constructors.
+
+#[cfg(not(all(loom, test)))]
+pub(crate) use core::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+#[cfg(all(loom, test))]
+pub(crate) use loom::sync::atomic::{AtomicU16, AtomicU32, Ordering};
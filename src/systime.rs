@@ -0,0 +1,208 @@
+//! `SYSTem:TIME` and `SYSTem:DATE` over an application [`WallClock`], and
+//! `SYSTem:TIME:TICK?` for correlating host and instrument timestamps.
+//!
+//! `TICK?` answers the instrument's monotonic clock in microseconds. A host that notes its
+//! own time before sending the query and after reading the answer gets the round trip, and
+//! the midpoint as the host time matching the tick, to within half the round trip.
+//!
+//! Instruments without an RTC use [`MonotonicClock`], which keeps the time the host set on
+//! top of embassy-time.
+
+use core::fmt::Write;
+
+use embassy_time::Instant;
+use heapless::{String, Vec};
+
+use crate::params::{Params, Token, parse_f32, split_header};
+use crate::scpi::{
+    SCPI_ERR_DATA_OUT_OF_RANGE, SCPI_ERR_ILLEGAL_PARAMETER, SCPI_ERR_NUMERIC_DATA,
+    SCPI_ERR_PARAMETER_NOT_ALLOWED, header_matches,
+};
+use crate::{Command, MAX_SCPI_LEN, RESP_CHANNEL, Response, clock};
+
+/// A calendar date and time of day, in whatever time zone the host sets.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00.
+    pub fn to_unix(&self) -> u64 {
+        let days = days_from_civil(
+            i64::from(self.year),
+            u32::from(self.month),
+            u32::from(self.day),
+        );
+        let seconds = u64::from(self.hour) * 3600 + u64::from(self.minute) * 60;
+        days as u64 * 86_400 + seconds + u64::from(self.second)
+    }
+
+    pub fn from_unix(seconds: u64) -> Self {
+        let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+        let rest = seconds % 86_400;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (rest / 3600) as u8,
+            minute: (rest / 60 % 60) as u8,
+            second: (rest % 60) as u8,
+        }
+    }
+}
+
+/// The instrument's real-time clock.
+pub trait WallClock {
+    fn now(&self) -> DateTime;
+    /// Errors are SCPI codes for [`push_error`](crate::push_error).
+    fn set(&mut self, time: DateTime) -> Result<(), i16>;
+}
+
+/// A [`WallClock`] for boards without an RTC: the host sets it, embassy-time keeps it
+/// running. Starts at 1970-01-01 and forgets the time on reset.
+pub struct MonotonicClock {
+    unix_at_set: u64,
+    set_at: Instant,
+}
+
+impl MonotonicClock {
+    pub const fn new() -> Self {
+        Self {
+            unix_at_set: 0,
+            set_at: Instant::from_ticks(0),
+        }
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WallClock for MonotonicClock {
+    fn now(&self) -> DateTime {
+        DateTime::from_unix(self.unix_at_set + (clock::now() - self.set_at).as_secs())
+    }
+
+    fn set(&mut self, time: DateTime) -> Result<(), i16> {
+        self.unix_at_set = time.to_unix();
+        self.set_at = clock::now();
+        Ok(())
+    }
+}
+
+/// Handles `SYSTem:TIME[?]`, `SYSTem:DATE[?]` and `SYSTem:TIME:TICK?`. `None` if `cmd` is
+/// something else; otherwise the outcome, with an error ready for
+/// [`push_error`](crate::push_error). Query answers are queued for the host.
+pub async fn serve<C: WallClock>(clock: &mut C, cmd: &Command) -> Option<Result<(), i16>> {
+    // Read first, so the tick is as close to the command's arrival as it gets.
+    let tick = clock::now().as_micros();
+    let (header, params) = split_header(&cmd.data[..cmd.len]);
+    let mut text = String::<24>::new();
+    if header_matches(header, b"SYSTem:TIME:TICK?") {
+        let _ = write!(text, "{tick}");
+    } else if header_matches(header, b"SYSTem:TIME?") {
+        let now = clock.now();
+        let _ = write!(text, "{},{},{}", now.hour, now.minute, now.second);
+    } else if header_matches(header, b"SYSTem:DATE?") {
+        let now = clock.now();
+        let _ = write!(text, "{},{},{}", now.year, now.month, now.day);
+    } else if header_matches(header, b"SYSTem:TIME") {
+        return Some(set_fields(
+            clock,
+            params,
+            [(0, 23), (0, 59), (0, 59)],
+            |now, [h, m, s]| DateTime {
+                hour: h as u8,
+                minute: m as u8,
+                second: s as u8,
+                ..now
+            },
+        ));
+    } else if header_matches(header, b"SYSTem:DATE") {
+        return Some(set_fields(
+            clock,
+            params,
+            [(1970, 2099), (1, 12), (1, 31)],
+            |now, [y, m, d]| DateTime {
+                year: y as u16,
+                month: m as u8,
+                day: d as u8,
+                ..now
+            },
+        ));
+    } else {
+        return None;
+    }
+
+    let mut resp = Response {
+        len: 0,
+        data: [0; MAX_SCPI_LEN],
+        segments: Vec::new(),
+        eom: true,
+        term_char_matched: false,
+        raw: false,
+        token: cmd.token,
+    };
+    let _ = text.push('\n');
+    resp.data[..text.len()].copy_from_slice(text.as_bytes());
+    resp.len = text.len();
+    RESP_CHANNEL.send(resp).await;
+    Some(Ok(()))
+}
+
+/// Reads three integer parameters within `ranges` and sets the clock to `update` of them.
+fn set_fields<C: WallClock>(
+    clock: &mut C,
+    params: &[u8],
+    ranges: [(u32, u32); 3],
+    update: impl FnOnce(DateTime, [u32; 3]) -> DateTime,
+) -> Result<(), i16> {
+    let mut params = Params::new(params);
+    let mut fields = [0; 3];
+    for (field, (min, max)) in fields.iter_mut().zip(ranges) {
+        let value = match params.next() {
+            Some(Ok(Token::Numeric(text))) => parse_f32(text).ok_or(SCPI_ERR_NUMERIC_DATA)?,
+            Some(Err(code)) => return Err(code),
+            _ => return Err(SCPI_ERR_ILLEGAL_PARAMETER),
+        };
+        if value < min as f32 || value > max as f32 || value as u32 as f32 != value {
+            return Err(SCPI_ERR_DATA_OUT_OF_RANGE);
+        }
+        *field = value as u32;
+    }
+    if params.next().is_some() {
+        return Err(SCPI_ERR_PARAMETER_NOT_ALLOWED);
+    }
+    clock.set(update(clock.now(), fields))
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date (H. Hinnant's algorithm).
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400) as u32;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + i64::from(doe) - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097) as u32;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = i64::from(yoe) + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,112 @@
+//! SCPI raw socket over embassy-net, feeding the same command/response channels as USBTMC.
+//!
+//! Commands are newline-terminated lines. A line containing `?` expects an answer, so the
+//! server waits for the next response and writes it back before reading on; responses to
+//! commands that arrived over USB stay queued for the USB host.
+
+use embassy_net::Stack;
+use embassy_net::tcp::{Error, TcpSocket};
+use embassy_time::Instant;
+
+use crate::lock::{self, LockReply, Session};
+use crate::{
+    CMD_CHANNEL, Command, MAX_SCPI_LEN, RESP_CHANNEL, SCPI_ERR_COMMAND_PROTECTED,
+    SCPI_ERR_TOO_MUCH_DATA, is_query, push_error, strip_terminator,
+};
+use crate::{arming, clock};
+
+/// IANA port for SCPI raw socket connections.
+pub const SCPI_RAW_PORT: u16 = 5025;
+
+/// Serves one SCPI raw socket client at a time on `port`. Never returns.
+///
+/// `rx_buf` and `tx_buf` become the socket buffers; a few hundred bytes each is plenty for
+/// line-oriented SCPI traffic.
+pub async fn serve(stack: Stack<'_>, port: u16, rx_buf: &mut [u8], tx_buf: &mut [u8]) -> ! {
+    loop {
+        let mut socket = TcpSocket::new(stack, rx_buf, tx_buf);
+        if socket.accept(port).await.is_ok() {
+            let _ = serve_connection(&mut socket).await;
+            lock::release(Session::Tcp);
+        }
+        socket.close();
+        let _ = socket.flush().await;
+    }
+}
+
+async fn serve_connection(socket: &mut TcpSocket<'_>) -> Result<(), Error> {
+    let cmd_tx = CMD_CHANNEL.sender();
+    let resp_rx = RESP_CHANNEL.receiver();
+
+    let mut line = Command {
+        len: 0,
+        data: [0; MAX_SCPI_LEN],
+        token: None,
+        query: false,
+        received: Instant::MIN,
+    };
+    let mut overflow = false;
+    let mut buf = [0u8; 64];
+
+    loop {
+        let n = socket.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+
+        for &byte in &buf[..n] {
+            if line.len < MAX_SCPI_LEN {
+                line.data[line.len] = byte;
+                line.len += 1;
+            } else {
+                overflow = true;
+            }
+            if byte != b'\n' {
+                continue;
+            }
+
+            let lock_reply = if overflow {
+                push_error(SCPI_ERR_TOO_MUCH_DATA);
+                LockReply::Done
+            } else {
+                lock::command(Session::Tcp, &line.data[..line.len])
+            };
+            match lock_reply {
+                LockReply::Done => {}
+                LockReply::Answer(answer) => write_all(socket, answer).await?,
+                LockReply::Refused => push_error(SCPI_ERR_COMMAND_PROTECTED),
+                LockReply::Pass => {
+                    if let Err(code) = arming::check(&line.data[..line.len]) {
+                        push_error(code);
+                        line.len = 0;
+                        continue;
+                    }
+                    let query = is_query(&line.data[..line.len]);
+                    line.len = strip_terminator(&line.data[..line.len]);
+                    line.query = query;
+                    line.received = clock::now();
+                    cmd_tx.send(line.clone()).await;
+                    if query {
+                        let resp = resp_rx.receive().await;
+                        for part in resp.parts() {
+                            write_all(socket, part).await?;
+                        }
+                    }
+                }
+            }
+            line.len = 0;
+            overflow = false;
+        }
+    }
+}
+
+async fn write_all(socket: &mut TcpSocket<'_>, mut data: &[u8]) -> Result<(), Error> {
+    while !data.is_empty() {
+        let n = socket.write(data).await?;
+        if n == 0 {
+            return Err(Error::ConnectionReset);
+        }
+        data = &data[n..];
+    }
+    Ok(())
+}
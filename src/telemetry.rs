@@ -0,0 +1,94 @@
+//! A telemetry stream over vendor-specific IN messages: fixed-size records the application
+//! logs with [`record`], pulled by the host with REQUEST_VENDOR_SPECIFIC_IN at its own pace,
+//! next to and independent of the SCPI message stream.
+//!
+//! Each VENDOR_SPECIFIC_IN carries one batch: the number of records dropped so far (u32 LE),
+//! then up to [`BATCH_RECORDS`] records of a sequence number (u32 LE) and [`RECORD_LEN`]
+//! payload bytes. Sequence numbers count every record logged, dropped or not, so the host
+//! sees exactly where the gaps are. Ask for at least [`MAX_BATCH_LEN`] bytes per request
+//! (plus the CRC-32 trailer under [`TmcConfig::vendor_crc`](crate::TmcConfig::vendor_crc));
+//! a shorter read cuts the batch and loses the records past the cut.
+//!
+//! [`run`] owns the vendor IN direction: don't also send on
+//! [`vendor_sender`](crate::vendor::vendor_sender).
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use heapless::Vec;
+
+use crate::vendor::{CRC_LEN, VENDOR_IN_CHANNEL};
+use crate::{MAX_SCPI_LEN, Response};
+
+/// Payload bytes per record.
+pub const RECORD_LEN: usize = 28;
+
+const SEQ_LEN: usize = 4;
+const DROPPED_LEN: usize = 4;
+
+/// Most records one batch carries.
+pub const BATCH_RECORDS: usize = (MAX_SCPI_LEN - CRC_LEN - DROPPED_LEN) / (SEQ_LEN + RECORD_LEN);
+
+/// Length of a full batch, without the CRC-32 trailer.
+pub const MAX_BATCH_LEN: usize = DROPPED_LEN + BATCH_RECORDS * (SEQ_LEN + RECORD_LEN);
+
+/// Records buffered between the application and the host.
+const BACKLOG: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Record {
+    seq: u32,
+    data: [u8; RECORD_LEN],
+}
+
+static RECORDS: Channel<CriticalSectionRawMutex, Record, BACKLOG> = Channel::new();
+static NEXT_SEQ: AtomicU32 = AtomicU32::new(0);
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Logs a record. Never blocks: when the host falls behind and the backlog is full the
+/// record is dropped and counted in [`dropped_records`].
+pub fn record(data: &[u8; RECORD_LEN]) {
+    let seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+    if RECORDS.try_send(Record { seq, data: *data }).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Records dropped since power-up.
+pub fn dropped_records() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Packs logged records into batches for the host. Never returns; spawn it in its own task.
+///
+/// A batch is built as soon as a record is waiting and holds whatever else has arrived by
+/// then, so a slow host gets full batches and a fast one gets fresh ones.
+pub async fn run() -> ! {
+    loop {
+        let first = RECORDS.receive().await;
+        let mut batch = Response {
+            len: DROPPED_LEN,
+            data: [0; MAX_SCPI_LEN],
+            segments: Vec::new(),
+            eom: true,
+            term_char_matched: false,
+            raw: false,
+            token: None,
+        };
+        batch.data[..DROPPED_LEN].copy_from_slice(&dropped_records().to_le_bytes());
+        let mut next = Some(first);
+        let mut count = 0;
+        while let Some(record) = next {
+            let at = batch.len;
+            batch.data[at..at + SEQ_LEN].copy_from_slice(&record.seq.to_le_bytes());
+            batch.data[at + SEQ_LEN..at + SEQ_LEN + RECORD_LEN].copy_from_slice(&record.data);
+            batch.len += SEQ_LEN + RECORD_LEN;
+            count += 1;
+            next = (count < BATCH_RECORDS)
+                .then(|| RECORDS.try_receive().ok())
+                .flatten();
+        }
+        VENDOR_IN_CHANNEL.send(batch).await;
+    }
+}
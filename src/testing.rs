@@ -0,0 +1,360 @@
+//! Host-side test doubles: the runner on a [`MockTransport`] with the test playing the host,
+//! control requests straight into the handler, and a [`ManualClock`](clock::ManualClock).
+//!
+//! The class keeps its state in statics, so sessions run one at a time; [`session`] takes a
+//! lock, puts the class back to power-up state and polls runner and script together until the
+//! script is done. A session that stops making progress fails instead of hanging the test.
+
+use core::future::Future;
+use core::pin::pin;
+use core::sync::atomic::Ordering;
+use core::task::{Context, Poll, Waker};
+use std::sync::{Mutex as StdMutex, MutexGuard};
+use std::vec::Vec as StdVec;
+
+use embassy_futures::select::{Either, select};
+use embassy_futures::yield_now;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant};
+use embassy_usb::Handler;
+use embassy_usb::control::{InResponse, Request};
+use embassy_usb::types::InterfaceNumber;
+use heapless::Vec;
+
+use crate::clock::{self, ManualClock};
+use crate::control::ControlRequest;
+use crate::header::{BulkInHeader, BulkOutHeader, HEADER_LEN, MsgId, padding};
+use crate::transport::{TmcTransport, TransportError};
+use crate::{
+    CMD_CHANNEL, COMMAND_CRC, Conformance, DEV_DEP_MSG_IN_EOM, DEV_DEP_MSG_OUT_EOM, IN_STAGING_LEN,
+    MAX_PACKET_SIZE, PRIORITY_CHANNEL, RESP_CHANNEL, STRICT, TmcConfig, TmcControlHandler,
+    message_loop, reset_transfers, set_host_quirks, tuning,
+};
+
+/// Full-speed bulk packets.
+pub(crate) const MPS: usize = 64;
+
+/// Polls a session may take before it counts as stalled.
+const POLL_BUDGET: usize = 100_000;
+
+pub(crate) static CLOCK: ManualClock = ManualClock::new(Instant::from_ticks(0));
+
+static SESSION: StdMutex<()> = StdMutex::new(());
+
+type Packet = Vec<u8, MAX_PACKET_SIZE>;
+
+/// The two bulk pipes between the test and the runner.
+pub(crate) struct Link {
+    out: Channel<CriticalSectionRawMutex, Packet, 16>,
+    inp: Channel<CriticalSectionRawMutex, Packet, 80>,
+}
+
+/// The runner's end of the [`Link`].
+pub(crate) struct MockTransport {
+    link: &'static Link,
+}
+
+impl TmcTransport for MockTransport {
+    fn max_packet_size(&self) -> usize {
+        MPS
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, TransportError> {
+        let packet = self.link.out.receive().await;
+        let target = buf
+            .get_mut(..packet.len())
+            .ok_or(TransportError::BufferOverflow)?;
+        target.copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), TransportError> {
+        self.link.inp.send(Vec::from_slice(data).unwrap()).await;
+        Ok(())
+    }
+}
+
+/// A Bulk-IN transfer as the host read it.
+pub(crate) struct Reply {
+    pub header: BulkInHeader,
+    /// The whole transfer: header, data and padding.
+    pub raw: StdVec<u8>,
+}
+
+impl Reply {
+    /// The message data the header announces.
+    pub fn data(&self) -> &[u8] {
+        &self.raw[HEADER_LEN..HEADER_LEN + self.header.transfer_size as usize]
+    }
+
+    pub fn eom(&self) -> bool {
+        self.header.attributes & DEV_DEP_MSG_IN_EOM != 0
+    }
+}
+
+/// What the host put on or took off the bus, in the order it did, as `host/tmc.py` taps it.
+pub(crate) enum Traffic {
+    /// A Bulk-OUT transfer.
+    Out(StdVec<u8>),
+    /// A Bulk-IN transfer, or a packet drained after an abort or clear.
+    In(StdVec<u8>),
+    /// A class request's setup packet and the answer it got.
+    Ctrl(StdVec<u8>),
+}
+
+/// The test's end of the [`Link`], and the host side of the control pipe.
+pub(crate) struct Host {
+    link: &'static Link,
+    handler: TmcControlHandler,
+    b_tag: u8,
+    /// Every transfer and class request made through the methods below, but [`send`](Self::send).
+    pub traffic: StdVec<Traffic>,
+}
+
+impl Host {
+    /// The bTag the next transfer goes out with, counting 1..=255 like VISA.
+    pub fn next_tag(&mut self) -> u8 {
+        self.b_tag = self.b_tag % 255 + 1;
+        self.b_tag
+    }
+
+    /// Sends one Bulk-OUT packet as it is.
+    pub async fn send(&self, packet: &[u8]) {
+        self.link.out.send(Vec::from_slice(packet).unwrap()).await;
+    }
+
+    /// Bulk-OUT packets the runner hasn't read yet.
+    pub fn unread(&self) -> usize {
+        self.link.out.len()
+    }
+
+    /// Drops the Bulk-OUT packets the runner hasn't read, as CLEAR_FEATURE(ENDPOINT_HALT)
+    /// flushes the endpoint.
+    pub fn flush_out(&self) {
+        self.link.out.clear();
+    }
+
+    /// Sends `data` as one DEV_DEP_MSG_OUT with EOM. Returns its bTag.
+    pub async fn write(&mut self, data: &[u8]) -> u8 {
+        self.write_message(data, true).await
+    }
+
+    /// Sends `data` as one DEV_DEP_MSG_OUT, ending the message if `eom`. Returns its bTag.
+    pub async fn write_message(&mut self, data: &[u8], eom: bool) -> u8 {
+        let b_tag = self.next_tag();
+        let attributes = if eom { DEV_DEP_MSG_OUT_EOM } else { 0 };
+        let header = out_header(MsgId::DevDepMsgOut, b_tag, data.len() as u32, attributes);
+        let mut transfer = StdVec::from(header);
+        transfer.extend_from_slice(data);
+        transfer.resize(transfer.len() + padding(data.len()), 0);
+        for packet in transfer.chunks(MPS) {
+            self.send(packet).await;
+        }
+        self.traffic.push(Traffic::Out(transfer));
+        b_tag
+    }
+
+    /// Sends REQUEST_DEV_DEP_MSG_IN for up to `max` bytes. Returns its bTag.
+    pub async fn request_read(&mut self, max: u32) -> u8 {
+        let b_tag = self.next_tag();
+        let header = out_header(MsgId::RequestDevDepMsgIn, b_tag, max, 0);
+        self.send(&header).await;
+        self.traffic.push(Traffic::Out(header.to_vec()));
+        b_tag
+    }
+
+    /// Reads Bulk-IN packets up to the short packet that ends a transfer.
+    pub async fn read_transfer(&mut self) -> Reply {
+        let mut raw = StdVec::new();
+        loop {
+            let packet = self.link.inp.receive().await;
+            raw.extend_from_slice(&packet);
+            if packet.len() < MPS {
+                break;
+            }
+        }
+        self.traffic.push(Traffic::In(raw.clone()));
+        let header = BulkInHeader::parse(&raw).ok().expect("bad Bulk-IN header");
+        Reply { header, raw }
+    }
+
+    /// [`read_transfer`](Self::read_transfer), or `None` if nothing comes within the 5 s a
+    /// host waits by default. The clock moves on by that much; a transfer the runner sends
+    /// later stays on the pipe for the next read, as it would in the device's FIFO.
+    pub async fn try_read_transfer(&mut self) -> Option<Reply> {
+        self.idle().await;
+        if self.link.inp.is_empty() {
+            CLOCK.advance(Duration::from_secs(5));
+            self.idle().await;
+        }
+        if self.link.inp.is_empty() {
+            return None;
+        }
+        Some(self.read_transfer().await)
+    }
+
+    /// Takes what the runner has sent up to a short packet, as a host drains Bulk-IN during a
+    /// clear or an abort.
+    pub fn drain_in(&mut self) {
+        while let Ok(packet) = self.link.inp.try_receive() {
+            self.traffic.push(Traffic::In(packet.to_vec()));
+            if packet.len() < MPS {
+                break;
+            }
+        }
+    }
+
+    /// A read as a host makes it: the request, then the transfer that answers it.
+    pub async fn read(&mut self, max: u32) -> Reply {
+        let b_tag = self.request_read(max).await;
+        let reply = self.read_transfer().await;
+        assert_eq!(reply.header.b_tag, b_tag);
+        reply
+    }
+
+    /// Sends a class request to the interface, or the bulk endpoint for the abort requests,
+    /// and returns the data stage, `None` if it was rejected or not handled.
+    pub fn control(
+        &mut self,
+        request: ControlRequest,
+        value: u16,
+        length: u16,
+    ) -> Option<StdVec<u8>> {
+        self.control_at(request, value, 0, length)
+    }
+
+    /// [`control`](Self::control) with `index` as wIndex: the interface number, or the
+    /// endpoint address for the abort requests.
+    pub fn control_at(
+        &mut self,
+        request: ControlRequest,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Option<StdVec<u8>> {
+        // Device-to-host, class, interface or endpoint.
+        let request_type = if request.to_endpoint() { 0xA2 } else { 0xA1 };
+        let [value_lo, value_hi] = value.to_le_bytes();
+        let [index_lo, index_hi] = index.to_le_bytes();
+        let [length_lo, length_hi] = length.to_le_bytes();
+        let setup = [
+            request_type,
+            request as u8,
+            value_lo,
+            value_hi,
+            index_lo,
+            index_hi,
+            length_lo,
+            length_hi,
+        ];
+        let mut buf = [0u8; 64];
+        let buf = &mut buf[..length as usize];
+        let answer = match self.handler.control_in(Request::parse(&setup), buf)? {
+            InResponse::Accepted(data) => data.to_vec(),
+            InResponse::Rejected => return None,
+        };
+        let mut record = setup.to_vec();
+        record.extend_from_slice(&answer);
+        self.traffic.push(Traffic::Ctrl(record));
+        Some(answer)
+    }
+
+    /// Lets the runner run until it waits on the host again.
+    pub async fn idle(&self) {
+        for _ in 0..64 {
+            yield_now().await;
+        }
+    }
+}
+
+/// A Bulk-OUT header.
+pub(crate) fn out_header(msg_id: MsgId, b_tag: u8, size: u32, attributes: u8) -> [u8; HEADER_LEN] {
+    BulkOutHeader {
+        msg_id,
+        b_tag,
+        reserved: 0,
+        transfer_size: size,
+        msg_specific: [attributes, 0, 0, 0],
+    }
+    .encode()
+}
+
+/// Runs the class with `config` on a fresh link while `script` plays the host and the
+/// application. Panics if the two stop making progress before the script ends.
+pub(crate) fn session<S, F>(config: TmcConfig, script: S)
+where
+    S: FnOnce(Host) -> F,
+    F: Future<Output = ()>,
+{
+    let _one_at_a_time = exclusive();
+    power_up(&config);
+    // Leaked so the script's future can hold the host without borrowing from this frame.
+    let link: &'static Link = Box::leak(Box::new(Link {
+        out: Channel::new(),
+        inp: Channel::new(),
+    }));
+    let host = Host {
+        link,
+        handler: TmcControlHandler {
+            config,
+            caps: config.capabilities(),
+            iface: InterfaceNumber(0),
+        },
+        b_tag: 0,
+        traffic: StdVec::new(),
+    };
+    let mut transport = MockTransport { link };
+    let mut in_staging = [0u8; IN_STAGING_LEN];
+    let runner = message_loop(&mut transport, &config, &mut in_staging);
+    match block_on(select(runner, script(host))) {
+        Either::First(never) => never,
+        Either::Second(()) => {}
+    }
+}
+
+/// Holds off sessions and other tests of the class's statics while the guard lives.
+pub(crate) fn exclusive() -> MutexGuard<'static, ()> {
+    SESSION
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Puts the class's shared state back the way a bus reset leaves it.
+fn power_up(config: &TmcConfig) {
+    clock::set_clock(&CLOCK);
+    reset_transfers();
+    CMD_CHANNEL.clear();
+    PRIORITY_CHANNEL.clear();
+    RESP_CHANNEL.clear();
+    crate::vendor::VENDOR_OUT_CHANNEL.clear();
+    crate::vendor::VENDOR_IN_CHANNEL.clear();
+    COMMAND_CRC.store(false, Ordering::Relaxed);
+    set_host_quirks(config.host_quirks);
+    STRICT.store(config.conformance == Conformance::Strict, Ordering::Relaxed);
+    tuning::init(config);
+    #[cfg(feature = "scpi")]
+    {
+        while crate::pop_error().is_some() {}
+        crate::set_echo(false);
+    }
+    #[cfg(feature = "ieee4882")]
+    crate::take_event_status();
+    #[cfg(feature = "usb488")]
+    {
+        crate::srq::set_service_request_enable(0);
+        crate::srq::set_status_byte(0);
+        crate::srq::clear();
+    }
+}
+
+fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+    let mut cx = Context::from_waker(Waker::noop());
+    for _ in 0..POLL_BUDGET {
+        if let Poll::Ready(output) = fut.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+    panic!("session stalled: runner and script are both waiting");
+}
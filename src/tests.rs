@@ -0,0 +1,485 @@
+//! Host sessions against the runner, on the doubles in [`testing`](crate::testing).
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::vec::Vec as StdVec;
+
+use embassy_time::Duration;
+
+use crate::control::{CAPABILITIES_LEN, ControlRequest, Status};
+use crate::header::MsgId;
+use crate::testing::{CLOCK, Host, MPS, out_header, session};
+use crate::{
+    CMD_CHANNEL, DEV_DEP_MSG_OUT_EOM, Inline, OverflowPolicy, ProtocolError, RESP_CHANNEL,
+    Response, TmcConfig, next_command, resp_sender,
+};
+
+/// INITIATE_ABORT_BULK_OUT in the middle of a payload: the runner stops reading it, reports
+/// how far it got and takes the next message from its header.
+#[test]
+fn abort_mid_payload() {
+    session(TmcConfig::default(), |mut host| async move {
+        let b_tag = host.next_tag();
+        let mut first = out_header(MsgId::DevDepMsgOut, b_tag, 200, DEV_DEP_MSG_OUT_EOM).to_vec();
+        first.resize(MPS, b'A');
+        host.send(&first).await;
+        host.send(&[b'B'; MPS]).await;
+        host.idle().await;
+        assert_eq!(host.unread(), 0);
+
+        let abort = host.control(ControlRequest::InitiateAbortBulkOut, b_tag.into(), 2);
+        assert_eq!(abort, Some(vec![Status::Success as u8, b_tag]));
+        host.idle().await;
+
+        // The rest of the message was already on its way; the halted pipe leaves it alone.
+        host.send(&[b'C'; MPS]).await;
+        host.idle().await;
+        assert_eq!(host.unread(), 1);
+
+        let check = host
+            .control(ControlRequest::CheckAbortBulkOutStatus, 0, 8)
+            .unwrap();
+        assert_eq!(check[0], Status::Success as u8);
+        let received = (MPS - 12 + MPS) as u32;
+        assert_eq!(check[4..8], received.to_le_bytes());
+        // CLEAR_FEATURE(ENDPOINT_HALT) flushes the endpoint.
+        host.flush_out();
+        assert!(CMD_CHANNEL.is_empty());
+
+        host.write(b"*IDN?\n").await;
+        let (cmd, _) = next_command().await;
+        assert_eq!(&cmd.data[..cmd.len], b"*IDN?");
+        assert!(CMD_CHANNEL.is_empty());
+    });
+}
+
+/// An inline handler that takes `QUIET?` and answers it with nothing.
+fn quiet(cmd: &[u8], _resp: &mut Response) -> Inline {
+    if cmd == b"QUIET?" {
+        Inline::Done
+    } else {
+        Inline::Pass
+    }
+}
+
+/// A query answered inline with an empty body still gets its empty message, and the next
+/// query's answer isn't held back behind it.
+#[test]
+fn inline_empty_answer() {
+    let config = TmcConfig {
+        inline_handler: Some(quiet),
+        ..TmcConfig::default()
+    };
+    session(config, |mut host| async move {
+        host.write(b"QUIET?\n").await;
+        host.write(b"NEXT?\n").await;
+        let (cmd, _) = next_command().await;
+        assert_eq!(&cmd.data[..cmd.len], b"NEXT?");
+        resp_sender()
+            .send(Response::from_static(b"2", cmd.token))
+            .await;
+
+        let quiet = host.read(256).await;
+        assert!(quiet.eom());
+        assert_eq!(quiet.data(), b"");
+        let next = host.read(256).await;
+        assert_eq!(next.data(), b"2\n");
+    });
+}
+
+/// A query dropped because the queue is full takes no token, so later queries are still
+/// answered in turn.
+#[test]
+fn query_lost_to_full_queue() {
+    session(TmcConfig::default(), |mut host| async move {
+        for query in [b"Q1?\n", b"Q2?\n", b"Q3?\n", b"Q4?\n", b"Q5?\n"] {
+            host.write(query).await;
+        }
+        host.idle().await;
+        #[cfg(feature = "scpi")]
+        assert_eq!(crate::pop_error(), Some(crate::SCPI_ERR_INPUT_OVERRUN));
+        for query in [b"Q1?", b"Q2?", b"Q3?", b"Q4?"] {
+            answer(query, b"1").await;
+            assert_eq!(host.read(256).await.data(), b"1\n");
+        }
+
+        host.write(b"LAST?\n").await;
+        answer(b"LAST?", b"2").await;
+        assert_eq!(host.read(256).await.data(), b"2\n");
+    });
+}
+
+/// Answers that arrive in reverse query order are held back and sent in query order.
+#[test]
+fn answers_out_of_order() {
+    session(TmcConfig::default(), |mut host| async move {
+        let stale = crate::stats().stale_responses;
+        for query in [b"Q1?\n", b"Q2?\n", b"Q3?\n"] {
+            host.write(query).await;
+        }
+        let mut tokens = [None; 3];
+        for token in &mut tokens {
+            *token = next_command().await.0.token;
+        }
+        for (token, body) in tokens.into_iter().zip([&b"1"[..], b"2", b"3"]).rev() {
+            resp_sender().send(Response::from_static(body, token)).await;
+        }
+        for body in [b"1\n", b"2\n", b"3\n"] {
+            assert_eq!(host.read(256).await.data(), body);
+        }
+        assert_eq!(crate::stats().stale_responses, stale);
+    });
+}
+
+/// An answer left unread past `response_ttl` is recorded as expired under the bTag its query
+/// came in.
+#[test]
+fn expired_answer_records_query_btag() {
+    let config = TmcConfig {
+        response_ttl: Some(Duration::from_secs(1)),
+        ..TmcConfig::default()
+    };
+    session(config, |mut host| async move {
+        host.write(b"SET 1\n").await;
+        let b_tag = host.write(b"Q?\n").await;
+        host.idle().await;
+        CLOCK.advance(Duration::from_secs(2));
+        host.idle().await;
+        let record = crate::last_protocol_error().unwrap();
+        assert!(record.error == ProtocolError::Expired);
+        assert_eq!(record.b_tag, b_tag);
+    });
+}
+
+/// A query dropped to make room for a newer command is skipped, and the reads get the
+/// answers to the queries that were kept, in turn.
+#[test]
+fn query_evicted_from_full_queue() {
+    let config = TmcConfig {
+        overflow: OverflowPolicy::DropOldest,
+        ..TmcConfig::default()
+    };
+    session(config, |mut host| async move {
+        for query in [b"Q1?\n", b"Q2?\n", b"Q3?\n", b"Q4?\n", b"Q5?\n"] {
+            host.write(query).await;
+        }
+        host.idle().await;
+        #[cfg(feature = "scpi")]
+        assert_eq!(crate::pop_error(), Some(crate::SCPI_ERR_INPUT_OVERRUN));
+        for (query, body) in [
+            (b"Q2?", b"2"),
+            (b"Q3?", b"3"),
+            (b"Q4?", b"4"),
+            (b"Q5?", b"5"),
+        ] {
+            answer(query, body).await;
+        }
+        for body in [b"2\n", b"3\n", b"4\n", b"5\n"] {
+            assert_eq!(host.read(256).await.data(), body);
+        }
+    });
+}
+
+/// With the echo on, a query that finds the response queue full is dropped rather than
+/// answered without its echo; once there is room it is echoed and answered as one message.
+#[cfg(feature = "scpi")]
+#[test]
+fn echo_needs_room() {
+    session(TmcConfig::default(), |mut host| async move {
+        crate::set_echo(true);
+        while resp_sender()
+            .try_send(Response::from_static(b"x", None))
+            .is_ok()
+        {}
+        host.write(b"Q?\n").await;
+        host.idle().await;
+        assert_eq!(crate::pop_error(), Some(crate::SCPI_ERR_INPUT_OVERRUN));
+        assert!(CMD_CHANNEL.is_empty());
+        for _ in 0..RESP_CHANNEL.capacity() {
+            assert_eq!(host.read(256).await.data(), b"x\n");
+        }
+
+        host.write(b"Q?\n").await;
+        answer(b"Q?", b"1").await;
+        assert_eq!(read_message(&mut host, 256).await, b"Q? -> 1\n");
+    });
+}
+
+/// With `vendor_crc`, a vendor-specific read too short for the CRC-32 trailer is refused and
+/// counted; the next one with room gets the message and its trailer.
+#[test]
+fn vendor_read_without_room_for_crc() {
+    let config = TmcConfig {
+        vendor_crc: true,
+        ..TmcConfig::default()
+    };
+    session(config, |mut host| async move {
+        let crc_errors = crate::stats().crc_errors;
+        crate::vendor::vendor_sender()
+            .send(Response::from_static(b"abc", None))
+            .await;
+        let b_tag = host.next_tag();
+        let request = out_header(MsgId::RequestVendorSpecificIn, b_tag, 3, 0);
+        host.send(&request).await;
+        assert!(host.try_read_transfer().await.is_none());
+        assert_eq!(crate::stats().crc_errors, crc_errors + 1);
+        let record = crate::last_protocol_error().unwrap();
+        assert!(record.error == ProtocolError::Crc);
+        assert_eq!(record.b_tag, b_tag);
+
+        let b_tag = host.next_tag();
+        host.send(&out_header(MsgId::RequestVendorSpecificIn, b_tag, 64, 0))
+            .await;
+        let reply = host.read_transfer().await;
+        assert!(reply.header.msg_id == MsgId::VendorSpecificIn);
+        let crc = crate::vendor::crc32(b"abc").to_le_bytes();
+        assert_eq!(reply.data(), [&b"abc"[..], &crc].concat());
+    });
+}
+
+/// A query that doesn't parse goes to the error queue and is answered with an empty
+/// message; the next one is answered as usual.
+#[cfg(feature = "scpi")]
+#[test]
+fn parse_error_answers_empty() {
+    use crate::SCPI_ERR_UNDEFINED_HEADER;
+    use crate::parser::ParsedCommands;
+
+    session(TmcConfig::default(), |mut host| async move {
+        let mut commands = ParsedCommands::new(|message: &[u8]| -> Result<(), i16> {
+            match message {
+                b"GOOD?" => Ok(()),
+                _ => Err(SCPI_ERR_UNDEFINED_HEADER),
+            }
+        });
+        host.write(b"BAD?\n").await;
+        host.write(b"GOOD?\n").await;
+        let good = commands.next().await;
+        assert!(good.query);
+        resp_sender()
+            .send(Response::from_static(b"ok", good.token))
+            .await;
+        assert_eq!(crate::pop_error(), Some(SCPI_ERR_UNDEFINED_HEADER));
+
+        let bad = host.read(256).await;
+        assert!(bad.eom());
+        assert_eq!(bad.data(), b"");
+        assert_eq!(host.read(256).await.data(), b"ok\n");
+    });
+}
+
+/// An answer longer than one transfer, for reads that have to come back for the rest.
+static LONG: [u8; 1500] = [b'x'; 1500];
+
+/// Reads one whole message the way the Linux driver does, with a fixed `max` per read until
+/// EOM.
+async fn read_message(host: &mut Host, max: u32) -> StdVec<u8> {
+    let mut message = StdVec::new();
+    loop {
+        let reply = host.read(max).await;
+        assert!(reply.data().len() <= max as usize);
+        message.extend_from_slice(reply.data());
+        if reply.eom() {
+            return message;
+        }
+    }
+}
+
+/// `linux_usbtmc`: a long answer comes back over as many fixed-size reads as it takes, for
+/// both the 1 KiB and the 4 KiB buffers the driver has used, and the `cat` read after it gets
+/// the empty message that ends the file.
+#[test]
+fn linux_usbtmc_reads() {
+    session(TmcConfig::linux_usbtmc(), |mut host| async move {
+        for max in [1030, 4096] {
+            host.write(b"DUMP?\n").await;
+            answer(b"DUMP?", &LONG).await;
+            let message = read_message(&mut host, max).await;
+            assert_eq!(message.len(), LONG.len() + 1);
+            assert_eq!(message[..LONG.len()], LONG);
+            assert_eq!(message.last(), Some(&b'\n'));
+
+            let eof = host.read(max).await;
+            assert!(eof.eom());
+            assert_eq!(eof.data(), b"");
+        }
+    });
+}
+
+/// `linux_usbtmc`: a query left unanswered is given up after 4 s with an empty message, ahead
+/// of the driver's own 5 s timeout, and its late answer doesn't reach the next read.
+#[test]
+fn linux_usbtmc_unanswered_query() {
+    session(TmcConfig::linux_usbtmc(), |mut host| async move {
+        host.write(b"SLOW?\n").await;
+        let (slow, _) = next_command().await;
+        let b_tag = host.request_read(4096).await;
+        host.idle().await;
+        CLOCK.advance(Duration::from_secs(4));
+        let stand_in = host.read_transfer().await;
+        assert_eq!(stand_in.header.b_tag, b_tag);
+        assert!(stand_in.eom());
+        assert_eq!(stand_in.data(), b"");
+
+        resp_sender()
+            .send(Response::from_static(b"late", slow.token))
+            .await;
+        host.write(b"*IDN?\n").await;
+        answer(b"*IDN?", b"id").await;
+        assert_eq!(host.read(4096).await.data(), b"id\n");
+    });
+}
+
+/// `linux_usbtmc`: the driver's INITIATE_ABORT_BULK_IN on a read still waiting for its answer
+/// ends that read with nothing sent, and the next query is answered as usual.
+#[test]
+fn linux_usbtmc_abort_read() {
+    session(TmcConfig::linux_usbtmc(), |mut host| async move {
+        host.write(b"SLOW?\n").await;
+        let (slow, _) = next_command().await;
+        let b_tag = host.request_read(4096).await;
+        host.idle().await;
+
+        let abort = host.control(ControlRequest::InitiateAbortBulkIn, b_tag.into(), 2);
+        assert_eq!(abort, Some(vec![Status::Success as u8, b_tag]));
+        host.idle().await;
+        let check = host
+            .control(ControlRequest::CheckAbortBulkInStatus, 0, 8)
+            .unwrap();
+        assert_eq!(check[0], Status::Success as u8);
+        assert_eq!(check[1], 0);
+        assert_eq!(check[4..8], 0u32.to_le_bytes());
+
+        resp_sender()
+            .send(Response::from_static(b"late", slow.token))
+            .await;
+        host.write(b"*IDN?\n").await;
+        answer(b"*IDN?", b"id").await;
+        assert_eq!(host.read(4096).await.data(), b"id\n");
+    });
+}
+
+/// pyvisa-py's default chunk size, the transferSize of every `read()` request.
+const PYVISA_CHUNK: u32 = 20 * 1024;
+
+/// pyvisa-py `open_resource`: GET_CAPABILITIES, answered from the config.
+#[test]
+fn pyvisa_open() {
+    let config = TmcConfig::default();
+    session(config, |mut host| async move {
+        let caps = host.control(ControlRequest::GetCapabilities, 0, CAPABILITIES_LEN as u16);
+        let caps = caps.unwrap();
+        assert_eq!(caps[0], Status::Success as u8);
+        assert_eq!(caps, config.capabilities().encode());
+    });
+}
+
+/// pyvisa-py `write` and `read`: the answer is split across requests of the chunk size, EOM
+/// on the last piece only.
+#[test]
+fn pyvisa_write_read() {
+    session(TmcConfig::default(), |mut host| async move {
+        host.write(b"*IDN?\n").await;
+        answer(b"*IDN?", &LONG).await;
+        let message = read_message(&mut host, PYVISA_CHUNK).await;
+        assert_eq!(message.len(), LONG.len() + 1);
+        assert_eq!(message.last(), Some(&b'\n'));
+    });
+}
+
+static CLEARED: AtomicBool = AtomicBool::new(false);
+
+fn note_clear() {
+    CLEARED.store(true, Ordering::Relaxed);
+}
+
+/// pyvisa-py `clear`: INITIATE_CLEAR, CHECK_CLEAR_STATUS until it isn't pending, then
+/// CLEAR_FEATURE(ENDPOINT_HALT) on Bulk-OUT. Both queues are flushed, `on_clear` is called and
+/// the answer to the query it cut off doesn't come back later.
+#[test]
+fn pyvisa_clear() {
+    let config = TmcConfig {
+        on_clear: Some(note_clear),
+        ..TmcConfig::default()
+    };
+    CLEARED.store(false, Ordering::Relaxed);
+    session(config, |mut host| async move {
+        host.write(b"MEAS?\n").await;
+        answer(b"MEAS?", b"1").await;
+        host.write(b"CONF\n").await;
+        host.idle().await;
+
+        let clear = host.control(ControlRequest::InitiateClear, 0, 1);
+        assert_eq!(clear, Some(vec![Status::Success as u8]));
+        let mut status = StdVec::new();
+        for _ in 0..8 {
+            host.idle().await;
+            status = host
+                .control(ControlRequest::CheckClearStatus, 0, 2)
+                .unwrap();
+            if status[0] != Status::Pending as u8 {
+                break;
+            }
+        }
+        assert_eq!(status, [Status::Success as u8, 0]);
+        host.flush_out();
+        assert!(CLEARED.load(Ordering::Relaxed));
+        assert!(CMD_CHANNEL.is_empty());
+        assert!(RESP_CHANNEL.is_empty());
+
+        host.write(b"*IDN?\n").await;
+        answer(b"*IDN?", b"id").await;
+        assert_eq!(host.read(PYVISA_CHUNK).await.data(), b"id\n");
+    });
+}
+
+/// pyvisa-py `read_stb`: READ_STATUS_BYTE without an interrupt endpoint answers with the
+/// status byte in the control response.
+#[cfg(feature = "usb488")]
+#[test]
+fn pyvisa_read_stb() {
+    let config = TmcConfig {
+        bcd_usb488: Some(0x0100),
+        ..TmcConfig::default()
+    };
+    session(config, |mut host| async move {
+        crate::srq::set_status_byte(0x10);
+        let stb = host.control(ControlRequest::ReadStatusByte, 2, 3);
+        assert_eq!(stb, Some(vec![Status::Success as u8, 2, 0x10]));
+    });
+}
+
+/// pyvisa-py read timeout: INITIATE_ABORT_BULK_IN and CHECK_ABORT_BULK_IN_STATUS end the read
+/// with nothing sent, and the answer that comes too late is dropped.
+#[test]
+fn pyvisa_read_timeout() {
+    session(TmcConfig::default(), |mut host| async move {
+        host.write(b"SLOW?\n").await;
+        let (slow, _) = next_command().await;
+        let b_tag = host.request_read(PYVISA_CHUNK).await;
+        host.idle().await;
+
+        let abort = host.control(ControlRequest::InitiateAbortBulkIn, b_tag.into(), 2);
+        assert_eq!(abort, Some(vec![Status::Success as u8, b_tag]));
+        host.idle().await;
+        let check = host
+            .control(ControlRequest::CheckAbortBulkInStatus, 0, 8)
+            .unwrap();
+        assert_eq!(check[..2], [Status::Success as u8, 0]);
+
+        resp_sender()
+            .send(Response::from_static(b"late", slow.token))
+            .await;
+        host.write(b"*IDN?\n").await;
+        answer(b"*IDN?", b"id").await;
+        assert_eq!(host.read(PYVISA_CHUNK).await.data(), b"id\n");
+    });
+}
+
+/// Plays the application: takes the next command, which must be `query`, and answers it.
+async fn answer(query: &[u8], body: &'static [u8]) {
+    let (cmd, _) = next_command().await;
+    assert_eq!(&cmd.data[..cmd.len], query);
+    resp_sender()
+        .send(Response::from_static(body, cmd.token))
+        .await;
+}
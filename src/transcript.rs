@@ -0,0 +1,326 @@
+//! The golden transcripts of `host/transcript.py`, recorded against the mock transport: each
+//! session in `host/sessions/` is played through a [`Host`] step by step, with the firmware's
+//! application task answering, and the traffic written out in the same format as the Python
+//! tool writes it. The result must match `host/golden/<session>.txt`, so a hardware run and
+//! this replay check the same file.
+//!
+//! `BLESS=1 cargo test --lib --target x86_64-unknown-linux-gnu transcript` records the
+//! goldens afresh. A session without a golden fails the test; record it with `BLESS=1` and
+//! check it in once the transcript reads as the behavior you meant.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use embassy_futures::select::{Either, select};
+
+use crate::control::{CAPABILITIES_LEN, ControlRequest, Status};
+use crate::header::{HEADER_LEN, MsgId};
+use crate::testing::{Host, Traffic, session};
+use crate::{Response, TmcConfig, cmd_receiver, resp_sender};
+
+/// What `src/main.rs` answers every command with.
+const FIRMWARE_IDN: &[u8] = b"RP2350-USBTMC,1,0,FW1.0\n";
+
+/// Endpoint addresses the abort requests go to, as embassy-usb allocates them on the RP2350.
+pub(crate) const EP_OUT: u16 = 0x01;
+pub(crate) const EP_IN: u16 = 0x81;
+
+/// How a step failed, noted in the transcript as `host/transcript.py` notes the exceptions.
+enum Failure {
+    Timeout,
+    /// The device stalled the control request.
+    Stall,
+    Protocol(String),
+}
+
+impl Failure {
+    fn note(&self) -> String {
+        match self {
+            Failure::Timeout => "timeout".into(),
+            Failure::Stall => "usb error: Pipe error".into(),
+            Failure::Protocol(error) => format!("protocol error: {error}"),
+        }
+    }
+}
+
+/// The host side of `host/tmc.py`, over a [`Host`].
+struct Replay {
+    host: Host,
+    last_out_btag: u8,
+    last_in_btag: u8,
+    stb_btag: u8,
+}
+
+impl Replay {
+    fn control(
+        &mut self,
+        request: ControlRequest,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Result<Vec<u8>, Failure> {
+        self.host
+            .control_at(request, value, index, length)
+            .ok_or(Failure::Stall)
+    }
+
+    async fn write(&mut self, message: &[u8], eom: bool) {
+        self.last_out_btag = self.host.write_message(message, eom).await;
+    }
+
+    async fn read(&mut self, max_len: u32) -> Result<Vec<u8>, Failure> {
+        let mut message = Vec::new();
+        loop {
+            let (payload, eom) = self.read_transfer(max_len).await?;
+            message.extend_from_slice(&payload);
+            if eom {
+                return Ok(message);
+            }
+        }
+    }
+
+    async fn read_transfer(&mut self, max_len: u32) -> Result<(Vec<u8>, bool), Failure> {
+        let b_tag = self.host.request_read(max_len).await;
+        self.last_in_btag = b_tag;
+        let reply = self
+            .host
+            .try_read_transfer()
+            .await
+            .ok_or(Failure::Timeout)?;
+        if reply.header.msg_id != MsgId::DevDepMsgIn || reply.header.b_tag != b_tag {
+            let header = hex(&reply.raw[..HEADER_LEN], "");
+            return Err(Failure::Protocol(format!("bad Bulk-IN header: {header}")));
+        }
+        Ok((reply.data().to_vec(), reply.eom()))
+    }
+
+    async fn clear(&mut self) -> Result<(), Failure> {
+        let status = self.control(ControlRequest::InitiateClear, 0, 0, 1)?[0];
+        if status != Status::Success as u8 {
+            return Err(Failure::Protocol(format!("INITIATE_CLEAR: {status:#04x}")));
+        }
+        loop {
+            self.host.idle().await;
+            let answer = self.control(ControlRequest::CheckClearStatus, 0, 0, 2)?;
+            if answer[0] != Status::Pending as u8 {
+                break;
+            }
+            if answer[1] & 0x01 != 0 {
+                self.host.drain_in();
+            }
+        }
+        self.host.flush_out();
+        Ok(())
+    }
+
+    async fn abort_out(&mut self) -> Result<Option<u32>, Failure> {
+        let value = self.last_out_btag.into();
+        let status = self.control(ControlRequest::InitiateAbortBulkOut, value, EP_OUT, 2)?[0];
+        if status != Status::Success as u8 {
+            return Ok(None);
+        }
+        let answer = loop {
+            self.host.idle().await;
+            let answer = self.control(ControlRequest::CheckAbortBulkOutStatus, 0, EP_OUT, 8)?;
+            if answer[0] != Status::Pending as u8 {
+                break answer;
+            }
+        };
+        self.host.flush_out();
+        Ok(Some(u32::from_le_bytes(answer[4..8].try_into().unwrap())))
+    }
+
+    async fn abort_in(&mut self) -> Result<Option<u32>, Failure> {
+        let value = self.last_in_btag.into();
+        let status = self.control(ControlRequest::InitiateAbortBulkIn, value, EP_IN, 2)?[0];
+        if status != Status::Success as u8 {
+            return Ok(None);
+        }
+        loop {
+            self.host.idle().await;
+            let answer = self.control(ControlRequest::CheckAbortBulkInStatus, 0, EP_IN, 8)?;
+            if answer[0] != Status::Pending as u8 {
+                return Ok(Some(u32::from_le_bytes(answer[4..8].try_into().unwrap())));
+            }
+            if answer[1] & 0x01 != 0 {
+                self.host.drain_in();
+            }
+        }
+    }
+
+    /// Runs one script step. Returns the note it leaves, if any.
+    async fn step(&mut self, step: &str, arg: &str) -> Result<Option<String>, Failure> {
+        let max_len = || arg.parse().unwrap_or(1024);
+        match step {
+            "write" => self.write(format!("{arg}\n").as_bytes(), true).await,
+            "write-part" => self.write(arg.as_bytes(), false).await,
+            "read" => {
+                self.read(max_len()).await?;
+            }
+            "read-part" => {
+                self.read_transfer(max_len()).await?;
+            }
+            "clear" => self.clear().await?,
+            "abort-out" => {
+                let taken = self.abort_out().await?;
+                return Ok(Some(format!("bytes taken: {}", shown(taken))));
+            }
+            "abort-in" => {
+                let sent = self.abort_in().await?;
+                return Ok(Some(format!("bytes sent: {}", shown(sent))));
+            }
+            "capabilities" => {
+                let length = CAPABILITIES_LEN as u16;
+                self.control(ControlRequest::GetCapabilities, 0, 0, length)?;
+            }
+            "stb" => {
+                self.stb_btag = self.stb_btag % 127 + 1;
+                let value = self.stb_btag.into();
+                self.control(ControlRequest::ReadStatusByte, value, 0, 3)?;
+            }
+            "pulse" => {
+                self.control(ControlRequest::IndicatorPulse, 0, 0, 1)?;
+            }
+            _ => panic!("unknown step {step:?}"),
+        }
+        Ok(None)
+    }
+}
+
+/// A count as Python prints it, `None` when the abort wasn't taken.
+fn shown(count: Option<u32>) -> String {
+    count.map_or("None".into(), |count| format!("{count}"))
+}
+
+fn hex(bytes: &[u8], separator: &str) -> String {
+    let digits: Vec<String> = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    digits.join(separator)
+}
+
+fn printable(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| {
+            if (0x20..0x7F).contains(&b) {
+                b as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+/// Transcript lines for one tapped transfer or request, 16 bytes to a line.
+fn tap_lines(traffic: &Traffic, lines: &mut Vec<String>) {
+    let (arrow, kind, data) = match traffic {
+        Traffic::Out(data) => ('>', "out", data),
+        Traffic::In(data) => ('<', "in", data),
+        Traffic::Ctrl(data) => ('=', "ctrl", data),
+    };
+    for offset in (0..data.len().max(1)).step_by(16) {
+        let chunk = &data[offset.min(data.len())..(offset + 16).min(data.len())];
+        lines.push(format!(
+            "{arrow} {kind:4} {:47}  |{}|",
+            hex(chunk, " "),
+            printable(chunk)
+        ));
+    }
+}
+
+/// Plays `script` against the class as `src/main.rs` sets it up. Returns the transcript and
+/// the traffic it is made of.
+pub(crate) fn record(script: &str) -> (String, Vec<Traffic>) {
+    let mut lines = Vec::new();
+    let mut traffic = Vec::new();
+    session(TmcConfig::default(), |host| {
+        let (lines, traffic) = (&mut lines, &mut traffic);
+        async move {
+            let mut replay = Replay {
+                host,
+                last_out_btag: 0,
+                last_in_btag: 0,
+                stb_btag: 1,
+            };
+            let steps = async {
+                // Every session starts from a cleared device, untapped, as in transcript.py.
+                let _ = replay.clear().await;
+                replay.host.traffic.clear();
+                for raw in script.lines() {
+                    let line = raw.split('#').next().unwrap().trim();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    lines.push(format!("## {line}"));
+                    let (step, arg) = line.split_once(' ').unwrap_or((line, ""));
+                    let note = replay.step(step, arg).await;
+                    for tapped in replay.host.traffic.drain(..) {
+                        tap_lines(&tapped, lines);
+                        traffic.push(tapped);
+                    }
+                    match note {
+                        Ok(Some(note)) => lines.push(format!("  {note}")),
+                        Ok(None) => {}
+                        Err(failure) => lines.push(format!("  {}", failure.note())),
+                    }
+                }
+            };
+            match select(firmware_app(), steps).await {
+                Either::First(never) => never,
+                Either::Second(()) => {}
+            }
+        }
+    });
+    (lines.join("\n") + "\n", traffic)
+}
+
+/// The application task of `src/main.rs`: every command is answered with the `*IDN?` string.
+async fn firmware_app() -> ! {
+    loop {
+        let cmd = cmd_receiver().receive().await;
+        let resp = Response::from_static(FIRMWARE_IDN, cmd.token);
+        let _ = resp_sender().try_send(resp);
+    }
+}
+
+/// The session scripts, sorted by name.
+pub(crate) fn sessions() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("host/sessions");
+    let mut scripts: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("host/sessions")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "txt"))
+        .collect();
+    scripts.sort();
+    scripts
+}
+
+#[test]
+fn goldens() {
+    let golden_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("host/golden");
+    let bless = std::env::var_os("BLESS").is_some();
+    for script in sessions() {
+        let (actual, _) = record(&fs::read_to_string(&script).unwrap());
+        let golden = golden_dir.join(script.file_name().unwrap());
+        if bless {
+            fs::write(&golden, &actual).unwrap();
+            continue;
+        }
+        let Ok(expected) = fs::read_to_string(&golden) else {
+            panic!("{} is missing; BLESS=1 records it", golden.display());
+        };
+        for (number, (want, got)) in expected.lines().zip(actual.lines()).enumerate() {
+            assert!(
+                want == got,
+                "{}:{}: golden `{want}`, replay `{got}`; BLESS=1 records it afresh",
+                golden.display(),
+                number + 1
+            );
+        }
+        assert_eq!(
+            expected.lines().count(),
+            actual.lines().count(),
+            "{}: transcript length differs; BLESS=1 records it afresh",
+            golden.display()
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use embassy_time::Duration;
+use embassy_usb::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+
+use crate::clock;
+
+/// One of the two bulk pipes of a USBTMC link.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Pipe {
+    BulkOut,
+    BulkIn,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TransportError {
+    /// The link is down, e.g. the USB device is not configured.
+    Disabled,
+    /// A packet did not fit the buffer it was read into.
+    BufferOverflow,
+}
+
+impl From<EndpointError> for TransportError {
+    fn from(err: EndpointError) -> Self {
+        match err {
+            EndpointError::BufferOverflow => TransportError::BufferOverflow,
+            EndpointError::Disabled => TransportError::Disabled,
+        }
+    }
+}
+
+/// Packet pipes the USBTMC message layer runs over.
+///
+/// The runner owns all framing: headers, alignment, aborts and clears. A transport only moves
+/// packets, so the same protocol core can sit on another embassy-usb driver wrapper or on a
+/// test double that replays host traffic.
+#[allow(async_fn_in_trait)]
+pub trait TmcTransport {
+    /// Largest packet moved per call. A shorter packet ends a transfer.
+    fn max_packet_size(&self) -> usize;
+
+    /// Waits for the next Bulk-OUT packet and returns its length.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, TransportError>;
+
+    /// Sends one Bulk-IN packet of at most [`max_packet_size`](Self::max_packet_size) bytes.
+    async fn write(&mut self, data: &[u8]) -> Result<(), TransportError>;
+
+    /// Shows the peer that `pipe` is halted, if the link has a way to.
+    ///
+    /// The runner stops servicing a halted pipe by itself, so the default does nothing.
+    fn set_halt(&mut self, _pipe: Pipe, _halted: bool) {}
+
+    /// Waits until the link is up again after a read or write returned
+    /// [`TransportError::Disabled`]. The default returns at once.
+    async fn wait_enabled(&mut self) {}
+}
+
+/// Writes `data` as a single Bulk-IN transfer, split into packets.
+///
+/// With `zlp`, a transfer that fills its last packet is closed with a zero-length packet so
+/// hosts reading into a larger buffer don't wait for more.
+pub async fn write_transfer<T: TmcTransport>(
+    transport: &mut T,
+    data: &[u8],
+    zlp: bool,
+) -> Result<(), TransportError> {
+    write_transfer_paced(transport, data, zlp, 0).await
+}
+
+/// [`write_transfer`] at no more than `bytes_per_ms` on average, 0 for no limit.
+///
+/// Packets go out at full speed but the next one waits until the transfer is back under the
+/// rate, so other endpoints on the bus get their share during a long readout.
+pub async fn write_transfer_paced<T: TmcTransport>(
+    transport: &mut T,
+    data: &[u8],
+    zlp: bool,
+    bytes_per_ms: u32,
+) -> Result<(), TransportError> {
+    let mps = transport.max_packet_size();
+    let start = clock::now();
+    let mut sent = 0u64;
+    for packet in data.chunks(mps) {
+        if bytes_per_ms > 0 {
+            let due = Duration::from_micros(sent * 1000 / bytes_per_ms as u64);
+            clock::at(start + due).await;
+        }
+        transport.write(packet).await?;
+        sent += packet.len() as u64;
+    }
+    if zlp && data.len() % mps == 0 {
+        transport.write(&[]).await?;
+    }
+    Ok(())
+}
+
+/// [`TmcTransport`] over a pair of embassy-usb bulk endpoints.
+pub struct EndpointTransport<'d, D: Driver<'d>> {
+    out: D::EndpointOut,
+    inp: D::EndpointIn,
+}
+
+impl<'d, D: Driver<'d>> EndpointTransport<'d, D> {
+    pub fn new(out: D::EndpointOut, inp: D::EndpointIn) -> Self {
+        Self { out, inp }
+    }
+}
+
+impl<'d, D: Driver<'d>> TmcTransport for EndpointTransport<'d, D> {
+    fn max_packet_size(&self) -> usize {
+        self.out.info().max_packet_size as usize
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, TransportError> {
+        Ok(self.out.read(buf).await?)
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), TransportError> {
+        Ok(self.inp.write(data).await?)
+    }
+
+    async fn wait_enabled(&mut self) {
+        self.out.wait_enabled().await;
+    }
+}
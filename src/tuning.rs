@@ -0,0 +1,156 @@
+//! Tuning the class at runtime through a [`Control`] handle, e.g. from
+//! `SYSTem:COMMunicate:USB:*` commands, without rebuilding the firmware.
+//!
+//! [`UsbTmc::new`](crate::UsbTmc::new) starts every setting out at its [`TmcConfig`] value.
+//! A change takes effect from the next message the runner reads; a transfer already under
+//! way finishes under the old settings.
+
+use core::cell::RefCell;
+use core::sync::atomic::Ordering;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Duration;
+
+use crate::{Conformance, EmptyReadPolicy, OverflowPolicy, STRICT, TmcConfig};
+
+/// The settings a [`Control`] handle can change.
+#[derive(Clone, Copy)]
+struct Tuning {
+    response_timeout: Option<Duration>,
+    response_ttl: Option<Duration>,
+    empty_read: EmptyReadPolicy,
+    overflow: OverflowPolicy,
+    conformance: Conformance,
+    /// Set by a setter, cleared once the runner has picked the change up.
+    changed: bool,
+}
+
+static TUNING: Mutex<CriticalSectionRawMutex, RefCell<Tuning>> = Mutex::new(RefCell::new(Tuning {
+    response_timeout: None,
+    response_ttl: None,
+    empty_read: EmptyReadPolicy::Wait,
+    overflow: OverflowPolicy::DropNewest,
+    conformance: Conformance::Lenient,
+    changed: false,
+}));
+
+/// Starts the settings out at `config`'s.
+pub(crate) fn init(config: &TmcConfig) {
+    TUNING.lock(|tuning| {
+        *tuning.borrow_mut() = Tuning {
+            response_timeout: config.response_timeout,
+            response_ttl: config.response_ttl,
+            empty_read: config.empty_read,
+            overflow: config.overflow,
+            conformance: config.conformance,
+            changed: false,
+        }
+    });
+}
+
+/// Copies changed settings into the runner's `config`.
+pub(crate) fn apply(config: &mut TmcConfig) {
+    TUNING.lock(|tuning| {
+        let mut tuning = tuning.borrow_mut();
+        if !tuning.changed {
+            return;
+        }
+        tuning.changed = false;
+        config.response_timeout = tuning.response_timeout;
+        config.response_ttl = tuning.response_ttl;
+        config.empty_read = tuning.empty_read;
+        config.overflow = tuning.overflow;
+        config.conformance = tuning.conformance;
+    });
+}
+
+fn update(f: impl FnOnce(&mut Tuning)) {
+    TUNING.lock(|tuning| {
+        let mut tuning = tuning.borrow_mut();
+        f(&mut tuning);
+        tuning.changed = true;
+    });
+}
+
+fn get<T>(f: impl FnOnce(&Tuning) -> T) -> T {
+    TUNING.lock(|tuning| f(&tuning.borrow()))
+}
+
+/// Changes the running class's settings, see [`tuning`](self). Get one from
+/// [`UsbTmc::control`](crate::UsbTmc::control); it is `Copy`, so the task handling the
+/// instrument's commands can keep its own.
+#[derive(Clone, Copy)]
+pub struct Control {
+    _private: (),
+}
+
+impl Control {
+    pub(crate) const fn new() -> Self {
+        Self { _private: () }
+    }
+
+    /// See [`TmcConfig::response_timeout`].
+    pub fn set_response_timeout(&self, timeout: Option<Duration>) {
+        update(|tuning| tuning.response_timeout = timeout);
+    }
+
+    pub fn response_timeout(&self) -> Option<Duration> {
+        get(|tuning| tuning.response_timeout)
+    }
+
+    /// See [`TmcConfig::response_ttl`]. Queries already waiting are held to the new limit.
+    pub fn set_response_ttl(&self, ttl: Option<Duration>) {
+        update(|tuning| tuning.response_ttl = ttl);
+    }
+
+    pub fn response_ttl(&self) -> Option<Duration> {
+        get(|tuning| tuning.response_ttl)
+    }
+
+    /// See [`TmcConfig::empty_read`].
+    pub fn set_empty_read(&self, policy: EmptyReadPolicy) {
+        update(|tuning| tuning.empty_read = policy);
+    }
+
+    pub fn empty_read(&self) -> EmptyReadPolicy {
+        get(|tuning| tuning.empty_read)
+    }
+
+    /// See [`TmcConfig::overflow`].
+    pub fn set_overflow(&self, policy: OverflowPolicy) {
+        update(|tuning| tuning.overflow = policy);
+    }
+
+    pub fn overflow(&self) -> OverflowPolicy {
+        get(|tuning| tuning.overflow)
+    }
+
+    /// See [`TmcConfig::conformance`]. [`host_quirks`](crate::host_quirks) follows at once.
+    pub fn set_conformance(&self, conformance: Conformance) {
+        update(|tuning| tuning.conformance = conformance);
+        STRICT.store(conformance == Conformance::Strict, Ordering::Relaxed);
+    }
+
+    pub fn conformance(&self) -> Conformance {
+        get(|tuning| tuning.conformance)
+    }
+
+    /// See [`set_in_pacing`](crate::set_in_pacing).
+    pub fn set_in_pacing(&self, bytes_per_ms: u32) {
+        crate::set_in_pacing(bytes_per_ms);
+    }
+
+    pub fn in_pacing(&self) -> u32 {
+        crate::in_pacing()
+    }
+
+    /// See [`set_in_chunk_size`](crate::set_in_chunk_size).
+    pub fn set_in_chunk_size(&self, bytes: u32) {
+        crate::set_in_chunk_size(bytes);
+    }
+
+    pub fn in_chunk_size(&self) -> u32 {
+        crate::in_chunk_size()
+    }
+}
@@ -0,0 +1,155 @@
+//! Resumable uploads over vendor-specific messages, for data too big for one transfer such as
+//! firmware assets or waveform banks.
+//!
+//! With [`TmcConfig::vendor_upload`](crate::TmcConfig::vendor_upload) the class takes over
+//! both vendor-specific directions:
+//!
+//! - Each VENDOR_SPECIFIC_OUT carries one chunk: its offset in the upload (u32 LE), the
+//!   upload's total length (u32 LE), then the data.
+//! - Each REQUEST_VENDOR_SPECIFIC_IN is answered with the upload's progress: bytes accepted
+//!   so far (u32 LE) and the total (u32 LE).
+//!
+//! A chunk at offset 0 with a new total, or after the last upload completed, starts a new
+//! upload. Otherwise the class only moves forward: a chunk that overlaps what was accepted
+//! has the repeated part cut off, and one past the end of it, leaving a gap, is dropped. So
+//! after a failed or aborted transfer the host reads the progress and resends from there;
+//! the application sees each byte once, in order, on [`chunk_receiver`].
+//!
+//! A chunk is only accepted once it is queued for the application, so a busy application
+//! slows the upload down but doesn't lose data: the host finds the progress short and
+//! resends.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::{Channel, Receiver};
+
+use crate::MAX_SCPI_LEN;
+
+/// Offset and total in front of each chunk's data.
+pub const CHUNK_HEADER_LEN: usize = 8;
+
+/// Length of the progress report.
+pub const PROGRESS_LEN: usize = 8;
+
+/// A piece of the upload, in order.
+#[derive(Clone)]
+pub struct Chunk {
+    /// Where [`data`](Self::data) goes in the upload.
+    pub offset: u32,
+    /// Length of the whole upload.
+    pub total: u32,
+    pub len: usize,
+    pub data: [u8; MAX_SCPI_LEN],
+}
+
+impl Chunk {
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    /// Whether this chunk completes the upload.
+    pub fn is_last(&self) -> bool {
+        self.offset as usize + self.len == self.total as usize
+    }
+}
+
+/// Progress of the current upload.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Bytes handed to the application.
+    pub accepted: u32,
+    pub total: u32,
+}
+
+impl Progress {
+    pub fn is_complete(&self) -> bool {
+        self.accepted == self.total
+    }
+}
+
+static CHUNKS: Channel<CriticalSectionRawMutex, Chunk, 2> = Channel::new();
+static PROGRESS: Mutex<CriticalSectionRawMutex, Cell<Progress>> = Mutex::new(Cell::new(Progress {
+    accepted: 0,
+    total: 0,
+}));
+
+/// The upload's chunks, in order, each byte once.
+pub fn chunk_receiver() -> Receiver<'static, CriticalSectionRawMutex, Chunk, 2> {
+    CHUNKS.receiver()
+}
+
+pub fn progress() -> Progress {
+    PROGRESS.lock(Cell::get)
+}
+
+/// Abandons the current upload, e.g. when the application can't store it; the host sees
+/// no progress and has to start over.
+pub fn reset() {
+    PROGRESS.lock(|progress| {
+        progress.set(Progress {
+            accepted: 0,
+            total: 0,
+        })
+    });
+}
+
+/// What became of a VENDOR_SPECIFIC_OUT payload.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Accepted {
+    /// Queued for the application, possibly after cutting off a repeated part.
+    Queued,
+    /// Nothing new in it, or the application's queue was full; the host resends.
+    Ignored,
+    /// Shorter than the chunk header, for another upload, or leaving a gap.
+    Invalid,
+}
+
+/// Takes a chunk from the class.
+pub(crate) fn accept(payload: &[u8]) -> Accepted {
+    let Some((header, data)) = payload.split_first_chunk::<CHUNK_HEADER_LEN>() else {
+        return Accepted::Invalid;
+    };
+    let offset = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let total = u32::from_le_bytes(header[4..].try_into().unwrap());
+    PROGRESS.lock(|cell| {
+        let mut progress = cell.get();
+        if offset == 0 && (total != progress.total || progress.is_complete()) {
+            progress = Progress { accepted: 0, total };
+        }
+        let end = offset as u64 + data.len() as u64;
+        if total != progress.total || offset > progress.accepted || end > total as u64 {
+            return Accepted::Invalid;
+        }
+        let skip = (progress.accepted - offset) as usize;
+        if skip >= data.len() {
+            cell.set(progress);
+            return Accepted::Ignored;
+        }
+        let fresh = &data[skip..];
+        let mut chunk = Chunk {
+            offset: progress.accepted,
+            total,
+            len: fresh.len(),
+            data: [0; MAX_SCPI_LEN],
+        };
+        chunk.data[..fresh.len()].copy_from_slice(fresh);
+        if CHUNKS.try_send(chunk).is_err() {
+            cell.set(progress);
+            return Accepted::Ignored;
+        }
+        progress.accepted += fresh.len() as u32;
+        cell.set(progress);
+        Accepted::Queued
+    })
+}
+
+/// The progress report answering REQUEST_VENDOR_SPECIFIC_IN.
+pub(crate) fn progress_report() -> [u8; PROGRESS_LEN] {
+    let progress = progress();
+    let mut report = [0; PROGRESS_LEN];
+    report[..4].copy_from_slice(&progress.accepted.to_le_bytes());
+    report[4..].copy_from_slice(&progress.total.to_le_bytes());
+    report
+}
@@ -0,0 +1,127 @@
+//! High-water marks of the class's buffers and queues, and of the stack the runner is polled
+//! on, for sizing [`MAX_SCPI_LEN`](crate::MAX_SCPI_LEN), [`UsbTmc`](crate::UsbTmc)'s
+//! `IN_STAGING` and the queue depths from a soak run instead of by guesswork.
+//!
+//! The buffer marks are kept from start-up on. The stack mark needs the stack painted first:
+//! call [`paint_stack`] early in `main`, before the executor starts, with the lowest address
+//! of the stack (`_stack_end` under cortex-m-rt, `__stack_limit`-style symbols elsewhere).
+//! Every task on an executor is polled on the same stack, so the figure covers all of them,
+//! interrupts at the same stack included; it is an upper bound for the runner.
+
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Peak use since start-up or the last [`reset`].
+#[derive(Clone, Copy, Default)]
+pub struct Usage {
+    /// Most commands waiting in [`cmd_receiver`](crate::cmd_receiver)'s queue at once.
+    pub cmd_queue: usize,
+    /// Most commands waiting in [`priority_cmd_receiver`](crate::priority_cmd_receiver)'s
+    /// queue at once.
+    pub priority_queue: usize,
+    /// Most responses queued when the host came to read.
+    pub resp_queue: usize,
+    /// Longest command payload, out of [`MAX_SCPI_LEN`](crate::MAX_SCPI_LEN).
+    pub command_len: usize,
+    /// Longest response body, out of [`MAX_SCPI_LEN`](crate::MAX_SCPI_LEN).
+    pub response_len: usize,
+    /// Most of the Bulk-IN staging buffer one DEV_DEP_MSG_IN took, header and padding
+    /// included, out of `IN_STAGING`.
+    pub in_staging: usize,
+    /// Bytes of stack used below the point [`paint_stack`] was called from, `None` if it
+    /// wasn't.
+    pub stack: Option<usize>,
+}
+
+/// Fill pattern for unused stack.
+const PAINT: u32 = 0xC0FF_EE55;
+
+static CMD_QUEUE: AtomicUsize = AtomicUsize::new(0);
+static PRIORITY_QUEUE: AtomicUsize = AtomicUsize::new(0);
+static RESP_QUEUE: AtomicUsize = AtomicUsize::new(0);
+static COMMAND_LEN: AtomicUsize = AtomicUsize::new(0);
+static RESPONSE_LEN: AtomicUsize = AtomicUsize::new(0);
+static IN_STAGING: AtomicUsize = AtomicUsize::new(0);
+/// The painted stack region, lowest address first; both zero until painted.
+static STACK_LIMIT: AtomicUsize = AtomicUsize::new(0);
+static STACK_TOP: AtomicUsize = AtomicUsize::new(0);
+
+/// Fills the stack from `limit` up to a little below the caller's frame with a pattern that
+/// [`usage`] later scans for.
+///
+/// # Safety
+///
+/// `limit` must be the lowest address of the stack this is called on, 4-byte aligned, with
+/// nothing below the current stack pointer worth keeping.
+pub unsafe fn paint_stack(limit: *mut u32) {
+    let marker = 0u32;
+    // Stay clear of this function's own frame and of whatever the writes below spill.
+    let top = (ptr::addr_of!(marker) as usize).saturating_sub(256) & !3;
+    let mut word = limit;
+    while (word as usize) < top {
+        // SAFETY: below the live stack, as the caller promises.
+        unsafe {
+            ptr::write_volatile(word, PAINT);
+            word = word.add(1);
+        }
+    }
+    STACK_LIMIT.store(limit as usize, Ordering::Relaxed);
+    STACK_TOP.store(top, Ordering::Relaxed);
+}
+
+/// Deepest the stack has grown into the painted region, in bytes.
+fn stack_used() -> Option<usize> {
+    let limit = STACK_LIMIT.load(Ordering::Relaxed);
+    let top = STACK_TOP.load(Ordering::Relaxed);
+    if top == 0 {
+        return None;
+    }
+    let mut word = limit as *const u32;
+    // SAFETY: the region was painted by `paint_stack` and stays stack memory; reading a word
+    // another frame is using only races with that frame's own writes to the same value.
+    while (word as usize) < top && unsafe { ptr::read_volatile(word) } == PAINT {
+        word = unsafe { word.add(1) };
+    }
+    Some(top - word as usize)
+}
+
+pub fn usage() -> Usage {
+    Usage {
+        cmd_queue: CMD_QUEUE.load(Ordering::Relaxed),
+        priority_queue: PRIORITY_QUEUE.load(Ordering::Relaxed),
+        resp_queue: RESP_QUEUE.load(Ordering::Relaxed),
+        command_len: COMMAND_LEN.load(Ordering::Relaxed),
+        response_len: RESPONSE_LEN.load(Ordering::Relaxed),
+        in_staging: IN_STAGING.load(Ordering::Relaxed),
+        stack: stack_used(),
+    }
+}
+
+/// Starts the buffer marks over. The stack mark stays; paint the stack again to reset it.
+pub fn reset() {
+    for mark in [
+        &CMD_QUEUE,
+        &PRIORITY_QUEUE,
+        &RESP_QUEUE,
+        &COMMAND_LEN,
+        &RESPONSE_LEN,
+        &IN_STAGING,
+    ] {
+        mark.store(0, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn note_command(len: usize, cmd_queue: usize, priority_queue: usize) {
+    COMMAND_LEN.fetch_max(len, Ordering::Relaxed);
+    CMD_QUEUE.fetch_max(cmd_queue, Ordering::Relaxed);
+    PRIORITY_QUEUE.fetch_max(priority_queue, Ordering::Relaxed);
+}
+
+pub(crate) fn note_read(resp_queue: usize) {
+    RESP_QUEUE.fetch_max(resp_queue, Ordering::Relaxed);
+}
+
+pub(crate) fn note_response(len: usize, framed: usize) {
+    RESPONSE_LEN.fetch_max(len, Ordering::Relaxed);
+    IN_STAGING.fetch_max(framed, Ordering::Relaxed);
+}
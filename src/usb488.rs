@@ -0,0 +1,121 @@
+//! USB488 subclass layer: IEEE 488.1 remote/local state and device triggers.
+//!
+//! Sits on the USBTMC core; the control handler and the runner call in here for REN_CONTROL,
+//! GO_TO_LOCAL, LOCAL_LOCKOUT and TRIGGER.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Instant;
+
+use crate::control::{self, ControlRequest};
+use crate::events::{self, ClassEvent};
+use crate::{CONTROL_WORK, TmcConfig, clock};
+
+static TRIGGER_COUNT: AtomicU32 = AtomicU32::new(0);
+static LAST_TRIGGER: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+static REN: AtomicBool = AtomicBool::new(false);
+static REMOTE_STATE: AtomicU8 = AtomicU8::new(RemoteState::Local as u8);
+/// Last value [`TmcConfig::on_remote_change`] was told about.
+static NOTIFIED_REMOTE_STATE: AtomicU8 = AtomicU8::new(RemoteState::Local as u8);
+
+/// Number of USB488 TRIGGER messages received since power-up. Wraps at `u32::MAX`.
+pub fn trigger_count() -> u32 {
+    TRIGGER_COUNT.load(Ordering::Relaxed)
+}
+
+/// When the most recent TRIGGER message was received, `None` before the first.
+pub fn last_trigger() -> Option<Instant> {
+    LAST_TRIGGER.lock(Cell::get)
+}
+
+/// IEEE 488.1 remote/local state, driven by the USB488 REN_CONTROL, GO_TO_LOCAL and
+/// LOCAL_LOCKOUT requests and by messages arriving while REN is asserted.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RemoteState {
+    /// LOCS: front panel in control.
+    Local,
+    /// REMS: host in control; the front panel's "local" key may take it back.
+    Remote,
+    /// LWLS: front panel in control until the host next addresses the device.
+    LocalLockout,
+    /// RWLS: host in control and the "local" key is locked out too.
+    RemoteLockout,
+}
+
+impl RemoteState {
+    /// Whether the front panel should accept input in this state.
+    pub fn front_panel_enabled(self) -> bool {
+        matches!(self, RemoteState::Local | RemoteState::LocalLockout)
+    }
+}
+
+pub fn remote_state() -> RemoteState {
+    match REMOTE_STATE.load(Ordering::Relaxed) {
+        1 => RemoteState::Remote,
+        2 => RemoteState::LocalLockout,
+        3 => RemoteState::RemoteLockout,
+        _ => RemoteState::Local,
+    }
+}
+
+fn set_remote_state(state: RemoteState) {
+    REMOTE_STATE.store(state as u8, Ordering::Relaxed);
+    CONTROL_WORK.signal(());
+}
+
+/// Applies REN_CONTROL, GO_TO_LOCAL or LOCAL_LOCKOUT.
+pub(crate) fn remote_request(request: ControlRequest, w_value: u16) {
+    let lockout = matches!(
+        remote_state(),
+        RemoteState::LocalLockout | RemoteState::RemoteLockout
+    );
+    match request {
+        ControlRequest::RenControl => {
+            let asserted = control::value_ren(w_value);
+            REN.store(asserted, Ordering::Relaxed);
+            if !asserted {
+                set_remote_state(RemoteState::Local);
+            }
+        }
+        ControlRequest::GoToLocal if lockout => set_remote_state(RemoteState::LocalLockout),
+        ControlRequest::GoToLocal => set_remote_state(RemoteState::Local),
+        _ if !remote_state().front_panel_enabled() => set_remote_state(RemoteState::RemoteLockout),
+        _ => set_remote_state(RemoteState::LocalLockout),
+    }
+}
+
+/// Being addressed with REN asserted puts the device in remote.
+pub(crate) fn addressed() {
+    if REN.load(Ordering::Relaxed) {
+        match remote_state() {
+            RemoteState::Local => set_remote_state(RemoteState::Remote),
+            RemoteState::LocalLockout => set_remote_state(RemoteState::RemoteLockout),
+            RemoteState::Remote | RemoteState::RemoteLockout => {}
+        }
+    }
+}
+
+/// Handles a TRIGGER message.
+pub(crate) fn trigger(config: &TmcConfig) {
+    if let Some(on_trigger) = config.on_trigger {
+        on_trigger();
+    }
+    LAST_TRIGGER.lock(|last| last.set(Some(clock::now())));
+    TRIGGER_COUNT.fetch_add(1, Ordering::Relaxed);
+    events::publish(ClassEvent::Trigger);
+}
+
+/// Tells [`TmcConfig::on_remote_change`] about a new state; called from the runner.
+pub(crate) fn notify_remote_change(config: &TmcConfig) {
+    let state = REMOTE_STATE.load(Ordering::Relaxed);
+    if NOTIFIED_REMOTE_STATE.swap(state, Ordering::Relaxed) != state
+        && let Some(on_remote_change) = config.on_remote_change
+    {
+        on_remote_change(remote_state());
+    }
+}
@@ -0,0 +1,52 @@
+//! USBTMC vendor-specific messages: a binary side channel next to the SCPI message stream.
+//!
+//! VENDOR_SPECIFIC_OUT payloads arrive on [`vendor_receiver`]; a REQUEST_VENDOR_SPECIFIC_IN
+//! is answered with the next message from [`vendor_sender`]. With
+//! [`TmcConfig::vendor_crc`](crate::TmcConfig::vendor_crc) every payload carries a CRC-32
+//! trailer in both directions, so long binary transfers are checked end to end.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::{Channel, Receiver, Sender};
+
+use crate::{Command, Response};
+
+pub(crate) static VENDOR_OUT_CHANNEL: Channel<CriticalSectionRawMutex, Command, 2> = Channel::new();
+pub(crate) static VENDOR_IN_CHANNEL: Channel<CriticalSectionRawMutex, Response, 2> = Channel::new();
+
+/// Length of the CRC-32 trailer.
+pub const CRC_LEN: usize = 4;
+
+/// Payloads of VENDOR_SPECIFIC_OUT messages, trailer already checked and removed.
+pub fn vendor_receiver() -> Receiver<'static, CriticalSectionRawMutex, Command, 2> {
+    VENDOR_OUT_CHANNEL.receiver()
+}
+
+/// Messages for REQUEST_VENDOR_SPECIFIC_IN, one per request. The class appends the trailer;
+/// `eom` and `term_char_matched` don't apply.
+pub fn vendor_sender() -> Sender<'static, CriticalSectionRawMutex, Response, 2> {
+    VENDOR_IN_CHANNEL.sender()
+}
+
+/// CRC-32 (IEEE 802.3, as in zlib and Ethernet) of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+/// Feeds `data` into a running CRC-32 register. Start from `!0` and invert the result.
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
+
+/// Checks the little-endian CRC-32 trailer of `payload`. Returns the length of the data in
+/// front of it, `None` if the trailer is missing or wrong.
+pub(crate) fn check_crc(payload: &[u8]) -> Option<usize> {
+    let len = payload.len().checked_sub(CRC_LEN)?;
+    let trailer = u32::from_le_bytes(payload[len..].try_into().unwrap());
+    (crc32(&payload[..len]) == trailer).then_some(len)
+}
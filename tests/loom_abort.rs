@@ -0,0 +1,105 @@
+//! The abort handshake of [`TransferAbort`] under loom: the control handler's requests and
+//! status checks against the runner finishing its transfer, in every interleaving.
+//!
+//! `src/abort.rs` and `src/sync.rs` are included as they are; under `--cfg loom` in a test
+//! build the atomics are loom's.
+
+#![cfg(loom)]
+
+#[allow(dead_code)]
+#[path = "../src/sync.rs"]
+mod sync;
+
+#[allow(dead_code)]
+#[path = "../src/abort.rs"]
+mod abort;
+
+use abort::TransferAbort;
+use loom::sync::Arc;
+use loom::sync::atomic::{AtomicBool, Ordering};
+use loom::thread;
+
+/// INITIATE_ABORT against the runner finishing the transfer it names: an abort the handler
+/// requested is completed by this `finish`, or refused, so none is left pending; it is retired
+/// exactly once, and one it didn't request never shows up.
+#[test]
+fn request_races_finish() {
+    loom::model(|| {
+        let abort = Arc::new(TransferAbort::new());
+        abort.begin(7);
+
+        let handler = {
+            let abort = abort.clone();
+            thread::spawn(move || abort.btag() == 7 && abort.request(7))
+        };
+        let completed = abort.finish();
+        let requested = handler.join().unwrap();
+
+        assert!(!abort.is_pending());
+        assert_eq!(completed, requested);
+        assert_eq!(abort.retire(), requested);
+        assert!(!abort.retire());
+    });
+}
+
+/// INITIATE_ABORT_BULK_OUT as the control handler answers it, halt included, against the
+/// runner publishing Idle and finishing: whenever the host is told the abort succeeded, the
+/// runner dropped the message and the next status check retires the abort and releases the
+/// halt, so Bulk-OUT never stays halted behind a `finish` that already ran.
+#[test]
+fn abort_out_halts_only_what_finish_completes() {
+    loom::model(|| {
+        let abort = Arc::new(TransferAbort::new());
+        let in_progress = Arc::new(AtomicBool::new(true));
+        let halted = Arc::new(AtomicBool::new(false));
+        abort.begin(7);
+
+        let handler = {
+            let (abort, in_progress, halted) = (abort.clone(), in_progress.clone(), halted.clone());
+            thread::spawn(move || {
+                if abort.btag() != 7 {
+                    false
+                } else if !in_progress.load(Ordering::Relaxed) {
+                    abort.abort_idle(7)
+                } else if abort.request(7) {
+                    halted.store(true, Ordering::Relaxed);
+                    true
+                } else {
+                    false
+                }
+            })
+        };
+        in_progress.store(false, Ordering::Relaxed);
+        let aborted = abort.finish();
+        let success = handler.join().unwrap();
+
+        assert_eq!(aborted, success);
+        assert!(!abort.is_pending());
+        // CHECK_ABORT_BULK_OUT_STATUS: a retired abort clears the halt.
+        if abort.retire() {
+            halted.store(false, Ordering::Relaxed);
+        }
+        assert!(!halted.load(Ordering::Relaxed));
+    });
+}
+
+/// CHECK_ABORT_STATUS against the runner completing a pending abort: the check retires the
+/// abort only once it is done, and a check that comes too early leaves it for the next.
+#[test]
+fn status_check_races_finish() {
+    loom::model(|| {
+        let abort = Arc::new(TransferAbort::new());
+        abort.begin(7);
+        abort.request(7);
+
+        let handler = {
+            let abort = abort.clone();
+            thread::spawn(move || !abort.is_pending() && abort.retire())
+        };
+        assert!(abort.finish());
+        let retired = handler.join().unwrap();
+
+        assert!(!abort.is_pending());
+        assert_eq!(abort.retire(), !retired);
+    });
+}